@@ -0,0 +1,127 @@
+//! # xrust::collation
+//!
+//! Collations determine how strings are compared and sorted.
+//!
+//! A collation is identified by a URI. The Unicode codepoint collation and the HTML ASCII case-insensitive collation are always available. An application can add its own collations by implementing the [Collation] trait and registering it with the [StaticContext](crate::evaluate::StaticContext).
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::cell::RefCell;
+use crate::xdmerror::*;
+
+/// The Unicode codepoint collation. This is the default collation.
+pub const CODEPOINT: &str = "http://www.w3.org/2005/xpath-functions/collation/codepoint";
+/// The HTML ASCII case-insensitive collation.
+pub const HTML_ASCII_CASE_INSENSITIVE: &str = "http://www.w3.org/2005/xpath-functions/collation/html-ascii-case-insensitive";
+
+/// A collation.
+///
+/// Strings are compared by their collation keys: two strings are equal in the collation if their keys are equal. Substring matching (e.g. fn:contains) is also performed on the keys.
+pub trait Collation {
+  /// The collation key for a string.
+  fn key(&self, s: &str) -> String;
+  /// Compare two strings.
+  fn compare(&self, a: &str, b: &str) -> Ordering {
+    self.key(a).cmp(&self.key(b))
+  }
+  /// Are two strings equal in this collation?
+  fn equal(&self, a: &str, b: &str) -> bool {
+    self.key(a) == self.key(b)
+  }
+}
+
+/// Compares strings by their Unicode codepoints.
+pub struct CodepointCollation;
+
+impl Collation for CodepointCollation {
+  fn key(&self, s: &str) -> String {
+    s.to_string()
+  }
+  fn compare(&self, a: &str, b: &str) -> Ordering {
+    // The byte ordering of UTF-8 strings is the same as codepoint ordering
+    a.cmp(b)
+  }
+  fn equal(&self, a: &str, b: &str) -> bool {
+    a == b
+  }
+}
+
+/// Compares strings by their codepoints, after folding ASCII upper case letters to lower case.
+pub struct HtmlAsciiCaseInsensitiveCollation;
+
+impl Collation for HtmlAsciiCaseInsensitiveCollation {
+  fn key(&self, s: &str) -> String {
+    s.to_ascii_lowercase()
+  }
+}
+
+/// A registry of collations, keyed by URI.
+///
+/// Cloning the registry gives a handle to the same set of collations, so that collations registered with the static context are also available during evaluation.
+#[derive(Clone)]
+pub struct Collations(Rc<RefCell<HashMap<String, Rc<dyn Collation>>>>);
+
+impl Collations {
+  /// Create a registry with the builtin collations.
+  pub fn new() -> Self {
+    let c = Collations(Rc::new(RefCell::new(HashMap::new())));
+    c.register(CODEPOINT, Rc::new(CodepointCollation));
+    c.register(HTML_ASCII_CASE_INSENSITIVE, Rc::new(HtmlAsciiCaseInsensitiveCollation));
+    c
+  }
+  /// Register a collation. Replaces any collation already registered with the same URI.
+  pub fn register(&self, uri: &str, c: Rc<dyn Collation>) {
+    self.0.borrow_mut().insert(uri.to_string(), c);
+  }
+  /// Find a collation. If no URI is given then the default collation is returned.
+  /// An unknown collation is an error (FOCH0002).
+  pub fn get(&self, uri: Option<&str>) -> Result<Rc<dyn Collation>, Error> {
+    let u = uri.unwrap_or(CODEPOINT);
+    self.0.borrow().get(u)
+      .cloned()
+      .ok_or_else(|| Error::new(ErrorKind::UnknownCollation, format!("collation \"{}\" is not supported", u)))
+  }
+}
+
+impl Default for Collations {
+  fn default() -> Self {
+    Collations::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn codepoint() {
+    let c = Collations::new().get(None).expect("no default collation");
+    assert_eq!(c.compare("B", "a"), Ordering::Less);
+    assert!(!c.equal("a", "A"));
+  }
+  #[test]
+  fn html_ascii_case_insensitive() {
+    let c = Collations::new().get(Some(HTML_ASCII_CASE_INSENSITIVE)).expect("collation not found");
+    assert_eq!(c.compare("B", "a"), Ordering::Greater);
+    assert!(c.equal("Hello", "hELLO"));
+    // Only ASCII letters are folded
+    assert!(!c.equal("É", "é"));
+  }
+  #[test]
+  fn register() {
+    struct Reverse;
+    impl Collation for Reverse {
+      fn key(&self, s: &str) -> String {
+        s.to_string()
+      }
+      fn compare(&self, a: &str, b: &str) -> Ordering {
+        b.cmp(a)
+      }
+    }
+    let c = Collations::new();
+    c.register("http://example.org/reverse", Rc::new(Reverse));
+    assert_eq!(c.get(Some("http://example.org/reverse")).expect("collation not found").compare("a", "b"), Ordering::Greater);
+    assert!(c.get(Some("http://example.org/unknown")).is_err());
+  }
+}
@@ -9,6 +9,7 @@ use std::cell::{RefCell, RefMut};
 use std::convert::TryFrom;
 use std::collections::HashMap;
 use std::fmt;
+use std::cmp::Ordering;
 use unicode_segmentation::UnicodeSegmentation;
 #[allow(unused_imports)]
 use chrono::{DateTime, Local, Datelike, Timelike, FixedOffset};
@@ -16,35 +17,80 @@ use chrono::{DateTime, Local, Datelike, Timelike, FixedOffset};
 use rust_decimal_macros::dec;
 use crate::qname::*;
 use crate::parsepicture::parse as picture_parse;
+use crate::numbering::{format_integer, format_numbers};
+use crate::parsecommon::is_ncname;
+use crate::collation::{Collation, Collations};
+use crate::resolver::{DocumentResolver, Documents, decode};
+use crate::message::{Message, MessageListener, StderrListener};
+use crate::resultdocument::{ResultDocumentHandler, RejectHandler};
 use crate::xdmerror::*;
-use crate::output::OutputDefinition;
+use crate::output::{OutputDefinition, OutputMethod};
 use crate::value::{Value, Operator};
-use crate::forest::{Forest, TreeIndex, Node, NodeType};
-use crate::item::{Sequence, SequenceTrait, Item};
+pub use crate::value::ArithmeticOperator;
+use crate::forest::{Forest, TreeIndex, Node, NodeType, XMLNS};
+use crate::item::{Sequence, SequenceTrait, Item, Map, Array};
+use crate::json::{self, Duplicates, ParseOptions};
 use url::Url;
 
+/// The namespace URI for the XPath standard functions.
+pub const FNNS: &str = "http://www.w3.org/2005/xpath-functions";
+/// The namespace URI for the XPath math functions.
+pub const MATHNS: &str = "http://www.w3.org/2005/xpath-functions/math";
+/// The namespace URI for the XPath map functions.
+pub const MAPNS: &str = "http://www.w3.org/2005/xpath-functions/map";
+/// The namespace URI for the XPath array functions.
+pub const ARRAYNS: &str = "http://www.w3.org/2005/xpath-functions/array";
+/// The namespace URI for error codes.
+pub const ERRNS: &str = "http://www.w3.org/2005/xqt-errors";
+/// The EXPath binary module namespace.
+pub const BINNS: &str = "http://expath.org/ns/binary";
+/// The XML Schema namespace, for the built-in types.
+pub const XSDNS: &str = "http://www.w3.org/2001/XMLSchema";
+/// The namespace URI for serialization parameters.
+pub const SERNS: &str = "http://www.w3.org/2010/xslt-xquery-serialization";
+
 // The dynamic evaluation context.
 //
 // The dynamic context stores parts that can change as evaluation proceeds,
 // such as the value of declared variables.
 pub struct DynamicContext {
-    vars: RefCell<HashMap<String, Vec<Sequence>>>,
+    vars: RefCell<HashMap<String, Vec<Sequence>>>,	// Local variables, in scope for the current template invocation
+    params: RefCell<HashMap<String, Sequence>>,	// Values supplied for parameters, see set_parameter
+    nesting: RefCell<usize>,	// Depth of calls to evaluate; the outermost call starts a transformation
     depth: RefCell<usize>,
     current_grouping_key: RefCell<Vec<Option<Rc<Item>>>>,
     current_group: RefCell<Vec<Option<Sequence>>>,
     current_import: RefCell<usize>,
+    tunnel: RefCell<Vec<HashMap<String, Sequence>>>,	// Tunnel parameters, for each template invocation
+    current_mode: RefCell<Vec<Mode>>,
+    constructing: RefCell<Vec<Node>>,	// Elements whose content is being constructed, for namespace nodes
+    globals: RefCell<HashMap<QualifiedName, Option<Sequence>>>,	// Values of global variables; None while the value is being evaluated
     deps: RefCell<Vec<Url>>,	// URIs for included/imported stylesheets
+    documents: Documents,	// Secondary documents, loaded by fn:doc, etc
+    result_uris: RefCell<Vec<String>>,	// URIs of the result documents that have been produced
+    keys: RefCell<HashMap<(TreeIndex, QualifiedName), Rc<KeyIndex>>>,	// Indexes for keys, built when a key is first used for a tree
+    idrefs: RefCell<HashMap<TreeIndex, Rc<KeyIndex>>>,	// Attributes, indexed by the IDs they refer to. Built when fn:idref is first used for a tree
 }
 
 impl DynamicContext {
     pub fn new() -> Self {
 	DynamicContext{
 	    vars: RefCell::new(HashMap::new()),
+	    params: RefCell::new(HashMap::new()),
+	    nesting: RefCell::new(0),
 	    depth: RefCell::new(0),
 	    current_grouping_key: RefCell::new(vec![None]),
 	    current_group: RefCell::new(vec![None]),
 	    current_import: RefCell::new(0),
+	    tunnel: RefCell::new(vec![HashMap::new()]),
+	    current_mode: RefCell::new(vec![Mode::Default]),
+	    constructing: RefCell::new(vec![]),
+	    globals: RefCell::new(HashMap::new()),
 	    deps: RefCell::new(vec![]),
+	    documents: Documents::default(),
+	    result_uris: RefCell::new(vec![]),
+	    keys: RefCell::new(HashMap::new()),
+	    idrefs: RefCell::new(HashMap::new()),
 	}
     }
     /// Retrieve the dependencies for the stylesheet
@@ -56,6 +102,10 @@ impl DynamicContext {
     pub fn add_dependency(&self, u: Url) {
 	self.deps.borrow_mut().push(u);
     }
+    /// Use the given resolver to load secondary documents. Any documents that have already been loaded are forgotten.
+    pub fn set_document_resolver(&mut self, r: Rc<dyn DocumentResolver>) {
+	self.documents = Documents::new(r);
+    }
 
     fn push_current_grouping_key(&self, k: Item) {
 	self.current_grouping_key.borrow_mut().push(Some(Rc::new(k)));
@@ -114,7 +164,15 @@ impl DynamicContext {
     // Stylesheet parameters. Overrides the previous value if it is already set.
     // TODO: namespaced name
     pub fn set_parameter(&self, name: String, value: Sequence) {
-	self.vars.borrow_mut().insert(name, vec![value]);
+	self.params.borrow_mut().insert(name, value);
+    }
+
+    // Forget the values that were computed by a previous transformation
+    fn reset(&self) {
+	self.globals.borrow_mut().clear();
+	self.keys.borrow_mut().clear();
+	self.idrefs.borrow_mut().clear();
+	self.result_uris.borrow_mut().clear();
     }
 }
 
@@ -128,6 +186,16 @@ pub struct Evaluator {
     builtin_templates: Vec<Template>,	// TODO: use import precedence for builtins
     od: OutputDefinition,	// Output definition for the final result tree
     base: Option<Url>,	// The base URL of the primary stylesheet
+    collations: Collations,
+    globals: HashMap<QualifiedName, (GlobalVariable, usize)>,	// Global variable declarations, with their import precedence
+    params: HashMap<QualifiedName, Sequence>,	// Values supplied for stylesheet parameters
+    attribute_sets: HashMap<QualifiedName, Vec<Constructor>>,	// Named attribute sets
+    keys: HashMap<QualifiedName, Vec<KeyDefinition>>,	// Key definitions, by name
+    namespaces: HashMap<String, String>,	// Namespace prefixes, for resolving names that are evaluated at run time
+    messages: Rc<dyn MessageListener>,	// Receives the output of xsl:message
+    output_definitions: HashMap<QualifiedName, OutputDefinition>,	// Named output definitions
+    base_output: Option<Url>,	// The base output URI, for resolving the href of result documents
+    results: Rc<dyn ResultDocumentHandler>,	// Receives the output of xsl:result-document
 }
 
 impl Evaluator {
@@ -139,6 +207,16 @@ impl Evaluator {
 	    builtin_templates: Vec::new(),
 	    od: OutputDefinition::new(),
 	    base: None,
+	    collations: Collations::new(),
+	    globals: HashMap::new(),
+	    params: HashMap::new(),
+	    attribute_sets: HashMap::new(),
+	    keys: HashMap::new(),
+	    namespaces: HashMap::new(),
+	    messages: Rc::new(StderrListener),
+	    output_definitions: HashMap::new(),
+	    base_output: None,
+	    results: Rc::new(RejectHandler),
 	}
     }
     pub fn from_dynamic_context(
@@ -150,9 +228,38 @@ impl Evaluator {
 	    builtin_templates: Vec::new(),
 	    od: OutputDefinition::new(),
 	    base: None,
+	    collations: Collations::new(),
+	    globals: HashMap::new(),
+	    params: HashMap::new(),
+	    attribute_sets: HashMap::new(),
+	    keys: HashMap::new(),
+	    namespaces: HashMap::new(),
+	    messages: Rc::new(StderrListener),
+	    output_definitions: HashMap::new(),
+	    base_output: None,
+	    results: Rc::new(RejectHandler),
 	}
     }
 
+    /// Use the given collations, usually those of the static context.
+    pub fn set_collations(&mut self, c: Collations) {
+	self.collations = c;
+    }
+    /// Find a collation by its URI. If no URI is given then the default collation is returned.
+    pub fn collation(&self, uri: Option<&str>) -> Result<Rc<dyn Collation>, Error> {
+	self.collations.get(uri)
+    }
+
+    /// Use the given resolver to load secondary documents, i.e. for fn:doc, fn:collection and the XSLT document() function. By default documents are loaded from the file system.
+    pub fn set_document_resolver(&mut self, r: Rc<dyn DocumentResolver>) {
+	self.dc.set_document_resolver(r);
+    }
+
+    /// Use the given listener to receive the messages produced by xsl:message. By default messages are written to stderr.
+    pub fn set_message_listener(&mut self, l: Rc<dyn MessageListener>) {
+	self.messages = l;
+    }
+
     /// Base URI
     pub fn baseurl(&self) -> Option<Url> {
 	self.base.clone()
@@ -162,27 +269,70 @@ impl Evaluator {
 	self.base = Some(url);
     }
 
-    /// Add a template to the dynamic context. The first argument is the pattern. The second argument is the body of the template. The third argument is the mode. The fourth argument is the priority. The fifth argument is the import precedence.
+    /// Add a template to the dynamic context. The first argument is the pattern. The second argument is the body of the template. The third argument is the template's parameters. The fourth argument is the modes of the template. The fifth argument is the priority. The sixth argument is the import precedence.
     pub fn add_template(&mut self,
 			p: Vec<Constructor>,
 			b: Vec<Constructor>,
-			m: Option<String>,
+			params: Vec<TemplateParam>,
+			m: Vec<Mode>,
 			pr: f64,
 			im: usize,
     ) {
-	self.templates.push(Template{pattern: p, body: b, mode: m, priority: pr, import: im});
+	self.templates.push(Template{pattern: p, body: b, modes: m, priority: pr, import: im, name: None, params});
+    }
+    /// Add a named template to the dynamic context, to be invoked by xsl:call-template. The first argument is the name. The second argument is the body of the template. The third argument is the template's parameters. The fourth argument is the import precedence.
+    pub fn add_named_template(&mut self,
+			      n: QualifiedName,
+			      b: Vec<Constructor>,
+			      params: Vec<TemplateParam>,
+			      im: usize,
+    ) {
+	self.templates.push(Template{pattern: vec![], body: b, modes: vec![], priority: 0.0, import: im, name: Some(n), params});
+    }
+    /// Declare a global variable or stylesheet parameter. The second argument is the import precedence. If a variable with the same name has already been declared, the declaration with the lowest import precedence is used.
+    pub fn add_global_variable(&mut self, v: GlobalVariable, im: usize) {
+	if self.globals.get(&v.name).is_none_or(|(_, i)| im < *i) {
+	    self.globals.insert(v.name.clone(), (v, im));
+	}
+    }
+    /// Add to a named attribute set (i.e. xsl:attribute-set). The constructors produce attribute nodes. If the attribute set has already been declared then the constructors are added to those of the previous declarations.
+    pub fn add_attribute_set(&mut self, n: QualifiedName, c: Vec<Constructor>) {
+	self.attribute_sets.entry(n).or_default().extend(c)
+    }
+    /// Define a key (i.e. xsl:key). If a key with the same name has already been defined, then the nodes that match either definition are indexed.
+    pub fn add_key(&mut self, n: QualifiedName, k: KeyDefinition) {
+	self.keys.entry(n).or_default().push(k)
+    }
+    /// Bind a prefix to a namespace URI, for resolving names that are evaluated at run time (such as the name of a key).
+    pub fn declare_namespace(&mut self, prefix: String, nsuri: String) {
+	self.namespaces.insert(prefix, nsuri);
+    }
+    /// Supply the value of a stylesheet parameter. Overrides the previous value if it is already set.
+    pub fn set_parameter(&mut self, n: QualifiedName, v: Sequence) {
+	self.params.insert(n, v);
+	// Global variables may depend on the parameter
+	self.dc.reset();
+    }
+    /// Check that a value has been supplied for every required stylesheet parameter.
+    pub fn check_parameters(&self) -> Result<(), Error> {
+	match self.globals.values().find(|(g, _)| g.param && g.required && !self.params.contains_key(&g.name)) {
+	    Some((g, _)) => Result::Err(Error{kind: ErrorKind::MissingStylesheetParameter, message: format!("no value supplied for required stylesheet parameter \"{}\"", g.name.to_string())}),
+	    None => Ok(()),
+	}
     }
     /// Add a template to the set of builtin templates in the dynamic context. See above for arguments.
+    /// The builtin templates for a mode implement its on-no-match behaviour. If a mode has no builtin templates then it is an error if no template matches a node.
     pub fn add_builtin_template(&mut self,
 				p: Vec<Constructor>,
 				b: Vec<Constructor>,
-				m: Option<String>,
+				m: Vec<Mode>,
 				pr: f64,
 				im: usize,
     ) {
-	self.builtin_templates.push(Template{pattern: p, body: b, mode: m, priority: pr, import: im});
+	self.builtin_templates.push(Template{pattern: p, body: b, modes: m, priority: pr, import: im, name: None, params: vec![]});
     }
     /// Determine if an item matches a pattern and return the highest priority sequence constructor for that template.
+    /// Only templates in the default mode are considered.
     /// If import precedence is None, then return the lowest import precedence. Otherwise return the matching template with the highest priority that has an imoprt precedence higher than the given value.
     /// If no template is found, returns None.
     pub fn find_match(
@@ -198,7 +348,7 @@ impl Evaluator {
 	loop {
 	    match it.next() {
 		Some(t) => {
-		    if self.item_matches(&t.pattern, i, f, sd, rd)? {
+		    if t.in_mode(&Mode::Default) && self.item_matches(&t.pattern, i, f, sd, rd)? {
 			r.push(t)
 		    }
 		}
@@ -244,13 +394,25 @@ impl Evaluator {
     pub fn set_output_definition(&mut self, od: OutputDefinition) {
 	self.od = od;
     }
+    /// Add a named output definition (i.e. a named xsl:output), for use by xsl:result-document.
+    pub fn add_output_definition(&mut self, n: QualifiedName, od: OutputDefinition) {
+	self.output_definitions.insert(n, od);
+    }
+    /// Set the base output URI. The href of a result document is resolved against this URI.
+    pub fn set_base_output_uri(&mut self, url: Url) {
+	self.base_output = Some(url);
+    }
+    /// Use the given handler to receive the result documents produced by xsl:result-document. By default result documents are rejected, i.e. xsl:result-document raises an error.
+    pub fn set_result_document_handler(&mut self, h: Rc<dyn ResultDocumentHandler>) {
+	self.results = h;
+    }
 
     // Printout templates, for debugging.
     pub fn dump_templates(&self) {
 	self.templates.iter().for_each(
 	    |t| {
-		println!("Template (mode \"{}\" priority {} import precedence {}) matching pattern:\n{}\nBody:\n{}",
-			 t.mode.as_ref().map_or("--no mode--", |u| u.as_str()),
+		println!("Template (modes {:?} priority {} import precedence {}) matching pattern:\n{}\nBody:\n{}",
+			 t.modes,
 			 t.priority,
 			 t.import,
 			 format_constructor(&t.pattern, 4),
@@ -260,8 +422,8 @@ impl Evaluator {
 	);
 	self.builtin_templates.iter().for_each(
 	    |t| {
-		println!("Builtin template (mode \"{}\" priority {} import precedence {}) matching pattern:\n{}\nBody:\n{}",
-			 t.mode.as_ref().map_or("--no mode--", |u| u.as_str()),
+		println!("Builtin template (modes {:?} priority {} import precedence {}) matching pattern:\n{}\nBody:\n{}",
+			 t.modes,
 			 t.priority,
 			 t.import,
 			 format_constructor(&t.pattern, 4),
@@ -276,6 +438,8 @@ impl Evaluator {
     /// The dynamic context consists of the supplied context, as well as the context item. The context item, which is optional, consists of a [Sequence] and an index to an item. If the context sequence is supplied, then the index (posn) must also be supplied and be a valid index for the sequence.
     ///
     /// Any nodes created by the sequence constructor are created in the result Tree.
+    ///
+    /// A call that is not made during another evaluation starts a new transformation, so values that were computed by a previous transformation (such as the values of global variables) are discarded.
     pub fn evaluate(
 	&self,
 	ctxt: Option<Sequence>,
@@ -285,30 +449,50 @@ impl Evaluator {
 	sd: TreeIndex,	// Source document
 	rd: TreeIndex,	// Result document
     ) -> Result<Sequence, Error> {
+	if *self.dc.nesting.borrow() == 0 {
+	    self.dc.reset()
+	}
+	*self.dc.nesting.borrow_mut() += 1;
+	let r = self.evaluate_sequence(ctxt, posn, c, f, sd, rd);
+	*self.dc.nesting.borrow_mut() -= 1;
+	r
+    }
 
-	// Evaluate all sequence constructors. This will result in a sequence of sequences.
-	// If an error occurs, propagate the first error (TODO: return all errors)
-	// Otherwise, flatten the sequences into a single sequence
-
-	let (results, errors): (Vec<_>, Vec<_>) = c.iter()
-	    .map(|a| self.evaluate_one(ctxt.clone(), posn, a, f, sd, rd))
-	    .partition(Result::is_ok);
-	if errors.len() != 0 {
-	    Result::Err(
-		errors.iter()
-		    .nth(0)
-		    .map(|e| e.clone().err().unwrap())
-		    .unwrap()
-	    )
-	} else {
-	    Ok(results.iter()
-	       .map(|a| {
-		   let b: Sequence = a.clone().ok().unwrap_or(vec![]);
-		   b
-	       })
-	       .flatten()
-	       .collect::<Vec<Rc<Item>>>()
-	    )
+    fn evaluate_sequence(
+	&self,
+	ctxt: Option<Sequence>,
+	posn: Option<usize>,
+	c: &[Constructor],
+	f: &mut Forest,
+	sd: TreeIndex,
+	rd: TreeIndex,
+    ) -> Result<Sequence, Error> {
+
+	// Evaluate the sequence constructors in order, flattening their results into a single sequence.
+	// Evaluation stops at the first error, so that nothing after a dynamic error (or a terminating message) is evaluated.
+
+	let mut result: Sequence = vec![];
+	let mut declared = vec![];
+	let mut error = None;
+	for a in c {
+	    match self.evaluate_one(ctxt.clone(), posn, a, f, sd, rd) {
+		Ok(mut s) => {
+		    if let Constructor::VariableDeclaration(v, _) = a {
+			declared.push(v)
+		    }
+		    result.append(&mut s)
+		}
+		Err(e) => {
+		    error = Some(e);
+		    break
+		}
+	    }
+	}
+	// Variables declared by this sequence constructor are now out of scope
+	declared.iter().for_each(|v| self.dc.var_pop(v));
+	match error {
+	    Some(e) => Result::Err(e),
+	    None => Ok(result),
 	}
     }
 
@@ -332,34 +516,146 @@ impl Evaluator {
 
 	    // This creates a Node in the current result document
 	    Constructor::LiteralElement(n, c) => {
+		self.element(n.clone(), c, ctxt.clone(), posn, f, sd, rd)
+	    }
+	    Constructor::Element(cn, c) => {
+		let qn = self.computed_name(cn, false, ctxt.clone(), posn, f, sd, rd)?;
+		self.element(qn, c, ctxt.clone(), posn, f, sd, rd)
+	    }
+	    Constructor::Attribute(cn, v, sep) => {
+		let qn = self.computed_name(cn, true, ctxt.clone(), posn, f, sd, rd)?;
+		let x = self.simple_content(v, sep, ctxt.clone(), posn, f, sd, rd)?;
 		let l = f.get_ref_mut(rd)
 		    .ok_or(Error::new(ErrorKind::Unknown, String::from("no result document")))?
-		    .new_element(n.clone())?;
-
-      		// add content to newly created element
-		let seq = self.evaluate(ctxt.clone(), posn, c, f, sd, rd)?;
-		seq.iter()
-		    .try_for_each(
-			|i| {
-			    // Item could be a Node or text
-			    match **i {
-				Item::Node(t) => {
-				    l.append_child(f, t)
-				}
-	      			_ => {
-				    // Values become a text node in the result tree
-				    let v = Value::from(i.to_string(Some(f)));
-				    let t = f.get_ref_mut(rd)
-					.ok_or(Error::new(ErrorKind::Unknown, String::from("no result document")))?
-					.new_text(v)?;
-				    l.append_child(f, t)
-				}
-			    }
-			}
-		    )?;
-
+		    .new_attribute(qn, Value::from(x))?;
+		Ok(vec![Rc::new(Item::Node(l))])
+	    }
+	    Constructor::Text(v, sep) => {
+		let x = self.simple_content(v, sep, ctxt.clone(), posn, f, sd, rd)?;
+		let l = f.get_ref_mut(rd)
+		    .ok_or(Error::new(ErrorKind::Unknown, String::from("no result document")))?
+		    .new_text(Value::from(x))?;
+		Ok(vec![Rc::new(Item::Node(l))])
+	    }
+	    Constructor::Comment(v, sep) => {
+		// A comment must not contain "--" or end with "-"
+		let mut x = self.simple_content(v, sep, ctxt.clone(), posn, f, sd, rd)?;
+		while x.contains("--") {
+		    x = x.replace("--", "- -")
+		}
+		if x.ends_with('-') {
+		    x.push(' ')
+		}
+		let l = f.get_ref_mut(rd)
+		    .ok_or(Error::new(ErrorKind::Unknown, String::from("no result document")))?
+		    .new_comment(Value::from(x))?;
 		Ok(vec![Rc::new(Item::Node(l))])
 	    }
+	    Constructor::ProcessingInstruction(n, v, sep) => {
+		let name = self.evaluate(ctxt.clone(), posn, n, f, sd, rd)?.to_string(Some(f));
+		let name = name.trim();
+		if !is_ncname(name) || name.eq_ignore_ascii_case("xml") {
+		    return Result::Err(Error{kind: ErrorKind::InvalidProcessingInstructionName, message: format!("\"{}\" is not a valid processing instruction name", name)})
+		}
+		// The value must not contain "?>" and must not start with whitespace
+		let mut x = self.simple_content(v, sep, ctxt.clone(), posn, f, sd, rd)?;
+		while x.contains("?>") {
+		    x = x.replace("?>", "? >")
+		}
+		let l = f.get_ref_mut(rd)
+		    .ok_or(Error::new(ErrorKind::Unknown, String::from("no result document")))?
+		    .new_processing_instruction(QualifiedName::new(None, None, name.to_string()), Value::from(x.trim_start()))?;
+		Ok(vec![Rc::new(Item::Node(l))])
+	    }
+	    Constructor::Namespace(p, u) => {
+		// The namespace is declared on the element that is being constructed
+		let prefix = self.evaluate(ctxt.clone(), posn, p, f, sd, rd)?.to_string(Some(f));
+		let prefix = prefix.trim();
+		let uri = self.evaluate(ctxt.clone(), posn, u, f, sd, rd)?.to_string(Some(f));
+		if !(prefix.is_empty() || is_ncname(prefix)) || prefix == "xmlns" {
+		    return Result::Err(Error{kind: ErrorKind::InvalidNamespaceNode, message: format!("\"{}\" is not a valid namespace prefix", prefix)})
+		}
+		if uri.is_empty() || uri == "http://www.w3.org/2000/xmlns/" || (prefix == "xml") != (uri == XMLNS) {
+		    return Result::Err(Error{kind: ErrorKind::InvalidNamespaceNode, message: format!("cannot bind the prefix \"{}\" to the namespace \"{}\"", prefix, uri)})
+		}
+		let e = self.dc.constructing.borrow().last().cloned();
+		match e {
+		    Some(e) => {
+			e.declare_namespace(f, if prefix.is_empty() {None} else {Some(prefix.to_string())}, uri)?;
+			Ok(vec![])
+		    }
+		    None => Result::Err(Error{kind: ErrorKind::NotImplemented, message: "namespace nodes can only be added to an element".to_string()}),
+		}
+	    }
+	    Constructor::Message(c, t, e) => {
+		let content = self.evaluate(ctxt.clone(), posn, c, f, sd, rd)?.to_xml(Some(f));
+		let terminate = yes_no(self.evaluate(ctxt.clone(), posn, t, f, sd, rd)?.to_string(Some(f)).as_str(), "terminate")?;
+		// An error code that is not a valid QName is replaced by the default
+		let error_code = match e {
+		    Some(cn) => match self.computed_name(cn, true, ctxt.clone(), posn, f, sd, rd) {
+			Ok(qn) => Some(qn),
+			Err(Error{kind: ErrorKind::InvalidAttributeName, ..}) => None,
+			Err(err) => return Result::Err(err),
+		    },
+		    None => None,
+		}.unwrap_or_else(|| QualifiedName::new(Some(ERRNS.to_string()), Some("err".to_string()), "XTMM9000".to_string()));
+		self.messages.message(&Message{content: content.clone(), terminate, error_code: error_code.clone()});
+		if terminate {
+		    Result::Err(Error{kind: ErrorKind::Terminated, message: format!("{}: {}", error_code.to_string(), content)})
+		} else {
+		    Ok(vec![])
+		}
+	    }
+	    Constructor::ResultDocument(r, c) => {
+		let mut od = match &r.format {
+		    Some(cn) => {
+			let qn = match self.computed_name(cn, true, ctxt.clone(), posn, f, sd, rd) {
+			    Ok(qn) => qn,
+			    Err(Error{kind: ErrorKind::InvalidAttributeName, message}) => return Result::Err(Error{kind: ErrorKind::NoSuchOutputDefinition, message}),
+			    Err(err) => return Result::Err(err),
+			};
+			self.output_definitions.get(&qn).cloned()
+			    .ok_or_else(|| Error{kind: ErrorKind::NoSuchOutputDefinition, message: format!("no output definition named \"{}\"", qn.to_string())})?
+		    }
+		    None => self.od.clone(),
+		};
+		// Serialization attributes override the output definition
+		if let Some(m) = &r.method {
+		    od.set_method(OutputMethod::try_from(self.evaluate(ctxt.clone(), posn, m, f, sd, rd)?.to_string(Some(f)).as_str())?)
+		}
+		if let Some(i) = &r.indent {
+		    od.set_indent(yes_no(self.evaluate(ctxt.clone(), posn, i, f, sd, rd)?.to_string(Some(f)).as_str(), "indent")?)
+		}
+		// An empty href identifies the primary result, i.e. the base output URI
+		let href = match &r.href {
+		    Some(h) => self.evaluate(ctxt.clone(), posn, h, f, sd, rd)?.to_string(Some(f)),
+		    None => String::new(),
+		};
+		let uri = match &self.base_output {
+		    Some(b) => b.join(href.trim()),
+		    None => Url::parse(href.trim()),
+		}.map_err(|_| Error{kind: ErrorKind::InvalidAttributeValue, message: format!("unable to resolve \"{}\" against the base output URI", href)})?;
+		if self.dc.result_uris.borrow().contains(&uri.to_string()) {
+		    return Result::Err(Error{kind: ErrorKind::DuplicateResultURI, message: format!("a result document with URI \"{}\" has already been produced", uri)})
+		}
+		self.dc.result_uris.borrow_mut().push(uri.to_string());
+		let doc = match self.evaluate(ctxt.clone(), posn, c, f, sd, rd)?.first().map(|i| &**i) {
+		    Some(Item::Node(d)) => *d,
+		    _ => return Result::Err(Error::new(ErrorKind::Unknown, String::from("unable to construct the result document"))),
+		};
+		self.results.result_document(&uri, &doc, f, &od)?;
+		Ok(vec![])
+	    }
+	    Constructor::UseAttributeSets(v) => {
+		let mut result = vec![];
+		for n in v {
+		    match self.attribute_sets.get(n) {
+			Some(c) => result.append(&mut self.evaluate(ctxt.clone(), posn, c, f, sd, rd)?),
+			None => return Result::Err(Error{kind: ErrorKind::NoSuchAttributeSet, message: format!("no attribute set named \"{}\"", n.to_string())}),
+		    }
+		}
+		Ok(result)
+	    }
 	    // This creates a Node in the current result document
 	    Constructor::LiteralAttribute(n, v) => {
 		let w = self.evaluate(ctxt.clone(), posn, v, f, sd, rd)?;
@@ -501,6 +797,118 @@ impl Evaluator {
 		    Result::Err(Error{kind: ErrorKind::Unknown, message: "incorrect number of operands".to_string()})
 		}
 	    }
+	    Constructor::InstanceOf(v, st) => {
+		let seq = self.evaluate(ctxt.clone(), posn, v, f, sd, rd)?;
+		Ok(vec![Rc::new(Item::Value(Value::Boolean(st.matches(&seq, f))))])
+	    }
+	    Constructor::MapConstructor(v) => {
+		let mut m = Map::new();
+		for (k, w) in v {
+		    let kseq = self.evaluate(ctxt.clone(), posn, k, f, sd, rd)?;
+		    if kseq.len() != 1 {
+			return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("type error (map key is not a single value)")})
+		    }
+		    let key = atomize(&kseq[0], f)?;
+		    if m.contains(&key) {
+			return Result::Err(Error{kind: ErrorKind::DuplicateMapKey, message: format!("duplicate key \"{}\" in map constructor", key.to_string())})
+		    }
+		    m.insert(key, self.evaluate(ctxt.clone(), posn, w, f, sd, rd)?);
+		}
+		Ok(vec![Rc::new(Item::Map(m))])
+	    }
+	    Constructor::ArrayConstructor(v) => {
+		let mut a = Array::new();
+		for w in v {
+		    a.push(self.evaluate(ctxt.clone(), posn, w, f, sd, rd)?)
+		}
+		Ok(vec![Rc::new(Item::Array(a))])
+	    }
+	    Constructor::CurlyArrayConstructor(v) => {
+		let seq = self.evaluate(ctxt.clone(), posn, v, f, sd, rd)?;
+		Ok(vec![Rc::new(Item::Array(Array::from(seq.into_iter().map(|i| vec![i]).collect::<Vec<Sequence>>())))])
+	    }
+	    Constructor::Lookup(v, k) => {
+		let seq = self.evaluate(ctxt.clone(), posn, v, f, sd, rd)?;
+		let keys = match k {
+		    Some(l) => {
+			let kseq = self.evaluate(ctxt.clone(), posn, l, f, sd, rd)?;
+			let mut r = vec![];
+			for i in &kseq {
+			    r.push(atomize(i, f)?)
+			}
+			Some(r)
+		    }
+		    None => None,
+		};
+		let mut result: Sequence = vec![];
+		for i in &seq {
+		    match (&**i, &keys) {
+			(Item::Map(m), Some(l)) => {
+			    for key in l {
+				if let Some(w) = m.get(key) {
+				    result.extend(w.iter().cloned())
+				}
+			    }
+			}
+			(Item::Map(m), None) => {
+			    m.iter().for_each(|(_, w)| result.extend(w.iter().cloned()))
+			}
+			(Item::Array(a), Some(l)) => {
+			    for key in l {
+				result.extend(array_member(a, key)?.iter().cloned())
+			    }
+			}
+			(Item::Array(a), None) => {
+			    a.iter().for_each(|w| result.extend(w.iter().cloned()))
+			}
+			_ => return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("type error (lookup requires a map or an array)")}),
+		    }
+		}
+		Ok(result)
+	    }
+	    Constructor::Cast(v, t, empty) => {
+		let seq = self.evaluate(ctxt.clone(), posn, v, f, sd, rd)?;
+		let tn = xsd_type_name(t)?;
+		match cast_operand(&seq, f, *empty)? {
+		    Some(u) => {
+			let r = u.cast_as(&tn)?;
+			// Casting to a list type gives a sequence of its members
+			match r.list_items() {
+			    Some(l) => Ok(l.into_iter().map(|w| Rc::new(Item::Value(w))).collect()),
+			    None => Ok(vec![Rc::new(Item::Value(r))]),
+			}
+		    }
+		    None => Ok(vec![]),
+		}
+	    }
+	    Constructor::Castable(v, t, empty) => {
+		let seq = self.evaluate(ctxt.clone(), posn, v, f, sd, rd)?;
+		let tn = xsd_type_name(t)?;
+		let b = match cast_operand(&seq, f, *empty) {
+		    Ok(Some(u)) => match u.cast_as(&tn) {
+			Ok(_) => true,
+			Err(Error{kind: ErrorKind::UnknownType, message}) => return Result::Err(Error{kind: ErrorKind::UnknownType, message}),
+			Err(_) => false,
+		    },
+		    Ok(None) => true,
+		    Err(_) => false,
+		};
+		Ok(vec![Rc::new(Item::Value(Value::Boolean(b)))])
+	    }
+	    Constructor::AttributeValueTemplate(v) => {
+		let mut r = String::new();
+		for u in v {
+		    let t = self.evaluate(ctxt.clone(), posn, u, f, sd, rd)?;
+		    r.push_str(
+			t.iter()
+			    .map(|i| i.to_string(Some(f)))
+			    .collect::<Vec<String>>()
+			    .join(" ")
+			    .as_str()
+		    );
+		}
+		Ok(vec![Rc::new(Item::Value(Value::from(r)))])
+	    }
 	    Constructor::Concat(v) => {
 		let mut r = String::new();
       		for u in v {
@@ -548,33 +956,25 @@ impl Evaluator {
 		}
 	    }
 	    Constructor::Arithmetic(v) => {
-		// Type: the result will be a number, but integer or double?
-      		// If all of the operands are integers, then the result is integer otherwise double
-      		// TODO: check the type of all operands to determine type of result (can probably do this in static analysis phase)
-      		// In the meantime, let's assume the result will be double and convert any integers
-
-      		let mut acc: f64 = 0.0;
+		// The type of the result is determined by the types of the operands,
+		// see Value::arithmetic for the type promotion rules.
+		// If any operand is the empty sequence, then the result is the empty sequence.
+      		let mut acc = Value::Integer(0);
 
       		for j in v {
 		    let k = self.evaluate(ctxt.clone(), posn, &j.operand, f, sd, rd)?;
-		    let u: f64;
-		    if k.len() != 1 {
-			return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("type error (not a singleton sequence)")});
-		    } else {
-			u = k[0].to_double();
-			match j.op {
-			    ArithmeticOperator::Noop => acc = u,
-			    ArithmeticOperator::Add => acc += u,
-			    ArithmeticOperator::Subtract => acc -= u,
-			    ArithmeticOperator::Multiply => acc *= u,
-			    ArithmeticOperator::Divide => acc /= u,
-			    ArithmeticOperator::IntegerDivide => acc /= u, // TODO: convert to integer
-			    ArithmeticOperator::Modulo => acc = acc % u,
+		    match k.len() {
+			0 => return Ok(vec![]),
+			1 => {
+			    // Atomize the operand; nodes are untyped
+			    let u = atomize(&k[0], f)?;
+			    acc = acc.arithmetic(j.op, &u)?;
 			}
+			_ => return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("type error (not a singleton sequence)")}),
 		    }
 		}
       		let mut seq = Sequence::new();
-      		seq.push_value(Value::from(acc));
+      		seq.push_value(acc);
       		Ok(seq)
 	    }
 	    Constructor::Root => {
@@ -1022,16 +1422,12 @@ impl Evaluator {
       		Ok(Sequence::new())
 	    }
 	    Constructor::VariableReference(v) => {
-		match self.dc.vars.borrow().get(v) {
-		    Some(s) => {
-			match s.last() {
-			    Some(t) => Ok(t.clone()),
-			    None => Result::Err(Error{kind: ErrorKind::Unknown, message: "no value for variable".to_string()})
-			}
-		    }
-		    None => {
-      			Result::Err(Error{kind: ErrorKind::Unknown, message: format!("reference to undefined variable \"{}\"", v)})
-		    }
+		// Local variables shadow supplied parameters, which shadow global variables
+		let local = self.dc.vars.borrow().get(v).and_then(|s| s.last().cloned())
+		    .or_else(|| self.dc.params.borrow().get(v).cloned());
+		match local {
+		    Some(t) => Ok(t),
+		    None => self.global_value(v, f, sd, rd),
 		}
 	    }
 	    Constructor::Loop(v, b) => {
@@ -1082,99 +1478,97 @@ impl Evaluator {
 		};
 		Ok(candidate)
 	    }
-	    Constructor::ApplyTemplates(s) => {
+	    Constructor::ApplyTemplates(s, m, w) => {
 		// Evaluate 's' to find the nodes to apply templates to
       		// For each node, find a matching template and evaluate its sequence constructor. The result of that becomes an item in the new sequence
 
       		let sel = self.evaluate(ctxt.clone(), posn, s, f, sd, rd)?;
-      		// TODO: Don't Panic
-      		let result = sel.iter().fold(
-		    vec![],
-		    |mut acc, i| {
-			let mut matching_template: Vec<&Template> = vec![];
-			for t in &self.templates {
-			    let e = self.evaluate(Some(vec![i.clone()]), Some(0), &t.pattern, f, sd, rd)
-				.expect("evaluating pattern failed");
-			    if e.len() != 0 {
-				matching_template.push(&t)
-			    }
+		// Parameter values are evaluated once, in the current context
+		let wp = self.with_params(ctxt.clone(), posn, w, f, sd, rd)?;
+		let mode = match m {
+		    Mode::Current => self.dc.current_mode.borrow().last().cloned().unwrap_or(Mode::Default),
+		    _ => m.clone(),
+		};
+		let mut result = vec![];
+		for i in sel.iter() {
+		    let mut matching_template: Vec<&Template> = vec![];
+		    for t in &self.templates {
+			if !t.pattern.is_empty() && t.in_mode(&mode) && self.item_matches(&t.pattern, i, f, sd, rd)? {
+			    matching_template.push(t)
 			}
+		    }
 
-			if matching_template.len() != 0 {
-			    // find the template(s) with the lowest priority
-			    matching_template
-				.sort_unstable_by(|s, t| s.priority.partial_cmp(&t.priority).unwrap());
-			    let l = matching_template[0].priority;
-			    let mut mt_lowest: Vec<&Template> = matching_template.into_iter()
-				.take_while(|t| t.priority == l)
-				.collect();
-
-			    // It's OK to have more than one matching template, if they all have different import precedence
-			    mt_lowest
-				.sort_unstable_by_key(|t| t.import);
-			    let mut p = mt_lowest[0].import;
-			    mt_lowest.iter().skip(1)
-				.for_each(|t| {
-				    if t.import == p {
-					panic!("too many matching templates")
-				    } else {
-					p = t.import;
-					()
-				    }
-				});
-
-			    // Use the template with the lowest import precedence
-			    // Unless we're inside an apply-imports
-			    let mut u = mt_lowest.iter().take(1)
-				.flat_map(|t| {
-				    self.dc.depth_incr();
-				    let rs = self.evaluate(Some(vec![i.clone()]), Some(0), &t.body, f, sd, rd)
-					.expect("failed to evaluate template body");
-	    			    self.dc.depth_decr();
-				    rs
-				})
-				.collect::<Sequence>();
-			    acc.append(&mut u);
-			} else {
-			    // If no templates match then apply a built-in template
-			    // See XSLT 6.7.
-			    // TODO: use import precedence to implement this feature
-			    let builtin_template: Vec<&Template> = self.builtin_templates.iter()
-				.filter(|t| {
-				    let e = self.evaluate(Some(vec![i.clone()]), Some(0), &t.pattern, f, sd, rd)
-					.expect("failed to evaluate pattern");
-				    if e.len() == 0 {false} else {true}
-				})
-				.scan(-2.0,
-				      |prio, t| {
-					  if *prio < t.priority {
-					      *prio = t.priority;
-					      Some(t)
-					  } else {
-					      None
-					  }
-				      }
-				)
-				.collect();
-			    if builtin_template.len() > 1 {
-				panic!("too many matching builtin templates")
-			    }
-			    let mut u = builtin_template.iter()
-				.flat_map(|t| {
-				    self.dc.depth_incr();
-				    let rs = self.evaluate(Some(vec![i.clone()]), Some(0), &t.body, f, sd, rd)
-					.expect("failed to evaluate template body");
-	    			    self.dc.depth_decr();
-				    rs
-				})
-				.collect::<Sequence>();
-			    acc.append(&mut u);
+		    if !matching_template.is_empty() {
+			// find the template(s) with the lowest priority
+			matching_template
+			    .sort_unstable_by(|s, t| s.priority.partial_cmp(&t.priority).unwrap());
+			let l = matching_template[0].priority;
+			let mut mt_lowest: Vec<&Template> = matching_template.into_iter()
+			    .take_while(|t| t.priority == l)
+			    .collect();
+
+			// It's OK to have more than one matching template, if they all have different import precedence
+			mt_lowest
+			    .sort_unstable_by_key(|t| t.import);
+			if mt_lowest.windows(2).any(|p| p[0].import == p[1].import) {
+			    return Result::Err(Error{kind: ErrorKind::Unknown, message: String::from("too many matching templates")})
+			}
+
+			// Use the template with the lowest import precedence
+			// Unless we're inside an apply-imports
+			let mut u = self.invoke_template_in_mode(mt_lowest[0], &mode, i, &wp, f, sd, rd)?;
+			result.append(&mut u);
+		    } else {
+			// If no templates match then apply a built-in template for the mode
+			// See XSLT 6.7.
+			// TODO: use import precedence to implement this feature
+			let builtins: Vec<&Template> = self.builtin_templates.iter()
+			    .filter(|t| t.in_mode(&mode))
+			    .collect();
+			if builtins.is_empty() {
+			    // The mode's on-no-match behaviour is to fail
+			    return Result::Err(Error{kind: ErrorKind::NoMatchingTemplate, message: format!("no template matches item in mode {:?}", mode)})
+			}
+			let mut builtin_template: Option<&Template> = None;
+			for t in builtins {
+			    if self.item_matches(&t.pattern, i, f, sd, rd)? &&
+				!builtin_template.is_some_and(|b| b.priority >= t.priority) {
+				    builtin_template = Some(t)
+				}
+			}
+			if let Some(t) = builtin_template {
+			    let mut u = self.invoke_template_in_mode(t, &mode, i, &wp, f, sd, rd)?;
+			    result.append(&mut u);
 			}
-			acc
 		    }
-		);
+		}
       		Ok(result)
 	    }
+	    Constructor::CallTemplate(n, w) => {
+		// The context is unchanged; the template with the highest import precedence is used
+		let wp = self.with_params(ctxt.clone(), posn, w, f, sd, rd)?;
+		match self.templates.iter()
+		    .filter(|t| t.name.as_ref() == Some(n))
+		    .min_by_key(|t| t.import) {
+			Some(t) => self.invoke_template(t, ctxt, posn, &wp, f, sd, rd),
+			None => Result::Err(Error{kind: ErrorKind::NoSuchTemplate, message: format!("no template named \"{}\"", n.to_string())}),
+		    }
+	    }
+	    Constructor::Document(c) => {
+		// Build a temporary tree from the content
+		let seq = self.evaluate(ctxt, posn, c, f, sd, rd)?;
+		let t = f.plant_tree();
+		for n in complex_content(seq, f, t)? {
+		    let d = match n.node_type(f) {
+			NodeType::Attribute => return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("cannot add an attribute to a document node")}),
+			// Text nodes have already been created in the new tree
+			NodeType::Text => n,
+			_ => n.deep_copy(f, Some(t))?,
+		    };
+		    f.get_ref_mut(t).unwrap().push_doc_node(d)?;
+		}
+		Ok(vec![Rc::new(Item::Node(f.get_ref(t).unwrap().get_doc_node()))])
+	    }
 	    Constructor::ApplyImports => {
 		// Evaluate templates with higher import precedence
       		// Find a matching template with import precedence greater than the current precedence
@@ -1183,7 +1577,8 @@ impl Evaluator {
 
 		let mut result = vec![];
 		let mut matching_template: Vec<&Template> = vec![];
-		for t in &self.templates {
+		let mode = self.dc.current_mode.borrow().last().cloned().unwrap_or(Mode::Default);
+		for t in self.templates.iter().filter(|t| t.in_mode(&mode)) {
 		    let e = self.evaluate(ctxt.clone(), posn.clone(), &t.pattern, f, sd, rd)
 			.expect("evaluating pattern failed");
 		    if e.len() != 0 {
@@ -1258,7 +1653,7 @@ impl Evaluator {
 		}
       		Ok(result)
 	    }
-	    Constructor::ForEach(s, t, g) => {
+	    Constructor::ForEach(s, t, g, k) => {
 		// Evaluate 's' to find the nodes to iterate over
       		// Use 'g' to group the nodes
       		// Evaluate 't' for each group
@@ -1317,11 +1712,24 @@ impl Evaluator {
 		    Some(Grouping::StartingWith(_h)) => {}
 		    Some(Grouping::EndingWith(_h)) => {}
 		    None => {
-			for i in sel {
+			for i in &sel {
 			    groups.push((None, vec![i.clone()]));
 			}
 		    }
 		}
+		if !k.is_empty() {
+		    // Sort the groups. If there is no grouping, then each item is sorted with the selected sequence as its context.
+		    let order = if g.is_some() {
+			self.sort_order(k, ctxt.clone(), posn, &groups, None, f, sd, rd)?
+		    } else {
+			self.sort_order(k, ctxt.clone(), posn, &[], Some(&sel), f, sd, rd)?
+		    };
+		    let mut sorted = Vec::with_capacity(groups.len());
+		    for i in order {
+			sorted.push(groups[i].clone())
+		    }
+		    groups = sorted
+		}
 
       		let result = groups.iter().fold(
 		    vec![],
@@ -1347,12 +1755,193 @@ impl Evaluator {
 		);
 		Ok(result)
 	    }
+	    Constructor::Sort(s, k) => {
+		let sel = self.evaluate(ctxt.clone(), posn, s, f, sd, rd)?;
+		let order = self.sort_order(k, ctxt, posn, &[], Some(&sel), f, sd, rd)?;
+		Ok(order.iter().map(|i| sel[*i].clone()).collect())
+	    }
+	    Constructor::Number(n) => {
+		let nums = match &n.value {
+		    Some(v) => {
+			let seq = self.evaluate(ctxt.clone(), posn, v, f, sd, rd)?;
+			let mut nums = vec![];
+			for i in seq {
+			    let d = i.to_double().round();
+			    if d.is_nan() || d < 0.0 {
+				return Result::Err(Error{kind: ErrorKind::TypeError, message: format!("unable to number value \"{}\"", i.to_string(Some(f)))})
+			    }
+			    nums.push(d as u64);
+			}
+			nums
+		    }
+		    None => {
+			let target = match &n.select {
+			    Some(s) => self.evaluate(ctxt.clone(), posn, s, f, sd, rd)?,
+			    None => match (&ctxt, posn) {
+				(Some(c), Some(p)) => vec![c[p].clone()],
+				_ => return Result::Err(Error{kind: ErrorKind::DynamicAbsent, message: String::from("no context item")}),
+			    }
+			};
+			if target.len() != 1 {
+			    return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("number must be for a single node")})
+			}
+			match &*target[0] {
+			    Item::Node(t) => self.number_node(t, n, f, sd, rd)?,
+			    _ => return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("number must be for a node")}),
+			}
+		    }
+		};
+		let format = self.evaluate(ctxt.clone(), posn, &n.format, f, sd, rd)?.to_string(Some(f));
+		// The other attributes are also attribute value templates
+		let avt = |v: &Option<Vec<Constructor>>, f: &mut Forest| -> Result<Option<String>, Error> {
+		    match v {
+			Some(c) => Ok(Some(self.evaluate(ctxt.clone(), posn, c, f, sd, rd)?.to_string(Some(f)))),
+			None => Ok(None),
+		    }
+		};
+		// Grouping is only used if both the separator and the size are given
+		let separator = avt(&n.grouping_separator, f)?;
+		let size = match avt(&n.grouping_size, f)? {
+		    Some(s) => Some(s.trim().parse::<usize>()
+			.map_err(|_| Error{kind: ErrorKind::InvalidAttributeValue, message: format!("\"{}\" is not a valid value for the grouping-size attribute", s.trim())})?),
+		    None => None,
+		};
+		let grouping = match (&separator, size) {
+		    (Some(sep), Some(size)) if size > 0 => Some((sep.as_str(), size)),
+		    _ => None,
+		};
+		// Only English is supported, so the language is not used
+		avt(&n.lang, f)?;
+		match avt(&n.letter_value, f)?.as_deref().map(str::trim) {
+		    None | Some("alphabetic") | Some("traditional") => {}
+		    Some(l) => return Result::Err(Error{kind: ErrorKind::InvalidAttributeValue, message: format!("\"{}\" is not a valid value for the letter-value attribute", l)}),
+		}
+		// Any non-empty value requests ordinal numbering
+		let ordinal = avt(&n.ordinal, f)?.is_some_and(|o| !o.trim().is_empty());
+		Ok(vec![Rc::new(Item::Value(Value::String(format_numbers(&nums, format.as_str(), ordinal, grouping))))])
+	    }
 	    Constructor::NotImplemented(m) => {
 		Result::Err(Error{kind: ErrorKind::NotImplemented, message: format!("sequence constructor not implemented: {}", m)})
 	    }
 	}
     }
 
+    // Determine the place of a node in the source document, for xsl:number
+    fn number_node(
+	&self,
+	target: &Node,
+	n: &Numbering,
+	f: &mut Forest,
+	sd: TreeIndex,
+	rd: TreeIndex,
+    ) -> Result<Vec<u64>, Error> {
+	match n.level {
+	    NumberLevel::Single |
+	    NumberLevel::Multiple => {
+		// Find the ancestor-or-self nodes that are counted, innermost first.
+		// Stop at the first node that matches the from pattern.
+		let mut anc = vec![*target];
+		let mut ait = target.ancestor_iter();
+		loop {
+		    match ait.next(f) {
+			Some(a) => anc.push(a),
+			None => break,
+		    }
+		}
+		let mut counted = vec![];
+		for a in anc {
+		    if self.number_count_matches(&a, target, &n.count, f, sd, rd)? {
+			counted.push(a);
+			if n.level == NumberLevel::Single {
+			    break
+			}
+		    }
+		    if self.number_from_matches(&a, &n.from, f, sd, rd)? {
+			break
+		    }
+		}
+		counted.reverse();
+		let mut result = vec![];
+		for c in counted {
+		    // Count the preceding siblings that also match
+		    let mut count = 1;
+		    if c.node_type(f) != NodeType::Attribute && c.parent(f).is_some() {
+			let mut pit = c.prev_iter(f);
+			loop {
+			    match pit.next(f) {
+				Some(p) => {
+				    if self.number_count_matches(&p, target, &n.count, f, sd, rd)? {
+					count += 1
+				    }
+				}
+				None => break,
+			    }
+			}
+		    }
+		    result.push(count);
+		}
+		Ok(result)
+	    }
+	    NumberLevel::Any => {
+		// Count the matching nodes that precede the target in document order (i.e. on the preceding and ancestor-or-self axes),
+		// walking backwards from the target and stopping at the first node that matches the from pattern.
+		let mut count = 0;
+		let mut cur = Some(*target);
+		while let Some(m) = cur {
+		    if self.number_count_matches(&m, target, &n.count, f, sd, rd)? {
+			count += 1
+		    }
+		    if self.number_from_matches(&m, &n.from, f, sd, rd)? {
+			break
+		    }
+		    cur = m.previous_in_document(f);
+		}
+		if count == 0 {
+		    Ok(vec![])
+		} else {
+		    Ok(vec![count])
+		}
+	    }
+	}
+    }
+    // The default count pattern matches nodes of the same type and name as the target node
+    fn number_count_matches(
+	&self,
+	m: &Node,
+	target: &Node,
+	count: &Option<Vec<Constructor>>,
+	f: &mut Forest,
+	sd: TreeIndex,
+	rd: TreeIndex,
+    ) -> Result<bool, Error> {
+	match count {
+	    Some(c) => self.item_matches(c, &Rc::new(Item::Node(*m)), f, sd, rd),
+	    None => {
+		match (m.node_type(f), target.node_type(f)) {
+		    (NodeType::Element, NodeType::Element) |
+		    (NodeType::Attribute, NodeType::Attribute) |
+		    (NodeType::ProcessingInstruction, NodeType::ProcessingInstruction) => {
+			Ok(m.to_name(f) == target.to_name(f))
+		    }
+		    (a, b) => Ok(a == b),
+		}
+	    }
+	}
+    }
+    fn number_from_matches(
+	&self,
+	m: &Node,
+	from: &Option<Vec<Constructor>>,
+	f: &mut Forest,
+	sd: TreeIndex,
+	rd: TreeIndex,
+    ) -> Result<bool, Error> {
+	match from {
+	    Some(c) => self.item_matches(c, &Rc::new(Item::Node(*m)), f, sd, rd),
+	    None => Ok(false),
+	}
+    }
+
     // Deep copy an item
     fn item_deep_copy(
 	&self,
@@ -1578,52 +2167,516 @@ impl Evaluator {
 	}
     }
 
-    fn general_comparison(
+    // Find the value of a global variable or stylesheet parameter.
+    // The value is evaluated when it is first referenced, with the source document as the context item.
+    fn global_value(
 	&self,
-	ctxt: Option<Sequence>,
-	posn: Option<usize>,
-	op: Operator,
-	left: &Vec<Constructor>,
-	right: &Vec<Constructor>,
+	v: &str,
 	f: &mut Forest,
 	sd: TreeIndex,
 	rd: TreeIndex,
-    ) -> Result<bool, Error> {
-	let mut b = false;
-	let left_seq =  self.evaluate(ctxt.clone(), posn, left, f, sd, rd)?;
-	let right_seq = self.evaluate(ctxt.clone(), posn, right, f, sd, rd)?;
-	for l in left_seq {
-	    for r in &right_seq {
-		b = l.compare(&*r, op, Some(f)).unwrap();
-      		if b { break }
+    ) -> Result<Sequence, Error> {
+	let qn = variable_qname(v);
+	let g = match self.globals.get(&qn) {
+	    Some((g, _)) => g,
+	    None => return Result::Err(Error{kind: ErrorKind::Unknown, message: format!("reference to undefined variable \"{}\"", v)}),
+	};
+	if let Some(state) = self.dc.globals.borrow().get(&qn) {
+	    return match state {
+		Some(s) => Ok(s.clone()),
+		None => Result::Err(Error{kind: ErrorKind::CircularVariable, message: format!("the value of variable \"{}\" depends on itself", v)}),
 	    }
-	    if b { break }
+	}
+
+	self.dc.globals.borrow_mut().insert(qn.clone(), None);
+	let r = match (g.param, self.params.get(&qn)) {
+	    (true, Some(s)) => Ok(s.clone()),
+	    (true, None) if g.required => Result::Err(Error{kind: ErrorKind::MissingStylesheetParameter, message: format!("no value supplied for required stylesheet parameter \"{}\"", v)}),
+	    _ => match &g.value {
+		Some(c) => {
+		    let ctxt = f.get_ref(sd)
+			.map(|t| vec![Rc::new(Item::Node(t.get_doc_node()))]);
+		    self.evaluate(ctxt, Some(0), c, f, sd, rd)
+		}
+		None if g.as_type.is_some() => Ok(vec![]),
+		None => Ok(vec![Rc::new(Item::Value(Value::from("")))]),
+	    },
+	}.and_then(|s| param_value(v, &g.as_type, s, f));
+	match &r {
+	    Ok(s) => self.dc.globals.borrow_mut().insert(qn, Some(s.clone())),
+	    Err(_) => self.dc.globals.borrow_mut().remove(&qn),
 	};
-	Ok(b)
+	r
     }
 
-    // Operands must be singletons
-    fn value_comparison(
+    // Evaluate the values of xsl:with-param, in the context of the caller.
+    // The result is tuples of (name, value, tunnel).
+    fn with_params(
 	&self,
 	ctxt: Option<Sequence>,
 	posn: Option<usize>,
-	op: Operator,
-	left: &Vec<Constructor>,
-	right: &Vec<Constructor>,
+	w: &[WithParam],
 	f: &mut Forest,
 	sd: TreeIndex,
 	rd: TreeIndex,
-    ) -> Result<bool, Error> {
-	let left_seq = self.evaluate(ctxt.clone(), posn, left, f, sd, rd)?;
-	if left_seq.len() == 0 {
-	    return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("left-hand sequence is empty"),})
+    ) -> Result<Vec<(String, Sequence, bool)>, Error> {
+	let mut result = vec![];
+	for p in w {
+	    let v = self.evaluate(ctxt.clone(), posn, &p.value, f, sd, rd)?;
+	    result.push((p.name.clone(), v, p.tunnel));
 	}
-	// TODO: Don't Panic
-	if left_seq.len() == 1 {
-	    let right_seq = self.evaluate(ctxt.clone(), posn, right, f, sd, rd)?;
-	    if right_seq.len() == 1 {
-		Ok(left_seq[0].compare(&*right_seq[0], op, Some(f)).unwrap())
-	    } else {
+	Ok(result)
+    }
+
+    // Evaluate a template for an item selected by xsl:apply-templates. The mode becomes the current mode.
+    #[allow(clippy::too_many_arguments)]
+    fn invoke_template_in_mode(
+	&self,
+	t: &Template,
+	m: &Mode,
+	i: &Rc<Item>,
+	wp: &[(String, Sequence, bool)],
+	f: &mut Forest,
+	sd: TreeIndex,
+	rd: TreeIndex,
+    ) -> Result<Sequence, Error> {
+	self.dc.current_mode.borrow_mut().push(m.clone());
+	let r = self.invoke_template(t, Some(vec![i.clone()]), Some(0), wp, f, sd, rd);
+	self.dc.current_mode.borrow_mut().pop();
+	r
+    }
+
+    // Find the nodes that have the given key values, within the top node (i.e. the key function).
+    // The index for the key is built when the key is first used for a tree.
+    fn key(
+	&self,
+	name: &str,
+	values: &Sequence,
+	top: &Node,
+	f: &mut Forest,
+	sd: TreeIndex,
+	rd: TreeIndex,
+    ) -> Result<Sequence, Error> {
+	// The name is a lexical QName, which is matched by its expanded name.
+	// A literal name has already been resolved to an EQName during static analysis; otherwise the prefix is resolved using the namespaces of the stylesheet.
+	let qn = match name.trim().split_once(':').filter(|_| !name.trim().starts_with("Q{")) {
+	    Some((p, l)) => match self.namespaces.get(p) {
+		Some(ns) => QualifiedName::new(Some(ns.clone()), Some(p.to_string()), l.to_string()),
+		None => return Result::Err(Error{kind: ErrorKind::NoSuchKey, message: format!("no namespace declared for prefix \"{}\" in key name \"{}\"", p, name)}),
+	    },
+	    None => variable_qname(name.trim()),
+	};
+	let defs = self.keys.get(&qn)
+	    .ok_or_else(|| Error{kind: ErrorKind::NoSuchKey, message: format!("no key named \"{}\"", name)})?;
+	let collation = self.collation(defs[0].collation.as_deref())?;
+	let composite = defs[0].composite;
+
+	let root = top.owner_document(f);
+	let cached = self.dc.keys.borrow().get(&(root.tree_index(), qn.clone())).cloned();
+	let index = match cached {
+	    Some(index) => index,
+	    None => {
+	    // Build the index. Attributes follow their element in document order.
+	    let mut nodes = vec![];
+	    preorder(&root, f, &mut nodes);
+	    let mut entries = vec![];
+	    for n in nodes {
+		let mut candidates = vec![n];
+		let mut ait = n.attribute_iter(f);
+		while let Some(a) = ait.next() {
+		    candidates.push(a)
+		}
+		for c in candidates {
+		    let i = Rc::new(Item::Node(c));
+		    for d in defs {
+			if self.item_matches(&d.matches, &i, f, sd, rd)? {
+			    let v = self.evaluate(Some(vec![i.clone()]), Some(0), &d.key_use, f, sd, rd)?;
+			    if composite {
+				entries.push((composite_key_string(&v, f, collation.as_ref()), c))
+			    } else {
+				for j in v {
+				    entries.push((key_string(&j, f, collation.as_ref()), c))
+				}
+			    }
+			}
+		    }
+		}
+	    }
+	    let index = Rc::new(KeyIndex::new(entries));
+	    self.dc.keys.borrow_mut().insert((root.tree_index(), qn), index.clone());
+	    index
+	    }
+	};
+
+	let wanted: Vec<String> = if composite {
+	    vec![composite_key_string(values, f, collation.as_ref())]
+	} else {
+	    values.iter().map(|j| key_string(j, f, collation.as_ref())).collect()
+	};
+	let found = index.get(&wanted);
+	// Only the nodes within the top node are returned
+	let result = if *top == root {
+	    found
+	} else {
+	    found.into_iter()
+		.filter(|n| {
+		    let mut within = n == top;
+		    let mut ait = n.ancestor_iter();
+		    while let (false, Some(a)) = (within, ait.next(f)) {
+			within = a == *top
+		    }
+		    within
+		})
+		.collect()
+	};
+	Ok(result.into_iter().map(|n| Rc::new(Item::Node(n))).collect())
+    }
+
+    // Sort a sequence, or a list of groups, by the given sort keys.
+    // The result is the positions of the items (or groups) in sorted order.
+    // The sort key values of an item are evaluated with the sequence as the context. For a group, the context is the group and the current group is set.
+    // The sort is stable.
+    #[allow(clippy::too_many_arguments)]
+    fn sort_order(
+	&self,
+	keys: &[SortKey],
+	ctxt: Option<Sequence>,
+	posn: Option<usize>,
+	groups: &[(Option<String>, Sequence)],
+	sel: Option<&Sequence>,
+	f: &mut Forest,
+	sd: TreeIndex,
+	rd: TreeIndex,
+    ) -> Result<Vec<usize>, Error> {
+	// Evaluate the attribute value templates of each key
+	let mut specs = vec![];
+	for k in keys {
+	    let avt = |v: &Option<Vec<Constructor>>, f: &mut Forest| -> Result<Option<String>, Error> {
+		match v {
+		    Some(c) => Ok(Some(self.evaluate(ctxt.clone(), posn, c, f, sd, rd)?.to_string(Some(f)).trim().to_string())),
+		    None => Ok(None),
+		}
+	    };
+	    let descending = match avt(&k.order, f)?.as_deref() {
+		None | Some("ascending") => false,
+		Some("descending") => true,
+		Some(o) => return Result::Err(Error{kind: ErrorKind::InvalidAttributeValue, message: format!("invalid sort order \"{}\"", o)}),
+	    };
+	    let data_type = avt(&k.data_type, f)?;
+	    match data_type.as_deref() {
+		None | Some("text") | Some("number") => {}
+		Some(d) => return Result::Err(Error{kind: ErrorKind::InvalidAttributeValue, message: format!("invalid sort data type \"{}\"", d)}),
+	    }
+	    let upper_first = match avt(&k.case_order, f)?.as_deref() {
+		None => None,
+		Some("upper-first") => Some(true),
+		Some("lower-first") => Some(false),
+		Some(c) => return Result::Err(Error{kind: ErrorKind::InvalidAttributeValue, message: format!("invalid case order \"{}\"", c)}),
+	    };
+	    // The language is not used to choose a collation
+	    avt(&k.lang, f)?;
+	    match avt(&k.stable, f)?.as_deref() {
+		None | Some("yes") | Some("no") | Some("true") | Some("false") | Some("1") | Some("0") => {}
+		Some(st) => return Result::Err(Error{kind: ErrorKind::InvalidAttributeValue, message: format!("invalid value \"{}\" for stable attribute", st)}),
+	    }
+	    let collation = self.collation(avt(&k.collation, f)?.as_deref())?;
+	    specs.push(SortSpec{descending, data_type, upper_first, collation});
+	}
+
+	// Find the sort key values of each item or group
+	let n = sel.map_or(groups.len(), |s| s.len());
+	let mut values: Vec<Vec<Option<Value>>> = Vec::with_capacity(n);
+	for i in 0..n {
+	    let mut v = vec![];
+	    for (k, spec) in keys.iter().zip(specs.iter()) {
+		let seq = match (sel, groups.get(i)) {
+		    (Some(s), _) => self.evaluate(Some(s.clone()), Some(i), &k.select, f, sd, rd)?,
+		    (None, Some((gk, grp))) => {
+			if let Some(u) = gk {
+			    self.dc.push_current_grouping_key(Item::Value(Value::from(u.to_string())));
+			    self.dc.push_current_group(grp.clone());
+			}
+			let r = self.evaluate(Some(grp.clone()), Some(0), &k.select, f, sd, rd);
+			if gk.is_some() {
+			    self.dc.pop_current_grouping_key();
+			    self.dc.pop_current_group();
+			}
+			r?
+		    }
+		    (None, None) => vec![],
+		};
+		if seq.len() > 1 {
+		    return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("a sort key must be a single item")})
+		}
+		v.push(seq.first().map(|j| {
+		    let w = match &**j {
+			Item::Value(w) => w.clone(),
+			_ => Value::from(j.to_string(Some(f))),
+		    };
+		    match spec.data_type.as_deref() {
+			Some("number") => Value::Double(w.to_double()),
+			Some("text") => Value::from(w.to_string()),
+			_ => w,
+		    }
+		}));
+	    }
+	    values.push(v)
+	}
+
+	// Sort the positions. The first error that occurs when comparing values is reported.
+	let mut order: Vec<usize> = (0..n).collect();
+	let mut error: Option<Error> = None;
+	order.sort_by(|a, b| {
+	    for (j, spec) in specs.iter().enumerate() {
+		let o = match compare_sort_values(&values[*a][j], &values[*b][j], spec) {
+		    Ok(o) => o,
+		    Err(e) => {
+			error.get_or_insert(e);
+			Ordering::Equal
+		    }
+		};
+		if o != Ordering::Equal {
+		    return if spec.descending {o.reverse()} else {o}
+		}
+	    }
+	    Ordering::Equal
+	});
+	match error {
+	    Some(e) => Result::Err(e),
+	    None => Ok(order),
+	}
+    }
+    // Create an element in the result document, and add its content.
+    // Attribute nodes in the content become attributes of the element, other values become text nodes.
+    #[allow(clippy::too_many_arguments)]
+    fn element(
+	&self,
+	n: QualifiedName,
+	c: &Vec<Constructor>,
+	ctxt: Option<Sequence>,
+	posn: Option<usize>,
+	f: &mut Forest,
+	sd: TreeIndex,
+	rd: TreeIndex,
+    ) -> Result<Sequence, Error> {
+	let l = f.get_ref_mut(rd)
+	    .ok_or(Error::new(ErrorKind::Unknown, String::from("no result document")))?
+	    .new_element(n)?;
+
+	// add content to newly created element
+	self.dc.constructing.borrow_mut().push(l);
+	let seq = self.evaluate(ctxt, posn, c, f, sd, rd);
+	self.dc.constructing.borrow_mut().pop();
+	for t in complex_content(seq?, f, rd)? {
+	    if t.node_type(f) == NodeType::Attribute {
+		l.add_attribute(f, t)?
+	    } else {
+		l.append_child(f, t)?
+	    }
+	}
+
+	Ok(vec![Rc::new(Item::Node(l))])
+    }
+    // The string value of the content of a text node, attribute, comment or processing instruction (XSLT 5.7.2).
+    // Zero-length text nodes are discarded and adjacent text nodes are merged. The remaining items are separated by the value of the separator.
+    #[allow(clippy::too_many_arguments)]
+    fn simple_content(
+	&self,
+	v: &Vec<Constructor>,
+	sep: &Vec<Constructor>,
+	ctxt: Option<Sequence>,
+	posn: Option<usize>,
+	f: &mut Forest,
+	sd: TreeIndex,
+	rd: TreeIndex,
+    ) -> Result<String, Error> {
+	let s = self.evaluate(ctxt.clone(), posn, sep, f, sd, rd)?.to_string(Some(f));
+	let w = self.evaluate(ctxt, posn, v, f, sd, rd)?;
+	let mut parts: Vec<String> = vec![];
+	let mut text = false;	// Was the previous item a text node?
+	for i in w {
+	    match &*i {
+		Item::Node(n) if n.node_type(f) == NodeType::Text => {
+		    let t = n.to_string(f);
+		    if t.is_empty() {
+			continue
+		    }
+		    match parts.last_mut() {
+			Some(p) if text => p.push_str(t.as_str()),
+			_ => parts.push(t),
+		    }
+		    text = true
+		}
+		_ => {
+		    parts.push(i.to_string(Some(f)));
+		    text = false
+		}
+	    }
+	}
+	Ok(parts.join(s.as_str()))
+    }
+    // Determine the name of an element or attribute (i.e. for xsl:element and xsl:attribute).
+    #[allow(clippy::too_many_arguments)]
+    fn computed_name(
+	&self,
+	cn: &ComputedName,
+	attribute: bool,
+	ctxt: Option<Sequence>,
+	posn: Option<usize>,
+	f: &mut Forest,
+	sd: TreeIndex,
+	rd: TreeIndex,
+    ) -> Result<QualifiedName, Error> {
+	let kind = if attribute {ErrorKind::InvalidAttributeName} else {ErrorKind::InvalidElementName};
+	let lexical = self.evaluate(ctxt.clone(), posn, &cn.name, f, sd, rd)?.to_string(Some(f));
+	let lexical = lexical.trim();
+	let (prefix, local) = match lexical.split_once(':') {
+	    Some((p, l)) => (Some(p), l),
+	    None => (None, lexical),
+	};
+	if !is_ncname(local) || prefix.is_some_and(|p| !is_ncname(p)) {
+	    return Result::Err(Error{kind, message: format!("\"{}\" is not a valid name", lexical)})
+	}
+	if attribute && prefix.is_none() && local == "xmlns" && cn.namespace.is_none() {
+	    return Result::Err(Error{kind, message: "an attribute cannot be named \"xmlns\"".to_string()})
+	}
+	match &cn.namespace {
+	    Some(ns) => {
+		let uri = self.evaluate(ctxt, posn, ns, f, sd, rd)?.to_string(Some(f));
+		if uri.is_empty() {
+		    Ok(QualifiedName::new(None, None, local.to_string()))
+		} else if attribute && prefix.is_none() {
+		    // An attribute in a namespace must have a prefix
+		    let p = cn.bindings.iter()
+			.find(|(p, u)| p.is_some() && *u == uri)
+			.and_then(|(p, _)| p.clone())
+			.unwrap_or_else(|| String::from("ns0"));
+		    Ok(QualifiedName::new(Some(uri), Some(p), local.to_string()))
+		} else {
+		    Ok(QualifiedName::new(Some(uri), prefix.map(String::from), local.to_string()))
+		}
+	    }
+	    None => {
+		if attribute && prefix.is_none() {
+		    // The default namespace does not apply to attributes
+		    return Ok(QualifiedName::new(None, None, local.to_string()))
+		}
+		match cn.bindings.iter().find(|(p, _)| p.as_deref() == prefix) {
+		    Some((_, u)) => Ok(QualifiedName::new(Some(u.clone()), prefix.map(String::from), local.to_string())),
+		    None if prefix.is_none() => Ok(QualifiedName::new(None, None, local.to_string())),
+		    None => Result::Err(Error{kind, message: format!("no namespace declared for prefix \"{}\"", prefix.unwrap_or(""))}),
+		}
+	    }
+	}
+    }
+    // Evaluate the body of a template with the given context.
+    // The template has its own scope for local variables, so the caller's local variables are not visible.
+    // Each parameter of the template is bound to its supplied value, or else its default value.
+    // Tunnel parameters supplied by the caller are added to those already in effect.
+    #[allow(clippy::too_many_arguments)]
+    fn invoke_template(
+	&self,
+	t: &Template,
+	ctxt: Option<Sequence>,
+	posn: Option<usize>,
+	wp: &[(String, Sequence, bool)],
+	f: &mut Forest,
+	sd: TreeIndex,
+	rd: TreeIndex,
+    ) -> Result<Sequence, Error> {
+	let mut tunnel = self.dc.tunnel.borrow().last().cloned().unwrap_or_default();
+	wp.iter()
+	    .filter(|(_, _, tn)| *tn)
+	    .for_each(|(n, v, _)| {
+		tunnel.insert(n.clone(), v.clone());
+	    });
+
+	let outer = self.dc.vars.replace(HashMap::new());
+	let mut status = Ok(());
+	for p in &t.params {
+	    let supplied = if p.tunnel {
+		tunnel.get(&p.name).cloned()
+	    } else {
+		wp.iter()
+		    .find(|(n, _, tn)| !*tn && *n == p.name)
+		    .map(|(_, v, _)| v.clone())
+	    };
+	    let v = match supplied {
+		Some(v) => Ok(v),
+		None if p.required => Result::Err(Error{kind: ErrorKind::MissingParameter, message: format!("no value supplied for required parameter \"{}\"", p.name)}),
+		None => match &p.default {
+		    // The default may refer to parameters that have already been bound
+		    Some(d) => self.evaluate(ctxt.clone(), posn, d, f, sd, rd),
+		    None if p.as_type.is_some() => Ok(vec![]),
+		    None => Ok(vec![Rc::new(Item::Value(Value::from("")))]),
+		},
+	    }.and_then(|v| param_value(&p.name, &p.as_type, v, f));
+	    match v {
+		Ok(u) => self.dc.var_push(&p.name, u),
+		Err(e) => {
+		    status = Result::Err(e);
+		    break
+		}
+	    }
+	}
+	let result = status.and_then(|_| {
+	    self.dc.tunnel.borrow_mut().push(tunnel);
+	    self.dc.depth_incr();
+	    let r = self.evaluate(ctxt, posn, &t.body, f, sd, rd);
+	    self.dc.depth_decr();
+	    self.dc.tunnel.borrow_mut().pop();
+	    r
+	});
+	self.dc.vars.replace(outer);
+	result
+    }
+
+    fn general_comparison(
+	&self,
+	ctxt: Option<Sequence>,
+	posn: Option<usize>,
+	op: Operator,
+	left: &Vec<Constructor>,
+	right: &Vec<Constructor>,
+	f: &mut Forest,
+	sd: TreeIndex,
+	rd: TreeIndex,
+    ) -> Result<bool, Error> {
+	let mut b = false;
+	let left_seq =  self.evaluate(ctxt.clone(), posn, left, f, sd, rd)?;
+	let right_seq = self.evaluate(ctxt.clone(), posn, right, f, sd, rd)?;
+	for l in left_seq {
+	    for r in &right_seq {
+		b = l.compare(&*r, op, Some(f)).unwrap();
+      		if b { break }
+	    }
+	    if b { break }
+	};
+	Ok(b)
+    }
+
+    // Operands must be singletons
+    fn value_comparison(
+	&self,
+	ctxt: Option<Sequence>,
+	posn: Option<usize>,
+	op: Operator,
+	left: &Vec<Constructor>,
+	right: &Vec<Constructor>,
+	f: &mut Forest,
+	sd: TreeIndex,
+	rd: TreeIndex,
+    ) -> Result<bool, Error> {
+	let left_seq = self.evaluate(ctxt.clone(), posn, left, f, sd, rd)?;
+	if left_seq.len() == 0 {
+	    return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("left-hand sequence is empty"),})
+	}
+	// TODO: Don't Panic
+	if left_seq.len() == 1 {
+	    let right_seq = self.evaluate(ctxt.clone(), posn, right, f, sd, rd)?;
+	    if right_seq.len() == 1 {
+		Ok(left_seq[0].compare(&*right_seq[0], op, Some(f)).unwrap())
+	    } else {
 		Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("right-hand sequence is not a singleton sequence"),})
 	    }
 	} else {
@@ -1644,9 +2697,11 @@ pub enum Constructor {
   /// Arguments are: element name, content
   LiteralElement(QualifiedName, Vec<Constructor>),
   /// A literal attribute. This will become a node in the result tree.
-  /// TODO: allow for attribute value templates
   /// Arguments are: attribute name, value
   LiteralAttribute(QualifiedName, Vec<Constructor>),
+  /// An attribute value template. Each element of the vector is either a fixed part or an enclosed expression.
+  /// The result is the concatenation of the string values of the parts; the items that result from an expression are separated by a space.
+  AttributeValueTemplate(Vec<Vec<Constructor>>),
   /// Construct a node by copying something. The first argument is what to copy; an empty vector selects the current item. The second argument constructs the content.
   Copy(Vec<Constructor>, Vec<Constructor>),
   DeepCopy(Vec<Constructor>),
@@ -1658,10 +2713,27 @@ pub enum Constructor {
   And(Vec<Vec<Constructor>>),
   // Union,
   // IntersectExcept,
-  // InstanceOf,
+  /// Test whether a sequence matches a sequence type (i.e. 'instance of').
+  InstanceOf(Vec<Constructor>, SequenceType),
   // Treat,
-  // Castable,
-  // Cast,
+  /// Test whether a value can be cast to a type (i.e. 'castable as').
+  /// Arguments are the same as for Cast.
+  Castable(Vec<Constructor>, QualifiedName, bool),
+  /// Cast a value to an XML Schema atomic or list type.
+  /// The first argument is the value, the second argument is the name of the type.
+  /// The third argument is whether the empty sequence is allowed.
+  Cast(Vec<Constructor>, QualifiedName, bool),
+  /// Construct a map (i.e. 'map { key : value, ... }').
+  /// Each element of the vector is a pair of constructors for a key and its value.
+  MapConstructor(Vec<(Vec<Constructor>, Vec<Constructor>)>),
+  /// Construct an array (i.e. '[ member, ... ]'). Each element of the outer vector is a member.
+  ArrayConstructor(Vec<Vec<Constructor>>),
+  /// Construct an array (i.e. 'array { ... }'). Each item in the sequence is a member.
+  CurlyArrayConstructor(Vec<Constructor>),
+  /// Look up values in maps or arrays (i.e. the '?' operator).
+  /// The first argument selects the maps and arrays, the second argument gives the keys.
+  /// If there are no keys (i.e. '?*') then all of the values are selected.
+  Lookup(Vec<Constructor>, Option<Vec<Constructor>>),
   // Arrow,
   // Unary,
   // SimpleMap,
@@ -1707,22 +2779,129 @@ pub enum Constructor {
   /// The second argument is the otherwise clause
   Switch(Vec<Vec<Constructor>>, Vec<Constructor>),
     /// Find a matching template and evaluate its sequence constructor.
-    /// The argument is the select attribute.
-    ApplyTemplates(Vec<Constructor>),
+    /// The first argument is the select attribute, the second argument is the mode, the third argument is the parameter values.
+    ApplyTemplates(Vec<Constructor>, Mode, Vec<WithParam>),
+    /// Evaluate the named template (i.e. xsl:call-template).
+    /// The first argument is the name of the template, the second argument is the parameter values.
+    CallTemplate(QualifiedName, Vec<WithParam>),
     /// Find a matching template at the next import precedence
     /// and evaluate its sequence constructor.
     ApplyImports,
     /// Evaluate a sequence constructor for each item, possibly grouped.
     /// First argument is the select expression, second argument is the template,
-    /// third argument is the (optional) grouping spec.
-    ForEach(Vec<Constructor>, Vec<Constructor>, Option<Grouping>),
+    /// third argument is the (optional) grouping spec, fourth argument is the sort keys.
+    /// If the items are grouped, then the groups are sorted.
+    ForEach(Vec<Constructor>, Vec<Constructor>, Option<Grouping>, Vec<SortKey>),
+    /// Sort a sequence (i.e. xsl:perform-sort, or the select expression of xsl:apply-templates).
+    /// The first argument is the sequence to sort, the second argument is the sort keys.
+    Sort(Vec<Constructor>, Vec<SortKey>),
     /// Set the value of an attribute. Context item must be an element node.
     /// First argument is the name of the attribute, second attribute is the value to set
     SetAttribute(QualifiedName, Vec<Constructor>),
+    /// Construct an element with a computed name (i.e. xsl:element).
+    /// The first argument is the name, the second argument is the content of the element.
+    Element(ComputedName, Vec<Constructor>),
+    /// Construct an attribute with a computed name (i.e. xsl:attribute).
+    /// The first argument is the name, the second argument is the value and the third argument is the separator between the items of the value.
+    Attribute(ComputedName, Vec<Constructor>, Vec<Constructor>),
+    /// Construct a text node (i.e. xsl:value-of, xsl:text, or text in a sequence constructor). The first argument is the value, the second argument is the separator between the items of the value.
+    /// A zero-length text node may be constructed, but it is discarded when it is added to an element or document.
+    Text(Vec<Constructor>, Vec<Constructor>),
+    /// Construct a comment. The first argument is the value, the second argument is the separator between the items of the value.
+    Comment(Vec<Constructor>, Vec<Constructor>),
+    /// Construct a processing instruction.
+    /// The first argument is the name, the second argument is the value and the third argument is the separator between the items of the value.
+    ProcessingInstruction(Vec<Constructor>, Vec<Constructor>, Vec<Constructor>),
+    /// Add a namespace node to the element that is being constructed (i.e. xsl:namespace).
+    /// The first argument is the prefix, the second argument is the namespace URI.
+    Namespace(Vec<Constructor>, Vec<Constructor>),
+    /// Construct the attributes of the named attribute sets.
+    UseAttributeSets(Vec<QualifiedName>),
+    /// Number a node, or format a number (i.e. xsl:number).
+    Number(Numbering),
+    /// Construct a document node, i.e. a temporary tree. The argument is the content of the document.
+    Document(Vec<Constructor>),
+    /// Produce a secondary result document, and give it to the result document handler (i.e. xsl:result-document).
+    /// The second argument constructs the document node of the result document.
+    ResultDocument(ResultDocument, Vec<Constructor>),
+    /// Send a message to the message listener (i.e. xsl:message).
+    /// The first argument is the content of the message, the second argument is whether to terminate processing ("yes" or "no") and the third argument is the error code.
+    Message(Vec<Constructor>, Vec<Constructor>, Option<ComputedName>),
     /// Something that is not yet implemented
     NotImplemented(String),
 }
 
+/// The name of an element or attribute that is computed when the stylesheet is evaluated.
+/// The name and namespace are usually attribute value templates. The bindings are the namespaces that are in scope for the instruction, and are used to resolve the prefix of the name if no namespace is given.
+#[derive(Clone)]
+pub struct ComputedName {
+  pub name: Vec<Constructor>,
+  pub namespace: Option<Vec<Constructor>>,
+  pub bindings: Vec<(Option<String>, String)>,
+}
+
+/// How to determine the number for a node, and how to format it.
+/// If the value is given then it is formatted, otherwise the number of the selected node (or the context item) is determined.
+/// The count and from patterns are compiled patterns (see [to_pattern]). If count is None, then nodes with the same type and name as the selected node are counted. If from is None, then numbering starts from the root.
+#[derive(Clone)]
+pub struct Numbering {
+  pub level: NumberLevel,
+  pub count: Option<Vec<Constructor>>,
+  pub from: Option<Vec<Constructor>>,
+  pub value: Option<Vec<Constructor>>,
+  pub select: Option<Vec<Constructor>>,
+  pub format: Vec<Constructor>,
+  pub grouping_separator: Option<Vec<Constructor>>,
+  pub grouping_size: Option<Vec<Constructor>>,
+  pub lang: Option<Vec<Constructor>>,
+  pub letter_value: Option<Vec<Constructor>>,
+  pub ordinal: Option<Vec<Constructor>>,
+}
+
+impl Default for Numbering {
+  fn default() -> Self {
+    Numbering{
+      level: NumberLevel::Single,
+      count: None,
+      from: None,
+      value: None,
+      select: None,
+      format: vec![Constructor::Literal(Value::from("1"))],
+      grouping_separator: None,
+      grouping_size: None,
+      lang: None,
+      letter_value: None,
+      ordinal: None,
+    }
+  }
+}
+
+/// Which nodes are counted when numbering a node.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum NumberLevel {
+  /// Count preceding siblings of the first matching ancestor-or-self.
+  Single,
+  /// Count preceding siblings of each matching ancestor-or-self, giving a hierarchic number.
+  Multiple,
+  /// Count matching nodes that precede the node in document order.
+  Any,
+}
+
+// Nodes of a tree in document order, not including attributes.
+// The nodes that are yet to be visited are kept on a stack, rather than recursing, so that a deeply nested tree does not overflow the call stack.
+fn preorder(n: &Node, f: &Forest, v: &mut Vec<Node>) {
+  let mut stack = vec![*n];
+  while let Some(m) = stack.pop() {
+    v.push(m);
+    let mut children = vec![];
+    let mut cit = m.child_iter();
+    while let Some(c) = cit.next(f) {
+      children.push(c)
+    }
+    stack.extend(children.into_iter().rev());
+  }
+}
+
 /// Determine how a collection is to be divided into groups.
 /// This enum would normally be inside an Option. The None value means that the collection is not to be grouped.
 #[derive(Clone)]
@@ -1733,6 +2912,233 @@ pub enum Grouping {
   Adjacent(Vec<Constructor>),
 }
 
+/// The properties of a result document (i.e. xsl:result-document). Each property is an attribute value template.
+/// The format is the name of an output definition, otherwise the unnamed output definition is used. The method and indent attributes override the output definition.
+#[derive(Clone)]
+pub struct ResultDocument {
+  pub href: Option<Vec<Constructor>>,
+  pub format: Option<ComputedName>,
+  pub method: Option<Vec<Constructor>>,
+  pub indent: Option<Vec<Constructor>>,
+}
+
+/// A key definition (i.e. xsl:key).
+/// The match pattern (see [to_pattern]) selects the nodes that are indexed. The use expression is evaluated for each of those nodes to find its key values.
+/// If the key is composite, then the sequence of values is a single key value. Strings are compared using the collation, or the default collation if it is None.
+#[derive(Clone)]
+pub struct KeyDefinition {
+  pub matches: Vec<Constructor>,
+  pub key_use: Vec<Constructor>,
+  pub composite: bool,
+  pub collation: Option<String>,
+}
+
+/// A sort key (i.e. xsl:sort).
+/// The select expression is evaluated for each item to find its sort key value. The other properties are attribute value templates that are evaluated before sorting begins.
+/// If there are several sort keys then the first is the major key.
+#[derive(Clone)]
+pub struct SortKey {
+  pub select: Vec<Constructor>,
+  pub order: Option<Vec<Constructor>>,
+  pub data_type: Option<Vec<Constructor>>,
+  pub case_order: Option<Vec<Constructor>>,
+  pub lang: Option<Vec<Constructor>>,
+  pub collation: Option<Vec<Constructor>>,
+  pub stable: Option<Vec<Constructor>>,
+}
+
+impl Default for SortKey {
+  fn default() -> Self {
+    SortKey{
+      select: vec![Constructor::ContextItem],
+      order: None,
+      data_type: None,
+      case_order: None,
+      lang: None,
+      collation: None,
+      stable: None,
+    }
+  }
+}
+
+// A sort key, with its attribute value templates evaluated
+struct SortSpec {
+  descending: bool,
+  data_type: Option<String>,
+  upper_first: Option<bool>,
+  collation: Rc<dyn Collation>,
+}
+
+/// The name of a variable, as used in a [Constructor::VariableDeclaration] or [Constructor::VariableReference]. A name in a namespace is written as an EQName, i.e. "Q{uri}local".
+pub fn variable_name(qn: &QualifiedName) -> String {
+  match qn.get_nsuri_ref() {
+    Some(ns) => format!("Q{{{}}}{}", ns, qn.get_localname()),
+    None => qn.get_localname(),
+  }
+}
+
+// The qualified name of a variable (see variable_name)
+fn variable_qname(v: &str) -> QualifiedName {
+  match v.strip_prefix("Q{").and_then(|r| r.split_once('}')) {
+    Some((ns, local)) => QualifiedName::new(Some(ns.to_string()), None, local.to_string()),
+    None => QualifiedName::new(None, None, v.to_string()),
+  }
+}
+
+// The value of a yes-or-no attribute
+pub(crate) fn yes_no(v: &str, attr: &str) -> Result<bool, Error> {
+  match v.trim() {
+    "yes" | "true" | "1" => Ok(true),
+    "no" | "false" | "0" => Ok(false),
+    _ => Result::Err(Error{kind: ErrorKind::InvalidAttributeValue, message: format!("\"{}\" is not a valid value for the {} attribute", v.trim(), attr)}),
+  }
+}
+
+// Construct complex content, i.e. the content of an element or document node (XSLT 5.7.1).
+// Adjacent atomic values are separated by a space, and become text nodes in the tree t. Document nodes are replaced by their children.
+// Zero-length text nodes are discarded and adjacent text nodes are merged, so every text node in the result is a new node in t.
+fn complex_content(seq: Sequence, f: &mut Forest, t: TreeIndex) -> Result<Vec<Node>, Error> {
+  let mut result = vec![];
+  let mut text = String::new();
+  let mut atomic = false;	// Was the previous item an atomic value?
+  let mut nodes = vec![];
+  for i in seq {
+    match &*i {
+      Item::Node(n) if n.node_type(f) == NodeType::Document => {
+        let mut cit = n.child_iter();
+        while let Some(c) = cit.next(f) {
+          nodes.push(Some(c))
+        }
+      }
+      Item::Node(n) => nodes.push(Some(*n)),
+      _ => {
+        // Atomic values are converted to text before any following items are considered
+        nodes.push(None);
+        if atomic {
+          text.push(' ')
+        }
+        text.push_str(i.to_string(Some(f)).as_str());
+      }
+    }
+    for n in nodes.drain(..) {
+      match n {
+        None => atomic = true,
+        Some(m) if m.node_type(f) == NodeType::Text => {
+          text.push_str(m.to_string(f).as_str());
+          atomic = false
+        }
+        Some(m) => {
+          if !text.is_empty() {
+            let tn = f.get_ref_mut(t)
+              .ok_or(Error::new(ErrorKind::Unknown, String::from("no result document")))?
+              .new_text(Value::from(std::mem::take(&mut text)))?;
+            result.push(tn)
+          }
+          result.push(m);
+          atomic = false
+        }
+      }
+    }
+  }
+  if !text.is_empty() {
+    let tn = f.get_ref_mut(t)
+      .ok_or(Error::new(ErrorKind::Unknown, String::from("no result document")))?
+      .new_text(Value::from(text))?;
+    result.push(tn)
+  }
+  Ok(result)
+}
+
+// The index for a key (i.e. xsl:key) in a tree. Nodes are indexed by key value, with their position in document order.
+struct KeyIndex(HashMap<String, Vec<(usize, Node)>>);
+
+impl KeyIndex {
+    // The entries are pairs of a key value and a node, in document order. A node that has several key values has an entry for each value.
+    fn new(entries: Vec<(String, Node)>) -> Self {
+	let mut index: HashMap<String, Vec<(usize, Node)>> = HashMap::new();
+	let mut posn = 0;
+	let mut last: Option<Node> = None;
+	for (k, n) in entries {
+	    if last != Some(n) {
+		posn += 1;
+		last = Some(n);
+	    }
+	    index.entry(k).or_default().push((posn, n))
+	}
+	KeyIndex(index)
+    }
+    // Find the nodes that have any of the given key values, in document order and without duplicates.
+    fn get(&self, values: &[String]) -> Vec<Node> {
+	let mut found: Vec<(usize, Node)> = values.iter()
+	    .filter_map(|v| self.0.get(v))
+	    .flatten()
+	    .cloned()
+	    .collect();
+	found.sort_by_key(|(p, _)| *p);
+	found.dedup_by_key(|(p, _)| *p);
+	found.into_iter().map(|(_, n)| n).collect()
+    }
+}
+
+// Compare two sort key values. An empty key sorts before any value, and NaN sorts before any other number.
+// Strings are compared using the collation of the sort key.
+fn compare_sort_values(a: &Option<Value>, b: &Option<Value>, spec: &SortSpec) -> Result<Ordering, Error> {
+  match (a, b) {
+    (None, None) => Ok(Ordering::Equal),
+    (None, Some(_)) => Ok(Ordering::Less),
+    (Some(_), None) => Ok(Ordering::Greater),
+    (Some(v), Some(w)) => {
+      if v.is_numeric() && w.is_numeric() {
+        match (v.to_double().is_nan(), w.to_double().is_nan()) {
+          (true, true) => return Ok(Ordering::Equal),
+          (true, false) => return Ok(Ordering::Less),
+          (false, true) => return Ok(Ordering::Greater),
+          _ => {}
+        }
+      } else if is_string_like(v) && is_string_like(w) {
+        let (s, t) = (v.to_string(), w.to_string());
+        // Strings that differ only by case are ordered by the case of the first character that differs
+        if let Some(upper) = spec.upper_first {
+          if s != t && s.to_lowercase() == t.to_lowercase() {
+            if let Some((c, _)) = s.chars().zip(t.chars()).find(|(c, d)| c != d) {
+              return Ok(if c.is_uppercase() == upper {Ordering::Less} else {Ordering::Greater})
+            }
+          }
+        }
+        return Ok(spec.collation.compare(&s, &t))
+      }
+      if v.compare(w, Operator::LessThan)? {
+        Ok(Ordering::Less)
+      } else if v.compare(w, Operator::Equal)? {
+        Ok(Ordering::Equal)
+      } else {
+        Ok(Ordering::Greater)
+      }
+    }
+  }
+}
+
+// The value of an item in a key index. Strings are compared using the collation, and numbers are compared by their value.
+fn key_string(i: &Item, f: &Forest, c: &dyn Collation) -> String {
+  let v = match i {
+    Item::Value(v) => v.clone(),
+    _ => Value::from(i.to_string(Some(f))),
+  };
+  if v.is_numeric() {
+    format!("n{}", v.to_double())
+  } else {
+    format!("s{}", c.key(v.to_string().as_str()))
+  }
+}
+// The value of a composite key, which is the values of all of the items
+fn composite_key_string(s: &Sequence, f: &Forest, c: &dyn Collation) -> String {
+  s.iter().map(|i| key_string(i, f, c)).collect::<Vec<String>>().join("\u{0}")
+}
+
+fn is_string_like(v: &Value) -> bool {
+  matches!(v, Value::String(_) | Value::AnyURI(_)) || v.is_string_derived()
+}
+
 // Apply the node test to a Node.
 // TODO: Make this a method of the Node trait?
 fn is_node_match(nt: &NodeTest, n: &Node, f: &Forest) -> bool {
@@ -1870,21 +3276,159 @@ impl NodeTest {
   }
 }
 
+/// A sequence type, e.g. "xs:NCName?" or "element()*".
 #[derive(Clone)]
-pub enum KindTest {
-  DocumentTest,
-  ElementTest,
-  AttributeTest,
-  SchemaElementTest,
-  SchemaAttributeTest,
-  PITest,
-  CommentTest,
-  TextTest,
-  NamespaceNodeTest,
-  AnyKindTest,
+pub enum SequenceType {
+  /// empty-sequence()
+  Empty,
+  /// An item type, with its occurrence indicator
+  Items(ItemType, Occurrence),
 }
 
-impl KindTest {
+impl SequenceType {
+  /// Does the sequence match this type?
+  pub fn matches(&self, s: &Sequence, f: &Forest) -> bool {
+    match self {
+      SequenceType::Empty => s.is_empty(),
+      SequenceType::Items(t, o) => {
+	let n = match o {
+	  Occurrence::One => s.len() == 1,
+	  Occurrence::ZeroOrOne => s.len() <= 1,
+	  Occurrence::ZeroOrMore => true,
+	  Occurrence::OneOrMore => !s.is_empty(),
+	};
+	n && s.iter().all(|i| t.matches(i, f))
+      }
+    }
+  }
+}
+
+impl fmt::Display for SequenceType {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      SequenceType::Empty => f.write_str("empty-sequence()"),
+      SequenceType::Items(t, o) => write!(f, "{}{}", t, o.to_string()),
+    }
+  }
+}
+
+/// The type of an item in a sequence type.
+#[derive(Clone)]
+pub enum ItemType {
+  /// item(), i.e. any item
+  Item,
+  /// A node kind test
+  Kind(KindTest),
+  /// An atomic or list type, named in the XML Schema namespace
+  Atomic(QualifiedName),
+}
+
+impl ItemType {
+  /// Does the item match this type?
+  pub fn matches(&self, i: &Item, f: &Forest) -> bool {
+    match (self, i) {
+      (ItemType::Item, _) => true,
+      (ItemType::Kind(k), Item::Node(n)) => is_node_match(&NodeTest::Kind(k.clone()), n, f),
+      (ItemType::Atomic(t), Item::Value(v)) => xsd_type_name(t).is_ok_and(|n| v.instance_of(&n)),
+      _ => false,
+    }
+  }
+}
+
+impl fmt::Display for ItemType {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      ItemType::Item => f.write_str("item()"),
+      ItemType::Kind(k) => f.write_str(k.to_string()),
+      ItemType::Atomic(t) => f.write_str(&t.to_string()),
+    }
+  }
+}
+
+/// How many items may occur in a sequence type.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Occurrence {
+  /// exactly one item
+  One,
+  /// '?'
+  ZeroOrOne,
+  /// '*'
+  ZeroOrMore,
+  /// '+'
+  OneOrMore,
+}
+
+impl Occurrence {
+  pub fn to_string(&self) -> &'static str {
+    match self {
+      Occurrence::One => "",
+      Occurrence::ZeroOrOne => "?",
+      Occurrence::ZeroOrMore => "*",
+      Occurrence::OneOrMore => "+",
+    }
+  }
+}
+
+// The local name of a built-in type. The name must be in the XML Schema namespace.
+fn xsd_type_name(t: &QualifiedName) -> Result<String, Error> {
+  let xs = match t.get_nsuri_ref() {
+    Some(ns) => ns == XSDNS,
+    None => t.get_prefix().as_deref() == Some("xs"),
+  };
+  if xs {
+    Ok(t.get_localname())
+  } else {
+    Result::Err(Error{kind: ErrorKind::UnknownType, message: format!("unknown type \"{}\"", t.to_string())})
+  }
+}
+
+// Check that the value of a template parameter matches its required type.
+// Atomic values, and the atomized values of nodes, are cast to the required type if necessary.
+fn param_value(name: &str, as_type: &Option<SequenceType>, v: Sequence, f: &Forest) -> Result<Sequence, Error> {
+  match as_type {
+    None => Ok(v),
+    Some(st) if st.matches(&v, f) => Ok(v),
+    Some(st) => {
+      let converted = match st {
+	SequenceType::Items(ItemType::Atomic(t), _) => {
+	  let tn = xsd_type_name(t)?;
+	  v.iter()
+	    .map(|i| atomize(i, f).and_then(|a| a.cast_as(&tn)).map(|a| Rc::new(Item::Value(a))))
+	    .collect::<Result<Sequence, Error>>()
+	    .ok()
+	    .filter(|c| st.matches(c, f))
+	}
+	_ => None,
+      };
+      converted.ok_or_else(|| Error{kind: ErrorKind::ParameterType, message: format!("value of parameter \"{}\" does not match required type {}", name, st)})
+    }
+  }
+}
+
+// The operand of a cast must be a single atomic value, or the empty sequence if that is allowed.
+fn cast_operand(s: &Sequence, f: &Forest, empty: bool) -> Result<Option<Value>, Error> {
+  match s.len() {
+    0 if empty => Ok(None),
+    1 => atomize(&s[0], f).map(Some),
+    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("operand of a cast must be a single atomic value")}),
+  }
+}
+
+#[derive(Clone)]
+pub enum KindTest {
+  DocumentTest,
+  ElementTest,
+  AttributeTest,
+  SchemaElementTest,
+  SchemaAttributeTest,
+  PITest,
+  CommentTest,
+  TextTest,
+  NamespaceNodeTest,
+  AnyKindTest,
+}
+
+impl KindTest {
   pub fn to_string(&self) -> &'static str {
     match self {
       KindTest::DocumentTest => "DocumentTest",
@@ -2016,31 +3560,6 @@ impl Axis {
   }
 }
 
-#[derive(Copy, Clone)]
-pub enum ArithmeticOperator {
-  Noop,
-  Add,
-  Multiply,
-  Divide,
-  IntegerDivide,
-  Subtract,
-  Modulo,
-}
-
-impl From<&str> for ArithmeticOperator {
-  fn from(a: &str) -> Self {
-    match a {
-      "+" => ArithmeticOperator::Add,
-      "*" => ArithmeticOperator::Multiply,
-      "div" => ArithmeticOperator::Divide,
-      "idiv" => ArithmeticOperator::IntegerDivide,
-      "-" => ArithmeticOperator::Subtract,
-      "mod" => ArithmeticOperator::Modulo,
-      _ => ArithmeticOperator::Noop,
-    }
-  }
-}
-
 #[derive(Clone)]
 pub struct ArithmeticOperand {
   pub op: ArithmeticOperator,
@@ -2183,16 +3702,75 @@ pub struct Template {
     pattern: Vec<Constructor>,
     body: Vec<Constructor>,
     priority: f64,
-    mode: Option<String>,
+    modes: Vec<Mode>,
     import: usize,
+    name: Option<QualifiedName>,
+    params: Vec<TemplateParam>,
+}
+
+/// The mode of a template, or of xsl:apply-templates. A template is only matched by xsl:apply-templates in the same mode.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Mode {
+    /// The unnamed mode
+    Default,
+    /// A named mode
+    Named(QualifiedName),
+    /// The template is in all modes (i.e. #all)
+    All,
+    /// Apply templates in the current mode (i.e. #current)
+    Current,
+}
+
+/// A parameter declared by a template (i.e. xsl:param).
+#[derive(Clone)]
+pub struct TemplateParam {
+    pub name: String,	// TODO: support QName
+    /// The default value, used if no value is supplied. If there is no default then the value is the empty string, or the empty sequence if the parameter has a required type.
+    pub default: Option<Vec<Constructor>>,
+    /// A value must be supplied for a required parameter.
+    pub required: bool,
+    /// Tunnel parameters are passed on implicitly to templates invoked by this template.
+    pub tunnel: bool,
+    /// The required type of the value (i.e. the as attribute).
+    pub as_type: Option<SequenceType>,
+}
+
+/// A global variable or stylesheet parameter (i.e. a top-level xsl:variable or xsl:param).
+#[derive(Clone)]
+pub struct GlobalVariable {
+    pub name: QualifiedName,
+    /// The value of the variable, or the default value of the parameter. If there is no value then it is the empty string, or the empty sequence if the variable has a required type.
+    pub value: Option<Vec<Constructor>>,
+    /// The required type of the value (i.e. the as attribute).
+    pub as_type: Option<SequenceType>,
+    /// Whether this is a stylesheet parameter, whose value may be supplied by the caller.
+    pub param: bool,
+    /// A value must be supplied for a required stylesheet parameter.
+    pub required: bool,
+}
+
+/// A value supplied for a template parameter (i.e. xsl:with-param).
+#[derive(Clone)]
+pub struct WithParam {
+    pub name: String,	// TODO: support QName
+    pub value: Vec<Constructor>,
+    pub tunnel: bool,
+}
+
+impl Template {
+    // Is the template in the given mode?
+    fn in_mode(&self, m: &Mode) -> bool {
+	self.modes.iter().any(|tm| *tm == Mode::All || tm == m)
+    }
 }
 
 impl fmt::Debug for Template {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-	write!(f, "match {} prio {}, import {}",
+	write!(f, "match {} prio {}, import {}, modes {:?}",
 	       format_constructor(&self.pattern, 0),
 	       self.priority,
-	       self.import
+	       self.import,
+	       self.modes
 	)
     }
 }
@@ -2203,8 +3781,31 @@ impl fmt::Debug for Template {
 ///
 /// Currently, this stores the set of functions and variables available to a constructor.
 pub struct StaticContext {
-  pub funcs: RefCell<HashMap<String, Function>>,
+  pub funcs: RefCell<HashMap<String, Function>>,	// keyed by expanded name, see function_key
   pub vars: RefCell<HashMap<String, Vec<Sequence>>>, // each entry in the vector is an inner scope of the variable
+  pub namespaces: RefCell<HashMap<String, String>>,	// statically known namespaces, keyed by prefix
+  pub collations: Collations,	// statically known collations, keyed by URI
+}
+
+// Functions in the standard function namespace (or no namespace) are keyed by their local name.
+// Other functions are keyed by their URIQualifiedName, i.e. Q{namespace-uri}local-name
+fn function_key(nsuri: Option<&str>, name: &str) -> String {
+  match nsuri {
+    None | Some(FNNS) | Some("") => name.to_string(),
+    Some(ns) => format!("Q{{{}}}{}", ns, name),
+  }
+}
+
+// The predeclared namespace prefixes
+fn default_namespaces() -> HashMap<String, String> {
+  let mut ns = HashMap::new();
+  ns.insert("fn".to_string(), FNNS.to_string());
+  ns.insert("math".to_string(), MATHNS.to_string());
+  ns.insert("xs".to_string(), XSDNS.to_string());
+  ns.insert("bin".to_string(), BINNS.to_string());
+  ns.insert("map".to_string(), MAPNS.to_string());
+  ns.insert("array".to_string(), ARRAYNS.to_string());
+  ns
 }
 
 impl StaticContext {
@@ -2213,6 +3814,8 @@ impl StaticContext {
     StaticContext{
       funcs: RefCell::new(HashMap::new()),
       vars: RefCell::new(HashMap::new()),
+      namespaces: RefCell::new(default_namespaces()),
+      collations: Collations::new(),
     }
   }
   /// Creates a new StaticContext and initializes it with the pre-defined XPath functions.
@@ -2250,10 +3853,92 @@ impl StaticContext {
   /// * format-dateTime()
   /// * format-date()
   /// * format-time()
+  /// * abs()
+  /// * round-half-to-even()
+  /// * node-name()
+  /// * QName()
+  /// * resolve-QName()
+  /// * prefix-from-QName()
+  /// * local-name-from-QName()
+  /// * namespace-uri-from-QName()
+  /// * compare()
+  /// * distinct-values()
+  /// * deep-equal()
+  /// * doc()
+  /// * doc-available()
+  /// * collection()
+  /// * resolve-uri()
+  /// * encode-for-uri()
+  /// * iri-to-uri()
+  /// * escape-html-uri()
+  /// * base-uri()
+  /// * document-uri()
+  /// * static-base-uri()
+  /// * id()
+  /// * idref()
+  ///
+  /// These functions are defined for XPath 3.0:
+  ///
+  /// * format-integer()
+  /// * sort()
+  /// * unparsed-text()
+  /// * unparsed-text-lines()
+  /// * unparsed-text-available()
+  /// * generate-id()
+  /// * element-with-id()
+  ///
+  /// These functions are defined for XPath 3.1:
+  ///
+  /// * parse-json()
+  /// * json-doc()
+  /// * json-to-xml()
+  /// * xml-to-json()
+  /// * serialize()
+  /// * parse-xml()
+  /// * parse-xml-fragment()
+  ///
+  /// The math functions are defined in the namespace http://www.w3.org/2005/xpath-functions/math, which is bound to the prefix "math":
+  ///
+  /// * math:pi()
+  /// * math:exp()
+  /// * math:exp10()
+  /// * math:log()
+  /// * math:log10()
+  /// * math:pow()
+  /// * math:sqrt()
+  /// * math:sin()
+  /// * math:cos()
+  /// * math:tan()
+  /// * math:asin()
+  /// * math:acos()
+  /// * math:atan()
+  /// * math:atan2()
+  ///
+  /// The basic functions of the EXPath binary module are defined in the namespace http://expath.org/ns/binary, which is bound to the prefix "bin":
+  ///
+  /// * bin:length()
+  /// * bin:part()
+  /// * bin:to-octets()
+  ///
+  /// The map functions are defined in the namespace http://www.w3.org/2005/xpath-functions/map, which is bound to the prefix "map":
+  ///
+  /// * map:size()
+  /// * map:keys()
+  /// * map:contains()
+  /// * map:get()
+  /// * map:entry()
+  /// * map:merge()
+  ///
+  /// The array functions are defined in the namespace http://www.w3.org/2005/xpath-functions/array, which is bound to the prefix "array":
+  ///
+  /// * array:size()
+  /// * array:get()
   pub fn new_with_builtins() -> StaticContext {
     let sc = StaticContext{
       funcs: RefCell::new(HashMap::new()),
       vars: RefCell::new(HashMap::new()),
+      namespaces: RefCell::new(default_namespaces()),
+      collations: Collations::new(),
     };
     sc.funcs.borrow_mut().insert("position".to_string(),
       Function{
@@ -2462,368 +4147,2533 @@ impl StaticContext {
 	body: Some(func_round)
       }
     );
-    sc.funcs.borrow_mut().insert("current-dateTime".to_string(),
+    sc.funcs.borrow_mut().insert("abs".to_string(),
       Function{
-        name: "current-dateTime".to_string(),
+        name: "abs".to_string(),
 	nsuri: None,
 	prefix: None,
 	params: vec![],
-	body: Some(func_current_date_time)
+	body: Some(func_abs)
       }
     );
-    sc.funcs.borrow_mut().insert("current-date".to_string(),
+    sc.funcs.borrow_mut().insert("round-half-to-even".to_string(),
       Function{
-        name: "current-date".to_string(),
+        name: "round-half-to-even".to_string(),
 	nsuri: None,
 	prefix: None,
 	params: vec![],
-	body: Some(func_current_date)
+	body: Some(func_round_half_to_even)
       }
     );
-    sc.funcs.borrow_mut().insert("current-time".to_string(),
+    sc.funcs.borrow_mut().insert("node-name".to_string(),
       Function{
-        name: "current-time".to_string(),
+        name: "node-name".to_string(),
 	nsuri: None,
 	prefix: None,
 	params: vec![],
-	body: Some(func_current_time)
+	body: Some(func_node_name)
       }
     );
-    sc.funcs.borrow_mut().insert("format-dateTime".to_string(),
+    sc.funcs.borrow_mut().insert("QName".to_string(),
       Function{
-        name: "format-dateTime".to_string(),
+        name: "QName".to_string(),
 	nsuri: None,
 	prefix: None,
 	params: vec![],
-	body: Some(func_format_date_time)
+	body: Some(func_qname)
       }
     );
-    sc.funcs.borrow_mut().insert("format-date".to_string(),
+    sc.funcs.borrow_mut().insert("resolve-QName".to_string(),
       Function{
-        name: "format-date".to_string(),
+        name: "resolve-QName".to_string(),
 	nsuri: None,
 	prefix: None,
 	params: vec![],
-	body: Some(func_format_date)
+	body: Some(func_resolve_qname)
       }
     );
-    sc.funcs.borrow_mut().insert("format-time".to_string(),
+    sc.funcs.borrow_mut().insert("prefix-from-QName".to_string(),
       Function{
-        name: "format-time".to_string(),
+        name: "prefix-from-QName".to_string(),
 	nsuri: None,
 	prefix: None,
 	params: vec![],
-	body: Some(func_format_time)
+	body: Some(func_prefix_from_qname)
       }
     );
-
-    sc
-  }
-  /// Create a new StaticContext with builtin functions defined,
-  /// including additional functions defined by XSLT.
-  pub fn new_with_xslt_builtins() -> StaticContext {
-    let sc = StaticContext::new_with_builtins();
-
-    sc.funcs.borrow_mut().insert("current-grouping-key".to_string(),
+    sc.funcs.borrow_mut().insert("local-name-from-QName".to_string(),
       Function{
-        name: "current-grouping-key".to_string(),
+        name: "local-name-from-QName".to_string(),
 	nsuri: None,
 	prefix: None,
 	params: vec![],
-	body: Some(func_current_grouping_key)
+	body: Some(func_local_name_from_qname)
       }
     );
-    sc.funcs.borrow_mut().insert("current-group".to_string(),
+    sc.funcs.borrow_mut().insert("namespace-uri-from-QName".to_string(),
       Function{
-        name: "current-group".to_string(),
+        name: "namespace-uri-from-QName".to_string(),
 	nsuri: None,
 	prefix: None,
 	params: vec![],
-	body: Some(func_current_group)
+	body: Some(func_namespace_uri_from_qname)
       }
     );
-
-    sc
-  }
-  /// Register an extension function
-  pub fn extension_function(&mut self, name: String, _ns: String, f: Function) {
-    // TODO: namespace
-    self.funcs.borrow_mut().insert(name, f);
-  }
-  /// Declares a function in the static context. The first argument is the name of the function. The second argument is the namespace URI (not currently supported). The third argument defines the arity of the function, and the types of each parameter (not currently supported).
-  pub fn declare_function(&self, n: String, _ns: String, p: Vec<Param>) {
-    self.funcs.borrow_mut().insert(n.clone(), Function{name: n, nsuri: None, prefix: None, body: None, params: p});
-  }
-  /// Declares a variable in the static context. The first argument is the name of the variable. The second argument is the namespace URI (not currently supported).
-  pub fn declare_variable(&self, n: String, _ns:String) {
-    self.vars.borrow_mut().insert(n.clone(), vec![]);
-  }
-
-  /// Perform static analysis of a sequence constructor.
-  ///
-  /// This checks that functions and variables are declared. It also rewrites the constructors to provide the implementation of functions that are used in expressions.
-  pub fn static_analysis(&mut self, e: &mut Vec<Constructor>) {
-    // TODO: return Result
-    // TODO: iterate through the tree structure instead of doing a recursive depth first search. This should mean that the method would not have to use interior mutability
-    for d in e {
-      match d {
-        Constructor::Switch(v, o) => {
-          for i in v {
-	    self.static_analysis(i)
-	  }
-	  self.static_analysis(o);
-	}
-      	Constructor::Loop(v, a) => {
-	  self.static_analysis(v);
-	  self.static_analysis(a);
-        }
-      	Constructor::SetAttribute(_, v) => {
-          self.static_analysis(v);
-        }
-      	Constructor::FunctionCall(f, a) => {
-	  // Fill in function body
-	  match self.funcs.borrow().get(&f.name) {
-	    Some(g) => {
-	      f.body.replace(g.body.unwrap());
-	    }
-	    None => {
-	      // TODO: Don't Panic
-	      panic!("call to unknown function \"{}\"", f.name)
-	    }
-	  }
-          for i in a {
-	    self.static_analysis(i)
-	  }
-        }
-      	Constructor::VariableDeclaration(v, a) => {
-          self.declare_variable(v.to_string(), "".to_string());
-	  self.static_analysis(a)
-        }
-      	Constructor::VariableReference(_v) => {
-          // TODO: check that variable has been declared
-        }
-      	Constructor::Or(a) |
-      	Constructor::And(a) |
-      	Constructor::Path(a) |
-      	Constructor::Concat(a) |
-      	Constructor::Range(a) => {
-	  for i in a {
-	    self.static_analysis(i)
-	  }
-        }
-      	Constructor::Step(_, a) => {
-          for i in a {
-	    self.static_analysis(i)
-	  }
-        }
-      	Constructor::GeneralComparison(_, a) |
-      	Constructor::ValueComparison(_, a) => {
-          for i in a {
-	    self.static_analysis(i)
-	  }
-        }
-      	Constructor::Arithmetic(a) => {
-          for i in a {
-	    self.static_analysis(&mut i.operand)
-	  }
-        }
-      	  Constructor::ApplyTemplates(s)  => {
-	  self.static_analysis(s)
-        }
-      	Constructor::ForEach(s, t, _g) => {
-	  self.static_analysis(s);
-	  self.static_analysis(t);
-        }
-      	Constructor::Copy(_, c) |
-      	Constructor::LiteralElement(_, c) => {
-	  self.static_analysis(c)
-        }
-      	Constructor::DeepCopy(c) => {
-	  self.static_analysis(c);
-        }
-      	  Constructor::Literal(_) |
-      	  Constructor::LiteralAttribute(_, _) |
-      	  Constructor::ContextItem |
-      	  Constructor::Root |
-	  Constructor::ApplyImports |
-      	  Constructor::NotImplemented(_) => {}
+    sc.funcs.borrow_mut().insert("compare".to_string(),
+      Function{
+        name: "compare".to_string(),
+	nsuri: None,
+	prefix: None,
+	params: vec![],
+	body: Some(func_compare)
       }
-    }
-  }
-}
-
-// Functions
-
-pub type FunctionImpl = fn(
-    &Evaluator,
-    Option<Sequence>,		// Context
-    Option<usize>,		// Context position
-    Vec<Sequence>,		// Actual parameters
-    &mut Forest,
-    TreeIndex,
-    TreeIndex,
-  ) -> Result<Sequence, Error>;
-
-#[derive(Clone)]
-pub struct Function {
-  name: String,
-  nsuri: Option<String>,
-  prefix: Option<String>,
-  params: Vec<Param>,	// The number of parameters in the vector is the arity of the function
-  body: Option<FunctionImpl>,	// Function implementation must be provided during static analysis
-}
-
-impl Function {
-  pub fn new(n: String, p: Vec<Param>, i: Option<FunctionImpl>) -> Function {
-    Function{name: n, nsuri: None, prefix: None, params: p, body: i}
-  }
-  pub fn get_name(&self) -> String {
-    self.name.clone()
-  }
-  pub fn get_nsuri(&self) -> Option<String> {
-    self.nsuri.clone()
-  }
-  pub fn get_prefix(&self) -> Option<String> {
-    self.prefix.clone()
-  }
-  // TODO: make this an iterator over the formal parameters
-  pub fn get_params(&self) -> Vec<Param> {
-    self.params.clone()
-  }
-  pub fn get_body(&self) -> Option<FunctionImpl> {
-    self.body.clone()
-  }
+    );
+    sc.funcs.borrow_mut().insert("distinct-values".to_string(),
+      Function{
+        name: "distinct-values".to_string(),
+	nsuri: None,
+	prefix: None,
+	params: vec![],
+	body: Some(func_distinct_values)
+      }
+    );
+    sc.funcs.borrow_mut().insert("sort".to_string(),
+      Function{
+        name: "sort".to_string(),
+	nsuri: None,
+	prefix: None,
+	params: vec![],
+	body: Some(func_sort)
+      }
+    );
+    sc.funcs.borrow_mut().insert("deep-equal".to_string(),
+      Function{
+        name: "deep-equal".to_string(),
+	nsuri: None,
+	prefix: None,
+	params: vec![],
+	body: Some(func_deep_equal)
+      }
+    );
+    sc.funcs.borrow_mut().insert("doc".to_string(),
+      Function{
+        name: "doc".to_string(),
+	nsuri: None,
+	prefix: None,
+	params: vec![],
+	body: Some(func_doc)
+      }
+    );
+    sc.funcs.borrow_mut().insert("doc-available".to_string(),
+      Function{
+        name: "doc-available".to_string(),
+	nsuri: None,
+	prefix: None,
+	params: vec![],
+	body: Some(func_doc_available)
+      }
+    );
+    sc.funcs.borrow_mut().insert("collection".to_string(),
+      Function{
+        name: "collection".to_string(),
+	nsuri: None,
+	prefix: None,
+	params: vec![],
+	body: Some(func_collection)
+      }
+    );
+    sc.funcs.borrow_mut().insert("resolve-uri".to_string(),
+      Function{
+        name: "resolve-uri".to_string(),
+	nsuri: None,
+	prefix: None,
+	params: vec![],
+	body: Some(func_resolve_uri)
+      }
+    );
+    sc.funcs.borrow_mut().insert("encode-for-uri".to_string(),
+      Function{
+        name: "encode-for-uri".to_string(),
+	nsuri: None,
+	prefix: None,
+	params: vec![],
+	body: Some(func_encode_for_uri)
+      }
+    );
+    sc.funcs.borrow_mut().insert("iri-to-uri".to_string(),
+      Function{
+        name: "iri-to-uri".to_string(),
+	nsuri: None,
+	prefix: None,
+	params: vec![],
+	body: Some(func_iri_to_uri)
+      }
+    );
+    sc.funcs.borrow_mut().insert("escape-html-uri".to_string(),
+      Function{
+        name: "escape-html-uri".to_string(),
+	nsuri: None,
+	prefix: None,
+	params: vec![],
+	body: Some(func_escape_html_uri)
+      }
+    );
+    sc.funcs.borrow_mut().insert("base-uri".to_string(),
+      Function{
+        name: "base-uri".to_string(),
+	nsuri: None,
+	prefix: None,
+	params: vec![],
+	body: Some(func_base_uri)
+      }
+    );
+    sc.funcs.borrow_mut().insert("document-uri".to_string(),
+      Function{
+        name: "document-uri".to_string(),
+	nsuri: None,
+	prefix: None,
+	params: vec![],
+	body: Some(func_document_uri)
+      }
+    );
+    sc.funcs.borrow_mut().insert("static-base-uri".to_string(),
+      Function{
+        name: "static-base-uri".to_string(),
+	nsuri: None,
+	prefix: None,
+	params: vec![],
+	body: Some(func_static_base_uri)
+      }
+    );
+    sc.funcs.borrow_mut().insert("id".to_string(),
+      Function{
+        name: "id".to_string(),
+	nsuri: None,
+	prefix: None,
+	params: vec![],
+	body: Some(func_id)
+      }
+    );
+    sc.funcs.borrow_mut().insert("idref".to_string(),
+      Function{
+        name: "idref".to_string(),
+	nsuri: None,
+	prefix: None,
+	params: vec![],
+	body: Some(func_idref)
+      }
+    );
+    sc.funcs.borrow_mut().insert("generate-id".to_string(),
+      Function{
+        name: "generate-id".to_string(),
+	nsuri: None,
+	prefix: None,
+	params: vec![],
+	body: Some(func_generate_id)
+      }
+    );
+    sc.funcs.borrow_mut().insert("element-with-id".to_string(),
+      Function{
+        name: "element-with-id".to_string(),
+	nsuri: None,
+	prefix: None,
+	params: vec![],
+	body: Some(func_element_with_id)
+      }
+    );
+    sc.funcs.borrow_mut().insert("unparsed-text".to_string(),
+      Function{
+        name: "unparsed-text".to_string(),
+	nsuri: None,
+	prefix: None,
+	params: vec![],
+	body: Some(func_unparsed_text)
+      }
+    );
+    sc.funcs.borrow_mut().insert("unparsed-text-lines".to_string(),
+      Function{
+        name: "unparsed-text-lines".to_string(),
+	nsuri: None,
+	prefix: None,
+	params: vec![],
+	body: Some(func_unparsed_text_lines)
+      }
+    );
+    sc.funcs.borrow_mut().insert("unparsed-text-available".to_string(),
+      Function{
+        name: "unparsed-text-available".to_string(),
+	nsuri: None,
+	prefix: None,
+	params: vec![],
+	body: Some(func_unparsed_text_available)
+      }
+    );
+    sc.funcs.borrow_mut().insert("parse-json".to_string(),
+      Function{
+        name: "parse-json".to_string(),
+	nsuri: None,
+	prefix: None,
+	params: vec![],
+	body: Some(func_parse_json)
+      }
+    );
+    sc.funcs.borrow_mut().insert("json-doc".to_string(),
+      Function{
+        name: "json-doc".to_string(),
+	nsuri: None,
+	prefix: None,
+	params: vec![],
+	body: Some(func_json_doc)
+      }
+    );
+    sc.funcs.borrow_mut().insert("json-to-xml".to_string(),
+      Function{
+        name: "json-to-xml".to_string(),
+	nsuri: None,
+	prefix: None,
+	params: vec![],
+	body: Some(func_json_to_xml)
+      }
+    );
+    sc.funcs.borrow_mut().insert("xml-to-json".to_string(),
+      Function{
+        name: "xml-to-json".to_string(),
+	nsuri: None,
+	prefix: None,
+	params: vec![],
+	body: Some(func_xml_to_json)
+      }
+    );
+    sc.funcs.borrow_mut().insert("serialize".to_string(),
+      Function{
+        name: "serialize".to_string(),
+	nsuri: None,
+	prefix: None,
+	params: vec![],
+	body: Some(func_serialize)
+      }
+    );
+    sc.funcs.borrow_mut().insert("parse-xml".to_string(),
+      Function{
+        name: "parse-xml".to_string(),
+	nsuri: None,
+	prefix: None,
+	params: vec![],
+	body: Some(func_parse_xml)
+      }
+    );
+    sc.funcs.borrow_mut().insert("parse-xml-fragment".to_string(),
+      Function{
+        name: "parse-xml-fragment".to_string(),
+	nsuri: None,
+	prefix: None,
+	params: vec![],
+	body: Some(func_parse_xml_fragment)
+      }
+    );
+    sc.funcs.borrow_mut().insert("format-integer".to_string(),
+      Function{
+        name: "format-integer".to_string(),
+	nsuri: None,
+	prefix: None,
+	params: vec![],
+	body: Some(func_format_integer)
+      }
+    );
+    sc.funcs.borrow_mut().insert("current-dateTime".to_string(),
+      Function{
+        name: "current-dateTime".to_string(),
+	nsuri: None,
+	prefix: None,
+	params: vec![],
+	body: Some(func_current_date_time)
+      }
+    );
+    sc.funcs.borrow_mut().insert("current-date".to_string(),
+      Function{
+        name: "current-date".to_string(),
+	nsuri: None,
+	prefix: None,
+	params: vec![],
+	body: Some(func_current_date)
+      }
+    );
+    sc.funcs.borrow_mut().insert("current-time".to_string(),
+      Function{
+        name: "current-time".to_string(),
+	nsuri: None,
+	prefix: None,
+	params: vec![],
+	body: Some(func_current_time)
+      }
+    );
+    sc.funcs.borrow_mut().insert("format-dateTime".to_string(),
+      Function{
+        name: "format-dateTime".to_string(),
+	nsuri: None,
+	prefix: None,
+	params: vec![],
+	body: Some(func_format_date_time)
+      }
+    );
+    sc.funcs.borrow_mut().insert("format-date".to_string(),
+      Function{
+        name: "format-date".to_string(),
+	nsuri: None,
+	prefix: None,
+	params: vec![],
+	body: Some(func_format_date)
+      }
+    );
+    sc.funcs.borrow_mut().insert("format-time".to_string(),
+      Function{
+        name: "format-time".to_string(),
+	nsuri: None,
+	prefix: None,
+	params: vec![],
+	body: Some(func_format_time)
+      }
+    );
+
+    let math: Vec<(&str, FunctionImpl)> = vec![
+      ("pi", func_math_pi),
+      ("exp", func_math_exp),
+      ("exp10", func_math_exp10),
+      ("log", func_math_log),
+      ("log10", func_math_log10),
+      ("pow", func_math_pow),
+      ("sqrt", func_math_sqrt),
+      ("sin", func_math_sin),
+      ("cos", func_math_cos),
+      ("tan", func_math_tan),
+      ("asin", func_math_asin),
+      ("acos", func_math_acos),
+      ("atan", func_math_atan),
+      ("atan2", func_math_atan2),
+    ];
+    for (name, body) in math {
+      sc.funcs.borrow_mut().insert(function_key(Some(MATHNS), name),
+	Function{
+	  name: name.to_string(),
+	  nsuri: Some(MATHNS.to_string()),
+	  prefix: Some("math".to_string()),
+	  params: vec![],
+	  body: Some(body)
+	}
+      );
+    }
+
+    let maps: Vec<(&str, FunctionImpl)> = vec![
+      ("size", func_map_size),
+      ("keys", func_map_keys),
+      ("contains", func_map_contains),
+      ("get", func_map_get),
+      ("entry", func_map_entry),
+      ("merge", func_map_merge),
+    ];
+    for (name, body) in maps {
+      sc.funcs.borrow_mut().insert(function_key(Some(MAPNS), name),
+	Function{
+	  name: name.to_string(),
+	  nsuri: Some(MAPNS.to_string()),
+	  prefix: Some("map".to_string()),
+	  params: vec![],
+	  body: Some(body)
+	}
+      );
+    }
+
+    let arrays: Vec<(&str, FunctionImpl)> = vec![
+      ("size", func_array_size),
+      ("get", func_array_get),
+    ];
+    for (name, body) in arrays {
+      sc.funcs.borrow_mut().insert(function_key(Some(ARRAYNS), name),
+	Function{
+	  name: name.to_string(),
+	  nsuri: Some(ARRAYNS.to_string()),
+	  prefix: Some("array".to_string()),
+	  params: vec![],
+	  body: Some(body)
+	}
+      );
+    }
+
+    let bin: Vec<(&str, FunctionImpl)> = vec![
+      ("length", func_bin_length),
+      ("part", func_bin_part),
+      ("to-octets", func_bin_to_octets),
+    ];
+    for (name, body) in bin {
+      sc.funcs.borrow_mut().insert(function_key(Some(BINNS), name),
+	Function{
+	  name: name.to_string(),
+	  nsuri: Some(BINNS.to_string()),
+	  prefix: Some("bin".to_string()),
+	  params: vec![],
+	  body: Some(body)
+	}
+      );
+    }
+
+    sc
+  }
+  /// Create a new StaticContext with builtin functions defined,
+  /// including additional functions defined by XSLT:
+  ///
+  /// * current-grouping-key()
+  /// * current-group()
+  /// * document()
+  /// * key()
+  pub fn new_with_xslt_builtins() -> StaticContext {
+    let sc = StaticContext::new_with_builtins();
+
+    sc.funcs.borrow_mut().insert("current-grouping-key".to_string(),
+      Function{
+        name: "current-grouping-key".to_string(),
+	nsuri: None,
+	prefix: None,
+	params: vec![],
+	body: Some(func_current_grouping_key)
+      }
+    );
+    sc.funcs.borrow_mut().insert("current-group".to_string(),
+      Function{
+        name: "current-group".to_string(),
+	nsuri: None,
+	prefix: None,
+	params: vec![],
+	body: Some(func_current_group)
+      }
+    );
+    sc.funcs.borrow_mut().insert("document".to_string(),
+      Function{
+        name: "document".to_string(),
+	nsuri: None,
+	prefix: None,
+	params: vec![],
+	body: Some(func_document)
+      }
+    );
+    sc.funcs.borrow_mut().insert("key".to_string(),
+      Function{
+        name: "key".to_string(),
+	nsuri: None,
+	prefix: None,
+	params: vec![],
+	body: Some(func_key)
+      }
+    );
+
+    sc
+  }
+  /// Register an extension function. The function is called using a prefix that is bound to the namespace URI (see declare_namespace).
+  pub fn extension_function(&mut self, name: String, ns: String, mut f: Function) {
+    let key = function_key(Some(ns.as_str()), name.as_str());
+    f.nsuri = Some(ns);
+    self.funcs.borrow_mut().insert(key, f);
+  }
+  /// Bind a prefix to a namespace URI, for use in expressions. The prefixes "fn", "math", "xs" and "bin" are predeclared.
+  pub fn declare_namespace(&self, prefix: String, nsuri: String) {
+    self.namespaces.borrow_mut().insert(prefix, nsuri);
+  }
+  /// Perform static analysis of a sequence constructor (see [StaticContext::static_analysis]), where the given (prefix, URI) pairs are also in scope. These take precedence over the prefixes that have been declared, and are only in scope for this sequence constructor.
+  pub fn static_analysis_in_scope(&mut self, e: &mut Vec<Constructor>, namespaces: &[(Option<String>, String)]) -> Result<(), Error> {
+    let saved = self.namespaces.borrow().clone();
+    for (p, u) in namespaces {
+      if let Some(p) = p {
+	self.declare_namespace(p.clone(), u.clone())
+      }
+    }
+    let r = self.static_analysis(e);
+    *self.namespaces.borrow_mut() = saved;
+    r
+  }
+  /// Register a collation with the given URI. The collation can then be used by functions that take a collation argument.
+  /// For the collation to be available during evaluation the collations must be shared with the evaluator, see [Evaluator::set_collations].
+  pub fn register_collation(&self, uri: &str, c: Rc<dyn Collation>) {
+    self.collations.register(uri, c);
+  }
+  /// Declares a function in the static context. The first argument is the name of the function. The second argument is the namespace URI (not currently supported). The third argument defines the arity of the function, and the types of each parameter (not currently supported).
+  pub fn declare_function(&self, n: String, _ns: String, p: Vec<Param>) {
+    self.funcs.borrow_mut().insert(n.clone(), Function{name: n, nsuri: None, prefix: None, body: None, params: p});
+  }
+  /// Declares a variable in the static context. The first argument is the name of the variable. The second argument is the namespace URI (not currently supported).
+  pub fn declare_variable(&self, n: String, _ns:String) {
+    self.vars.borrow_mut().insert(n.clone(), vec![]);
+  }
+
+  /// Perform static analysis of a sequence constructor.
+  ///
+  /// This checks that functions and variables are declared. It also rewrites the constructors to provide the implementation of functions that are used in expressions.
+  // Resolve the prefix of a type name to its namespace URI
+  fn resolve_type_name(&self, t: &mut QualifiedName) {
+    if t.get_nsuri_ref().is_none() {
+      if let Some(ns) = t.get_prefix().and_then(|p| self.namespaces.borrow().get(&p).cloned()) {
+	*t = QualifiedName::new(Some(ns), t.get_prefix(), t.get_localname())
+      }
+    }
+  }
+  // Resolve the prefix of a variable name, so that the name is an EQName (see variable_name)
+  fn resolve_variable_name(&self, v: &mut String) -> Result<(), Error> {
+    if v.starts_with("Q{") {
+      return Ok(())
+    }
+    if let Some((p, l)) = v.split_once(':') {
+      let ns = self.namespaces.borrow().get(p).cloned()
+	.ok_or_else(|| Error{kind: ErrorKind::UnknownPrefix, message: format!("unknown namespace prefix \"{}\"", p)})?;
+      *v = variable_name(&QualifiedName::new(Some(ns), None, l.to_string()))
+    }
+    Ok(())
+  }
+  fn sort_key_analysis(&mut self, k: &mut SortKey) -> Result<(), Error> {
+    self.static_analysis(&mut k.select)?;
+    for c in vec![&mut k.order, &mut k.data_type, &mut k.case_order, &mut k.lang, &mut k.collation, &mut k.stable].into_iter().flatten() {
+      self.static_analysis(c)?
+    }
+    Ok(())
+  }
+  pub fn static_analysis(&mut self, e: &mut Vec<Constructor>) -> Result<(), Error> {
+    // TODO: iterate through the tree structure instead of doing a recursive depth first search. This should mean that the method would not have to use interior mutability
+    for d in e {
+      // A constructor function for a built-in type is a cast
+      let mut cast = None;
+      if let Constructor::FunctionCall(fc, a) = d {
+	let ns = fc.nsuri.clone().or_else(|| fc.prefix.as_ref().and_then(|p| self.namespaces.borrow().get(p).cloned()));
+	if ns.as_deref() == Some(XSDNS) && a.len() == 1 {
+	  cast = Some(Constructor::Cast(a.pop().unwrap(), QualifiedName::new(ns, fc.prefix.clone(), fc.name.clone()), true));
+	}
+      }
+      if let Some(c) = cast {
+	*d = c;
+      }
+      match d {
+        Constructor::Switch(v, o) => {
+          for i in v {
+	    self.static_analysis(i)?
+	  }
+	  self.static_analysis(o)?;
+	}
+      	Constructor::Loop(v, a) => {
+	  self.static_analysis(v)?;
+	  self.static_analysis(a)?;
+        }
+      	Constructor::SetAttribute(_, v) => {
+          self.static_analysis(v)?;
+        }
+      	Constructor::FunctionCall(f, a) => {
+	  // Resolve the prefix of the function name
+	  if f.nsuri.is_none() {
+	    if let Some(p) = &f.prefix {
+	      match self.namespaces.borrow().get(p) {
+		Some(ns) => f.nsuri = Some(ns.clone()),
+		None => return Result::Err(Error{kind: ErrorKind::UnknownPrefix, message: format!("unknown namespace prefix \"{}\"", p)}),
+	      }
+	    }
+	  }
+	  // Fill in function body
+	  let key = function_key(f.nsuri.as_deref(), f.name.as_str());
+	  match self.funcs.borrow().get(&key) {
+	    Some(g) => {
+	      f.body.replace(g.body.unwrap());
+	    }
+	    None => return Result::Err(Error{kind: ErrorKind::StaticBadFunction, message: format!("call to unknown function \"{}\"", f.name)}),
+	  }
+	  // A key name that is a literal is resolved using the namespaces that are in scope for the expression, see Evaluator::key
+	  if key == "key" {
+	    if let Some([Constructor::Literal(Value::String(n))]) = a.first_mut().map(|k| k.as_mut_slice()) {
+	      let resolved = n.trim().split_once(':')
+		.filter(|_| !n.trim().starts_with("Q{"))
+		.and_then(|(p, l)| self.namespaces.borrow().get(p).map(|ns| variable_name(&QualifiedName::new(Some(ns.clone()), None, l.to_string()))));
+	      if let Some(r) = resolved {
+		*n = r
+	      }
+	    }
+	  }
+          for i in a {
+	    self.static_analysis(i)?
+	  }
+        }
+      	Constructor::VariableDeclaration(v, a) => {
+	  self.resolve_variable_name(v)?;
+          self.declare_variable(v.to_string(), "".to_string());
+	  self.static_analysis(a)?
+        }
+      	Constructor::VariableReference(v) => {
+	  self.resolve_variable_name(v)?;
+          // TODO: check that variable has been declared
+        }
+      	Constructor::Or(a) |
+      	Constructor::And(a) |
+      	Constructor::Path(a) |
+      	Constructor::Concat(a) |
+      	Constructor::AttributeValueTemplate(a) |
+      	Constructor::Range(a) => {
+	  for i in a {
+	    self.static_analysis(i)?
+	  }
+        }
+      	Constructor::Step(_, a) => {
+          for i in a {
+	    self.static_analysis(i)?
+	  }
+        }
+      	Constructor::GeneralComparison(_, a) |
+      	Constructor::ValueComparison(_, a) => {
+          for i in a {
+	    self.static_analysis(i)?
+	  }
+        }
+      	Constructor::Arithmetic(a) => {
+          for i in a {
+	    self.static_analysis(&mut i.operand)?
+	  }
+        }
+      	  Constructor::ApplyTemplates(s, _, w)  => {
+	  self.static_analysis(s)?;
+	  for p in w {
+	    self.static_analysis(&mut p.value)?
+	  }
+        }
+      	Constructor::CallTemplate(_, w) => {
+	  for p in w {
+	    self.static_analysis(&mut p.value)?
+	  }
+        }
+      	Constructor::Document(c) => {
+	  self.static_analysis(c)?
+        }
+      	Constructor::ForEach(s, t, _g, k) => {
+	  self.static_analysis(s)?;
+	  self.static_analysis(t)?;
+	  for i in k {
+	    self.sort_key_analysis(i)?
+	  }
+        }
+      	Constructor::Sort(s, k) => {
+	  self.static_analysis(s)?;
+	  for i in k {
+	    self.sort_key_analysis(i)?
+	  }
+        }
+      	Constructor::Copy(_, c) |
+      	Constructor::LiteralElement(_, c) => {
+	  self.static_analysis(c)?
+        }
+      	Constructor::Element(n, c) => {
+	  self.static_analysis(&mut n.name)?;
+	  if let Some(ns) = &mut n.namespace {
+	    self.static_analysis(ns)?
+	  }
+	  self.static_analysis(c)?
+        }
+      	Constructor::Attribute(n, v, sep) => {
+	  self.static_analysis(&mut n.name)?;
+	  if let Some(ns) = &mut n.namespace {
+	    self.static_analysis(ns)?
+	  }
+	  self.static_analysis(v)?;
+	  self.static_analysis(sep)?
+        }
+      	Constructor::Text(v, sep) |
+      	Constructor::Comment(v, sep) |
+      	Constructor::Namespace(v, sep) => {
+	  self.static_analysis(v)?;
+	  self.static_analysis(sep)?
+        }
+      	Constructor::ResultDocument(r, c) => {
+	  for v in vec![&mut r.href, &mut r.method, &mut r.indent].into_iter().flatten() {
+	    self.static_analysis(v)?
+	  }
+	  if let Some(n) = &mut r.format {
+	    self.static_analysis(&mut n.name)?
+	  }
+	  self.static_analysis(c)?
+        }
+      	Constructor::Message(c, t, e) => {
+	  self.static_analysis(c)?;
+	  self.static_analysis(t)?;
+	  if let Some(n) = e {
+	    self.static_analysis(&mut n.name)?
+	  }
+        }
+      	Constructor::ProcessingInstruction(n, v, sep) => {
+	  self.static_analysis(n)?;
+	  self.static_analysis(v)?;
+	  self.static_analysis(sep)?
+        }
+      	Constructor::DeepCopy(c) => {
+	  self.static_analysis(c)?;
+        }
+      	Constructor::InstanceOf(c, st) => {
+	  if let SequenceType::Items(ItemType::Atomic(t), _) = st {
+	    self.resolve_type_name(t)
+	  }
+	  self.static_analysis(c)?;
+        }
+      	Constructor::Cast(c, t, _) |
+      	Constructor::Castable(c, t, _) => {
+	  self.resolve_type_name(t);
+	  self.static_analysis(c)?;
+        }
+      	Constructor::MapConstructor(v) => {
+	  for (k, w) in v {
+	    self.static_analysis(k)?;
+	    self.static_analysis(w)?;
+	  }
+        }
+      	Constructor::ArrayConstructor(v) => {
+	  for w in v {
+	    self.static_analysis(w)?
+	  }
+        }
+      	Constructor::CurlyArrayConstructor(c) => {
+	  self.static_analysis(c)?
+        }
+      	Constructor::Lookup(c, k) => {
+	  self.static_analysis(c)?;
+	  if let Some(l) = k {
+	    self.static_analysis(l)?
+	  }
+        }
+      	Constructor::Number(n) => {
+	  for c in [&mut n.count, &mut n.from, &mut n.value, &mut n.select].iter_mut() {
+	    if let Some(d) = c {
+	      self.static_analysis(d)?
+	    }
+	  }
+	  self.static_analysis(&mut n.format)?;
+        }
+      	Constructor::LiteralAttribute(_, c) => {
+	  self.static_analysis(c)?
+        }
+      	  Constructor::Literal(_) |
+      	  Constructor::ContextItem |
+      	  Constructor::Root |
+	  Constructor::ApplyImports |
+	  Constructor::UseAttributeSets(_) |
+      	  Constructor::NotImplemented(_) => {}
+      }
+    }
+    Ok(())
+  }
+}
+
+// Functions
+
+pub type FunctionImpl = fn(
+    &Evaluator,
+    Option<Sequence>,		// Context
+    Option<usize>,		// Context position
+    Vec<Sequence>,		// Actual parameters
+    &mut Forest,
+    TreeIndex,
+    TreeIndex,
+  ) -> Result<Sequence, Error>;
+
+#[derive(Clone)]
+pub struct Function {
+  name: String,
+  nsuri: Option<String>,
+  prefix: Option<String>,
+  params: Vec<Param>,	// The number of parameters in the vector is the arity of the function
+  body: Option<FunctionImpl>,	// Function implementation must be provided during static analysis
+}
+
+impl Function {
+  pub fn new(n: String, p: Vec<Param>, i: Option<FunctionImpl>) -> Function {
+    Function{name: n, nsuri: None, prefix: None, params: p, body: i}
+  }
+  /// Create a function with a qualified name. The namespace URI may be unknown until static analysis resolves the prefix.
+  pub fn new_qualified(n: String, nsuri: Option<String>, prefix: Option<String>, p: Vec<Param>, i: Option<FunctionImpl>) -> Function {
+    Function{name: n, nsuri, prefix, params: p, body: i}
+  }
+  pub fn get_name(&self) -> String {
+    self.name.clone()
+  }
+  pub fn get_nsuri(&self) -> Option<String> {
+    self.nsuri.clone()
+  }
+  pub fn get_prefix(&self) -> Option<String> {
+    self.prefix.clone()
+  }
+  // TODO: make this an iterator over the formal parameters
+  pub fn get_params(&self) -> Vec<Param> {
+    self.params.clone()
+  }
+  pub fn get_body(&self) -> Option<FunctionImpl> {
+    self.body.clone()
+  }
+}
+
+// A formal parameter
+#[derive(Clone)]
+pub struct Param {
+  name: String,
+  datatype: String,	// TODO
+}
+
+impl Param {
+  pub fn new(n: String, t: String) -> Param {
+    Param{name: n, datatype: t}
+  }
+  pub fn get_name(&self) -> String {
+    self.name.clone()
+  }
+  pub fn get_datatype(&self) -> String {
+    self.datatype.clone()
+  }
+}
+
+fn func_position(
+    _: &Evaluator,
+    _ctxt: Option<Sequence>,
+    posn: Option<usize>,
+    _args: Vec<Sequence>,
+    _f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  match posn {
+    Some(u) => {
+      Ok(vec![Rc::new(Item::Value(Value::Integer(u as i64 + 1)))])
+    }
+    None => Result::Err(Error{kind: ErrorKind::DynamicAbsent, message: String::from("no context item"),})
+  }
+}
+
+fn func_last(
+    _: &Evaluator,
+    ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    _args: Vec<Sequence>,
+    _f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  match ctxt {
+    Some(u) => {
+      Ok(vec![Rc::new(Item::Value(Value::Integer(u.len() as i64)))])
+    }
+    None => Result::Err(Error{kind: ErrorKind::DynamicAbsent, message: String::from("no context item"),})
+  }
+}
+
+pub fn func_count(
+    _: &Evaluator,
+    ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    _f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  match args.len() {
+    0 => {
+      // count the context items
+      match ctxt {
+        Some(u) => Ok(vec![Rc::new(Item::Value(Value::Integer(u.len() as i64)))]),
+        None => Result::Err(Error{kind: ErrorKind::DynamicAbsent, message: String::from("no context item"),})
+      }
+    }
+    1 => {
+      // count the argument items
+      Ok(vec![Rc::new(Item::Value(Value::Integer(args[0].len() as i64)))])
+    }
+    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+}
+
+// The node that is the argument of a function, or the context item if there is no argument.
+// The empty sequence gives None.
+fn node_arg(ctxt: &Option<Sequence>, posn: Option<usize>, args: &[Sequence]) -> Result<Option<Node>, Error> {
+  let item = match args.first() {
+    Some(a) => match a.len() {
+      0 => return Ok(None),
+      1 => a[0].clone(),
+      _ => return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("not a singleton sequence"),}),
+    },
+    None => match (ctxt, posn) {
+      (Some(u), Some(p)) => u[p].clone(),
+      _ => return Result::Err(Error{kind: ErrorKind::DynamicAbsent, message: String::from("no context item"),}),
+    },
+  };
+  match *item {
+    Item::Node(n) => Ok(Some(n)),
+    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("not a node"),}),
+  }
+}
+
+// The name of a node. Only elements, attributes and processing instructions have a name.
+fn node_qname(n: &Node, f: &Forest) -> Option<QualifiedName> {
+  match n.node_type(f) {
+    NodeType::Element |
+    NodeType::Attribute |
+    NodeType::ProcessingInstruction => Some(n.to_name(f)),
+    _ => None,
+  }
+}
+
+pub fn func_localname(
+    _: &Evaluator,
+    ctxt: Option<Sequence>,
+    posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  let n = node_arg(&ctxt, posn, &args)?;
+  let l = n.and_then(|m| node_qname(&m, f)).map_or(String::new(), |q| q.get_localname());
+  Ok(vec![Rc::new(Item::Value(Value::String(l)))])
+}
+
+pub fn func_name(
+    _e: &Evaluator,
+    ctxt: Option<Sequence>,
+    posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  let n = node_arg(&ctxt, posn, &args)?;
+  let l = n.and_then(|m| node_qname(&m, f)).map_or(String::new(), |q| q.to_string());
+  Ok(vec![Rc::new(Item::Value(Value::String(l)))])
+}
+
+pub fn func_node_name(
+    _e: &Evaluator,
+    ctxt: Option<Sequence>,
+    posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  match node_arg(&ctxt, posn, &args)?.and_then(|m| node_qname(&m, f)) {
+    Some(q) => Ok(vec![Rc::new(Item::Value(Value::QName(q)))]),
+    None => Ok(vec![]),
+  }
+}
+
+// Split a lexical QName into its prefix and local part.
+fn parse_lexical_qname(s: &str) -> Result<(Option<String>, String), Error> {
+  let t = s.trim();
+  let (p, l) = match t.split_once(':') {
+    Some((p, l)) => (Some(p), l),
+    None => (None, t),
+  };
+  if p.is_none_or(is_ncname) && is_ncname(l) {
+    Ok((p.map(String::from), l.to_string()))
+  } else {
+    Result::Err(Error{kind: ErrorKind::InvalidLexicalValue, message: format!("\"{}\" is not a valid QName", s)})
+  }
+}
+
+pub fn func_qname(
+    _e: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  if args.len() != 2 {
+    return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+  let uri = args[0].to_string(Some(f));
+  let (p, l) = parse_lexical_qname(&args[1].to_string(Some(f)))?;
+  if uri.is_empty() {
+    if p.is_some() {
+      return Result::Err(Error{kind: ErrorKind::InvalidLexicalValue, message: String::from("a prefixed QName must have a namespace URI")})
+    }
+    Ok(vec![Rc::new(Item::Value(Value::QName(QualifiedName::new(None, None, l))))])
+  } else {
+    Ok(vec![Rc::new(Item::Value(Value::QName(QualifiedName::new(Some(uri), p, l))))])
+  }
+}
+
+pub fn func_resolve_qname(
+    _e: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  if args.len() != 2 {
+    return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+  if args[0].is_empty() {
+    return Ok(vec![])
+  }
+  let (p, l) = parse_lexical_qname(&args[0].to_string(Some(f)))?;
+  let e = match node_arg(&None, None, &args[1..])? {
+    Some(n) if n.node_type(f) == NodeType::Element => n,
+    _ => return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("second argument must be an element"),}),
+  };
+  // An unprefixed name is in the default namespace of the element
+  match (e.namespace_uri(f, p.as_deref()), &p) {
+    (Some(u), _) => Ok(vec![Rc::new(Item::Value(Value::QName(QualifiedName::new(Some(u), p, l))))]),
+    (None, None) => Ok(vec![Rc::new(Item::Value(Value::QName(QualifiedName::new(None, None, l))))]),
+    (None, Some(q)) => Result::Err(Error{kind: ErrorKind::NoNamespace, message: format!("no namespace declaration in scope for prefix \"{}\"", q)}),
+  }
+}
+
+// The QName that is the argument of a function. The empty sequence gives None.
+fn qname_arg(args: &[Sequence]) -> Result<Option<QualifiedName>, Error> {
+  if args.len() != 1 {
+    return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+  match args[0].len() {
+    0 => Ok(None),
+    1 => match &*args[0][0] {
+      Item::Value(Value::QName(q)) => Ok(Some(q.clone())),
+      _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("not a QName"),}),
+    },
+    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("not a singleton sequence"),}),
+  }
+}
+
+pub fn func_prefix_from_qname(
+    _e: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    _f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  match qname_arg(&args)?.and_then(|q| q.get_prefix()) {
+    Some(p) => Ok(vec![Rc::new(Item::Value(Value::String(p).cast_as("NCName")?))]),
+    None => Ok(vec![]),
+  }
+}
+
+pub fn func_local_name_from_qname(
+    _e: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    _f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  match qname_arg(&args)? {
+    Some(q) => Ok(vec![Rc::new(Item::Value(Value::String(q.get_localname()).cast_as("NCName")?))]),
+    None => Ok(vec![]),
+  }
+}
+
+pub fn func_namespace_uri_from_qname(
+    _e: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    _f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  match qname_arg(&args)? {
+    Some(q) => Ok(vec![Rc::new(Item::Value(Value::AnyURI(q.get_nsuri().unwrap_or_default())))]),
+    None => Ok(vec![]),
+  }
+}
+
+// TODO: implement string value properly
+pub fn func_string(
+    _e: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  match args.len() {
+    1 => {
+      // return string value
+      let mut s = String::new();
+      for i in &args[0] {
+	s.push_str(i.string_value(Some(f))?.as_str())
+      }
+      Ok(vec![Rc::new(Item::Value(Value::String(s)))])
+    }
+    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+}
+
+pub fn func_concat(
+    _e: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  Ok(vec![Rc::new(Item::Value(Value::String(
+    args.iter().fold(
+      String::new(),
+      |mut a, b| {
+        a.push_str(b.to_string(Some(f)).as_str());
+	a
+      }
+    )
+  )))])
+}
+
+pub fn func_startswith(
+    e: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  // must have 2 arguments, and optionally a collation
+  if args.len() == 2 || args.len() == 3 {
+     // arg[0] is the string to search
+     // arg[1] is what to search for
+     let c = collation_arg(e, &args, 2, f)?;
+     Ok(vec![Rc::new(Item::Value(Value::Boolean(
+       c.key(args[0].to_string(Some(f)).as_str()).starts_with(c.key(args[1].to_string(Some(f)).as_str()).as_str())
+    )))])
+  } else {
+    Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+}
+
+pub fn func_contains(
+    e: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  // must have 2 arguments, and optionally a collation
+  if args.len() == 2 || args.len() == 3 {
+     // arg[0] is the string to search
+     // arg[1] is what to search for
+     let c = collation_arg(e, &args, 2, f)?;
+     Ok(vec![Rc::new(Item::Value(Value::Boolean(
+       c.key(args[0].to_string(Some(f)).as_str()).contains(c.key(args[1].to_string(Some(f)).as_str()).as_str())
+    )))])
+  } else {
+    Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+}
+
+// The collation given by an optional argument. If the argument is not present then the default collation is used.
+fn collation_arg(e: &Evaluator, args: &[Sequence], i: usize, f: &Forest) -> Result<Rc<dyn Collation>, Error> {
+  match args.get(i) {
+    Some(c) => e.collation(Some(c.to_string(Some(f)).as_str())),
+    None => e.collation(None),
+  }
+}
+
+// Atomize an item, for comparison.
+// Returns the string value if the item is of a string type, otherwise the atomic value.
+fn comparison_key(i: &Item, f: &Forest) -> Result<Result<String, Value>, Error> {
+  match atomize(i, f)? {
+    Value::String(s) => Ok(Ok(s)),
+    v if v.is_string_derived() || matches!(v, Value::AnyURI(_)) => Ok(Ok(v.to_string())),
+    v => Ok(Err(v)),
+  }
+}
+
+pub fn func_compare(
+    e: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  // must have 2 arguments, and optionally a collation
+  if args.len() != 2 && args.len() != 3 {
+    return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+  if args[0].is_empty() || args[1].is_empty() {
+    return Ok(vec![])
+  }
+  let c = collation_arg(e, &args, 2, f)?;
+  let r = match c.compare(args[0].to_string(Some(f)).as_str(), args[1].to_string(Some(f)).as_str()) {
+    Ordering::Less => -1,
+    Ordering::Equal => 0,
+    Ordering::Greater => 1,
+  };
+  Ok(vec![Rc::new(Item::Value(Value::Integer(r)))])
+}
+
+pub fn func_distinct_values(
+    e: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  // 1 argument, and optionally a collation
+  if args.len() != 1 && args.len() != 2 {
+    return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+  let c = collation_arg(e, &args, 1, f)?;
+  let mut keys: Vec<Result<String, Value>> = vec![];
+  let mut result: Sequence = vec![];
+  for i in &args[0] {
+    let k = match comparison_key(i, f)? {
+      Ok(s) => Ok(c.key(&s)),
+      Err(v) => Err(v),
+    };
+    let seen = keys.iter().any(|j| match (j, &k) {
+      (Ok(s), Ok(t)) => s == t,
+      // NaN is considered to be equal to itself
+      (Err(v), Err(w)) => v == w || (v.is_numeric() && w.is_numeric() && v.to_double().is_nan() && w.to_double().is_nan()),
+      _ => false,
+    });
+    if !seen {
+      result.push(Rc::new(Item::Value(match &k {
+	Ok(_) => atomize(i, f)?,
+	Err(v) => v.clone(),
+      })));
+      keys.push(k);
+    }
+  }
+  Ok(result)
+}
+
+pub fn func_sort(
+    e: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  // 1 argument, and optionally a collation
+  // TODO: the sort key function argument (requires function items)
+  if args.len() != 1 && args.len() != 2 {
+    return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+  let c = collation_arg(e, &args, 1, f)?;
+  let mut keyed = vec![];
+  for i in &args[0] {
+    keyed.push((comparison_key(i, f)?, i.clone()))
+  }
+  let mut err = None;
+  keyed.sort_by(|(a, _), (b, _)| {
+    match compare_keys(a, b, c.as_ref()) {
+      Ok(o) => o,
+      Err(x) => {
+	err.get_or_insert(x);
+	Ordering::Equal
+      }
+    }
+  });
+  match err {
+    Some(x) => Result::Err(x),
+    None => Ok(keyed.into_iter().map(|(_, i)| i).collect()),
+  }
+}
+
+pub fn func_deep_equal(
+    e: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  // 2 arguments, and optionally a collation
+  if args.len() != 2 && args.len() != 3 {
+    return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+  let c = collation_arg(e, &args, 2, f)?;
+  let r = args[0].len() == args[1].len() &&
+    args[0].iter().zip(args[1].iter())
+      .all(|(i, j)| deep_equal_items(i, j, f, c.as_ref()));
+  Ok(vec![Rc::new(Item::Value(Value::Boolean(r)))])
+}
+
+// Two items are deep-equal if they are both nodes that are deep-equal, or both atomic values that are equal.
+// Atomic values that cannot be compared are not equal, rather than being an error.
+fn deep_equal_items(i: &Item, j: &Item, f: &Forest, c: &dyn Collation) -> bool {
+  match (i, j) {
+    (Item::Node(m), Item::Node(n)) => m.deep_equal_collation(f, n, c),
+    (Item::Value(_), Item::Value(_)) => {
+      match (comparison_key(i, f), comparison_key(j, f)) {
+	(Ok(Ok(s)), Ok(Ok(t))) => c.equal(&s, &t),
+	(Ok(Err(v)), Ok(Err(w))) => {
+	  if v.is_numeric() && w.is_numeric() && v.to_double().is_nan() && w.to_double().is_nan() {
+	    true
+	  } else {
+	    v.compare(&w, Operator::Equal).unwrap_or(false)
+	  }
+	}
+	_ => false,
+      }
+    }
+    _ => false,
+  }
+}
+
+// Compare two sort keys. Strings are compared using the collation, other values must be of comparable types.
+// NaN sorts before all other numeric values.
+fn compare_keys(a: &Result<String, Value>, b: &Result<String, Value>, c: &dyn Collation) -> Result<Ordering, Error> {
+  match (a, b) {
+    (Ok(s), Ok(t)) => Ok(c.compare(s, t)),
+    (Err(v), Err(w)) => {
+      if v.is_numeric() && w.is_numeric() {
+	match (v.to_double().is_nan(), w.to_double().is_nan()) {
+	  (true, true) => return Ok(Ordering::Equal),
+	  (true, false) => return Ok(Ordering::Less),
+	  (false, true) => return Ok(Ordering::Greater),
+	  _ => {}
+	}
+      }
+      if v.compare(w, Operator::LessThan)? {
+	Ok(Ordering::Less)
+      } else if v.compare(w, Operator::Equal)? {
+	Ok(Ordering::Equal)
+      } else {
+	Ok(Ordering::Greater)
+      }
+    }
+    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("values are not comparable"),}),
+  }
+}
+
+pub fn func_substring(
+    _e: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  // must have 2 or 3 arguments
+  match args.len() {
+    2 => {
+     // arg[0] is the string to search
+     // arg[1] is the index to start at
+     // 2-argument version takes the rest of the string
+     Ok(vec![Rc::new(Item::Value(Value::String(
+       args[0].to_string(Some(f)).graphemes(true).skip(args[1].to_int()? as usize - 1).collect()
+     )))])
+    }
+    3 => {
+     // arg[0] is the string to search
+     // arg[1] is the index to start at
+     // arg[2] is the length of the substring to extract
+     Ok(vec![Rc::new(Item::Value(Value::String(
+       args[0].to_string(Some(f)).graphemes(true).skip(args[1].to_int()? as usize - 1).take(args[2].to_int()? as usize).collect()
+     )))])
+    }
+    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+}
+
+pub fn func_substringbefore(
+    _e: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  // must have 2 arguments
+  match args.len() {
+    2 => {
+     // arg[0] is the string to search
+     // arg[1] is the string to find
+     match args[0].to_string(Some(f)).find(args[1].to_string(Some(f)).as_str()) {
+       Some(i) => {
+         match args[0].to_string(Some(f)).get(0..i) {
+	   Some(s) => {
+     	     Ok(vec![Rc::new(Item::Value(Value::String(
+	       String::from(s)
+     	     )))])
+	   }
+	   None => {
+	     // This shouldn't happen!
+	     Result::Err(Error{kind: ErrorKind::Unknown, message: String::from("unable to extract substring"),})
+	   }
+	 }
+       }
+       None => {
+         Ok(vec![])
+       }
+     }
+    }
+    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+}
+
+pub fn func_substringafter(
+    _e: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  // must have 2 arguments
+  match args.len() {
+    2 => {
+     // arg[0] is the string to search
+     // arg[1] is the string to find
+     match args[0].to_string(Some(f)).find(args[1].to_string(Some(f)).as_str()) {
+       Some(i) => {
+         match args[0].to_string(Some(f)).get(i + args[1].to_string(Some(f)).len()..args[0].to_string(Some(f)).len()) {
+	   Some(s) => {
+     	     Ok(vec![Rc::new(Item::Value(Value::String(
+	       String::from(s)
+     	     )))])
+	   }
+	   None => {
+	     // This shouldn't happen!
+	     Result::Err(Error{kind: ErrorKind::Unknown, message: String::from("unable to extract substring"),})
+	   }
+	 }
+       }
+       None => {
+         Ok(vec![])
+       }
+     }
+    }
+    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+}
+
+pub fn func_normalizespace(
+    _e: &Evaluator,
+    ctxt: Option<Sequence>,
+    posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  // must have 0 or 1 arguments
+  let s: Result<Option<String>, Error> = match args.len() {
+    0 => {
+      // Use the current item
+      match ctxt {
+        Some(c) => {
+	  Ok(Some(c[posn.unwrap()].to_string(Some(f))))
+	}
+	None => Ok(None)
+      }
+    }
+    1 => {
+      Ok(Some(args[0].to_string(Some(f))))
+    }
+    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  };
+
+  match s {
+    Ok(u) => {
+      match u {
+        Some(t) => {
+          Ok(vec![Rc::new(Item::Value(Value::String(
+            t.split_whitespace().collect()
+          )))])
+        }
+        None => Result::Err(Error{kind: ErrorKind::DynamicAbsent, message: String::from("no context item"),})
+      }
+    }
+    Result::Err(e) => {
+      Result::Err(e)
+    }
+  }
+}
+
+pub fn func_translate(
+    _e: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  // must have 3 arguments
+  match args.len() {
+    3 => {
+      // arg[0] is the string to search
+      // arg[1] is the map chars
+      // arg[2] is the translate chars
+      let o = args[1].to_string(Some(f));
+      let m: Vec<&str> = o.graphemes(true).collect();
+      let u = args[2].to_string(Some(f));
+      let t: Vec<&str> = u.graphemes(true).collect();
+      let mut result: String = String::new();
+
+      for c in args[0].to_string(Some(f)).graphemes(true) {
+	let mut a: Option<Option<usize>> = Some(None);
+        for i in 0..m.len() {
+	  if c == m[i] {
+	    if i < t.len() {
+	      a = Some(Some(i));
+	      break
+            } else {
+              // omit this character
+	      a = None
+            }
+	  } else {
+	    // keep looking for a match
+	  }
+        }
+	match a {
+	  Some(None) => {
+	    result.push_str(c);
+	  }
+	  Some(Some(j)) => {
+	    result.push_str(t[j])
+	  }
+	  None => {
+	    // omit char
+	  }
+	}
+      }
+      Ok(vec![Rc::new(Item::Value(Value::String(result)))])
+    }
+    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+}
+
+pub fn func_boolean(
+    _: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    _f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  // must have 1 arguments
+  match args.len() {
+    1 => {
+      Ok(vec![Rc::new(Item::Value(Value::Boolean(args[0].to_bool())))])
+    }
+    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+}
+
+pub fn func_not(
+    _: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    _f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  // must have 1 arguments
+  match args.len() {
+    1 => {
+      Ok(vec![Rc::new(Item::Value(Value::Boolean(!args[0].to_bool())))])
+    }
+    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+}
+
+pub fn func_true(
+    _: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    _f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  // must have 0 arguments
+  match args.len() {
+    0 => {
+      Ok(vec![Rc::new(Item::Value(Value::Boolean(true)))])
+    }
+    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+}
+
+pub fn func_false(
+    _: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    _f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  // must have 0 arguments
+  match args.len() {
+    0 => {
+      Ok(vec![Rc::new(Item::Value(Value::Boolean(false)))])
+    }
+    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+}
+
+pub fn func_number(
+    _: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    _f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  // must have 1 argument
+  match args.len() {
+    1 => {
+      match args[0].len() {
+        1 => {
+	  // TODO: if the item is already an integer, then just clone it
+      	  // First try converting to an integer
+	  match args[0][0].to_int() {
+	    Ok(i) => {
+      	      Ok(vec![Rc::new(Item::Value(Value::Integer(i)))])
+	    }
+	    Result::Err(_) => {
+      	      // If that fails, convert to double
+	      // NB. this can't fail. At worst it returns NaN
+      	      Ok(vec![Rc::new(Item::Value(Value::Double(args[0][0].to_double())))])
+	    }
+	  }
+	}
+	_ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("not a singleton sequence"),})
+      }
+    }
+    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+}
+
+// Atomize an item, for use in arithmetic and comparisons. Nodes give an untyped value.
+fn atomize(i: &Item, f: &Forest) -> Result<Value, Error> {
+  match i {
+    Item::Value(v) => Ok(v.clone()),
+    Item::Node(n) => Ok(Value::String(n.to_string(f))),
+    Item::Function => Result::Err(Error{kind: ErrorKind::FunctionAtomization, message: String::from("function item cannot be atomized"),}),
+    Item::Map(_) => Result::Err(Error{kind: ErrorKind::FunctionAtomization, message: String::from("map cannot be atomized"),}),
+    // An array atomizes to the atomized values of its members, so only an array with a single value is a single value
+    Item::Array(a) => {
+      match (a.len(), a.get(0)) {
+	(1, Some(m)) if m.len() == 1 => atomize(&m[0], f),
+	_ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("array does not atomize to a single value"),}),
+      }
+    }
+  }
+}
+
+// Get a member of an array. The index is one-based.
+fn array_member<'a>(a: &'a Array, i: &Value) -> Result<&'a Sequence, Error> {
+  let n = match i {
+    Value::Integer(_) | Value::Long(_) | Value::Int(_) | Value::Short(_) | Value::Byte(_) |
+    Value::NonPositiveInteger(_) | Value::NegativeInteger(_) | Value::NonNegativeInteger(_) | Value::PositiveInteger(_) |
+    Value::UnsignedLong(_) | Value::UnsignedInt(_) | Value::UnsignedShort(_) | Value::UnsignedByte(_) => i.to_int()?,
+    _ => return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("array index is not an integer"),}),
+  };
+  usize::try_from(n).ok()
+    .and_then(|m| m.checked_sub(1))
+    .and_then(|m| a.get(m))
+    .ok_or_else(|| Error{kind: ErrorKind::ArrayIndexOutOfBounds, message: format!("array index {} is out of bounds (size {})", n, a.len()),})
+}
+
+// Apply a numeric operation to an optional singleton argument.
+// The empty sequence gives the empty sequence.
+fn numeric_unary(arg: &Sequence, f: &Forest, op: impl Fn(&Value) -> Result<Value, Error>) -> Result<Sequence, Error> {
+  match arg.len() {
+    0 => Ok(vec![]),
+    1 => Ok(vec![Rc::new(Item::Value(op(&atomize(&arg[0], f)?)?))]),
+    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("not a singleton sequence"),}),
+  }
+}
+
+pub fn func_sum(
+    _: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  // must have 1 or 2 arguments
+  // The second argument is the value to return for an empty sequence
+  match args.len() {
+    1 | 2 => {
+      if args[0].is_empty() {
+	if args.len() == 2 {
+	  Ok(args[1].clone())
+	} else {
+	  Ok(vec![Rc::new(Item::Value(Value::Integer(0)))])
+	}
+      } else {
+	let mut acc = atomize(&args[0][0], f)?;
+	// Check that a single value is numeric
+	acc = Value::Integer(0).arithmetic(ArithmeticOperator::Noop, &acc)?;
+	for i in args[0].iter().skip(1) {
+	  acc = acc.arithmetic(ArithmeticOperator::Add, &atomize(i, f)?)?;
+	}
+	Ok(vec![Rc::new(Item::Value(acc))])
+      }
+    }
+    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+}
+
+pub fn func_floor(
+    _: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  // must have 1 argument
+  match args.len() {
+    1 => numeric_unary(&args[0], f, |v| v.floor()),
+    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+}
+
+pub fn func_ceiling(
+    _: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  // must have 1 argument
+  match args.len() {
+    1 => numeric_unary(&args[0], f, |v| v.ceiling()),
+    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+}
+
+pub fn func_abs(
+    _: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  // must have 1 argument
+  match args.len() {
+    1 => numeric_unary(&args[0], f, |v| v.abs()),
+    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+}
+
+// The precision argument of round and round-half-to-even
+fn precision_arg(args: &[Sequence]) -> Result<i64, Error> {
+  match args.len() {
+    1 => Ok(0),
+    2 => args[1].to_int(),
+    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),}),
+  }
+}
+
+pub fn func_round(
+    _: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  // must have 1 or 2 arguments
+  // The second argument is the precision; if not given then round to the nearest whole number
+  let p = precision_arg(&args)?;
+  numeric_unary(&args[0], f, |v| v.round(p))
+}
+
+pub fn func_round_half_to_even(
+    _: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  // must have 1 or 2 arguments
+  // The second argument is the precision; if not given then round to the nearest whole number
+  let p = precision_arg(&args)?;
+  numeric_unary(&args[0], f, |v| v.round_half_to_even(p))
+}
+
+pub fn func_format_integer(
+    _: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  // must have 2 or 3 arguments
+  // TODO: the language argument is ignored
+  match args.len() {
+    2 | 3 => {
+      let picture = args[1].to_string(Some(f));
+      match args[0].len() {
+	0 => Ok(vec![Rc::new(Item::Value(Value::from("")))]),	// Empty value returns the zero-length string
+	1 => {
+	  let i = args[0][0].to_int()?;
+	  Ok(vec![Rc::new(Item::Value(Value::String(format_integer(i, picture.as_str())?)))])
+	}
+	_ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("not a singleton sequence"),}),
+      }
+    }
+    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),}),
+  }
+}
+
+// Math functions
+
+// The xs:double value of a math function argument.
+// A node's value is untyped, so it is cast to a double; any other non-numeric value is a type error.
+fn math_double(i: &Item, f: &Forest) -> Result<f64, Error> {
+  match i {
+    Item::Node(_) => Ok(atomize(i, f)?.cast_as("double")?.to_double()),
+    _ => {
+      let v = atomize(i, f)?;
+      if v.is_numeric() {
+	Ok(v.to_double())
+      } else {
+	Result::Err(Error{kind: ErrorKind::TypeError, message: format!("type \"{}\" is not a numeric type", v.value_type()),})
+      }
+    }
+  }
+}
+
+// Most of the math functions take a single xs:double? argument.
+// An empty sequence returns an empty sequence.
+fn math_unary(args: Vec<Sequence>, f: &Forest, op: fn(f64) -> f64) -> Result<Sequence, Error> {
+  if args.len() != 1 {
+    return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+  match args[0].len() {
+    0 => Ok(vec![]),
+    1 => Ok(vec![Rc::new(Item::Value(Value::Double(op(math_double(&args[0][0], f)?))))]),
+    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("not a singleton sequence"),}),
+  }
+}
+
+pub fn func_math_pi(
+    _: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    _f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  if !args.is_empty() {
+    return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+  Ok(vec![Rc::new(Item::Value(Value::Double(std::f64::consts::PI)))])
+}
+pub fn func_math_exp(
+    _: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  math_unary(args, f, f64::exp)
+}
+pub fn func_math_exp10(
+    _: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  math_unary(args, f, |d| 10f64.powf(d))
+}
+pub fn func_math_log(
+    _: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  math_unary(args, f, f64::ln)
+}
+pub fn func_math_log10(
+    _: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  math_unary(args, f, f64::log10)
+}
+pub fn func_math_sqrt(
+    _: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  math_unary(args, f, f64::sqrt)
+}
+pub fn func_math_sin(
+    _: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  math_unary(args, f, f64::sin)
+}
+pub fn func_math_cos(
+    _: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  math_unary(args, f, f64::cos)
+}
+pub fn func_math_tan(
+    _: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  math_unary(args, f, f64::tan)
+}
+pub fn func_math_asin(
+    _: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  math_unary(args, f, f64::asin)
+}
+pub fn func_math_acos(
+    _: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  math_unary(args, f, f64::acos)
+}
+pub fn func_math_atan(
+    _: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  math_unary(args, f, f64::atan)
+}
+pub fn func_math_pow(
+    _: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  // must have 2 arguments: the first is xs:double?, the second is numeric
+  if args.len() != 2 {
+    return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+  match (args[0].len(), args[1].len()) {
+    (0, _) => Ok(vec![]),
+    (1, 1) => Ok(vec![Rc::new(Item::Value(Value::Double(math_double(&args[0][0], f)?.powf(math_double(&args[1][0], f)?))))]),
+    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("not a singleton sequence"),}),
+  }
+}
+pub fn func_math_atan2(
+    _: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  // must have 2 arguments, both xs:double
+  if args.len() != 2 {
+    return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+  match (args[0].len(), args[1].len()) {
+    (1, 1) => Ok(vec![Rc::new(Item::Value(Value::Double(math_double(&args[0][0], f)?.atan2(math_double(&args[1][0], f)?))))]),
+    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("not a singleton sequence"),}),
+  }
 }
 
-// A formal parameter
-#[derive(Clone)]
-pub struct Param {
-  name: String,
-  datatype: String,	// TODO
+// Binary functions (EXPath binary module)
+
+// The octets of an optional binary argument.
+// Either xs:base64Binary or xs:hexBinary is accepted.
+fn binary_arg(arg: &Sequence) -> Result<Option<Vec<u8>>, Error> {
+  match arg.len() {
+    0 => Ok(None),
+    1 => match &*arg[0] {
+      Item::Value(v) => v.octets()
+	.map(|o| Some(o.to_vec()))
+	.ok_or_else(|| Error{kind: ErrorKind::TypeError, message: format!("type \"{}\" is not a binary type", v.value_type())}),
+      _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("not a binary value"),}),
+    },
+    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("not a singleton sequence"),}),
+  }
 }
 
-impl Param {
-  pub fn new(n: String, t: String) -> Param {
-    Param{name: n, datatype: t}
+pub fn func_bin_length(
+    _: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    _f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  if args.len() != 1 {
+    return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
   }
-  pub fn get_name(&self) -> String {
-    self.name.clone()
+  match binary_arg(&args[0])? {
+    Some(b) => Ok(vec![Rc::new(Item::Value(Value::Integer(b.len() as i64)))]),
+    None => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("not a singleton sequence"),}),
   }
-  pub fn get_datatype(&self) -> String {
-    self.datatype.clone()
+}
+
+pub fn func_bin_part(
+    _: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    _f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  // 2 or 3 arguments: the binary data, the offset, and (optionally) the size
+  if args.len() != 2 && args.len() != 3 {
+    return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+  let b = match binary_arg(&args[0])? {
+    Some(b) => b,
+    None => return Ok(vec![]),
+  };
+  if args[1].len() != 1 {
+    return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("not a singleton sequence"),})
+  }
+  let offset = args[1][0].to_int()?;
+  if offset < 0 || offset as usize > b.len() {
+    return Result::Err(Error{kind: ErrorKind::BinaryIndexOutOfRange, message: format!("offset {} is out of range", offset)})
+  }
+  let start = offset as usize;
+  let end = match args.get(2).and_then(|s| s.first()) {
+    Some(s) => {
+      let size = s.to_int()?;
+      if size < 0 {
+	return Result::Err(Error{kind: ErrorKind::BinaryNegativeSize, message: format!("size {} is negative", size)})
+      }
+      match start.checked_add(size as usize) {
+	Some(e) if e <= b.len() => e,
+	_ => return Result::Err(Error{kind: ErrorKind::BinaryIndexOutOfRange, message: format!("offset {} plus size {} is out of range", offset, size)}),
+      }
+    }
+    None => b.len(),
+  };
+  Ok(vec![Rc::new(Item::Value(Value::Base64Binary(b[start..end].to_vec())))])
+}
+
+pub fn func_bin_to_octets(
+    _: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    _f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  if args.len() != 1 {
+    return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+  match binary_arg(&args[0])? {
+    Some(b) => Ok(b.iter().map(|o| Rc::new(Item::Value(Value::Integer(*o as i64)))).collect()),
+    None => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("not a singleton sequence"),}),
+  }
+}
+
+pub fn func_current_date_time(
+    _: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    _args: Vec<Sequence>,
+    _f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  // must have 0 arguments
+  // TODO: check number of arguments
+  // TODO: do the check in static analysis phase
+
+  Ok(vec![Rc::new(Item::Value(Value::DateTime(Local::now())))])
+}
+
+pub fn func_current_date(
+    _: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    _args: Vec<Sequence>,
+    _f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  // must have 0 arguments
+  // TODO: check number of arguments
+  // TODO: do the check in static analysis phase
+
+  Ok(vec![Rc::new(Item::Value(Value::Date(Local::today())))])
+}
+
+pub fn func_current_time(
+    _: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    _args: Vec<Sequence>,
+    _f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  // must have 0 arguments
+  // TODO: check number of arguments
+  // TODO: do the check in static analysis phase
+
+  Ok(vec![Rc::new(Item::Value(Value::Time(Local::now())))])
+}
+
+pub fn func_format_date_time(
+    _e: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  // must have 2 or 5 arguments
+  // TODO: implement 5 argument version
+
+  match args.len() {
+    2 => {
+      // First argument is the dateTime value
+      // Second argument is the picture
+      let pic = match picture_parse(&args[1].to_string(Some(f))) {
+        Ok(p) => p,
+	Err(_) => return Result::Err(Error{kind: ErrorKind::Unknown, message: String::from("bad picture"),})
+      };
+
+      match args[0].len() {
+        0 => Ok(vec![]),	// Empty value returns empty sequence
+        1 => {
+	  match *args[0][0] {
+	    Item::Value(Value::DateTime(dt)) => {
+	      Ok(vec![Rc::new(Item::Value(Value::String(dt.format(&pic).to_string())))])
+	    }
+	    Item::Value(Value::String(ref s)) => {
+	      // Try and coerce into a DateTime value
+	      match DateTime::<FixedOffset>::parse_from_rfc3339(s.as_str()) {
+	        Ok(dt) => {
+	      	  Ok(vec![Rc::new(Item::Value(Value::String(dt.format(&pic).to_string())))])
+		}
+		Err(_) => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("unable to determine date value"),})
+	      }
+	    }
+	    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("not a dateTime value"),})
+	  }
+	}
+	_ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("not a singleton sequence"),}),
+      }
+    }
+    5 => Result::Err(Error{kind: ErrorKind::NotImplemented, message: String::from("not yet implemented"),}),
+    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),}),
+  }
+}
+
+pub fn func_format_date(
+    _e: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  // must have 2 or 5 arguments
+  // TODO: implement 5 argument version
+
+  match args.len() {
+    2 => {
+      // First argument is the date value
+      // Second argument is the picture
+      let pic = match picture_parse(&args[1].to_string(Some(f))) {
+        Ok(p) => p,
+	Err(_) => return Result::Err(Error{kind: ErrorKind::Unknown, message: String::from("bad picture"),})
+      };
+      match args[0].len() {
+        0 => Ok(vec![]),	// Empty value returns empty sequence
+        1 => {
+	  match *args[0][0] {
+	    Item::Value(Value::Date(dt)) => {
+	      Ok(vec![Rc::new(Item::Value(Value::String(dt.format(&pic).to_string())))])
+	    }
+	    Item::Value(Value::String(ref s)) => {
+	      // Try and coerce into a Date value
+	      let a = format!("{}T00:00:00Z", s);
+	      match DateTime::<FixedOffset>::parse_from_rfc3339(a.as_str()) {
+	        Ok(dt) => {
+	      	  Ok(vec![Rc::new(Item::Value(Value::String(dt.date().format(&pic).to_string())))])
+		}
+		Err(_) => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("unable to determine date value"),})
+	      }
+	    }
+	    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("not a date value"),})
+	  }
+	}
+	_ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("not a singleton sequence"),}),
+      }
+    }
+    5 => Result::Err(Error{kind: ErrorKind::NotImplemented, message: String::from("not yet implemented"),}),
+    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),}),
+  }
+}
+
+pub fn func_format_time(
+    _e: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  // must have 2 or 5 arguments
+  // TODO: implement 5 argument version
+
+  match args.len() {
+    2 => {
+      // First argument is the time value
+      // Second argument is the picture
+      let pic = match picture_parse(&args[1].to_string(Some(f))) {
+        Ok(p) => p,
+	Err(_) => return Result::Err(Error{kind: ErrorKind::Unknown, message: String::from("bad picture"),})
+      };
+      match args[0].len() {
+        0 => Ok(vec![]),	// Empty value returns empty sequence
+        1 => {
+	  match *args[0][0] {
+	    Item::Value(Value::Time(dt)) => {
+	      Ok(vec![Rc::new(Item::Value(Value::String(dt.format(&pic).to_string())))])
+	    }
+	    Item::Value(Value::String(ref s)) => {
+	      // Try and coerce into a DateTime value
+	      let a = format!("1900-01-01T{}Z", s);
+	      match DateTime::<FixedOffset>::parse_from_rfc3339(a.as_str()) {
+	        Ok(dt) => {
+	      	  Ok(vec![Rc::new(Item::Value(Value::String(dt.time().format(&pic).to_string())))])
+		}
+		Err(_) => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("unable to determine time value"),})
+	      }
+	    }
+	    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("not a time value"),})
+	  }
+	}
+	_ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("not a singleton sequence"),}),
+      }
+    }
+    5 => Result::Err(Error{kind: ErrorKind::NotImplemented, message: String::from("not yet implemented"),}),
+    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),}),
   }
 }
 
-fn func_position(
-    _: &Evaluator,
+// Resolve a URI argument against the base URI.
+fn document_uri(e: &Evaluator, uri: &str) -> Result<Url, Error> {
+  e.baseurl().map_or_else(
+    || Url::parse(uri),
+    |b| b.join(uri),
+  ).map_err(|_| Error::new(ErrorKind::InvalidDocumentURI, format!("unable to resolve URI \"{}\"", uri)))
+}
+
+pub fn func_doc(
+    e: &Evaluator,
     _ctxt: Option<Sequence>,
-    posn: Option<usize>,
-    _args: Vec<Sequence>,
-    _f: &mut Forest,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
     _sd: TreeIndex,
     _rd: TreeIndex,
 ) -> Result<Sequence, Error> {
-  match posn {
-    Some(u) => {
-      Ok(vec![Rc::new(Item::Value(Value::Integer(u as i64 + 1)))])
-    }
-    None => Result::Err(Error{kind: ErrorKind::DynamicAbsent, message: String::from("no context item"),})
+  // must have 1 argument
+  if args.len() != 1 {
+    return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
   }
+  if args[0].is_empty() {
+    return Ok(vec![])
+  }
+  let u = document_uri(e, args[0].to_string(Some(f)).as_str())?;
+  Ok(vec![Rc::new(Item::Node(e.dc.documents.document(&u, f)?))])
 }
 
-fn func_last(
-    _: &Evaluator,
-    ctxt: Option<Sequence>,
+pub fn func_doc_available(
+    e: &Evaluator,
+    _ctxt: Option<Sequence>,
     _posn: Option<usize>,
-    _args: Vec<Sequence>,
-    _f: &mut Forest,
+    args: Vec<Sequence>,
+    f: &mut Forest,
     _sd: TreeIndex,
     _rd: TreeIndex,
 ) -> Result<Sequence, Error> {
-  match ctxt {
-    Some(u) => {
-      Ok(vec![Rc::new(Item::Value(Value::Integer(u.len() as i64)))])
-    }
-    None => Result::Err(Error{kind: ErrorKind::DynamicAbsent, message: String::from("no context item"),})
+  // must have 1 argument
+  if args.len() != 1 {
+    return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
   }
+  let r = !args[0].is_empty() &&
+    document_uri(e, args[0].to_string(Some(f)).as_str())
+      .and_then(|u| e.dc.documents.document(&u, f))
+      .is_ok();
+  Ok(vec![Rc::new(Item::Value(Value::Boolean(r)))])
 }
 
-pub fn func_count(
-    _: &Evaluator,
-    ctxt: Option<Sequence>,
+pub fn func_collection(
+    e: &Evaluator,
+    _ctxt: Option<Sequence>,
     _posn: Option<usize>,
     args: Vec<Sequence>,
-    _f: &mut Forest,
+    f: &mut Forest,
     _sd: TreeIndex,
     _rd: TreeIndex,
 ) -> Result<Sequence, Error> {
-  match args.len() {
-    0 => {
-      // count the context items
-      match ctxt {
-        Some(u) => Ok(vec![Rc::new(Item::Value(Value::Integer(u.len() as i64)))]),
-        None => Result::Err(Error{kind: ErrorKind::DynamicAbsent, message: String::from("no context item"),})
-      }
-    }
-    1 => {
-      // count the argument items
-      Ok(vec![Rc::new(Item::Value(Value::Integer(args[0].len() as i64)))])
-    }
-    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  // zero or one argument; an empty sequence is the default collection
+  let u = match args.len() {
+    0 => None,
+    1 if args[0].is_empty() => None,
+    1 => Some(document_uri(e, args[0].to_string(Some(f)).as_str())?),
+    _ => return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),}),
+  };
+  Ok(e.dc.documents.collection(u.as_ref(), f)?
+    .into_iter()
+    .map(|n| Rc::new(Item::Node(n)))
+    .collect())
+}
+
+// Resolve, retrieve and decode an unparsed text resource.
+// The first argument is the URI, the optional second argument is the encoding.
+fn unparsed_text(e: &Evaluator, args: &[Sequence], f: &Forest) -> Result<String, Error> {
+  let href = args[0].to_string(Some(f));
+  let url = e.baseurl().map_or_else(
+    || Url::parse(href.as_str()),
+    |b| b.join(href.as_str()),
+  ).map_err(|_| Error::new(ErrorKind::UnparsedTextNotFound, format!("unable to resolve URI \"{}\"", href)))?;
+  if url.fragment().is_some() {
+    return Result::Err(Error::new(ErrorKind::UnparsedTextNotFound, format!("URI \"{}\" has a fragment identifier", url)))
   }
+  let content = e.dc.documents.text(&url)?;
+  let encoding = args.get(1).map(|enc| enc.to_string(Some(f)));
+  decode(&content, encoding.as_deref())
 }
 
-pub fn func_localname(
-    _: &Evaluator,
-    ctxt: Option<Sequence>,
-    posn: Option<usize>,
-    _args: Vec<Sequence>,
+pub fn func_unparsed_text(
+    e: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
     f: &mut Forest,
     _sd: TreeIndex,
     _rd: TreeIndex,
 ) -> Result<Sequence, Error> {
-  match ctxt {
-    Some(u) => {
-      // Current item must be a node
-      match *u[posn.unwrap()] {
-        Item::Node(ref n) => {
-      	  Ok(vec![Rc::new(Item::Value(Value::String(n.to_name(f).get_localname())))])
-	}
-	_ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("not a node"),})
-      }
-    }
-    None => Result::Err(Error{kind: ErrorKind::DynamicAbsent, message: String::from("no context item"),})
+  // 1 argument, and optionally the encoding
+  if args.len() != 1 && args.len() != 2 {
+    return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
   }
+  if args[0].is_empty() {
+    return Ok(vec![])
+  }
+  Ok(vec![Rc::new(Item::Value(Value::String(unparsed_text(e, &args, f)?)))])
 }
 
-// TODO: handle qualified names
-pub fn func_name(
-    _e: &Evaluator,
-    ctxt: Option<Sequence>,
-    posn: Option<usize>,
-    _args: Vec<Sequence>,
+pub fn func_unparsed_text_lines(
+    e: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
     f: &mut Forest,
     _sd: TreeIndex,
     _rd: TreeIndex,
 ) -> Result<Sequence, Error> {
-  match ctxt {
-    Some(u) => {
-      // Current item must be a node
-      match *u[posn.unwrap()] {
-        Item::Node(ref n) => {
-      	  // TODO: handle QName prefixes
-	  Ok(vec![Rc::new(Item::Value(Value::String(n.to_name(f).get_localname())))])
-	}
-	_ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("not a node"),})
-      }
-    }
-    None => Result::Err(Error{kind: ErrorKind::DynamicAbsent, message: String::from("no context item"),})
+  // 1 argument, and optionally the encoding
+  if args.len() != 1 && args.len() != 2 {
+    return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
   }
+  if args[0].is_empty() {
+    return Ok(vec![])
+  }
+  // Lines are separated by CR, LF or CRLF. A final line separator does not start a new line.
+  let t = unparsed_text(e, &args, f)?.replace("\r\n", "\n").replace('\r', "\n");
+  let t = t.strip_suffix('\n').unwrap_or(t.as_str());
+  if t.is_empty() {
+    return Ok(vec![])
+  }
+  Ok(t.split('\n')
+    .map(|l| Rc::new(Item::Value(Value::from(l))))
+    .collect())
 }
 
-// TODO: implement string value properly
-pub fn func_string(
-    _e: &Evaluator,
+pub fn func_unparsed_text_available(
+    e: &Evaluator,
     _ctxt: Option<Sequence>,
     _posn: Option<usize>,
     args: Vec<Sequence>,
@@ -2831,57 +6681,71 @@ pub fn func_string(
     _sd: TreeIndex,
     _rd: TreeIndex,
 ) -> Result<Sequence, Error> {
-  match args.len() {
-    1 => {
-      // return string value
-      Ok(vec![Rc::new(Item::Value(Value::String(args[0].to_string(Some(f)))))])
+  // 1 argument, and optionally the encoding
+  if args.len() != 1 && args.len() != 2 {
+    return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+  let r = !args[0].is_empty() && unparsed_text(e, &args, f).is_ok();
+  Ok(vec![Rc::new(Item::Value(Value::Boolean(r)))])
+}
+
+// The map argument of a function
+fn map_arg(args: &[Sequence], i: usize) -> Result<&Map, Error> {
+  match args.get(i).map(|a| a.as_slice()) {
+    Some([m]) => match &**m {
+      Item::Map(n) => Ok(n),
+      _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("argument is not a map"),}),
     }
-    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("argument is not a single map"),}),
   }
 }
 
-pub fn func_concat(
+// The array argument of a function
+fn array_arg(args: &[Sequence], i: usize) -> Result<&Array, Error> {
+  match args.get(i).map(|a| a.as_slice()) {
+    Some([m]) => match &**m {
+      Item::Array(n) => Ok(n),
+      _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("argument is not an array"),}),
+    }
+    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("argument is not a single array"),}),
+  }
+}
+
+// A single atomic value argument, such as a map key
+fn key_arg(args: &[Sequence], i: usize, f: &Forest) -> Result<Value, Error> {
+  match args.get(i).map(|a| a.as_slice()) {
+    Some([k]) => atomize(k, f),
+    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("argument is not a single value"),}),
+  }
+}
+
+pub fn func_map_size(
     _e: &Evaluator,
     _ctxt: Option<Sequence>,
     _posn: Option<usize>,
     args: Vec<Sequence>,
-    f: &mut Forest,
+    _f: &mut Forest,
     _sd: TreeIndex,
     _rd: TreeIndex,
 ) -> Result<Sequence, Error> {
-  Ok(vec![Rc::new(Item::Value(Value::String(
-    args.iter().fold(
-      String::new(),
-      |mut a, b| {
-        a.push_str(b.to_string(Some(f)).as_str());
-	a
-      }
-    )
-  )))])
+  let m = map_arg(&args, 0)?;
+  Ok(vec![Rc::new(Item::Value(Value::Integer(m.len() as i64)))])
 }
 
-pub fn func_startswith(
+pub fn func_map_keys(
     _e: &Evaluator,
     _ctxt: Option<Sequence>,
     _posn: Option<usize>,
     args: Vec<Sequence>,
-    f: &mut Forest,
+    _f: &mut Forest,
     _sd: TreeIndex,
     _rd: TreeIndex,
 ) -> Result<Sequence, Error> {
-  // must have exactly 2 arguments
-  if args.len() == 2 {
-     // arg[0] is the string to search
-     // arg[1] is what to search for
-     Ok(vec![Rc::new(Item::Value(Value::Boolean(
-       args[0].to_string(Some(f)).starts_with(args[1].to_string(Some(f)).as_str())
-    )))])
-  } else {
-    Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
-  }
+  let m = map_arg(&args, 0)?;
+  Ok(m.keys().into_iter().map(|k| Rc::new(Item::Value(k))).collect())
 }
 
-pub fn func_contains(
+pub fn func_map_contains(
     _e: &Evaluator,
     _ctxt: Option<Sequence>,
     _posn: Option<usize>,
@@ -2890,19 +6754,15 @@ pub fn func_contains(
     _sd: TreeIndex,
     _rd: TreeIndex,
 ) -> Result<Sequence, Error> {
-  // must have exactly 2 arguments
-  if args.len() == 2 {
-     // arg[0] is the string to search
-     // arg[1] is what to search for
-     Ok(vec![Rc::new(Item::Value(Value::Boolean(
-       args[0].to_string(Some(f)).contains(args[1].to_string(Some(f)).as_str())
-    )))])
-  } else {
-    Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  if args.len() != 2 {
+    return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
   }
+  let m = map_arg(&args, 0)?;
+  let k = key_arg(&args, 1, f)?;
+  Ok(vec![Rc::new(Item::Value(Value::Boolean(m.contains(&k))))])
 }
 
-pub fn func_substring(
+pub fn func_map_get(
     _e: &Evaluator,
     _ctxt: Option<Sequence>,
     _posn: Option<usize>,
@@ -2911,29 +6771,15 @@ pub fn func_substring(
     _sd: TreeIndex,
     _rd: TreeIndex,
 ) -> Result<Sequence, Error> {
-  // must have 2 or 3 arguments
-  match args.len() {
-    2 => {
-     // arg[0] is the string to search
-     // arg[1] is the index to start at
-     // 2-argument version takes the rest of the string
-     Ok(vec![Rc::new(Item::Value(Value::String(
-       args[0].to_string(Some(f)).graphemes(true).skip(args[1].to_int()? as usize - 1).collect()
-     )))])
-    }
-    3 => {
-     // arg[0] is the string to search
-     // arg[1] is the index to start at
-     // arg[2] is the length of the substring to extract
-     Ok(vec![Rc::new(Item::Value(Value::String(
-       args[0].to_string(Some(f)).graphemes(true).skip(args[1].to_int()? as usize - 1).take(args[2].to_int()? as usize).collect()
-     )))])
-    }
-    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  if args.len() != 2 {
+    return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
   }
+  let m = map_arg(&args, 0)?;
+  let k = key_arg(&args, 1, f)?;
+  Ok(m.get(&k).cloned().unwrap_or_default())
 }
 
-pub fn func_substringbefore(
+pub fn func_map_entry(
     _e: &Evaluator,
     _ctxt: Option<Sequence>,
     _posn: Option<usize>,
@@ -2942,35 +6788,15 @@ pub fn func_substringbefore(
     _sd: TreeIndex,
     _rd: TreeIndex,
 ) -> Result<Sequence, Error> {
-  // must have 2 arguments
-  match args.len() {
-    2 => {
-     // arg[0] is the string to search
-     // arg[1] is the string to find
-     match args[0].to_string(Some(f)).find(args[1].to_string(Some(f)).as_str()) {
-       Some(i) => {
-         match args[0].to_string(Some(f)).get(0..i) {
-	   Some(s) => {
-     	     Ok(vec![Rc::new(Item::Value(Value::String(
-	       String::from(s)
-     	     )))])
-	   }
-	   None => {
-	     // This shouldn't happen!
-	     Result::Err(Error{kind: ErrorKind::Unknown, message: String::from("unable to extract substring"),})
-	   }
-	 }
-       }
-       None => {
-         Ok(vec![])
-       }
-     }
-    }
-    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  if args.len() != 2 {
+    return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
   }
+  let mut m = Map::new();
+  m.insert(key_arg(&args, 0, f)?, args[1].clone());
+  Ok(vec![Rc::new(Item::Map(m))])
 }
 
-pub fn func_substringafter(
+pub fn func_map_merge(
     _e: &Evaluator,
     _ctxt: Option<Sequence>,
     _posn: Option<usize>,
@@ -2979,78 +6805,124 @@ pub fn func_substringafter(
     _sd: TreeIndex,
     _rd: TreeIndex,
 ) -> Result<Sequence, Error> {
-  // must have 2 arguments
-  match args.len() {
-    2 => {
-     // arg[0] is the string to search
-     // arg[1] is the string to find
-     match args[0].to_string(Some(f)).find(args[1].to_string(Some(f)).as_str()) {
-       Some(i) => {
-         match args[0].to_string(Some(f)).get(i + args[1].to_string(Some(f)).len()..args[0].to_string(Some(f)).len()) {
-	   Some(s) => {
-     	     Ok(vec![Rc::new(Item::Value(Value::String(
-	       String::from(s)
-     	     )))])
-	   }
-	   None => {
-	     // This shouldn't happen!
-	     Result::Err(Error{kind: ErrorKind::Unknown, message: String::from("unable to extract substring"),})
-	   }
-	 }
-       }
-       None => {
-         Ok(vec![])
-       }
-     }
+  // A sequence of maps, and optionally an options map.
+  // The only option is "duplicates", which is one of "use-first" (the default), "use-last", "use-any", "combine" or "reject".
+  if args.len() != 1 && args.len() != 2 {
+    return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+  let duplicates = if args.len() == 2 {
+    map_arg(&args, 1)?
+      .get(&Value::from("duplicates"))
+      .map_or(String::from("use-first"), |d| d.to_string(Some(f)))
+  } else {
+    String::from("use-first")
+  };
+  let mut result = Map::new();
+  for i in &args[0] {
+    match &**i {
+      Item::Map(m) => {
+	for (k, v) in m.iter() {
+	  match (result.get(k), duplicates.as_str()) {
+	    (None, _) |
+	    (Some(_), "use-last") => result.insert(k.clone(), v.clone()),
+	    (Some(_), "use-first") |
+	    (Some(_), "use-any") => {}
+	    (Some(w), "combine") => {
+	      let mut c = w.clone();
+	      c.extend(v.iter().cloned());
+	      result.insert(k.clone(), c)
+	    }
+	    (Some(_), "reject") => return Result::Err(Error{kind: ErrorKind::JsonDuplicateKeys, message: format!("duplicate key \"{}\"", k.to_string()),}),
+	    (Some(_), d) => return Result::Err(Error{kind: ErrorKind::JsonOption, message: format!("invalid value \"{}\" for the duplicates option", d),}),
+	  }
+	}
+      }
+      _ => return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("argument is not a sequence of maps"),}),
     }
-    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
   }
+  Ok(vec![Rc::new(Item::Map(result))])
 }
 
-pub fn func_normalizespace(
+pub fn func_array_size(
     _e: &Evaluator,
-    ctxt: Option<Sequence>,
-    posn: Option<usize>,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
     args: Vec<Sequence>,
-    f: &mut Forest,
+    _f: &mut Forest,
     _sd: TreeIndex,
     _rd: TreeIndex,
 ) -> Result<Sequence, Error> {
-  // must have 0 or 1 arguments
-  let s: Result<Option<String>, Error> = match args.len() {
-    0 => {
-      // Use the current item
-      match ctxt {
-        Some(c) => {
-	  Ok(Some(c[posn.unwrap()].to_string(Some(f))))
-	}
-	None => Ok(None)
-      }
-    }
-    1 => {
-      Ok(Some(args[0].to_string(Some(f))))
-    }
-    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
-  };
+  let a = array_arg(&args, 0)?;
+  Ok(vec![Rc::new(Item::Value(Value::Integer(a.len() as i64)))])
+}
 
-  match s {
-    Ok(u) => {
-      match u {
-        Some(t) => {
-          Ok(vec![Rc::new(Item::Value(Value::String(
-            t.split_whitespace().collect()
-          )))])
-        }
-        None => Result::Err(Error{kind: ErrorKind::DynamicAbsent, message: String::from("no context item"),})
+pub fn func_array_get(
+    _e: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  if args.len() != 2 {
+    return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+  let a = array_arg(&args, 0)?;
+  let i = key_arg(&args, 1, f)?;
+  Ok(array_member(a, &i)?.clone())
+}
+
+// The options map of the JSON functions.
+// The options are "liberal", "duplicates" and "escape". The "fallback" option requires function items, which are not supported.
+fn json_options(args: &[Sequence], i: usize, f: &Forest, duplicates: Duplicates) -> Result<ParseOptions, Error> {
+  let mut o = ParseOptions::new(duplicates);
+  if args.get(i).is_none_or(|a| a.is_empty()) {
+    return Ok(o)
+  }
+  let m = map_arg(args, i)?;
+  let flag = |name: &str| -> Result<Option<bool>, Error> {
+    match m.get(&Value::from(name)).map(|v| v.as_slice()) {
+      None => Ok(None),
+      Some([b]) => match &**b {
+	Item::Value(Value::Boolean(c)) => Ok(Some(*c)),
+	_ => Result::Err(Error{kind: ErrorKind::TypeError, message: format!("the {} option must be a boolean", name),}),
       }
+      Some(_) => Result::Err(Error{kind: ErrorKind::TypeError, message: format!("the {} option must be a single boolean", name),}),
     }
-    Result::Err(e) => {
-      Result::Err(e)
+  };
+  if let Some(b) = flag("liberal")? {
+    o.liberal = b
+  }
+  if let Some(b) = flag("escape")? {
+    o.escape = b
+  }
+  if let Some(d) = m.get(&Value::from("duplicates")) {
+    o.duplicates = match d.to_string(Some(f)).as_str() {
+      "reject" => Duplicates::Reject,
+      "use-first" => Duplicates::UseFirst,
+      "use-last" => Duplicates::UseLast,
+      "retain" => Duplicates::Retain,
+      v => return Result::Err(Error{kind: ErrorKind::JsonOption, message: format!("invalid value \"{}\" for the duplicates option", v),}),
     }
   }
+  // The fallback option is a function, and function items are not supported
+  if m.contains(&Value::from("fallback")) {
+    return Result::Err(Error{kind: ErrorKind::NotImplemented, message: String::from("the fallback option is not supported"),})
+  }
+  Ok(o)
 }
 
-pub fn func_translate(
+// Parse JSON text into maps and arrays
+fn parse_json(s: &str, args: &[Sequence], f: &Forest) -> Result<Sequence, Error> {
+  let o = json_options(args, 1, f, Duplicates::UseFirst)?;
+  if o.duplicates == Duplicates::Retain {
+    return Result::Err(Error{kind: ErrorKind::JsonOption, message: String::from("the duplicates option \"retain\" is not allowed"),})
+  }
+  Ok(json::parse(s, &o)?.to_sequence())
+}
+
+pub fn func_parse_json(
     _e: &Evaluator,
     _ctxt: Option<Sequence>,
     _posn: Option<usize>,
@@ -3059,271 +6931,349 @@ pub fn func_translate(
     _sd: TreeIndex,
     _rd: TreeIndex,
 ) -> Result<Sequence, Error> {
-  // must have 3 arguments
-  match args.len() {
-    3 => {
-      // arg[0] is the string to search
-      // arg[1] is the map chars
-      // arg[2] is the translate chars
-      let o = args[1].to_string(Some(f));
-      let m: Vec<&str> = o.graphemes(true).collect();
-      let u = args[2].to_string(Some(f));
-      let t: Vec<&str> = u.graphemes(true).collect();
-      let mut result: String = String::new();
-
-      for c in args[0].to_string(Some(f)).graphemes(true) {
-	let mut a: Option<Option<usize>> = Some(None);
-        for i in 0..m.len() {
-	  if c == m[i] {
-	    if i < t.len() {
-	      a = Some(Some(i));
-	      break
-            } else {
-              // omit this character
-	      a = None
-            }
-	  } else {
-	    // keep looking for a match
-	  }
-        }
-	match a {
-	  Some(None) => {
-	    result.push_str(c);
-	  }
-	  Some(Some(j)) => {
-	    result.push_str(t[j])
-	  }
-	  None => {
-	    // omit char
-	  }
-	}
-      }
-      Ok(vec![Rc::new(Item::Value(Value::String(result)))])
-    }
-    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  // JSON text, and optionally an options map
+  if args.len() != 1 && args.len() != 2 {
+    return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
   }
+  if args[0].is_empty() {
+    return Ok(vec![])
+  }
+  parse_json(args[0].to_string(Some(f)).as_str(), &args, f)
 }
 
-pub fn func_boolean(
-    _: &Evaluator,
+pub fn func_json_doc(
+    e: &Evaluator,
     _ctxt: Option<Sequence>,
     _posn: Option<usize>,
     args: Vec<Sequence>,
-    _f: &mut Forest,
+    f: &mut Forest,
     _sd: TreeIndex,
     _rd: TreeIndex,
 ) -> Result<Sequence, Error> {
-  // must have 1 arguments
-  match args.len() {
-    1 => {
-      Ok(vec![Rc::new(Item::Value(Value::Boolean(args[0].to_bool())))])
-    }
-    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  // The URI of a JSON resource, and optionally an options map
+  if args.len() != 1 && args.len() != 2 {
+    return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
   }
+  if args[0].is_empty() {
+    return Ok(vec![])
+  }
+  let s = unparsed_text(e, &args[..1], f)?;
+  parse_json(s.as_str(), &args, f)
 }
 
-pub fn func_not(
-    _: &Evaluator,
+pub fn func_json_to_xml(
+    _e: &Evaluator,
     _ctxt: Option<Sequence>,
     _posn: Option<usize>,
     args: Vec<Sequence>,
-    _f: &mut Forest,
+    f: &mut Forest,
     _sd: TreeIndex,
     _rd: TreeIndex,
 ) -> Result<Sequence, Error> {
-  // must have 1 arguments
-  match args.len() {
-    1 => {
-      Ok(vec![Rc::new(Item::Value(Value::Boolean(!args[0].to_bool())))])
-    }
-    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  // JSON text, and optionally an options map
+  if args.len() != 1 && args.len() != 2 {
+    return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
   }
+  if args[0].is_empty() {
+    return Ok(vec![])
+  }
+  let o = json_options(&args, 1, f, Duplicates::Retain)?;
+  if o.duplicates == Duplicates::UseLast {
+    return Result::Err(Error{kind: ErrorKind::JsonOption, message: String::from("the duplicates option \"use-last\" is not allowed"),})
+  }
+  let j = json::parse(args[0].to_string(Some(f)).as_str(), &o)?;
+  Ok(vec![Rc::new(Item::Node(j.to_xml(f, o.escape)?))])
 }
 
-pub fn func_true(
-    _: &Evaluator,
+pub fn func_xml_to_json(
+    _e: &Evaluator,
     _ctxt: Option<Sequence>,
     _posn: Option<usize>,
     args: Vec<Sequence>,
-    _f: &mut Forest,
+    f: &mut Forest,
     _sd: TreeIndex,
     _rd: TreeIndex,
 ) -> Result<Sequence, Error> {
-  // must have 0 arguments
-  match args.len() {
-    0 => {
-      Ok(vec![Rc::new(Item::Value(Value::Boolean(true)))])
+  // A node, and optionally an options map.
+  // The only option is "indent".
+  if args.len() != 1 && args.len() != 2 {
+    return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+  let indent = if args.len() == 2 {
+    map_arg(&args, 1)?
+      .get(&Value::from("indent"))
+      .is_some_and(|i| i.to_bool())
+  } else {
+    false
+  };
+  match args[0].as_slice() {
+    [] => Ok(vec![]),
+    [n] => match &**n {
+      Item::Node(m) => Ok(vec![Rc::new(Item::Value(Value::String(json::xml_to_json(m, f, indent)?)))]),
+      _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("argument is not a node"),}),
     }
-    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("argument is not a single node"),}),
   }
 }
 
-pub fn func_false(
-    _: &Evaluator,
+// The serialization parameters of fn:serialize, given as a map or as an output:serialization-parameters element.
+// The supported parameters are "method" ("xml", "text" or "json") and "indent". Other parameters are ignored.
+fn serialization_parameters(args: &[Sequence], i: usize, f: &Forest) -> Result<OutputDefinition, Error> {
+  let mut od = OutputDefinition::new();
+  let params: Vec<(String, String)> = match args.get(i).map(|a| a.as_slice()) {
+    None | Some([]) => vec![],
+    Some([p]) => match &**p {
+      Item::Map(m) => m.iter()
+	.map(|(k, v)| (k.to_string(), v.to_string(Some(f))))
+	.collect(),
+      Item::Node(n) if n.node_type(f) == NodeType::Element => {
+	let name = n.to_name(f);
+	if name.get_nsuri_ref() != Some(SERNS) || name.get_localname() != "serialization-parameters" {
+	  return Result::Err(Error{kind: ErrorKind::SerializeParameters, message: String::from("parameters must be an output:serialization-parameters element"),})
+	}
+	let mut result = vec![];
+	let mut cit = n.child_iter();
+	while let Some(c) = cit.next(f) {
+	  if c.node_type(f) == NodeType::Element && c.to_name(f).get_nsuri_ref() == Some(SERNS) {
+	    let v = c.get_attribute(f, &QualifiedName::new(None, None, String::from("value")))
+	      .ok_or_else(|| Error{kind: ErrorKind::SerializeParameters, message: format!("parameter \"{}\" does not have a value", c.to_name(f).get_localname()),})?;
+	    result.push((c.to_name(f).get_localname(), v.to_string(f).trim().to_string()))
+	  }
+	}
+	result
+      }
+      _ => return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("parameters must be a map or an element"),}),
+    }
+    _ => return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("parameters must be a single map or element"),}),
+  };
+  // An invalid value is a serialization error, rather than an invalid attribute value
+  let invalid = |e: Error, k: &str, v: &str| match e.kind {
+    ErrorKind::InvalidAttributeValue => Error{kind: ErrorKind::SerializeParameterValue, message: format!("invalid value \"{}\" for the {} parameter", v, k),},
+    _ => e,
+  };
+  for (k, v) in params {
+    match k.as_str() {
+      "method" => od.set_method(OutputMethod::try_from(v.as_str()).map_err(|e| invalid(e, &k, &v))?),
+      "indent" => od.set_indent(yes_no(&v, "indent").map_err(|e| invalid(e, &k, &v))?),
+      _ => {}
+    }
+  }
+  Ok(od)
+}
+
+pub fn func_serialize(
+    _e: &Evaluator,
     _ctxt: Option<Sequence>,
     _posn: Option<usize>,
     args: Vec<Sequence>,
-    _f: &mut Forest,
+    f: &mut Forest,
     _sd: TreeIndex,
     _rd: TreeIndex,
 ) -> Result<Sequence, Error> {
-  // must have 0 arguments
-  match args.len() {
-    0 => {
-      Ok(vec![Rc::new(Item::Value(Value::Boolean(false)))])
+  // A sequence, and optionally the serialization parameters
+  if args.len() != 1 && args.len() != 2 {
+    return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+  let od = serialization_parameters(&args, 1, f)?;
+  if od.get_method() == OutputMethod::Json {
+    return Ok(vec![Rc::new(Item::Value(Value::String(args[0].to_json(&od, Some(f))?)))])
+  }
+  // Sequence normalisation: adjacent atomic values are separated by a space
+  let mut result = String::new();
+  let mut atomic = false;
+  for i in &args[0] {
+    match &**i {
+      Item::Value(v) => {
+	if atomic {
+	  result.push(' ')
+	}
+	result.push_str(v.to_string().as_str());
+	atomic = true
+      }
+      Item::Node(n) if n.node_type(f) != NodeType::Attribute => {
+	match od.get_method() {
+	  OutputMethod::Text => result.push_str(n.to_string(f).as_str()),
+	  _ => result.push_str(n.to_xml_with_options(f, &od).as_str()),
+	}
+	atomic = false
+      }
+      _ => return Result::Err(Error{kind: ErrorKind::SerializeNotAllowed, message: format!("unable to serialize an item of type {}", i.item_type()),}),
     }
-    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
   }
+  Ok(vec![Rc::new(Item::Value(Value::String(result)))])
 }
 
-pub fn func_number(
-    _: &Evaluator,
+pub fn func_parse_xml(
+    e: &Evaluator,
     _ctxt: Option<Sequence>,
     _posn: Option<usize>,
     args: Vec<Sequence>,
-    _f: &mut Forest,
+    f: &mut Forest,
     _sd: TreeIndex,
     _rd: TreeIndex,
 ) -> Result<Sequence, Error> {
   // must have 1 argument
-  match args.len() {
-    1 => {
-      match args[0].len() {
-        1 => {
-	  // TODO: if the item is already an integer, then just clone it
-      	  // First try converting to an integer
-	  match args[0][0].to_int() {
-	    Ok(i) => {
-      	      Ok(vec![Rc::new(Item::Value(Value::Integer(i)))])
-	    }
-	    Result::Err(_) => {
-      	      // If that fails, convert to double
-	      // NB. this can't fail. At worst it returns NaN
-      	      Ok(vec![Rc::new(Item::Value(Value::Double(args[0][0].to_double())))])
-	    }
-	  }
-	}
-	_ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("not a singleton sequence"),})
-      }
-    }
-    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  if args.len() != 1 {
+    return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+  if args[0].is_empty() {
+    return Ok(vec![])
   }
+  // Whitespace following the document element is insignificant
+  let t = f.grow_tree(args[0].to_string(Some(f)).trim_end())
+    .map_err(|er| Error{kind: ErrorKind::ParseXml, message: er.message,})?;
+  f.get_ref_mut(t).unwrap().set_base_uri(e.baseurl().map(|u| u.to_string()));
+  Ok(vec![Rc::new(Item::Node(f.get_ref(t).unwrap().get_doc_node()))])
 }
 
-pub fn func_sum(
-    _: &Evaluator,
+pub fn func_parse_xml_fragment(
+    e: &Evaluator,
     _ctxt: Option<Sequence>,
     _posn: Option<usize>,
     args: Vec<Sequence>,
-    _f: &mut Forest,
+    f: &mut Forest,
     _sd: TreeIndex,
     _rd: TreeIndex,
 ) -> Result<Sequence, Error> {
   // must have 1 argument
-  match args.len() {
-    1 => {
-      Ok(vec![Rc::new(Item::Value(Value::Double(args[0].iter().fold(0.0, |mut acc, i| {acc += i.to_double(); acc}))))])
+  if args.len() != 1 {
+    return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+  if args[0].is_empty() {
+    return Ok(vec![])
+  }
+  let t = f.grow_fragment(args[0].to_string(Some(f)).as_str())
+    .map_err(|er| Error{kind: ErrorKind::ParseXml, message: er.message,})?;
+  f.get_ref_mut(t).unwrap().set_base_uri(e.baseurl().map(|u| u.to_string()));
+  Ok(vec![Rc::new(Item::Node(f.get_ref(t).unwrap().get_doc_node()))])
+}
+
+// Percent-encode the characters of a string that are not to be kept. Characters are encoded as UTF-8.
+fn percent_encode(s: &str, keep: fn(char) -> bool) -> String {
+  let mut result = String::new();
+  for c in s.chars() {
+    if keep(c) {
+      result.push(c)
+    } else {
+      let mut b = [0; 4];
+      c.encode_utf8(&mut b).bytes().for_each(|d| result.push_str(format!("%{:02X}", d).as_str()))
     }
-    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
   }
+  result
 }
 
-pub fn func_floor(
-    _: &Evaluator,
+// The single string argument of the URI escaping functions. The empty sequence is the zero-length string.
+fn uri_escape(args: &[Sequence], f: &Forest, keep: fn(char) -> bool) -> Result<Sequence, Error> {
+  // must have 1 argument
+  if args.len() != 1 {
+    return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+  Ok(vec![Rc::new(Item::Value(Value::String(percent_encode(args[0].to_string(Some(f)).as_str(), keep))))])
+}
+
+pub fn func_encode_for_uri(
+    _e: &Evaluator,
     _ctxt: Option<Sequence>,
     _posn: Option<usize>,
     args: Vec<Sequence>,
-    _f: &mut Forest,
+    f: &mut Forest,
     _sd: TreeIndex,
     _rd: TreeIndex,
 ) -> Result<Sequence, Error> {
-  // must have 1 argument which is a singleton
-  match args.len() {
-    1 => {
-      match args[0].len() {
-        1 => {
-      	  Ok(vec![Rc::new(Item::Value(Value::Double(args[0][0].to_double().floor())))])
-	}
-	_ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("not a singleton sequence"),})
-      }
-    }
-    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
-  }
+  // Only the unreserved characters of RFC 3986 are not escaped
+  uri_escape(&args, f, |c| c.is_ascii_alphanumeric() || "-_.~".contains(c))
 }
 
-pub fn func_ceiling(
-    _: &Evaluator,
+pub fn func_iri_to_uri(
+    _e: &Evaluator,
     _ctxt: Option<Sequence>,
     _posn: Option<usize>,
     args: Vec<Sequence>,
-    _f: &mut Forest,
+    f: &mut Forest,
     _sd: TreeIndex,
     _rd: TreeIndex,
 ) -> Result<Sequence, Error> {
-  // must have 1 argument which is a singleton
-  match args.len() {
-    1 => {
-      match args[0].len() {
-        1 => {
-      	  Ok(vec![Rc::new(Item::Value(Value::Double(args[0][0].to_double().ceil())))])
-	}
-	_ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("not a singleton sequence"),})
-      }
-    }
-    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
-  }
+  // Characters that are not allowed in a URI are escaped
+  uri_escape(&args, f, |c| ('\u{21}'..='\u{7E}').contains(&c) && !"<>\"{}|\\^`".contains(c))
 }
 
-pub fn func_round(
-    _: &Evaluator,
+pub fn func_escape_html_uri(
+    _e: &Evaluator,
     _ctxt: Option<Sequence>,
     _posn: Option<usize>,
     args: Vec<Sequence>,
-    _f: &mut Forest,
+    f: &mut Forest,
     _sd: TreeIndex,
     _rd: TreeIndex,
 ) -> Result<Sequence, Error> {
-  // must have 1 or 2 arguments
-  match args.len() {
-    1 => {
-      // precision is 0 (i.e. round to nearest whole number
-      match args[0].len() {
-        1 => {
-      	  Ok(vec![Rc::new(Item::Value(Value::Double(args[0][0].to_double().round())))])
-	}
-	_ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("not a singleton sequence"),})
-      }
-    }
-    2 => {
-      match (args[0].len(), args[1].len()) {
-        (1, 1) => {
-      	  Ok(vec![Rc::new(Item::Value(Value::Double(args[0][0].to_double().powi(args[1][0].to_int().unwrap() as i32).round().powi(-1 * args[1][0].to_int().unwrap() as i32))))])
-	}
-	_ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("not a singleton sequence"),})
-      }
-    }
-    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
-  }
+  // Only printable ASCII characters are not escaped
+  uri_escape(&args, f, |c| (' '..='~').contains(&c))
 }
 
-pub fn func_current_date_time(
-    _: &Evaluator,
+pub fn func_resolve_uri(
+    e: &Evaluator,
     _ctxt: Option<Sequence>,
     _posn: Option<usize>,
-    _args: Vec<Sequence>,
-    _f: &mut Forest,
+    args: Vec<Sequence>,
+    f: &mut Forest,
     _sd: TreeIndex,
     _rd: TreeIndex,
 ) -> Result<Sequence, Error> {
-  // must have 0 arguments
-  // TODO: check number of arguments
-  // TODO: do the check in static analysis phase
+  // A relative URI reference, and optionally the base URI. The default base URI is the static base URI.
+  if args.len() != 1 && args.len() != 2 {
+    return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+  if args[0].is_empty() {
+    return Ok(vec![])
+  }
+  let relative = args[0].to_string(Some(f));
+  // An absolute URI is returned unchanged
+  if Url::parse(relative.as_str()).is_ok() {
+    return Ok(vec![Rc::new(Item::Value(Value::AnyURI(relative)))])
+  }
+  let base = match args.get(1) {
+    Some(b) => Url::parse(b.to_string(Some(f)).as_str())
+      .map_err(|_| Error{kind: ErrorKind::InvalidUriArgument, message: format!("\"{}\" is not an absolute URI", b.to_string(Some(f))),})?,
+    None => e.baseurl()
+      .ok_or_else(|| Error{kind: ErrorKind::NoBaseUri, message: String::from("the static base URI is not defined"),})?,
+  };
+  let u = base.join(relative.as_str())
+    .map_err(|_| Error{kind: ErrorKind::InvalidUriArgument, message: format!("unable to resolve \"{}\" against \"{}\"", relative, base),})?;
+  Ok(vec![Rc::new(Item::Value(Value::AnyURI(u.to_string())))])
+}
 
-  Ok(vec![Rc::new(Item::Value(Value::DateTime(Local::now())))])
+pub fn func_base_uri(
+    _e: &Evaluator,
+    ctxt: Option<Sequence>,
+    posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  match node_arg(&ctxt, posn, &args)?.and_then(|n| n.base_uri(f)) {
+    Some(u) => Ok(vec![Rc::new(Item::Value(Value::AnyURI(u)))]),
+    None => Ok(vec![]),
+  }
+}
+
+pub fn func_document_uri(
+    _e: &Evaluator,
+    ctxt: Option<Sequence>,
+    posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  match node_arg(&ctxt, posn, &args)?.and_then(|n| n.document_uri(f)) {
+    Some(u) => Ok(vec![Rc::new(Item::Value(Value::AnyURI(u)))]),
+    None => Ok(vec![]),
+  }
 }
 
-pub fn func_current_date(
-    _: &Evaluator,
+pub fn func_static_base_uri(
+    e: &Evaluator,
     _ctxt: Option<Sequence>,
     _posn: Option<usize>,
     _args: Vec<Sequence>,
@@ -3331,171 +7281,110 @@ pub fn func_current_date(
     _sd: TreeIndex,
     _rd: TreeIndex,
 ) -> Result<Sequence, Error> {
-  // must have 0 arguments
-  // TODO: check number of arguments
-  // TODO: do the check in static analysis phase
-
-  Ok(vec![Rc::new(Item::Value(Value::Date(Local::today())))])
+  match e.baseurl() {
+    Some(u) => Ok(vec![Rc::new(Item::Value(Value::AnyURI(u.to_string())))]),
+    None => Ok(vec![]),
+  }
 }
 
-pub fn func_current_time(
-    _: &Evaluator,
-    _ctxt: Option<Sequence>,
-    _posn: Option<usize>,
-    _args: Vec<Sequence>,
+pub fn func_generate_id(
+    _e: &Evaluator,
+    ctxt: Option<Sequence>,
+    posn: Option<usize>,
+    args: Vec<Sequence>,
     _f: &mut Forest,
     _sd: TreeIndex,
     _rd: TreeIndex,
 ) -> Result<Sequence, Error> {
-  // must have 0 arguments
-  // TODO: check number of arguments
-  // TODO: do the check in static analysis phase
+  let id = node_arg(&ctxt, posn, &args)?.map_or(String::new(), |n| n.generate_id());
+  Ok(vec![Rc::new(Item::Value(Value::String(id)))])
+}
 
-  Ok(vec![Rc::new(Item::Value(Value::Time(Local::now())))])
+// The IDREF values of the first argument of fn:id, fn:element-with-id and fn:idref, and the node that identifies the tree to search (by default, the context item).
+fn id_args(ctxt: &Option<Sequence>, posn: Option<usize>, args: &[Sequence], f: &Forest) -> Result<(Vec<String>, Node), Error> {
+  if args.len() != 1 && args.len() != 2 {
+    return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+  let ids = args[0].iter()
+    .flat_map(|i| i.to_string(Some(f)).split_whitespace().map(String::from).collect::<Vec<String>>())
+    .collect();
+  let n = node_arg(ctxt, posn, &args[1..])?
+    .ok_or_else(|| Error{kind: ErrorKind::TypeError, message: String::from("argument is not a node"),})?;
+  Ok((ids, n))
 }
 
-pub fn func_format_date_time(
+// The elements with the given IDs, in document order without duplicates
+fn elements_with_ids(ctxt: &Option<Sequence>, posn: Option<usize>, args: &[Sequence], f: &Forest) -> Result<Sequence, Error> {
+  let (ids, n) = id_args(ctxt, posn, args, f)?;
+  let mut found: Vec<Node> = ids.iter()
+    .filter_map(|i| n.element_with_id(f, i.as_str()))
+    .collect();
+  found.sort_by(|a, b| a.cmp_document_order(f, b));
+  found.dedup();
+  Ok(found.into_iter()
+    .map(|m| Rc::new(Item::Node(m)))
+    .collect())
+}
+
+pub fn func_id(
     _e: &Evaluator,
-    _ctxt: Option<Sequence>,
-    _posn: Option<usize>,
+    ctxt: Option<Sequence>,
+    posn: Option<usize>,
     args: Vec<Sequence>,
     f: &mut Forest,
     _sd: TreeIndex,
     _rd: TreeIndex,
 ) -> Result<Sequence, Error> {
-  // must have 2 or 5 arguments
-  // TODO: implement 5 argument version
-
-  match args.len() {
-    2 => {
-      // First argument is the dateTime value
-      // Second argument is the picture
-      let pic = match picture_parse(&args[1].to_string(Some(f))) {
-        Ok(p) => p,
-	Err(_) => return Result::Err(Error{kind: ErrorKind::Unknown, message: String::from("bad picture"),})
-      };
-
-      match args[0].len() {
-        0 => Ok(vec![]),	// Empty value returns empty sequence
-        1 => {
-	  match *args[0][0] {
-	    Item::Value(Value::DateTime(dt)) => {
-	      Ok(vec![Rc::new(Item::Value(Value::String(dt.format(&pic).to_string())))])
-	    }
-	    Item::Value(Value::String(ref s)) => {
-	      // Try and coerce into a DateTime value
-	      match DateTime::<FixedOffset>::parse_from_rfc3339(s.as_str()) {
-	        Ok(dt) => {
-	      	  Ok(vec![Rc::new(Item::Value(Value::String(dt.format(&pic).to_string())))])
-		}
-		Err(_) => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("unable to determine date value"),})
-	      }
-	    }
-	    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("not a dateTime value"),})
-	  }
-	}
-	_ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("not a singleton sequence"),}),
-      }
-    }
-    5 => Result::Err(Error{kind: ErrorKind::NotImplemented, message: String::from("not yet implemented"),}),
-    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),}),
-  }
+  elements_with_ids(&ctxt, posn, &args, f)
 }
 
-pub fn func_format_date(
+pub fn func_element_with_id(
     _e: &Evaluator,
-    _ctxt: Option<Sequence>,
-    _posn: Option<usize>,
+    ctxt: Option<Sequence>,
+    posn: Option<usize>,
     args: Vec<Sequence>,
     f: &mut Forest,
     _sd: TreeIndex,
     _rd: TreeIndex,
 ) -> Result<Sequence, Error> {
-  // must have 2 or 5 arguments
-  // TODO: implement 5 argument version
-
-  match args.len() {
-    2 => {
-      // First argument is the date value
-      // Second argument is the picture
-      let pic = match picture_parse(&args[1].to_string(Some(f))) {
-        Ok(p) => p,
-	Err(_) => return Result::Err(Error{kind: ErrorKind::Unknown, message: String::from("bad picture"),})
-      };
-      match args[0].len() {
-        0 => Ok(vec![]),	// Empty value returns empty sequence
-        1 => {
-	  match *args[0][0] {
-	    Item::Value(Value::Date(dt)) => {
-	      Ok(vec![Rc::new(Item::Value(Value::String(dt.format(&pic).to_string())))])
-	    }
-	    Item::Value(Value::String(ref s)) => {
-	      // Try and coerce into a Date value
-	      let a = format!("{}T00:00:00Z", s);
-	      match DateTime::<FixedOffset>::parse_from_rfc3339(a.as_str()) {
-	        Ok(dt) => {
-	      	  Ok(vec![Rc::new(Item::Value(Value::String(dt.date().format(&pic).to_string())))])
-		}
-		Err(_) => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("unable to determine date value"),})
-	      }
-	    }
-	    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("not a date value"),})
-	  }
-	}
-	_ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("not a singleton sequence"),}),
-      }
-    }
-    5 => Result::Err(Error{kind: ErrorKind::NotImplemented, message: String::from("not yet implemented"),}),
-    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),}),
-  }
+  // Without schema-validated element content, this is the same as fn:id
+  elements_with_ids(&ctxt, posn, &args, f)
 }
 
-pub fn func_format_time(
-    _e: &Evaluator,
-    _ctxt: Option<Sequence>,
-    _posn: Option<usize>,
+pub fn func_idref(
+    e: &Evaluator,
+    ctxt: Option<Sequence>,
+    posn: Option<usize>,
     args: Vec<Sequence>,
     f: &mut Forest,
     _sd: TreeIndex,
     _rd: TreeIndex,
 ) -> Result<Sequence, Error> {
-  // must have 2 or 5 arguments
-  // TODO: implement 5 argument version
-
-  match args.len() {
-    2 => {
-      // First argument is the time value
-      // Second argument is the picture
-      let pic = match picture_parse(&args[1].to_string(Some(f))) {
-        Ok(p) => p,
-	Err(_) => return Result::Err(Error{kind: ErrorKind::Unknown, message: String::from("bad picture"),})
-      };
-      match args[0].len() {
-        0 => Ok(vec![]),	// Empty value returns empty sequence
-        1 => {
-	  match *args[0][0] {
-	    Item::Value(Value::Time(dt)) => {
-	      Ok(vec![Rc::new(Item::Value(Value::String(dt.format(&pic).to_string())))])
-	    }
-	    Item::Value(Value::String(ref s)) => {
-	      // Try and coerce into a DateTime value
-	      let a = format!("1900-01-01T{}Z", s);
-	      match DateTime::<FixedOffset>::parse_from_rfc3339(a.as_str()) {
-	        Ok(dt) => {
-	      	  Ok(vec![Rc::new(Item::Value(Value::String(dt.time().format(&pic).to_string())))])
-		}
-		Err(_) => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("unable to determine time value"),})
-	      }
-	    }
-	    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("not a time value"),})
+  let (ids, n) = id_args(&ctxt, posn, &args, f)?;
+  let cached = e.dc.idrefs.borrow().get(&n.tree_index()).cloned();
+  let index = match cached {
+    Some(index) => index,
+    None => {
+      // Build the index. Attributes follow their element in document order.
+      let mut all = vec![];
+      preorder(&n.owner_document(f), f, &mut all);
+      let mut entries = vec![];
+      for m in all {
+	let mut ait = m.attribute_iter(f);
+	while let Some(a) = ait.next() {
+	  for r in a.idrefs(f) {
+	    entries.push((r, a))
 	  }
 	}
-	_ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("not a singleton sequence"),}),
       }
+      let index = Rc::new(KeyIndex::new(entries));
+      e.dc.idrefs.borrow_mut().insert(n.tree_index(), index.clone());
+      index
     }
-    5 => Result::Err(Error{kind: ErrorKind::NotImplemented, message: String::from("not yet implemented"),}),
-    _ => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),}),
-  }
+  };
+  Ok(index.get(&ids).into_iter()
+    .map(|a| Rc::new(Item::Node(a)))
+    .collect())
 }
 
 pub fn func_current_grouping_key(
@@ -3520,6 +7409,26 @@ pub fn func_current_grouping_key(
   }
 }
 
+pub fn func_key(
+    e: &Evaluator,
+    ctxt: Option<Sequence>,
+    posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    sd: TreeIndex,
+    rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  if args.len() != 2 && args.len() != 3 {
+    return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+  // The third argument is the top node; by default, the root of the tree that contains the context item
+  let n = node_arg(&ctxt, posn, &args[2..])?
+    .ok_or_else(|| Error{kind: ErrorKind::TypeError, message: String::from("argument is not a node"),})?;
+  let top = if args.len() == 3 {n} else {n.owner_document(f)};
+  let name = args[0].to_string(Some(f));
+  e.key(name.trim(), &args[1], &top, f, sd, rd)
+}
+
 pub fn func_current_group(
     e: &Evaluator,
     _ctxt: Option<Sequence>,
@@ -3542,6 +7451,50 @@ pub fn func_current_group(
   }
 }
 
+// XSLT document() function
+pub fn func_document(
+    e: &Evaluator,
+    _ctxt: Option<Sequence>,
+    _posn: Option<usize>,
+    args: Vec<Sequence>,
+    f: &mut Forest,
+    _sd: TreeIndex,
+    _rd: TreeIndex,
+) -> Result<Sequence, Error> {
+  // 1 argument, and optionally a node that gives the base URI
+  if args.len() != 1 && args.len() != 2 {
+    return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("wrong number of arguments"),})
+  }
+  // Relative URIs are resolved against the base URI of the $base node if it is given, otherwise against the base URI of the node the URI came from. URIs that are atomic values are resolved against the static base URI.
+  let base = match args.get(1).map(|b| b.as_slice()) {
+    Some([b]) => match &**b {
+      Item::Node(n) => Some(n.base_uri(f)),
+      _ => return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("base argument is not a node"),}),
+    },
+    Some(_) => return Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("base argument must be a single node"),}),
+    None => None,
+  };
+  let mut result: Vec<Node> = vec![];
+  for i in &args[0] {
+    let href = i.to_string(Some(f));
+    let b = match (&base, &**i) {
+      (Some(b), _) => b.clone(),
+      (None, Item::Node(n)) => n.base_uri(f),
+      (None, _) => None,
+    };
+    let u = match b {
+      Some(b) => Url::parse(b.as_str()).and_then(|b| b.join(href.as_str()))
+	.map_err(|_| Error::new(ErrorKind::InvalidDocumentURI, format!("unable to resolve URI \"{}\" against \"{}\"", href, b)))?,
+      None => document_uri(e, href.as_str())?,
+    };
+    let d = e.dc.documents.document(&u, f)?;
+    if !result.contains(&d) {
+      result.push(d)
+    }
+  }
+  Ok(result.into_iter().map(|n| Rc::new(Item::Node(n))).collect())
+}
+
 pub fn format_constructor(c: &Vec<Constructor>, i: usize) -> String {
   let mut result = String::new();
   for v in c {
@@ -3575,6 +7528,49 @@ pub fn format_constructor(c: &Vec<Constructor>, i: usize) -> String {
       Constructor::ContextItem => {
         format!("{:in$} Construct context item", "", in=i)
       }
+      Constructor::Element(_, c) => {
+        format!("{:in$} Construct element with computed name and content:\n{}", "",
+	  format_constructor(c, i + 4),
+	  in=i)
+      }
+      Constructor::Attribute(_, v, _) => {
+        format!("{:in$} Construct attribute with computed name and value:\n{}", "",
+	  format_constructor(v, i + 4),
+	  in=i)
+      }
+      Constructor::Text(v, _) => {
+        format!("{:in$} Construct text node with value:\n{}", "",
+	  format_constructor(v, i + 4),
+	  in=i)
+      }
+      Constructor::Comment(v, _) => {
+        format!("{:in$} Construct comment with value:\n{}", "",
+	  format_constructor(v, i + 4),
+	  in=i)
+      }
+      Constructor::ProcessingInstruction(_, v, _) => {
+        format!("{:in$} Construct processing instruction with value:\n{}", "",
+	  format_constructor(v, i + 4),
+	  in=i)
+      }
+      Constructor::Namespace(_, _) => {
+        format!("{:in$} Construct namespace", "", in=i)
+      }
+      Constructor::ResultDocument(_, c) => {
+        format!("{:in$} Construct result document with content:\n{}", "",
+	  format_constructor(c, i + 4),
+	  in=i)
+      }
+      Constructor::Message(c, _, _) => {
+        format!("{:in$} Send message with content:\n{}", "",
+	  format_constructor(c, i + 4),
+	  in=i)
+      }
+      Constructor::UseAttributeSets(v) => {
+        format!("{:in$} Construct attribute sets {}", "",
+	  v.iter().map(|n| n.to_string()).collect::<Vec<String>>().join(" "),
+	  in=i)
+      }
       Constructor::SetAttribute(qn, v) => {
         format!("{:in$} Construct set attribute named \"{}\":\n{}", "",
 	  qn.get_localname(),
@@ -3628,6 +7624,43 @@ pub fn format_constructor(c: &Vec<Constructor>, i: usize) -> String {
       Constructor::Concat(_v) => {
         format!("{:in$} concat constructor", "", in=i)
       }
+      Constructor::AttributeValueTemplate(v) => {
+        format!("{:in$} attribute value template constructor of {} parts", "", v.len(), in=i)
+      }
+      Constructor::InstanceOf(v, st) => {
+        format!("{:in$} instance of {} constructor:\n{}", "",
+	  st,
+	  format_constructor(v, i + 4),
+	  in=i)
+      }
+      Constructor::MapConstructor(v) => {
+        format!("{:in$} map constructor of {} entries", "", v.len(), in=i)
+      }
+      Constructor::ArrayConstructor(v) => {
+        format!("{:in$} array constructor of {} members", "", v.len(), in=i)
+      }
+      Constructor::CurlyArrayConstructor(v) => {
+        format!("{:in$} array constructor:\n{}", "",
+	  format_constructor(v, i + 4),
+	  in=i)
+      }
+      Constructor::Lookup(v, _) => {
+        format!("{:in$} lookup constructor:\n{}", "",
+	  format_constructor(v, i + 4),
+	  in=i)
+      }
+      Constructor::Cast(v, t, _) => {
+        format!("{:in$} cast as \"{}\" constructor:\n{}", "",
+	  t.to_string(),
+	  format_constructor(v, i + 4),
+	  in=i)
+      }
+      Constructor::Castable(v, t, _) => {
+        format!("{:in$} castable as \"{}\" constructor:\n{}", "",
+	  t.to_string(),
+	  format_constructor(v, i + 4),
+	  in=i)
+      }
       Constructor::Range(_v) => {
         format!("{:in$} range constructor", "", in=i)
       }
@@ -3653,15 +7686,32 @@ pub fn format_constructor(c: &Vec<Constructor>, i: usize) -> String {
       Constructor::Switch(_, _) => {
         format!("{:in$} switch constructor", "", in=i)
       }
-      Constructor::ApplyTemplates(_) => {
-        format!("{:in$} apply-templates constructor", "", in=i)
+      Constructor::ApplyTemplates(_, m, _) => {
+        format!("{:in$} apply-templates constructor, mode {:?}", "", m, in=i)
+      }
+      Constructor::CallTemplate(n, _) => {
+        format!("{:in$} call-template constructor named \"{}\"", "", n.to_string(), in=i)
+      }
+      Constructor::Document(c) => {
+        format!("{:in$} document constructor:\n{}", "",
+	  format_constructor(c, i + 4),
+	  in=i)
       }
       Constructor::ApplyImports => {
         format!("{:in$} apply-imports constructor", "", in=i)
       }
-      Constructor::ForEach(_, _, _) => {
+      Constructor::Sort(s, k) => {
+        format!("{:in$} sort by {} keys:\n{}", "",
+	  k.len(),
+	  format_constructor(s, i + 4),
+	  in=i)
+      }
+      Constructor::ForEach(_, _, _, _) => {
         format!("{:in$} for-each constructor", "", in=i)
       }
+      Constructor::Number(n) => {
+        format!("{:in$} number constructor, level {:?}", "", n.level, in=i)
+      }
       Constructor::NotImplemented(m) => {
         format!("{:in$} NotImplemented constructor: {}", "", m, in=i)
       }
@@ -4697,6 +8747,40 @@ mod tests {
 	    _ => panic!("not a singleton double value")
 	}
     }
+    #[test]
+    fn function_call_format_integer() {
+	let e = Evaluator::new();
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let c = Constructor::FunctionCall(
+            Function::new("format-integer".to_string(), vec![], Some(func_format_integer)),
+	    vec![
+		vec![Constructor::Literal(Value::Integer(1234))],
+		vec![Constructor::Literal(Value::from("#,##0"))],
+            ]
+	);
+	let vc = vec![c];
+	let r = e.evaluate(None, None, &vc, &mut f, sd, rd).expect("evaluation failed");
+	assert_eq!(r.to_string(None), "1,234")
+    }
+    #[test]
+    fn function_call_format_integer_empty() {
+	let e = Evaluator::new();
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let c = Constructor::FunctionCall(
+            Function::new("format-integer".to_string(), vec![], Some(func_format_integer)),
+	    vec![
+		vec![],
+		vec![Constructor::Literal(Value::from("w"))],
+            ]
+	);
+	let vc = vec![c];
+	let r = e.evaluate(None, None, &vc, &mut f, sd, rd).expect("evaluation failed");
+	assert_eq!(r.to_string(None), "")
+    }
 
     // Date/time related functions
 
@@ -5063,6 +9147,77 @@ mod tests {
 	assert_eq!(r.to_xml(Some(&f)), "<Test>data<Level-1>deeper</Level-1></Test>")
     }
 
+    #[test]
+    fn key_index() {
+	let mut f = Forest::new();
+	let t = f.grow_tree("<Test><a/><b/><c/></Test>").expect("unable to parse XML");
+	let top = f.get_ref(t).unwrap().get_doc_node().child_iter().next(&f).expect("no document element");
+	let mut c: Vec<Node> = vec![];
+	let mut children = top.child_iter();
+	while let Some(n) = children.next(&f) {
+	    c.push(n)
+	}
+	let index = KeyIndex::new(vec![
+	    ("x".to_string(), c[0]),
+	    ("y".to_string(), c[0]),
+	    ("y".to_string(), c[1]),
+	    ("x".to_string(), c[2]),
+	]);
+	assert_eq!(index.get(&["x".to_string()]), vec![c[0], c[2]]);
+	assert_eq!(index.get(&["y".to_string(), "x".to_string()]), vec![c[0], c[1], c[2]]);
+	assert!(index.get(&["z".to_string()]).is_empty());
+    }
+
+    #[test]
+    fn idref() {
+	let e = Evaluator::new();
+	let mut f = Forest::new();
+	let t = f.grow_tree("<doc><ref/><ref/></doc>").expect("unable to parse XML");
+	let top = f.get_ref(t).unwrap().get_doc_node().get_first_element(&f).expect("no document element");
+	let mut refs = top.child_iter();
+	let r1 = refs.next(&f).unwrap();
+	let r2 = refs.next(&f).unwrap();
+	let mut attrs = vec![];
+	for (r, v) in vec![(r1, "a b"), (r2, "b")] {
+	    let a = f.get_ref_mut(t).unwrap().new_attribute(QualifiedName::new(None, None, String::from("to")), Value::IDREFS(crate::value::IDREFS::try_from(v).unwrap())).unwrap();
+	    r.add_attribute(&mut f, a).unwrap();
+	    attrs.push(a)
+	}
+	let rd = f.plant_tree();
+	let found = |ids: Vec<&str>, f: &mut Forest| -> Vec<Node> {
+	    let args = vec![ids.into_iter().map(|i| Rc::new(Item::Value(Value::from(i)))).collect()];
+	    func_idref(&e, Some(vec![Rc::new(Item::Node(top))]), Some(0), args, f, t, rd)
+		.expect("idref failed")
+		.iter()
+		.map(|i| match &**i {Item::Node(n) => *n, _ => panic!("not a node")})
+		.collect()
+	};
+	assert_eq!(found(vec!["b", "a"], &mut f), attrs);
+	assert_eq!(found(vec!["b"], &mut f), attrs);
+	assert_eq!(found(vec!["a"], &mut f), vec![attrs[0]]);
+	assert!(found(vec!["c"], &mut f).is_empty());
+    }
+    #[test]
+    fn preorder_deep() {
+	// A deeply nested tree is walked without recursion
+	let mut f = Forest::new();
+	let t = f.plant_tree();
+	let doc = f.get_ref(t).unwrap().get_doc_node();
+	let mut cur = f.get_ref_mut(t).unwrap().new_element(QualifiedName::new(None, None, "e".to_string())).expect("unable to create element");
+	f.get_ref_mut(t).unwrap().push_doc_node(cur).expect("unable to add document element");
+	for _ in 1..100000 {
+	    let e = f.get_ref_mut(t).unwrap().new_element(QualifiedName::new(None, None, "e".to_string())).expect("unable to create element");
+	    cur.append_child(&mut f, e).expect("unable to append child");
+	    cur = e;
+	}
+	let x = f.get_ref_mut(t).unwrap().new_text(Value::from("x")).expect("unable to create text node");
+	cur.append_child(&mut f, x).expect("unable to append child");
+	let mut v = vec![];
+	preorder(&doc, &f, &mut v);
+	assert_eq!(v.len(), 100002);
+	assert_eq!(v.last().map(|n| n.to_string(&f)), Some("x".to_string()))
+    }
+
     // for-each, for-each-group
 
 }
@@ -5,6 +5,7 @@
 //! Both [Forest]s and [Tree]s use an arena allocator, so the object itself is simply an index that may be copied and cloned. However, in order to dererence the [Tree] or [Node] the [Forest] must be passed as an argument. This also makes deallocating memory difficult; the objects will persist until the entire [Forest] is freed.
 
 use std::convert::TryFrom;
+use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::collections::hash_map::Iter;
 use generational_arena::{Arena, Index};
@@ -13,6 +14,12 @@ use crate::output::OutputDefinition;
 use crate::xdmerror::{Error, ErrorKind};
 use crate::value::Value;
 use crate::parsexml::{XMLDocument, XMLNode};
+use crate::collation::{Collation, CodepointCollation};
+use crate::json::escape_string;
+use url::Url;
+
+/// The XML namespace, which is bound to the prefix "xml".
+pub(crate) const XMLNS: &str = "http://www.w3.org/XML/1998/namespace";
 
 /// A Forest. Forests contain [Tree]s. Each [Tree] is identified by a copyable value, similar to a Node value, that can be easily stored and passed as a parameter.
 #[derive(Clone)]
@@ -54,14 +61,23 @@ impl Forest {
 	if d.content.len() == 0 {
 	    Result::Err(Error::new(ErrorKind::Unknown, String::from("unable to parse XML")))
 	} else {
-	    let mut ns: HashMap<String, String> = HashMap::new();
-	    let ti = self.plant_tree();
-	    for c in d.content {
-		let e = make_node(c, self, ti, &mut ns)?;
-		self.get_ref_mut(ti).unwrap().push_doc_node(e)?;
-	    }
-	    Ok(ti)
+	    self.grow_content(d)
+	}
+    }
+    /// Parse a string as an XML fragment to create a [Tree]. The fragment may have any number of top-level elements, and text, comments and processing instructions.
+    pub fn grow_fragment(&mut self, s: &str) -> Result<TreeIndex, Error> {
+	let d = XMLDocument::fragment(s)?;
+	self.grow_content(d)
+    }
+    fn grow_content(&mut self, d: XMLDocument) -> Result<TreeIndex, Error> {
+	let mut ns: HashMap<String, String> = HashMap::new();
+	ns.insert(String::from("xml"), XMLNS.to_string());
+	let ti = self.plant_tree();
+	for c in d.content {
+	    let e = make_node(c, self, ti, &mut ns)?;
+	    self.get_ref_mut(ti).unwrap().push_doc_node(e)?;
 	}
+	Ok(ti)
     }
 }
 
@@ -72,6 +88,9 @@ pub struct Tree {
     i: TreeIndex,	// The index in the Forest
     a: Arena<NodeContent>,
     d: Index,	// The document node
+    base: Option<String>,	// The base URI of the document node
+    uri: Option<String>,	// The document URI
+    ids: HashMap<String, Index>,	// Elements, indexed by their ID
 }
 
 impl Tree
@@ -88,9 +107,34 @@ impl Tree
             i: i,
 	    a: a,
 	    d: d,
+	    base: None,
+	    uri: None,
+	    ids: HashMap::new(),
         }
     }
 
+    /// The base URI of the document node. This is used to resolve relative URIs in the document, unless overridden by xml:base attributes.
+    pub fn get_base_uri(&self) -> Option<String> {
+	self.base.clone()
+    }
+    /// Set the base URI of the document node.
+    pub fn set_base_uri(&mut self, uri: Option<String>) {
+	self.base = uri;
+    }
+    /// The document URI, i.e. the absolute URI from which the document was retrieved.
+    pub fn get_document_uri(&self) -> Option<String> {
+	self.uri.clone()
+    }
+    /// Set the document URI.
+    pub fn set_document_uri(&mut self, uri: Option<String>) {
+	self.uri = uri;
+    }
+    /// Find the element that has the given ID. If more than one element has the ID, then the first one that was given the ID is returned.
+    ///
+    /// An element has an ID if it has an ID attribute: either an xml:id attribute, or an attribute whose value is of type xs:ID.
+    pub fn get_element_by_id(&self, id: &str) -> Option<Node> {
+	self.ids.get(id).map(|i| Node::new(*i, self.i))
+    }
     fn get(&self, i: Index) -> Option<&NodeContent> {
 	self.a.get(i)
     }
@@ -205,6 +249,14 @@ fn make_node(
 			ns.insert(qn.get_localname(), v.to_string()).map(|_| {});
 		    }
 		});
+	    // Remember the declarations made on this element, including the default namespace
+	    let decls: Vec<(Option<String>, String)> = a.iter()
+		.filter_map(|b| match b {
+		    XMLNode::Attribute(qn, v) if qn.get_prefix().as_deref() == Some("xmlns") => Some((Some(qn.get_localname()), v.to_string())),
+		    XMLNode::Attribute(qn, v) if qn.get_prefix().is_none() && qn.get_localname() == "xmlns" => Some((None, v.to_string())),
+		    _ => None,
+		})
+		.collect();
 	    // Add element to the tree
 	    let newns = match m.get_prefix() {
 		Some(p) => {
@@ -224,6 +276,9 @@ fn make_node(
 		    m.get_localname(),
 		)
 	    )?;
+	    for (p, u) in decls {
+		new.declare_namespace(f, p, u)?;
+	    }
 
 	    // Attributes
 	    a.iter()
@@ -365,9 +420,19 @@ impl Node {
 	    None => return String::from(""),
 	};
 	match self.node_type(f) {
+	    NodeType::Document |
 	    NodeType::Element => {
-		// TODO: string value of all descendant text nodes
-		String::new()
+		// The string value of all descendant text nodes
+		let mut result = String::new();
+		let mut cit = self.child_iter();
+		while let Some(c) = cit.next(f) {
+		    match c.node_type(f) {
+			NodeType::Element |
+			NodeType::Text => result.push_str(c.to_string(f).as_str()),
+			_ => {}
+		    }
+		}
+		result
 	    }
 	    NodeType::Text |
 	    NodeType::Attribute |
@@ -438,18 +503,26 @@ impl Node {
 			None => {
 			    // Default namespace
 			    match ns.get(uri.as_str()) {
-				Some(_) => {
+				Some(None) => {
+				    // Already declared
+				}
+				_ => {
 				    ns.insert(uri.clone(), None);
 				    newns.push((None, uri));
 				}
-				None => {
-				    // Already declared
-				}
 			    }
 			}
 		    }
 		}
 
+		// Namespaces that were explicitly declared on the element
+		for (p, u) in &nc.namespaces {
+		    if ns.get(u.as_str()) != Some(p) && !newns.iter().any(|(q, v)| q == p && v == u) {
+			ns.insert(u.clone(), p.clone());
+			newns.push((p.clone(), u.clone()))
+		    }
+		}
+
 		result.push_str(name.to_string().as_str());
 		newns.iter().for_each(|(p, u)| {
 		    result.push_str(" xmlns");
@@ -540,6 +613,14 @@ impl Node {
 		result.push_str("?>");
 		result
 	    }
+	    NodeType::Document => {
+		let mut result = String::new();
+		let mut children = self.child_iter();
+		while let Some(c) = children.next(f) {
+		    result.push_str(c.to_xml_int(f, od, indent, ns).as_str());
+		}
+		result
+	    }
 	    _ => {
 		// TODO
 		String::from("-- not implemented --")
@@ -551,9 +632,13 @@ impl Node {
 	let mut ns: HashMap<String, Option<String>> = HashMap::new();
 	self.to_xml_int(f, od, 2, &mut ns)
     }
-    /// Serialise the node as JSON.
-    pub fn to_json(&self, _f: &Forest) -> String {
-	String::from("not implemented yet")
+    /// Serialise the node as JSON, i.e. as a JSON string containing the XML serialisation of the node.
+    /// Attributes cannot be serialised as JSON (SERE0021).
+    pub fn to_json(&self, f: &Forest) -> Result<String, Error> {
+	if self.node_type(f) == NodeType::Attribute {
+	    return Result::Err(Error::new(ErrorKind::SerializeItem, String::from("unable to serialize an attribute as JSON")))
+	}
+	Ok(format!("\"{}\"", escape_string(self.to_xml(f).as_str())))
     }
 
     /// A convenience method that converts the value to a string and then converts the string to an integer.
@@ -816,8 +901,92 @@ impl Node {
 	// Add a to self's attribute hashmap
 	let qn = d.get(a.0).unwrap().name().as_ref().unwrap().clone();
 	d.get_mut(self.0).unwrap().attributes.insert(qn, a);
+	// Maintain the ID index
+	if a.is_id(f) {
+	    let id = a.to_string(f).trim().to_string();
+	    f.get_ref_mut(self.1).unwrap().ids.entry(id).or_insert(self.0);
+	}
 	Ok(())
     }
+    /// Is this an ID attribute? An attribute is an ID if it is named xml:id, or its value is of type xs:ID.
+    pub fn is_id(&self, f: &Forest) -> bool {
+	self.node_type(f) == NodeType::Attribute &&
+	    (self.to_name(f) == QualifiedName::new(Some(XMLNS.to_string()), None, String::from("id")) ||
+	     matches!(self.get(f).and_then(|nc| nc.value().as_ref()), Some(Value::ID(_))))
+    }
+    /// The IDs that this node refers to. An attribute refers to IDs if its value is of type xs:IDREF or xs:IDREFS.
+    pub fn idrefs(&self, f: &Forest) -> Vec<String> {
+	if self.node_type(f) != NodeType::Attribute {
+	    return vec![]
+	}
+	match self.get(f).and_then(|nc| nc.value().as_ref()) {
+	    Some(Value::IDREF(r)) => vec![r.to_string()],
+	    Some(Value::IDREFS(r)) => r.items().iter().map(|i| i.to_string()).collect(),
+	    _ => vec![],
+	}
+    }
+    /// The index of the [Tree] that contains this node.
+    pub fn tree_index(&self) -> TreeIndex {
+	self.1
+    }
+    /// Generate an identifier for this node. The identifier is unique within the [Forest], is the same each time it is generated for the same node, and is an NCName.
+    ///
+    /// The identifier includes the generation of the node's slot in the arena, so a node that reuses the slot of a removed node has a different identifier.
+    pub fn generate_id(&self) -> String {
+	let (i, g) = self.0.into_raw_parts();
+	format!("t{}n{}g{}", self.1, i, g)
+    }
+    /// Compare the positions of this node and another node in document order. An element's attributes follow the element and precede its children; the order of the attributes of an element is stable, but implementation-dependent. Nodes in different [Tree]s are ordered by their tree.
+    ///
+    /// Only the ancestors of the two nodes, and the children of their closest common ancestor, are examined.
+    pub fn cmp_document_order(&self, f: &Forest, other: &Node) -> Ordering {
+	if self.1 != other.1 {
+	    return self.1.cmp(&other.1)
+	}
+	if self == other {
+	    return Ordering::Equal
+	}
+	// The paths from the document node to each node
+	let path = |n: &Node| {
+	    let mut p = vec![*n];
+	    let mut it = n.ancestor_iter();
+	    while let Some(a) = it.next(f) {
+		p.push(a)
+	    }
+	    if n.node_type(f) != NodeType::Document {
+		p.push(n.owner_document(f))
+	    }
+	    p.reverse();
+	    p
+	};
+	let (p, q) = (path(self), path(other));
+	let d = p.iter().zip(q.iter()).take_while(|(a, b)| a == b).count();
+	if d == p.len() {
+	    // This node is an ancestor of the other node
+	    return Ordering::Less
+	} else if d == q.len() {
+	    return Ordering::Greater
+	}
+	let (a, b) = (p[d], q[d]);
+	match (a.node_type(f) == NodeType::Attribute, b.node_type(f) == NodeType::Attribute) {
+	    (true, false) => Ordering::Less,
+	    (false, true) => Ordering::Greater,
+	    (true, true) => a.0.into_raw_parts().cmp(&b.0.into_raw_parts()),
+	    (false, false) => {
+		// Siblings. Nodes that are not attached to the tree are ordered by their position in the arena.
+		let siblings = a.get(f).and_then(|nc| nc.parent).and_then(|parent| parent.get(f)).map_or(&[][..], |nc| nc.children.as_slice());
+		let posn = |n: &Node| siblings.iter().position(|c| c == n);
+		match (posn(&a), posn(&b)) {
+		    (Some(i), Some(j)) => i.cmp(&j),
+		    _ => a.0.into_raw_parts().cmp(&b.0.into_raw_parts()),
+		}
+	    }
+	}
+    }
+    /// Find the element that has the given ID, in the same [Tree] as this node. See [Tree::get_element_by_id].
+    pub fn element_with_id(&self, f: &Forest, id: &str) -> Option<Node> {
+	f.get_ref(self.1).and_then(|t| t.get_element_by_id(id))
+    }
 
     /// Creates an interator for the ancestors of this node.
     pub fn ancestor_iter(&self) -> Ancestors {
@@ -829,6 +998,36 @@ impl Node {
     pub fn parent(&self, f: &Forest) -> Option<Node> {
 	self.ancestor_iter().next(f).map(|p| p)
     }
+    /// The base URI of the node. An xml:base attribute on an element is resolved against the base URI of its parent. Otherwise the base URI is inherited from the parent, and the base URI of the document node is the base URI of the [Tree].
+    pub fn base_uri(&self, f: &Forest) -> Option<String> {
+	match self.node_type(f) {
+	    NodeType::Document => f.get_ref(self.1).and_then(|t| t.get_base_uri()),
+	    NodeType::Element => {
+		let inherited = self.parent(f)
+		    .map_or_else(|| f.get_ref(self.1).and_then(|t| t.get_base_uri()), |p| p.base_uri(f));
+		match self.get_attribute(f, &QualifiedName::new(Some(XMLNS.to_string()), None, String::from("base"))) {
+		    Some(a) => {
+			let b = a.to_string(f);
+			inherited.and_then(|i| Url::parse(i.as_str()).and_then(|u| u.join(b.as_str())).ok())
+			    .map_or(Some(b), |u| Some(u.to_string()))
+		    }
+		    None => inherited,
+		}
+	    }
+	    _ => self.parent(f).and_then(|p| p.base_uri(f)),
+	}
+    }
+    /// The document URI of a document node. Other types of node do not have a document URI.
+    pub fn document_uri(&self, f: &Forest) -> Option<String> {
+	match self.node_type(f) {
+	    NodeType::Document => f.get_ref(self.1).and_then(|t| t.get_document_uri()),
+	    _ => None,
+	}
+    }
+    /// Returns the Document-type node of the [Tree] that this node belongs to.
+    pub fn owner_document(&self, f: &Forest) -> Node {
+	f.get_ref(self.1).unwrap().get_doc_node()
+    }
     /// Creates an iterator over the children of this node.
     pub fn child_iter(&self) -> Children {
 	Children::new(self.0, self.1)
@@ -853,6 +1052,24 @@ impl Node {
 	}
 	ret
     }
+    /// Returns the node that precedes this node in document order, not including attributes: the last descendant of the preceding sibling, or the preceding sibling itself, or otherwise the parent. The node that precedes an attribute is its element.
+    pub fn previous_in_document(&self, f: &Forest) -> Option<Node> {
+	let parent = self.get(f).and_then(|nc| nc.parent)?;
+	if self.node_type(f) == NodeType::Attribute {
+	    return Some(parent)
+	}
+	let siblings = &parent.get(f)?.children;
+	match siblings.iter().position(|c| c == self) {
+	    Some(i) if i > 0 => {
+		let mut p = siblings[i - 1];
+		while let Some(c) = p.get(f).and_then(|nc| nc.children.last().copied()) {
+		    p = c
+		}
+		Some(p)
+	    }
+	    _ => Some(parent),
+	}
+    }
     /// Creates an iterator over the following siblings of this node.
     pub fn next_iter(&self, f: &Forest) -> Siblings {
 	Siblings::new(self.0, self.1, 1, f)
@@ -887,6 +1104,129 @@ impl Node {
 	}
     }
 
+    /// Declare an XML Namespace on this element-type node. A prefix of None declares the default namespace.
+    pub fn declare_namespace(&self, f: &mut Forest, prefix: Option<String>, uri: String) -> Result<(), Error> {
+	match f.get_ref_mut(self.1).and_then(|d| d.get_mut(self.0)) {
+	    Some(nc) if nc.t == NodeType::Element => {
+		nc.namespaces.retain(|(p, _)| *p != prefix);
+		nc.namespaces.push((prefix, uri));
+		Ok(())
+	    }
+	    _ => Result::Err(Error::new(ErrorKind::TypeError, String::from("namespaces can only be declared on an element"))),
+	}
+    }
+    /// Returns the namespaces that are declared on this element, as (prefix, URI) pairs.
+    /// Unlike [Node::namespaces], the namespaces that are declared on its ancestors are not included.
+    pub fn namespace_declarations(&self, f: &Forest) -> Vec<(Option<String>, String)> {
+	f.get_ref(self.1)
+	    .and_then(|d| d.get(self.0))
+	    .map_or(vec![], |nc| nc.namespaces.clone())
+    }
+    /// Returns the namespaces that are in scope for this node, as (prefix, URI) pairs.
+    /// The nearest declaration of a prefix is the one returned.
+    /// The prefixes used by the names of elements are included, even if they were not explicitly declared.
+    /// The "xml" prefix is always in scope.
+    pub fn namespaces(&self, f: &Forest) -> Vec<(Option<String>, String)> {
+	let mut result: Vec<(Option<String>, String)> = vec![];
+	let mut add = |p: Option<String>, u: String| {
+	    if result.iter().all(|(q, _)| *q != p) {
+		result.push((p, u))
+	    }
+	};
+	let mut cur = Some(*self);
+	while let Some(n) = cur {
+	    if let Some(nc) = f.get_ref(n.1).and_then(|d| d.get(n.0)) {
+		if nc.t == NodeType::Element {
+		    nc.namespaces.iter().for_each(|(p, u)| add(p.clone(), u.clone()));
+		    if let Some(qn) = nc.name() {
+			if let Some(u) = qn.get_nsuri() {
+			    add(qn.get_prefix(), u)
+			}
+		    }
+		}
+	    }
+	    cur = n.parent(f);
+	}
+	add(Some(String::from("xml")), XMLNS.to_string());
+	// An empty URI undeclares the default namespace
+	result.retain(|(p, u)| p.is_some() || !u.is_empty());
+	result
+    }
+    /// Find the namespace URI that is bound to a prefix, in the scope of this node. A prefix of None finds the default namespace.
+    pub fn namespace_uri(&self, f: &Forest, prefix: Option<&str>) -> Option<String> {
+	self.namespaces(f).into_iter()
+	    .find(|(p, _)| p.as_deref() == prefix)
+	    .map(|(_, u)| u)
+    }
+
+    /// Are two nodes deep-equal, as defined for fn:deep-equal? The nodes may be in different [Tree]s.
+    ///
+    /// Names are compared by their namespace URI and local name, attributes are compared regardless of their order, and comments and processing instructions are ignored when comparing the children of document and element nodes. String values are compared using the codepoint collation.
+    pub fn deep_equal(&self, f: &Forest, other: &Node) -> bool {
+	self.deep_equal_collation(f, other, &CodepointCollation)
+    }
+    /// Are two nodes deep-equal, comparing string values using the given [Collation]?
+    pub fn deep_equal_collation(&self, f: &Forest, other: &Node, c: &dyn Collation) -> bool {
+	let t = self.node_type(f);
+	if t != other.node_type(f) {
+	    return false
+	}
+	match t {
+	    NodeType::Document => self.deep_equal_children(f, other, c),
+	    NodeType::Element => {
+		if self.to_name(f) != other.to_name(f) {
+		    return false
+		}
+		let mine = self.deep_equal_attributes(f);
+		let theirs = other.deep_equal_attributes(f);
+		mine.len() == theirs.len() &&
+		    theirs.iter().all(|a| mine.iter().any(|m| m.deep_equal_collation(f, a, c))) &&
+		    self.deep_equal_children(f, other, c)
+	    }
+	    NodeType::Attribute |
+	    NodeType::ProcessingInstruction => {
+		self.to_name(f) == other.to_name(f) &&
+		    c.equal(self.to_value(f).to_string().as_str(), other.to_value(f).to_string().as_str())
+	    }
+	    NodeType::Text |
+	    NodeType::Comment => {
+		c.equal(self.to_value(f).to_string().as_str(), other.to_value(f).to_string().as_str())
+	    }
+	    NodeType::Unknown => false,
+	}
+    }
+    // The attributes of an element, excluding namespace declarations
+    fn deep_equal_attributes(&self, f: &Forest) -> Vec<Node> {
+	let mut result = vec![];
+	let mut ait = self.attribute_iter(f);
+	while let Some(a) = ait.next() {
+	    let qn = a.to_name(f);
+	    if !(qn.get_nsuri().is_none() && qn.get_localname() == "xmlns") {
+		result.push(a)
+	    }
+	}
+	result
+    }
+    // Compare the children of two nodes, ignoring comments and processing instructions
+    fn deep_equal_children(&self, f: &Forest, other: &Node, c: &dyn Collation) -> bool {
+	let significant = |n: &Node| {
+	    let mut result = vec![];
+	    let mut cit = n.child_iter();
+	    while let Some(d) = cit.next(f) {
+		match d.node_type(f) {
+		    NodeType::Comment |
+		    NodeType::ProcessingInstruction => {}
+		    _ => result.push(d),
+		}
+	    }
+	    result
+	};
+	let mine = significant(self);
+	let theirs = significant(other);
+	mine.len() == theirs.len() &&
+	    mine.iter().zip(theirs.iter()).all(|(m, t)| m.deep_equal_collation(f, t, c))
+    }
+
     /// Convenience method that returns if this node is an element-type node
     pub fn is_element(&self, f: &Forest) -> bool {
 	match f.get_ref(self.1) {
@@ -915,6 +1255,9 @@ impl Node {
 	    NodeType::Element => {
 		let nm = self.to_name(f);
 		let new = f.get_ref_mut(cptreeidx).unwrap().new_element(nm)?;
+		for (p, u) in self.namespace_declarations(f) {
+		    new.declare_namespace(f, p, u)?;
+		}
 		let mut attrs = vec![];
 		let mut ait = self.attribute_iter(f);
 		loop {
@@ -1179,6 +1522,7 @@ pub struct NodeContent {
     parent: Option<Node>, // The document node has no parent
     attributes: HashMap<QualifiedName, Node>, // for non-elements nodes this is always. Should this be an Option?
     children: Vec<Node>, // for non-element nodes this is always empty. Should this be an Option?
+    namespaces: Vec<(Option<String>, String)>, // namespace declarations on an element. The default namespace has no prefix.
 }
 
 impl NodeContent {
@@ -1783,4 +2127,115 @@ mod tests {
 	    .expect("unable to append node");
 	assert_eq!(t1root.to_xml(&f), "<Test><one></one><two><Another><test>document</test></Another></two><three></three></Test>");
     }
+
+    #[test]
+    fn deep_equal() {
+	let mut f = Forest::new();
+	let t1 = f.grow_tree("<q:Test xmlns:q='urn:a' one='1' two='2'><!-- ignored --><q:a>text</q:a><?pi ignored?><q:b/></q:Test>")
+	    .expect("unable to parse document 1");
+	let t2 = f.grow_tree("<p:Test xmlns:p='urn:a' two='2' one='1'><p:a>text</p:a><p:b/></p:Test>")
+	    .expect("unable to parse document 2");
+	let t3 = f.grow_tree("<q:Test xmlns:q='urn:a' one='1' two='2'><q:a>other</q:a><q:b/></q:Test>")
+	    .expect("unable to parse document 3");
+	let d1 = f.get_ref(t1).unwrap().get_doc_node();
+	let d2 = f.get_ref(t2).unwrap().get_doc_node();
+	let d3 = f.get_ref(t3).unwrap().get_doc_node();
+	assert!(d1.deep_equal(&f, &d2));
+	assert!(d2.deep_equal(&f, &d1));
+	assert!(!d1.deep_equal(&f, &d3));
+	let r1 = d1.child_iter().next(&f).unwrap();
+	assert!(!r1.deep_equal(&f, &d2));
+	// Namespace declarations are not attributes
+	let t4 = f.grow_tree("<p:Test xmlns:p='urn:a' xmlns='urn:b'><p:a/></p:Test>")
+	    .expect("unable to parse document 4");
+	let t5 = f.grow_tree("<p:Test xmlns:p='urn:a'><p:a/></p:Test>")
+	    .expect("unable to parse document 5");
+	let d4 = f.get_ref(t4).unwrap().get_doc_node();
+	let d5 = f.get_ref(t5).unwrap().get_doc_node();
+	assert!(d4.deep_equal(&f, &d5));
+    }
+
+    #[test]
+    fn grow_fragment() {
+	let mut f = Forest::new();
+	let t = f.grow_fragment("one<a>two<!-- three --><b>four</b></a><c/>")
+	    .expect("unable to parse fragment");
+	let d = f.get_ref(t).unwrap().get_doc_node();
+	let mut cit = d.child_iter();
+	let mut types = vec![];
+	while let Some(c) = cit.next(&f) {
+	    types.push(c.node_type(&f))
+	}
+	assert_eq!(types, vec![NodeType::Text, NodeType::Element, NodeType::Element]);
+	assert_eq!(d.to_string(&f), "onetwofour");
+	assert_eq!(d.to_xml(&f), "one<a>two<!-- three --><b>four</b></a><c></c>");
+    }
+
+    #[test]
+    fn id_index() {
+	let mut f = Forest::new();
+	let t = f.grow_tree("<doc><sec xml:id=' a '>A</sec><sec xml:id='b'>B</sec><sec xml:id='a'>duplicate</sec></doc>")
+	    .expect("unable to parse document");
+	let top = f.get_ref(t).unwrap().get_doc_node().get_first_element(&f).unwrap();
+	let a = top.element_with_id(&f, "a").expect("no element with ID a");
+	assert_eq!(a.to_string(&f), "A");
+	assert_eq!(f.get_ref(t).unwrap().get_element_by_id("b").unwrap().to_string(&f), "B");
+	assert!(top.element_with_id(&f, "c").is_none());
+	assert_ne!(a.generate_id(), top.generate_id());
+	assert_eq!(a.generate_id(), top.element_with_id(&f, "a").unwrap().generate_id());
+	// Typed ID and IDREFS attributes
+	let e = f.get_ref_mut(t).unwrap().new_element(QualifiedName::new(None, None, String::from("ref"))).unwrap();
+	top.append_child(&mut f, e).unwrap();
+	let i = f.get_ref_mut(t).unwrap().new_attribute(QualifiedName::new(None, None, String::from("key")), Value::ID(crate::value::ID::try_from("c").unwrap())).unwrap();
+	e.add_attribute(&mut f, i).unwrap();
+	let r = f.get_ref_mut(t).unwrap().new_attribute(QualifiedName::new(None, None, String::from("to")), Value::IDREFS(crate::value::IDREFS::try_from("a b").unwrap())).unwrap();
+	e.add_attribute(&mut f, r).unwrap();
+	assert_eq!(top.element_with_id(&f, "c"), Some(e));
+	assert_eq!(r.idrefs(&f), vec!["a", "b"]);
+	assert!(i.idrefs(&f).is_empty());
+    }
+    #[test]
+    fn document_order() {
+	let mut f = Forest::new();
+	let t = f.grow_tree("<doc a='1'><sec><p/><p/></sec><sec b='2'>text</sec></doc>")
+	    .expect("unable to parse document");
+	let d = f.get_ref(t).unwrap().get_doc_node();
+	let top = d.get_first_element(&f).unwrap();
+	let a = top.get_attribute(&f, &QualifiedName::new(None, None, String::from("a"))).unwrap();
+	let mut secs = top.child_iter();
+	let s1 = secs.next(&f).unwrap();
+	let s2 = secs.next(&f).unwrap();
+	let mut ps = s1.child_iter();
+	let p1 = ps.next(&f).unwrap();
+	let p2 = ps.next(&f).unwrap();
+	let b = s2.get_attribute(&f, &QualifiedName::new(None, None, String::from("b"))).unwrap();
+	let text = s2.child_iter().next(&f).unwrap();
+	let mut nodes = vec![text, p2, s2, top, b, d, a, p1, s1];
+	nodes.sort_by(|m, n| m.cmp_document_order(&f, n));
+	assert_eq!(nodes, vec![d, top, a, s1, p1, p2, s2, b, text]);
+	assert_eq!(p1.cmp_document_order(&f, &p1), Ordering::Equal);
+	assert_eq!(b.cmp_document_order(&f, &s2), Ordering::Greater);
+    }
+    #[test]
+    fn previous_in_document() {
+	let mut f = Forest::new();
+	let t = f.grow_tree("<doc><sec><p/><p>text</p></sec><sec b='2'/></doc>")
+	    .expect("unable to parse document");
+	let d = f.get_ref(t).unwrap().get_doc_node();
+	let mut secs = d.get_first_element(&f).unwrap().child_iter();
+	secs.next(&f);
+	let s2 = secs.next(&f).unwrap();
+	let b = s2.get_attribute(&f, &QualifiedName::new(None, None, String::from("b"))).unwrap();
+	let mut names = vec![];
+	let mut cur = Some(b);
+	while let Some(n) = cur {
+	    names.push(match n.node_type(&f) {
+		NodeType::Element | NodeType::Attribute => n.to_name(&f).get_localname(),
+		NodeType::Text => n.to_string(&f),
+		_ => String::from("/"),
+	    });
+	    cur = n.previous_in_document(&f)
+	}
+	assert_eq!(names, vec!["b", "sec", "text", "p", "p", "sec", "doc", "/"]);
+    }
 }
@@ -1,7 +1,7 @@
 //! # xrust::item
 //!
 //! Sequence Item module.
-//! An Item is a Node, Function or Atomic Value.
+//! An Item is a Node, Function, Map, Array or Atomic Value.
 //!
 //! Nodes are implemented as a trait.
 
@@ -12,6 +12,7 @@ use crate::forest::{NodeType, Node, Forest};
 use crate::qname::QualifiedName;
 use crate::output::OutputDefinition;
 use crate::xdmerror::{Error, ErrorKind};
+use crate::json;
 
 /// In XPath, the Sequence is the fundamental data structure.
 /// It is an ordered collection of [Item]s.
@@ -27,8 +28,8 @@ pub trait SequenceTrait {
     fn to_xml(&self, d: Option<&Forest>) -> String;
     /// Return a XML formatted representation of the [Sequence], controlled by the supplied output definition.
     fn to_xml_with_options(&self, od: &OutputDefinition, d: Option<&Forest>) -> String;
-    /// Return a JSON formatted representation of the [Sequence].
-    fn to_json(&self, d: Option<&Forest>) -> String;
+    /// Return a JSON formatted representation of the [Sequence], controlled by the supplied output definition. See [json::serialize](crate::json::serialize).
+    fn to_json(&self, od: &OutputDefinition, d: Option<&Forest>) -> Result<String, Error>;
     /// Return the Effective Boolean Value of the [Sequence].
     fn to_bool(&self) -> bool;
     /// Convert the [Sequence] to an integer. The [Sequence] must be a singleton value.
@@ -67,12 +68,8 @@ impl SequenceTrait for Sequence {
 	r
     }
     /// Renders the Sequence as JSON
-    fn to_json(&self, d: Option<&Forest>) -> String {
-	let mut r = String::new();
-	for i in self {
-	    r.push_str(i.to_json(d).as_str())
-	}
-	r
+    fn to_json(&self, od: &OutputDefinition, d: Option<&Forest>) -> Result<String, Error> {
+	json::serialize(self, od, d)
     }
     /// Push a Document's [Node] on to the [Sequence]
     fn push_node(&mut self, n: Node) {
@@ -133,7 +130,7 @@ impl From<Item> for Sequence {
     }
 }
 
-/// An Item in a [Sequence]. Can be a [Node], Function or [Value].
+/// An Item in a [Sequence]. Can be a [Node], Function, [Map], [Array] or [Value].
 ///
 /// [Node]s are dynamic trait objects. [Node]s can only exist in the context of a Tree.
 ///
@@ -148,10 +145,16 @@ pub enum Item {
 
     /// A scalar value
     Value(Value),
+
+    /// A [Map]
+    Map(Map),
+
+    /// An [Array]
+    Array(Array),
 }
 
 impl Item {
-    /// Gives the string value of an item. All items have a string value.
+    /// Gives the string value of an item. Function items, maps and arrays do not have a string value, and give an empty string; see [Item::string_value].
     pub fn to_string(&self, d: Option<&Forest>) -> String {
 	match self {
 	    Item::Node(n) => d.map_or(
@@ -160,6 +163,17 @@ impl Item {
 	    ),
 	    Item::Function => "".to_string(),
 	    Item::Value(v) => v.to_string(),
+	    Item::Map(_) |
+	    Item::Array(_) => "".to_string(),
+	}
+    }
+    /// Gives the string value of an item, as for fn:string. It is an error (FOTY0014) if the item is a function item, map or array.
+    pub fn string_value(&self, d: Option<&Forest>) -> Result<String, Error> {
+	match self {
+	    Item::Function => Result::Err(Error{kind: ErrorKind::FunctionStringValue, message: String::from("a function item does not have a string value")}),
+	    Item::Map(_) => Result::Err(Error{kind: ErrorKind::FunctionStringValue, message: String::from("a map does not have a string value")}),
+	    Item::Array(_) => Result::Err(Error{kind: ErrorKind::FunctionStringValue, message: String::from("an array does not have a string value")}),
+	    _ => Ok(self.to_string(d)),
 	}
     }
     /// Serialize as XML
@@ -171,6 +185,8 @@ impl Item {
 	    ),
 	    Item::Function => "".to_string(),
 	    Item::Value(v) => v.to_string(),
+	    Item::Map(_) |
+	    Item::Array(_) => "".to_string(),
 	}
     }
     /// Serialize as XML, with options
@@ -182,18 +198,13 @@ impl Item {
 	    ),
 	    Item::Function => "".to_string(),
 	    Item::Value(v) => v.to_string(),
+	    Item::Map(_) |
+	    Item::Array(_) => "".to_string(),
 	}
     }
     /// Serialize as JSON
-    pub fn to_json(&self, d: Option<&Forest>) -> String {
-	match self {
-	    Item::Node(n) => d.map_or(
-		String::new(),
-		|e| n.to_json(e)
-	    ),
-	    Item::Function => "".to_string(),
-	    Item::Value(v) => v.to_string(),
-	}
+    pub fn to_json(&self, od: &OutputDefinition, d: Option<&Forest>) -> Result<String, Error> {
+	json::serialize_item(self, od, d)
     }
 
     /// Determine the effective boolean value of the item.
@@ -203,6 +214,8 @@ impl Item {
 	    Item::Node(..) => true,
 	    Item::Function => false,
 	    Item::Value(v) => v.to_bool(),
+	    Item::Map(_) |
+	    Item::Array(_) => false,
 	}
     }
 
@@ -211,6 +224,8 @@ impl Item {
 	match self {
 	    Item::Node(..) => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("type error: item is a node")}),
 	    Item::Function => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("type error: item is a function")}),
+	    Item::Map(_) => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("type error: item is a map")}),
+	    Item::Array(_) => Result::Err(Error{kind: ErrorKind::TypeError, message: String::from("type error: item is an array")}),
 	    Item::Value(v) => {
 		match v.to_int() {
 		    Ok(i) => {
@@ -230,6 +245,8 @@ impl Item {
 	    Item::Node(..) => f64::NAN,
 	    Item::Function => f64::NAN,
 	    Item::Value(v) => v.to_double(),
+	    Item::Map(_) |
+	    Item::Array(_) => f64::NAN,
 	}
     }
 
@@ -294,10 +311,109 @@ impl Item {
 	    Item::Node(..) => "Node",
 	    Item::Function => "Function",
 	    Item::Value(v) => v.value_type(),
+	    Item::Map(_) => "Map",
+	    Item::Array(_) => "Array",
 	}
     }
 }
 
+/// A map associates atomic keys with values, where each value is a [Sequence].
+///
+/// Entries are kept in the order in which they were added. Keys are compared as defined by op:same-key: strings are compared by codepoint, and numeric values are compared by their value (NaN is the same key as NaN).
+#[derive(Clone, Default)]
+pub struct Map(Vec<(Value, Sequence)>);
+
+impl Map {
+    /// Create an empty map.
+    pub fn new() -> Self {
+	Map(vec![])
+    }
+    /// The number of entries in the map.
+    pub fn len(&self) -> usize {
+	self.0.len()
+    }
+    /// Is the map empty?
+    pub fn is_empty(&self) -> bool {
+	self.0.is_empty()
+    }
+    /// Find the value associated with a key.
+    pub fn get(&self, k: &Value) -> Option<&Sequence> {
+	self.0.iter()
+	    .find(|(l, _)| same_key(k, l))
+	    .map(|(_, v)| v)
+    }
+    /// Does the map have an entry for the key?
+    pub fn contains(&self, k: &Value) -> bool {
+	self.get(k).is_some()
+    }
+    /// Add an entry to the map. If there is already an entry with the same key then its value is replaced.
+    pub fn insert(&mut self, k: Value, v: Sequence) {
+	match self.0.iter_mut().find(|(l, _)| same_key(&k, l)) {
+	    Some(e) => e.1 = v,
+	    None => self.0.push((k, v)),
+	}
+    }
+    /// The keys of the map.
+    pub fn keys(&self) -> Vec<Value> {
+	self.0.iter().map(|(k, _)| k.clone()).collect()
+    }
+    /// Iterate over the entries of the map.
+    pub fn iter(&self) -> std::slice::Iter<'_, (Value, Sequence)> {
+	self.0.iter()
+    }
+}
+
+// op:same-key
+fn same_key(a: &Value, b: &Value) -> bool {
+    let stringlike = |v: &Value| matches!(v, Value::String(_) | Value::AnyURI(_) | Value::UntypedAtomic) || v.is_string_derived();
+    if a.is_numeric() && b.is_numeric() {
+	(a.to_double().is_nan() && b.to_double().is_nan()) || a == b
+    } else if a.is_numeric() || b.is_numeric() {
+	false
+    } else if stringlike(a) || stringlike(b) {
+	stringlike(a) && stringlike(b) && a.to_string() == b.to_string()
+    } else {
+	a.value_type() == b.value_type() && a == b
+    }
+}
+
+/// An array is an ordered list of members, where each member is a [Sequence].
+#[derive(Clone, Default)]
+pub struct Array(Vec<Sequence>);
+
+impl Array {
+    /// Create an empty array.
+    pub fn new() -> Self {
+	Array(vec![])
+    }
+    /// The number of members in the array.
+    pub fn len(&self) -> usize {
+	self.0.len()
+    }
+    /// Is the array empty?
+    pub fn is_empty(&self) -> bool {
+	self.0.is_empty()
+    }
+    /// Get a member of the array. NB. the index is zero-based, unlike array:get.
+    pub fn get(&self, i: usize) -> Option<&Sequence> {
+	self.0.get(i)
+    }
+    /// Append a member to the array.
+    pub fn push(&mut self, m: Sequence) {
+	self.0.push(m)
+    }
+    /// Iterate over the members of the array.
+    pub fn iter(&self) -> std::slice::Iter<'_, Sequence> {
+	self.0.iter()
+    }
+}
+
+impl From<Vec<Sequence>> for Array {
+    fn from(v: Vec<Sequence>) -> Self {
+	Array(v)
+    }
+}
+
 impl fmt::Debug for Item {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
 	match self {
@@ -310,6 +426,12 @@ impl fmt::Debug for Item {
 	    Item::Value(v) => {
 		write!(f, "value type item ({})", v.to_string())
 	    }
+	    Item::Map(m) => {
+		write!(f, "map type item ({} entries)", m.len())
+	    }
+	    Item::Array(a) => {
+		write!(f, "array type item ({} members)", a.len())
+	    }
 	}
     }
 }
@@ -0,0 +1,789 @@
+//! # xrust::json
+//!
+//! Support for JSON, as defined by XPath and XQuery Functions and Operators 3.1.
+//!
+//! JSON text is parsed into a [Json] value. The value can then be converted to maps and arrays (as for fn:parse-json), or to the XML representation of JSON (as for fn:json-to-xml). The XML representation can be converted back to JSON text with [xml_to_json] (as for fn:xml-to-json).
+//!
+//! A sequence can be serialized as JSON text using the JSON output method, with [serialize].
+
+extern crate nom;
+use std::rc::Rc;
+use nom::{
+  IResult,
+  branch::alt,
+  bytes::complete::tag,
+  character::complete::{char, digit0, digit1, multispace0, one_of},
+  combinator::{cond, map, opt, recognize},
+  multi::separated_list0,
+  sequence::{pair, tuple},
+  error::{Error as NomError, ErrorKind as NomErrorKind},
+  Err as NomErr,
+};
+use crate::xdmerror::*;
+use crate::qname::QualifiedName;
+use crate::value::Value;
+use crate::item::{Sequence, Item, Map, Array};
+use crate::forest::{Forest, Node, NodeType, TreeIndex};
+use crate::output::OutputDefinition;
+use crate::parsecommon::is_char;
+use crate::evaluate::FNNS;
+
+/// A JSON value.
+///
+/// Numbers keep their lexical form. Objects keep their members in order, and may have duplicate keys (see [Duplicates]).
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+  Null,
+  Boolean(bool),
+  Number(String),
+  String(String),
+  Array(Vec<Json>),
+  Object(Vec<(String, Json)>),
+}
+
+/// How to handle duplicate keys in a JSON object.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Duplicates {
+  /// Duplicate keys are an error (FOJS0003)
+  Reject,
+  /// The first value for a key is used
+  UseFirst,
+  /// The last value for a key is used
+  UseLast,
+  /// All of the values are kept. Only meaningful for the XML representation.
+  Retain,
+}
+
+/// Options for parsing JSON.
+#[derive(Clone, Copy, Debug)]
+pub struct ParseOptions {
+  /// Accept JSON that does not strictly conform to RFC 7159. Trailing commas in arrays and objects, and unescaped control characters in strings, are allowed.
+  pub liberal: bool,
+  /// How to handle duplicate keys
+  pub duplicates: Duplicates,
+  /// Represent special characters in strings using JSON escape sequences. Otherwise, characters that are not permitted in XML are replaced by U+FFFD.
+  pub escape: bool,
+}
+
+impl ParseOptions {
+  /// The default options, with the given treatment of duplicate keys.
+  pub fn new(duplicates: Duplicates) -> Self {
+    ParseOptions{liberal: false, duplicates, escape: false}
+  }
+}
+
+impl Default for ParseOptions {
+  /// The default options for fn:parse-json
+  fn default() -> Self {
+    ParseOptions::new(Duplicates::UseFirst)
+  }
+}
+
+/// Parse JSON text. A syntax error is reported as FOJS0001.
+pub fn parse(s: &str, o: &ParseOptions) -> Result<Json, Error> {
+  let t = s.strip_prefix('\u{FEFF}').unwrap_or(s);
+  match tuple((multispace0, |i| json_value(i, o), multispace0))(t) {
+    Ok(("", (_, j, _))) => dedupe(j, o.duplicates),
+    Ok((rest, _)) |
+    Err(NomErr::Error(NomError{input: rest, ..})) |
+    Err(NomErr::Failure(NomError{input: rest, ..})) => {
+      Result::Err(Error::new(ErrorKind::JsonSyntax, format!("JSON syntax error at \"{}\"", rest.chars().take(20).collect::<String>())))
+    }
+    Err(NomErr::Incomplete(_)) => Result::Err(Error::new(ErrorKind::JsonSyntax, String::from("incomplete JSON text"))),
+  }
+}
+
+// Apply the duplicates option to objects
+fn dedupe(j: Json, d: Duplicates) -> Result<Json, Error> {
+  match j {
+    Json::Array(a) => Ok(Json::Array(a.into_iter().map(|m| dedupe(m, d)).collect::<Result<Vec<Json>, Error>>()?)),
+    Json::Object(o) => {
+      let mut result: Vec<(String, Json)> = vec![];
+      for (k, v) in o {
+	let v = dedupe(v, d)?;
+	match (result.iter().position(|(l, _)| *l == k), d) {
+	  (None, _) |
+	  (Some(_), Duplicates::Retain) => result.push((k, v)),
+	  (Some(_), Duplicates::UseFirst) => {}
+	  (Some(p), Duplicates::UseLast) => result[p].1 = v,
+	  (Some(_), Duplicates::Reject) => return Result::Err(Error::new(ErrorKind::JsonDuplicateKeys, format!("duplicate key \"{}\"", k))),
+	}
+      }
+      Ok(Json::Object(result))
+    }
+    _ => Ok(j),
+  }
+}
+
+fn json_value<'a>(input: &'a str, o: &ParseOptions) -> IResult<&'a str, Json> {
+  alt((
+    map(tag("null"), |_| Json::Null),
+    map(tag("true"), |_| Json::Boolean(true)),
+    map(tag("false"), |_| Json::Boolean(false)),
+    map(json_number, |n: &str| Json::Number(n.to_string())),
+    map(|i| json_string(i, o), Json::String),
+    |i| json_array(i, o),
+    |i| json_object(i, o),
+  ))
+  (input)
+}
+
+// number = [ minus ] int [ frac ] [ exp ]
+fn json_number(input: &str) -> IResult<&str, &str> {
+  recognize(
+    tuple((
+      opt(char('-')),
+      alt((
+        tag("0"),
+        recognize(pair(one_of("123456789"), digit0)),
+      )),
+      opt(pair(char('.'), digit1)),
+      opt(tuple((one_of("eE"), opt(one_of("+-")), digit1))),
+    ))
+  )
+  (input)
+}
+
+fn json_array<'a>(input: &'a str, o: &ParseOptions) -> IResult<&'a str, Json> {
+  map(
+    tuple((
+      char('['),
+      multispace0,
+      separated_list0(
+        tuple((multispace0, char(','), multispace0)),
+        |i| json_value(i, o),
+      ),
+      multispace0,
+      cond(o.liberal, opt(pair(char(','), multispace0))),
+      char(']'),
+    )),
+    |(_, _, m, _, _, _)| Json::Array(m)
+  )
+  (input)
+}
+
+fn json_object<'a>(input: &'a str, o: &ParseOptions) -> IResult<&'a str, Json> {
+  map(
+    tuple((
+      char('{'),
+      multispace0,
+      separated_list0(
+        tuple((multispace0, char(','), multispace0)),
+        map(
+          tuple((|i| json_string(i, o), multispace0, char(':'), multispace0, |i| json_value(i, o))),
+          |(k, _, _, _, v)| (k, v)
+        ),
+      ),
+      multispace0,
+      cond(o.liberal, opt(pair(char(','), multispace0))),
+      char('}'),
+    )),
+    |(_, _, m, _, _, _)| Json::Object(m)
+  )
+  (input)
+}
+
+// A JSON string. Escape sequences are decoded, and then the string is represented as specified by the escape option.
+fn json_string<'a>(input: &'a str, o: &ParseOptions) -> IResult<&'a str, String> {
+  let err = |i: &'a str| NomErr::Error(NomError::new(i, NomErrorKind::Char));
+  let rest = input.strip_prefix('"').ok_or_else(|| err(input))?;
+  let mut result = String::new();
+  let mut chars = rest.char_indices();
+  loop {
+    match chars.next() {
+      None => return Err(err(input)),
+      Some((i, '"')) => return Ok((&rest[i + 1..], result)),
+      Some((i, '\\')) => {
+	let u = match chars.next() {
+	  Some((_, '"')) => 0x22,
+	  Some((_, '\\')) => 0x5C,
+	  Some((_, '/')) => 0x2F,
+	  Some((_, 'b')) => 0x08,
+	  Some((_, 'f')) => 0x0C,
+	  Some((_, 'n')) => 0x0A,
+	  Some((_, 'r')) => 0x0D,
+	  Some((_, 't')) => 0x09,
+	  Some((_, 'u')) => {
+	    let high = hex4(&rest[i + 2..]).ok_or_else(|| err(&rest[i..]))?;
+	    chars.nth(3);
+	    // A high surrogate followed by a low surrogate is a single character
+	    match rest[i + 6..].strip_prefix("\\u").and_then(hex4) {
+	      Some(low) if (0xD800..=0xDBFF).contains(&high) && (0xDC00..=0xDFFF).contains(&low) => {
+		chars.nth(5);
+		0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+	      }
+	      _ => high,
+	    }
+	  }
+	  _ => return Err(err(&rest[i..])),
+	};
+	push_codepoint(&mut result, u, o.escape)
+      }
+      Some((i, c)) if (c as u32) < 0x20 && !o.liberal => return Err(err(&rest[i..])),
+      Some((_, c)) => push_codepoint(&mut result, c as u32, o.escape),
+    }
+  }
+}
+
+// Four hexadecimal digits
+fn hex4(s: &str) -> Option<u32> {
+  match s.get(0..4) {
+    Some(h) if h.chars().all(|c| c.is_ascii_hexdigit()) => u32::from_str_radix(h, 16).ok(),
+    _ => None,
+  }
+}
+
+// Add a codepoint (which may be an unpaired surrogate) to a string.
+// If escaping, then special characters are represented by escape sequences.
+// Otherwise characters that are not permitted in XML are replaced by U+FFFD.
+fn push_codepoint(s: &mut String, u: u32, escape: bool) {
+  let c = char::from_u32(u).filter(is_char);
+  if escape {
+    match u {
+      0x08 => s.push_str("\\b"),
+      0x09 => s.push_str("\\t"),
+      0x0A => s.push_str("\\n"),
+      0x0C => s.push_str("\\f"),
+      0x0D => s.push_str("\\r"),
+      0x5C => s.push_str("\\\\"),
+      _ => match c {
+	Some(d) if u > 0x1F && !(0x7F..=0x9F).contains(&u) => s.push(d),
+	_ => s.push_str(format!("\\u{:04X}", u).as_str()),
+      }
+    }
+  } else {
+    s.push(c.unwrap_or('\u{FFFD}'))
+  }
+}
+
+impl Json {
+  /// Convert to XDM values: objects become maps, arrays become arrays, numbers become xs:double values, and null becomes the empty sequence.
+  pub fn to_sequence(&self) -> Sequence {
+    match self {
+      Json::Null => vec![],
+      Json::Boolean(b) => vec![Rc::new(Item::Value(Value::Boolean(*b)))],
+      Json::Number(n) => vec![Rc::new(Item::Value(Value::Double(n.parse::<f64>().unwrap_or(f64::NAN))))],
+      Json::String(s) => vec![Rc::new(Item::Value(Value::from(s.as_str())))],
+      Json::Array(a) => vec![Rc::new(Item::Array(Array::from(a.iter().map(|m| m.to_sequence()).collect::<Vec<Sequence>>())))],
+      Json::Object(o) => {
+	let mut m = Map::new();
+	o.iter().for_each(|(k, v)| m.insert(Value::from(k.as_str()), v.to_sequence()));
+	vec![Rc::new(Item::Map(m))]
+      }
+    }
+  }
+
+  /// Create the XML representation of the value, in a new [Tree](crate::forest::Tree). Returns the document node.
+  ///
+  /// If the value was parsed with the escape option, then strings and keys that contain escape sequences are marked with the "escaped" and "escaped-key" attributes.
+  pub fn to_xml(&self, f: &mut Forest, escape: bool) -> Result<Node, Error> {
+    let t = f.plant_tree();
+    let e = self.to_xml_element(f, t, None, escape)?;
+    e.declare_namespace(f, None, FNNS.to_string())?;
+    f.get_ref_mut(t).unwrap().push_doc_node(e)?;
+    Ok(f.get_ref(t).unwrap().get_doc_node())
+  }
+  fn to_xml_element(&self, f: &mut Forest, t: TreeIndex, key: Option<&str>, escape: bool) -> Result<Node, Error> {
+    let name = match self {
+      Json::Null => "null",
+      Json::Boolean(_) => "boolean",
+      Json::Number(_) => "number",
+      Json::String(_) => "string",
+      Json::Array(_) => "array",
+      Json::Object(_) => "map",
+    };
+    let e = f.get_ref_mut(t).unwrap().new_element(QualifiedName::new(Some(FNNS.to_string()), None, name.to_string()))?;
+    let mut attrs = vec![];
+    if let Some(k) = key {
+      attrs.push(("key", k));
+      if escape && k.contains('\\') {
+	attrs.push(("escaped-key", "true"))
+      }
+    }
+    if let Json::String(s) = self {
+      if escape && s.contains('\\') {
+	attrs.push(("escaped", "true"))
+      }
+    }
+    for (n, v) in attrs {
+      let a = f.get_ref_mut(t).unwrap().new_attribute(QualifiedName::new(None, None, n.to_string()), Value::from(v))?;
+      e.add_attribute(f, a)?;
+    }
+    let text = match self {
+      Json::Boolean(b) => Some(b.to_string()),
+      Json::Number(n) => Some(n.clone()),
+      Json::String(s) if !s.is_empty() => Some(s.clone()),
+      _ => None,
+    };
+    if let Some(s) = text {
+      let n = f.get_ref_mut(t).unwrap().new_text(Value::from(s))?;
+      e.append_child(f, n)?;
+    }
+    match self {
+      Json::Array(a) => {
+	for m in a {
+	  let c = m.to_xml_element(f, t, None, escape)?;
+	  e.append_child(f, c)?;
+	}
+      }
+      Json::Object(o) => {
+	for (k, v) in o {
+	  let c = v.to_xml_element(f, t, Some(k.as_str()), escape)?;
+	  e.append_child(f, c)?;
+	}
+      }
+      _ => {}
+    }
+    Ok(e)
+  }
+}
+
+/// Escape a string for JSON output. Quotes, backslashes and control characters are escaped.
+pub fn escape_string(s: &str) -> String {
+  let mut r = String::new();
+  for c in s.chars() {
+    match c {
+      '"' => r.push_str("\\\""),
+      '\\' => r.push_str("\\\\"),
+      '\u{08}' => r.push_str("\\b"),
+      '\u{0C}' => r.push_str("\\f"),
+      '\n' => r.push_str("\\n"),
+      '\r' => r.push_str("\\r"),
+      '\t' => r.push_str("\\t"),
+      d if (d as u32) < 0x20 || (0x7F..=0x9F).contains(&(d as u32)) => r.push_str(format!("\\u{:04X}", d as u32).as_str()),
+      d => r.push(d),
+    }
+  }
+  r
+}
+
+// A string that already contains JSON escape sequences. The escape sequences are checked (FOJS0007), and other special characters are escaped.
+fn escape_escaped(s: &str) -> Result<String, Error> {
+  let mut r = String::new();
+  let mut chars = s.chars().peekable();
+  while let Some(c) = chars.next() {
+    if c == '\\' {
+      match chars.next() {
+	Some(e) if "\"\\/bfnrt".contains(e) => {
+	  r.push('\\');
+	  r.push(e)
+	}
+	Some('u') => {
+	  let h: String = chars.by_ref().take(4).collect();
+	  if h.len() != 4 || !h.chars().all(|d| d.is_ascii_hexdigit()) {
+	    return Result::Err(Error::new(ErrorKind::JsonInvalidEscape, format!("invalid escape sequence \"\\u{}\"", h)))
+	  }
+	  r.push_str("\\u");
+	  r.push_str(h.as_str())
+	}
+	_ => return Result::Err(Error::new(ErrorKind::JsonInvalidEscape, format!("invalid escape sequence in \"{}\"", s))),
+      }
+    } else {
+      r.push_str(escape_string(c.to_string().as_str()).as_str())
+    }
+  }
+  Ok(r)
+}
+
+// The reverse of escape_escaped, for comparing keys
+fn unescape(s: &str) -> Result<String, Error> {
+  parse(format!("\"{}\"", s).as_str(), &ParseOptions::default())
+    .and_then(|j| match j {
+      Json::String(t) => Ok(t),
+      _ => Result::Err(Error::new(ErrorKind::JsonInvalidEscape, format!("invalid escape sequence in \"{}\"", s))),
+    })
+    .map_err(|_| Error::new(ErrorKind::JsonInvalidEscape, format!("invalid escape sequence in \"{}\"", s)))
+}
+
+/// Convert the XML representation of JSON to JSON text. The node must be an element in the http://www.w3.org/2005/xpath-functions namespace, or a document node whose document element is such an element.
+///
+/// An invalid XML representation is an error (FOJS0006). If indent is true then the output is indented.
+pub fn xml_to_json(n: &Node, f: &Forest, indent: bool) -> Result<String, Error> {
+  let e = match n.node_type(f) {
+    NodeType::Document => {
+      let mut elements = significant_children(n, f)?;
+      if elements.len() != 1 {
+	return Result::Err(invalid("document must have a single element"))
+      }
+      elements.remove(0)
+    }
+    NodeType::Element => *n,
+    _ => return Result::Err(invalid("node must be an element or document")),
+  };
+  let mut out = String::new();
+  json_element(&e, f, &mut out, if indent {Some(0)} else {None}, false)?;
+  Ok(out)
+}
+
+fn invalid(m: &str) -> Error {
+  Error::new(ErrorKind::JsonInvalidXml, String::from(m))
+}
+
+// The element children of a node. Comments, processing instructions and whitespace-only text are ignored.
+fn significant_children(n: &Node, f: &Forest) -> Result<Vec<Node>, Error> {
+  let mut result = vec![];
+  let mut cit = n.child_iter();
+  while let Some(c) = cit.next(f) {
+    match c.node_type(f) {
+      NodeType::Element => result.push(c),
+      NodeType::Text if !c.to_string(f).trim().is_empty() => return Result::Err(invalid("unexpected text")),
+      _ => {}
+    }
+  }
+  Ok(result)
+}
+
+// The string value of an element, which must not have element children
+fn simple_content(n: &Node, f: &Forest) -> Result<String, Error> {
+  let mut result = String::new();
+  let mut cit = n.child_iter();
+  while let Some(c) = cit.next(f) {
+    match c.node_type(f) {
+      NodeType::Element => return Result::Err(invalid("element must not have element content")),
+      NodeType::Text => result.push_str(c.to_string(f).as_str()),
+      _ => {}
+    }
+  }
+  Ok(result)
+}
+
+// Is an attribute with a boolean value set?
+fn flag(n: &Node, f: &Forest, name: &str) -> Result<bool, Error> {
+  match n.get_attribute(f, &QualifiedName::new(None, None, name.to_string())) {
+    Some(a) => match a.to_string(f).trim() {
+      "true" | "1" => Ok(true),
+      "false" | "0" => Ok(false),
+      _ => Result::Err(invalid(format!("invalid value for {} attribute", name).as_str())),
+    }
+    None => Ok(false),
+  }
+}
+
+fn newline(out: &mut String, indent: Option<usize>) {
+  if let Some(i) = indent {
+    out.push('\n');
+    (0..i).for_each(|_| out.push_str("  "));
+  }
+}
+
+fn json_element(e: &Node, f: &Forest, out: &mut String, indent: Option<usize>, keyed: bool) -> Result<(), Error> {
+  let name = e.to_name(f);
+  if name.get_nsuri_ref() != Some(FNNS) {
+    return Result::Err(invalid(format!("element \"{}\" is not in the namespace {}", name.to_string(), FNNS).as_str()))
+  }
+  // Check attributes. Attributes in other namespaces are allowed.
+  let local = name.get_localname();
+  let mut ait = e.attribute_iter(f);
+  while let Some(a) = ait.next() {
+    let an = a.to_name(f);
+    if an.get_nsuri().is_none() {
+      let ok = match an.get_localname().as_str() {
+	"key" | "escaped-key" => keyed,
+	"escaped" => local == "string",
+	// Namespace declarations
+	"xmlns" => true,
+	_ => false,
+      };
+      if !ok {
+	return Result::Err(invalid(format!("attribute \"{}\" is not allowed on \"{}\"", an.get_localname(), local).as_str()))
+      }
+    }
+  }
+  match local.as_str() {
+    "null" => {
+      if !simple_content(e, f)?.trim().is_empty() {
+	return Result::Err(invalid("null element must be empty"))
+      }
+      out.push_str("null")
+    }
+    "boolean" => {
+      match simple_content(e, f)?.trim() {
+	"true" | "1" => out.push_str("true"),
+	"false" | "0" => out.push_str("false"),
+	_ => return Result::Err(invalid("invalid boolean")),
+      }
+    }
+    "number" => {
+      match simple_content(e, f)?.trim().parse::<f64>() {
+	Ok(d) if d.is_finite() => out.push_str(Value::Double(d).to_string().as_str()),
+	_ => return Result::Err(invalid("invalid number")),
+      }
+    }
+    "string" => {
+      let s = simple_content(e, f)?;
+      out.push('"');
+      if flag(e, f, "escaped")? {
+	out.push_str(escape_escaped(s.as_str())?.as_str())
+      } else {
+	out.push_str(escape_string(s.as_str()).as_str())
+      }
+      out.push('"')
+    }
+    "array" => {
+      let members = significant_children(e, f)?;
+      out.push('[');
+      for (i, m) in members.iter().enumerate() {
+	if i > 0 {
+	  out.push(',')
+	}
+	newline(out, indent.map(|j| j + 1));
+	json_element(m, f, out, indent.map(|j| j + 1), false)?;
+      }
+      if !members.is_empty() {
+	newline(out, indent)
+      }
+      out.push(']')
+    }
+    "map" => {
+      let entries = significant_children(e, f)?;
+      let mut keys: Vec<String> = vec![];
+      out.push('{');
+      for (i, m) in entries.iter().enumerate() {
+	let k = m.get_attribute(f, &QualifiedName::new(None, None, String::from("key")))
+	  .ok_or_else(|| invalid("map entry does not have a key"))?
+	  .to_string(f);
+	let escaped = flag(m, f, "escaped-key")?;
+	// Keys must be unique, after any escape sequences are decoded
+	let unique = if escaped {unescape(k.as_str())?} else {k.clone()};
+	if keys.contains(&unique) {
+	  return Result::Err(invalid(format!("duplicate key \"{}\"", k).as_str()))
+	}
+	keys.push(unique);
+	if i > 0 {
+	  out.push(',')
+	}
+	newline(out, indent.map(|j| j + 1));
+	out.push('"');
+	if escaped {
+	  out.push_str(escape_escaped(k.as_str())?.as_str())
+	} else {
+	  out.push_str(escape_string(k.as_str()).as_str())
+	}
+	out.push_str(if indent.is_some() {"\": "} else {"\":"});
+	json_element(m, f, out, indent.map(|j| j + 1), true)?;
+      }
+      if !entries.is_empty() {
+	newline(out, indent)
+      }
+      out.push('}')
+    }
+    _ => return Result::Err(invalid(format!("unknown element \"{}\"", local).as_str())),
+  }
+  Ok(())
+}
+
+/// Serialize a sequence using the JSON output method. See XSLT and XQuery Serialization 3.1, section 10.
+///
+/// Maps become objects and arrays become arrays. Numeric values are output as numbers, booleans as true or false, and other atomic values as strings. Nodes are serialized as XML, and output as a string. The empty sequence is output as null. A sequence of more than one item is an error (SERE0023), as are NaN and infinite values (SERE0020), attribute nodes and functions (SERE0021), and maps with two keys that have the same string value (SERE0022).
+///
+/// The output is indented if the output definition specifies indenting.
+pub fn serialize(s: &Sequence, od: &OutputDefinition, f: Option<&Forest>) -> Result<String, Error> {
+  let mut out = String::new();
+  serialize_sequence(s, f, &mut out, if od.get_indent() {Some(0)} else {None})?;
+  Ok(out)
+}
+
+/// Serialize a single item using the JSON output method. See [serialize].
+pub fn serialize_item(i: &Item, od: &OutputDefinition, f: Option<&Forest>) -> Result<String, Error> {
+  let mut out = String::new();
+  serialize_one(i, f, &mut out, if od.get_indent() {Some(0)} else {None})?;
+  Ok(out)
+}
+
+fn serialize_sequence(s: &Sequence, f: Option<&Forest>, out: &mut String, indent: Option<usize>) -> Result<(), Error> {
+  match s.as_slice() {
+    [] => {
+      out.push_str("null");
+      Ok(())
+    }
+    [i] => serialize_one(i, f, out, indent),
+    _ => Result::Err(Error::new(ErrorKind::SerializeSequence, format!("unable to serialize a sequence of {} items as JSON", s.len()))),
+  }
+}
+
+fn serialize_one(i: &Item, f: Option<&Forest>, out: &mut String, indent: Option<usize>) -> Result<(), Error> {
+  match i {
+    Item::Value(Value::Boolean(b)) => out.push_str(if *b {"true"} else {"false"}),
+    Item::Value(v) if v.is_numeric() => {
+      let n = v.to_string();
+      if n == "NaN" || n.ends_with("INF") {
+	return Result::Err(Error::new(ErrorKind::SerializeNumber, format!("unable to serialize {} as a JSON number", n)))
+      }
+      out.push_str(n.as_str())
+    }
+    Item::Value(v) => {
+      out.push('"');
+      out.push_str(escape_string(v.to_string().as_str()).as_str());
+      out.push('"')
+    }
+    Item::Node(n) => {
+      let forest = f.ok_or_else(|| Error::new(ErrorKind::DynamicAbsent, String::from("no forest for node")))?;
+      out.push_str(n.to_json(forest)?.as_str())
+    }
+    Item::Map(m) => {
+      let mut keys: Vec<String> = vec![];
+      out.push('{');
+      for (c, (k, v)) in m.iter().enumerate() {
+	let key = k.to_string();
+	if keys.contains(&key) {
+	  return Result::Err(Error::new(ErrorKind::SerializeDuplicateKey, format!("duplicate key \"{}\"", key)))
+	}
+	if c > 0 {
+	  out.push(',')
+	}
+	newline(out, indent.map(|j| j + 1));
+	out.push('"');
+	out.push_str(escape_string(key.as_str()).as_str());
+	out.push_str(if indent.is_some() {"\": "} else {"\":"});
+	serialize_sequence(v, f, out, indent.map(|j| j + 1))?;
+	keys.push(key);
+      }
+      if !m.is_empty() {
+	newline(out, indent)
+      }
+      out.push('}')
+    }
+    Item::Array(a) => {
+      out.push('[');
+      for (c, m) in a.iter().enumerate() {
+	if c > 0 {
+	  out.push(',')
+	}
+	newline(out, indent.map(|j| j + 1));
+	serialize_sequence(m, f, out, indent.map(|j| j + 1))?;
+      }
+      if !a.is_empty() {
+	newline(out, indent)
+      }
+      out.push(']')
+    }
+    Item::Function => return Result::Err(Error::new(ErrorKind::SerializeItem, String::from("unable to serialize a function as JSON"))),
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_values() {
+    let j = parse(" {\"a\": [1, -2.5e3, true, false, null], \"b\": \"x\\u00e9\\n\"} ", &ParseOptions::default()).expect("unable to parse JSON");
+    assert_eq!(j, Json::Object(vec![
+      (String::from("a"), Json::Array(vec![
+	Json::Number(String::from("1")),
+	Json::Number(String::from("-2.5e3")),
+	Json::Boolean(true),
+	Json::Boolean(false),
+	Json::Null,
+      ])),
+      (String::from("b"), Json::String(String::from("xé\n"))),
+    ]));
+  }
+  #[test]
+  fn parse_errors() {
+    for s in ["", "[1,]", "{'a':1}", "01", "[1 2]", "\"\\x\"", "\"a\nb\"", "nul", "[1] 2"] {
+      match parse(s, &ParseOptions::default()) {
+	Err(e) => assert_eq!(e.kind.to_string(), ErrorKind::JsonSyntax.to_string(), "wrong error for {}", s),
+	Ok(_) => panic!("parsed invalid JSON {}", s),
+      }
+    }
+  }
+  #[test]
+  fn parse_liberal() {
+    let o = ParseOptions{liberal: true, duplicates: Duplicates::UseFirst, escape: false};
+    assert_eq!(parse("[1, 2,]", &o).expect("unable to parse JSON"), Json::Array(vec![Json::Number(String::from("1")), Json::Number(String::from("2"))]));
+  }
+  #[test]
+  fn parse_duplicates() {
+    let s = "{\"a\": 1, \"a\": 2}";
+    assert!(parse(s, &ParseOptions::new(Duplicates::Reject)).is_err());
+    assert_eq!(parse(s, &ParseOptions::new(Duplicates::UseFirst)).expect("unable to parse JSON"), Json::Object(vec![(String::from("a"), Json::Number(String::from("1")))]));
+    assert_eq!(parse(s, &ParseOptions::new(Duplicates::UseLast)).expect("unable to parse JSON"), Json::Object(vec![(String::from("a"), Json::Number(String::from("2")))]));
+    assert_eq!(parse(s, &ParseOptions::new(Duplicates::Retain)).expect("unable to parse JSON"), Json::Object(vec![(String::from("a"), Json::Number(String::from("1"))), (String::from("a"), Json::Number(String::from("2")))]));
+  }
+  #[test]
+  fn parse_escape() {
+    let s = "\"tab\\there \\\\ \\ud83d\\ude00 \\ud800\"";
+    let mut o = ParseOptions::default();
+    assert_eq!(parse(s, &o).expect("unable to parse JSON"), Json::String(String::from("tab\there \\ \u{1F600} \u{FFFD}")));
+    o.escape = true;
+    assert_eq!(parse(s, &o).expect("unable to parse JSON"), Json::String(String::from("tab\\there \\\\ \u{1F600} \\uD800")));
+  }
+  #[test]
+  fn xml_roundtrip() {
+    let mut f = Forest::new();
+    let j = parse("{\"a\": [1, \"two\", null], \"b\": {\"c\": true}}", &ParseOptions::default()).expect("unable to parse JSON");
+    let d = j.to_xml(&mut f, false).expect("unable to create XML");
+    assert_eq!(d.get_first_element(&f).expect("no document element").to_xml(&f),
+      "<map xmlns='http://www.w3.org/2005/xpath-functions'><array key='a'><number>1</number><string>two</string><null></null></array><map key='b'><boolean key='c'>true</boolean></map></map>");
+    assert_eq!(xml_to_json(&d, &f, false).expect("unable to create JSON"), "{\"a\":[1,\"two\",null],\"b\":{\"c\":true}}");
+    assert_eq!(xml_to_json(&d, &f, true).expect("unable to create JSON"), "{\n  \"a\": [\n    1,\n    \"two\",\n    null\n  ],\n  \"b\": {\n    \"c\": true\n  }\n}");
+  }
+  #[test]
+  fn xml_invalid() {
+    let mut f = Forest::new();
+    for x in [
+      "<j:map xmlns:j='http://www.w3.org/2005/xpath-functions'><j:string>no key</j:string></j:map>",
+      "<j:array xmlns:j='http://www.w3.org/2005/xpath-functions'><j:string key='a'>key</j:string></j:array>",
+      "<j:number xmlns:j='http://www.w3.org/2005/xpath-functions'>one</j:number>",
+      "<j:map xmlns:j='http://www.w3.org/2005/xpath-functions'><j:null key='a'/><j:null key='a'/></j:map>",
+      "<map/>",
+    ] {
+      let t = f.grow_tree(x).expect("unable to parse XML");
+      let d = f.get_ref(t).unwrap().get_doc_node();
+      match xml_to_json(&d, &f, false) {
+	Err(e) => assert_eq!(e.kind.to_string(), ErrorKind::JsonInvalidXml.to_string(), "wrong error for {}", x),
+	Ok(s) => panic!("converted invalid XML {} to {}", x, s),
+      }
+    }
+  }
+  #[test]
+  fn xml_escaped() {
+    let mut f = Forest::new();
+    let t = f.grow_tree("<j:array xmlns:j='http://www.w3.org/2005/xpath-functions'><j:string escaped='true'>a\\u0041\"</j:string><j:string>b\\</j:string><j:string escaped='true'>\\q</j:string></j:array>")
+      .expect("unable to parse XML");
+    let d = f.get_ref(t).unwrap().get_doc_node();
+    match xml_to_json(&d, &f, false) {
+      Err(e) => assert_eq!(e.kind.to_string(), ErrorKind::JsonInvalidEscape.to_string()),
+      Ok(s) => panic!("converted invalid escape to {}", s),
+    }
+  }
+  #[test]
+  fn serialize_values() {
+    let od = OutputDefinition::new();
+    let j = parse("{\"a\": [1, 2.5, \"x\\\"y\", null, true], \"b\": {}}", &ParseOptions::default()).expect("unable to parse JSON");
+    assert_eq!(serialize(&j.to_sequence(), &od, None).expect("unable to serialize"), "{\"a\":[1,2.5,\"x\\\"y\",null,true],\"b\":{}}");
+    assert_eq!(serialize(&vec![], &od, None).expect("unable to serialize"), "null");
+    let s: Sequence = vec![Rc::new(Item::Value(Value::Integer(1))), Rc::new(Item::Value(Value::Integer(2)))];
+    assert_eq!(serialize(&s, &od, None).unwrap_err().kind.to_string(), ErrorKind::SerializeSequence.to_string());
+    let mut a = Array::new();
+    a.push(s);
+    assert_eq!(serialize(&vec![Rc::new(Item::Array(a))], &od, None).unwrap_err().kind.to_string(), ErrorKind::SerializeSequence.to_string());
+    assert_eq!(serialize(&vec![Rc::new(Item::Value(Value::Double(f64::NAN)))], &od, None).unwrap_err().kind.to_string(), ErrorKind::SerializeNumber.to_string());
+    let mut m = Map::new();
+    m.insert(Value::Integer(1), vec![]);
+    m.insert(Value::from("1"), vec![]);
+    assert_eq!(serialize(&vec![Rc::new(Item::Map(m))], &od, None).unwrap_err().kind.to_string(), ErrorKind::SerializeDuplicateKey.to_string());
+  }
+  #[test]
+  fn serialize_indent() {
+    let mut od = OutputDefinition::new();
+    od.set_indent(true);
+    let j = parse("{\"a\": [1, 2], \"b\": []}", &ParseOptions::default()).expect("unable to parse JSON");
+    assert_eq!(serialize(&j.to_sequence(), &od, None).expect("unable to serialize"), "{\n  \"a\": [\n    1,\n    2\n  ],\n  \"b\": []\n}");
+  }
+  #[test]
+  fn serialize_nodes() {
+    let mut f = Forest::new();
+    let t = f.grow_tree("<Test a='1'><x>\"quoted\"</x></Test>").expect("unable to parse XML");
+    let doc = f.get_ref(t).unwrap().get_doc_node();
+    let top = doc.get_first_element(&f).unwrap();
+    let od = OutputDefinition::new();
+    let mut m = Map::new();
+    m.insert(Value::from("doc"), vec![Rc::new(Item::Node(doc))]);
+    assert_eq!(serialize(&vec![Rc::new(Item::Map(m))], &od, Some(&f)).expect("unable to serialize"), "{\"doc\":\"<Test a='1'><x>\\\"quoted\\\"</x></Test>\"}");
+    let a = top.get_attribute(&f, &QualifiedName::new(None, None, String::from("a"))).unwrap();
+    assert_eq!(serialize(&vec![Rc::new(Item::Node(a))], &od, Some(&f)).unwrap_err().kind.to_string(), ErrorKind::SerializeItem.to_string());
+  }
+}
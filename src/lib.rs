@@ -45,10 +45,15 @@ pub use xdmerror::{Error, ErrorKind};
 
 pub mod qname;
 mod parsepicture;
-mod output;
+mod numbering;
+pub mod output;
 
 pub mod value;
 pub use value::Value;
+pub mod collation;
+pub mod resolver;
+pub mod message;
+pub mod resultdocument;
 pub mod forest;
 pub use forest::Node;
 pub mod item;
@@ -58,6 +63,7 @@ mod parsecommon;
 
 pub mod parsexml;
 pub use parsexml::XMLDocument;
+pub mod json;
 
 pub mod xpath;
 pub use xpath::parse;
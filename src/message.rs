@@ -0,0 +1,62 @@
+//! # xrust::message
+//!
+//! Messages are produced by the xsl:message instruction. Each [Message] is given to a [MessageListener], which is registered on the [Evaluator](crate::evaluate::Evaluator).
+//!
+//! The [StderrListener] writes messages to the standard error stream, and is used by default. The [MessageLog] keeps messages in memory, so that an application (or a test) can examine them after the transformation has finished. An application can implement its own listener, for example to send messages to a logging framework.
+//!
+//! When a message terminates the transformation the listener receives the message first, and then the transformation returns an error of kind [Terminated](crate::xdmerror::ErrorKind::Terminated).
+
+use std::cell::RefCell;
+use crate::qname::QualifiedName;
+
+/// A message produced by xsl:message.
+#[derive(Clone, Debug)]
+pub struct Message {
+  /// The content of the message, serialized as XML.
+  pub content: String,
+  /// Whether the transformation is terminated after the message.
+  pub terminate: bool,
+  /// The error code of the message. The default is err:XTMM9000.
+  pub error_code: QualifiedName,
+}
+
+/// Receives the messages produced during a transformation.
+pub trait MessageListener {
+  /// Called once for each message, in the order the messages are produced.
+  fn message(&self, m: &Message);
+}
+
+/// Writes messages to the standard error stream. Terminating messages are preceded by their error code.
+pub struct StderrListener;
+
+impl MessageListener for StderrListener {
+  fn message(&self, m: &Message) {
+    if m.terminate {
+      eprintln!("{}: {}", m.error_code.to_string(), m.content)
+    } else {
+      eprintln!("{}", m.content)
+    }
+  }
+}
+
+/// Keeps messages in memory.
+#[derive(Default)]
+pub struct MessageLog {
+  messages: RefCell<Vec<Message>>,
+}
+
+impl MessageLog {
+  pub fn new() -> Self {
+    MessageLog::default()
+  }
+  /// The messages that have been received, in order.
+  pub fn messages(&self) -> Vec<Message> {
+    self.messages.borrow().clone()
+  }
+}
+
+impl MessageListener for MessageLog {
+  fn message(&self, m: &Message) {
+    self.messages.borrow_mut().push(m.clone())
+  }
+}
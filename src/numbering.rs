@@ -0,0 +1,511 @@
+//! # xrust::numbering
+//!
+//! Formatting of integers, as used by the format-integer function and the xsl:number instruction.
+//!
+//! Supported format tokens are decimal digit patterns (including optional digits and grouping separators), roman numerals ('i', 'I'), alphabetic sequences ('a', 'A') and words ('w', 'W', 'Ww'). The ordinal modifier is supported for English.
+//!
+//! TODO: other languages, other digit families, traditional numbering
+
+use crate::xdmerror::*;
+
+/// Format an integer according to a picture string, as for the XPath format-integer function.
+pub fn format_integer(n: i64, picture: &str) -> Result<String, Error> {
+  // The last semicolon separates the primary format token from the format modifier
+  let (primary, modifier) = match picture.rfind(';') {
+    Some(i) => (&picture[..i], &picture[i + 1..]),
+    None => (picture, ""),
+  };
+  if primary.is_empty() {
+    return Result::Err(Error::new(ErrorKind::InvalidPicture, String::from("primary format token is empty")))
+  }
+  let ordinal = parse_modifier(modifier)?;
+  let tok = Token::try_from_picture(primary)?;
+
+  let mut result = String::new();
+  if n < 0 {
+    result.push('-');
+  }
+  result.push_str(tok.format(n.unsigned_abs(), ordinal, None).as_str());
+  Ok(result)
+}
+
+/// Format a list of numbers according to a format string, as for the xsl:number instruction.
+///
+/// The format string is a sequence of alternating punctuation and format tokens. If there are more numbers than format tokens, the last format token is reused.
+/// If ordinal is true, each number is given as an ordinal (e.g. "1st" or "first").
+/// The grouping argument is the grouping separator and the grouping size, which are applied to decimal format tokens.
+pub fn format_numbers(nums: &[u64], format: &str, ordinal: bool, grouping: Option<(&str, usize)>) -> String {
+  let (prefix, tokens, separators, suffix) = split_format(format);
+
+  if nums.is_empty() {
+    return String::new()
+  }
+
+  let mut result = String::from(prefix.as_str());
+  for (i, n) in nums.iter().enumerate() {
+    if i > 0 {
+      if i < tokens.len() {
+	result.push_str(separators[i - 1].as_str());
+      } else if !separators.is_empty() {
+	result.push_str(separators.last().unwrap().as_str());
+      } else {
+	result.push('.');
+      }
+    }
+    let t = &tokens[if i < tokens.len() {i} else {tokens.len() - 1}];
+    result.push_str(t.format(*n, ordinal, grouping).as_str());
+  }
+  result.push_str(suffix.as_str());
+  result
+}
+
+// Divide a format string into a prefix, format tokens, separators between the tokens, and a suffix.
+// A format token is a sequence of alphanumeric characters.
+fn split_format(format: &str) -> (String, Vec<Token>, Vec<String>, String) {
+  let mut runs: Vec<(bool, String)> = vec![];
+  for c in format.chars() {
+    let alnum = c.is_alphanumeric();
+    match runs.last_mut() {
+      Some((a, s)) if *a == alnum => s.push(c),
+      _ => runs.push((alnum, c.to_string())),
+    }
+  }
+
+  let mut prefix = String::new();
+  let mut suffix = String::new();
+  let mut tokens = vec![];
+  let mut separators = vec![];
+  let last_token = runs.iter().rposition(|(a, _)| *a);
+  for (i, (alnum, s)) in runs.iter().enumerate() {
+    if *alnum {
+      tokens.push(Token::from_format(s));
+    } else if tokens.is_empty() {
+      prefix = s.clone();
+    } else if last_token.is_some_and(|l| i > l) {
+      suffix = s.clone();
+    } else {
+      separators.push(s.clone());
+    }
+  }
+  if tokens.is_empty() {
+    tokens.push(Token::Decimal(DecimalPattern::default()));
+  }
+  (prefix, tokens, separators, suffix)
+}
+
+// Returns whether ordinal numbering has been requested.
+fn parse_modifier(m: &str) -> Result<bool, Error> {
+  let mut it = m.chars().peekable();
+  let ordinal = match it.peek() {
+    Some('o') => {
+      it.next();
+      true
+    }
+    Some('c') => {
+      it.next();
+      false
+    }
+    _ => false,
+  };
+  // Skip a parenthesised variation
+  if it.peek() == Some(&'(') {
+    loop {
+      match it.next() {
+	Some(')') => break,
+	Some(_) => {}
+	None => return Result::Err(Error::new(ErrorKind::InvalidPicture, String::from("unterminated format modifier"))),
+      }
+    }
+  }
+  match it.peek() {
+    Some('a') | Some('t') => {
+      it.next();
+    }
+    _ => {}
+  }
+  match it.next() {
+    Some(c) => Result::Err(Error::new(ErrorKind::InvalidPicture, format!("invalid character \"{}\" in format modifier", c))),
+    None => Ok(ordinal),
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+  Decimal(DecimalPattern),
+  Alphabetic(bool),	// true for uppercase
+  Roman(bool),		// true for uppercase
+  Words(WordCase),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum WordCase {
+  Lower,
+  Upper,
+  Title,
+}
+
+// A decimal digit pattern.
+// mandatory is the minimum number of digits.
+// separators are the grouping separators, given as the number of digits to the right of the separator.
+// If the separators are regular, then grouping is applied to the entire number.
+#[derive(Clone, Debug, PartialEq)]
+struct DecimalPattern {
+  mandatory: usize,
+  separators: Vec<(usize, String)>,
+  regular: bool,
+}
+
+impl Default for DecimalPattern {
+  fn default() -> Self {
+    DecimalPattern{mandatory: 1, separators: vec![], regular: false}
+  }
+}
+
+impl Token {
+  // A format token for xsl:number. Unrecognised tokens fall back to decimal.
+  fn from_format(t: &str) -> Token {
+    match t {
+      "a" => Token::Alphabetic(false),
+      "A" => Token::Alphabetic(true),
+      "i" => Token::Roman(false),
+      "I" => Token::Roman(true),
+      "w" => Token::Words(WordCase::Lower),
+      "W" => Token::Words(WordCase::Upper),
+      "Ww" => Token::Words(WordCase::Title),
+      _ => DecimalPattern::try_from_str(t)
+	.map_or(Token::Decimal(DecimalPattern::default()), Token::Decimal),
+    }
+  }
+  // A primary format token for format-integer.
+  fn try_from_picture(t: &str) -> Result<Token, Error> {
+    if t.chars().any(|c| c.is_ascii_digit()) {
+      DecimalPattern::try_from_str(t).map(Token::Decimal)
+    } else if t.chars().all(|c| c.is_alphanumeric()) {
+      Ok(Token::from_format(t))
+    } else if t.contains('#') {
+      Result::Err(Error::new(ErrorKind::InvalidPicture, String::from("decimal digit pattern has no mandatory digits")))
+    } else {
+      // Any other token is not supported, so fallback to '1'
+      Ok(Token::Decimal(DecimalPattern::default()))
+    }
+  }
+
+  fn format(&self, n: u64, ordinal: bool, grouping: Option<(&str, usize)>) -> String {
+    match self {
+      Token::Decimal(d) => {
+	let mut result = d.format(n, grouping);
+	if ordinal {
+	  result.push_str(ordinal_suffix(n));
+	}
+	result
+      }
+      Token::Alphabetic(upper) => {
+	if n == 0 {
+	  DecimalPattern::default().format(n, grouping)
+	} else {
+	  to_alphabetic(n, *upper)
+	}
+      }
+      Token::Roman(upper) => {
+	if n == 0 || n > 3999 {
+	  DecimalPattern::default().format(n, grouping)
+	} else if *upper {
+	  to_roman(n).to_uppercase()
+	} else {
+	  to_roman(n)
+	}
+      }
+      Token::Words(c) => {
+	let w = if ordinal {to_ordinal_words(n)} else {to_words(n)};
+	match c {
+	  WordCase::Lower => w,
+	  WordCase::Upper => w.to_uppercase(),
+	  WordCase::Title => {
+	    w.split(' ')
+	      .map(|s| {
+		let mut t = String::new();
+		let mut ch = s.chars();
+		if let Some(f) = ch.next() {
+		  t.extend(f.to_uppercase());
+		  t.push_str(ch.as_str());
+		}
+		t
+	      })
+	      .collect::<Vec<String>>()
+	      .join(" ")
+	  }
+	}
+      }
+    }
+  }
+}
+
+impl DecimalPattern {
+  fn try_from_str(t: &str) -> Result<DecimalPattern, Error> {
+    let mut mandatory = 0;
+    let mut optional = 0;
+    // Separators are first recorded with the number of digits to their left
+    let mut seps: Vec<(usize, String)> = vec![];
+    let mut last_was_sep = true;
+    for c in t.chars() {
+      if c.is_ascii_digit() {
+	mandatory += 1;
+	last_was_sep = false;
+      } else if c == '#' {
+	if mandatory > 0 {
+	  return Result::Err(Error::new(ErrorKind::InvalidPicture, String::from("optional digit follows a mandatory digit")))
+	}
+	optional += 1;
+	last_was_sep = false;
+      } else if c.is_alphanumeric() {
+	return Result::Err(Error::new(ErrorKind::InvalidPicture, format!("invalid character \"{}\" in decimal digit pattern", c)))
+      } else {
+	if last_was_sep {
+	  return Result::Err(Error::new(ErrorKind::InvalidPicture, String::from("grouping separator must be between digits")))
+	}
+	seps.push((mandatory + optional, c.to_string()));
+	last_was_sep = true;
+      }
+    }
+    if mandatory == 0 {
+      return Result::Err(Error::new(ErrorKind::InvalidPicture, String::from("decimal digit pattern has no mandatory digits")))
+    }
+    if last_was_sep {
+      return Result::Err(Error::new(ErrorKind::InvalidPicture, String::from("grouping separator must be between digits")))
+    }
+    // Convert positions to be counted from the right
+    let total = mandatory + optional;
+    let separators: Vec<(usize, String)> = seps.iter()
+      .rev()
+      .map(|(p, s)| (total - p, s.clone()))
+      .collect();
+    // Grouping is regular if all separators are the same, and they occur at multiples of the first position
+    let regular = !separators.is_empty() &&
+      separators.iter().all(|(_, s)| *s == separators[0].1) &&
+      separators.iter().enumerate().all(|(i, (p, _))| *p == separators[0].0 * (i + 1));
+    Ok(DecimalPattern{mandatory, separators, regular})
+  }
+
+  fn format(&self, n: u64, grouping: Option<(&str, usize)>) -> String {
+    let digits: Vec<char> = format!("{:0>w$}", n, w = self.mandatory).chars().collect();
+    let len = digits.len();
+    let mut result = String::new();
+    for (i, d) in digits.iter().enumerate() {
+      let from_right = len - i;
+      if i > 0 {
+	match grouping {
+	  Some((sep, size)) if size > 0 => {
+	    if from_right.is_multiple_of(size) {
+	      result.push_str(sep)
+	    }
+	  }
+	  Some(_) => {}
+	  None => {
+	    if self.regular {
+	      if from_right.is_multiple_of(self.separators[0].0) {
+		result.push_str(self.separators[0].1.as_str())
+	      }
+	    } else if let Some((_, s)) = self.separators.iter().find(|(p, _)| *p == from_right) {
+	      result.push_str(s.as_str())
+	    }
+	  }
+	}
+      }
+      result.push(*d);
+    }
+    result
+  }
+}
+
+fn ordinal_suffix(n: u64) -> &'static str {
+  match (n % 10, n % 100) {
+    (_, 11) | (_, 12) | (_, 13) => "th",
+    (1, _) => "st",
+    (2, _) => "nd",
+    (3, _) => "rd",
+    _ => "th",
+  }
+}
+
+// Bijective base-26: a, b, ..., z, aa, ab, ...
+fn to_alphabetic(n: u64, upper: bool) -> String {
+  let base = if upper {b'A'} else {b'a'};
+  let mut m = n;
+  let mut result = vec![];
+  while m > 0 {
+    m -= 1;
+    result.push((base + (m % 26) as u8) as char);
+    m /= 26;
+  }
+  result.iter().rev().collect()
+}
+
+fn to_roman(n: u64) -> String {
+  const NUMERALS: [(u64, &str); 13] = [
+    (1000, "m"), (900, "cm"), (500, "d"), (400, "cd"),
+    (100, "c"), (90, "xc"), (50, "l"), (40, "xl"),
+    (10, "x"), (9, "ix"), (5, "v"), (4, "iv"),
+    (1, "i"),
+  ];
+  let mut m = n;
+  let mut result = String::new();
+  for (v, s) in NUMERALS.iter() {
+    while m >= *v {
+      result.push_str(s);
+      m -= v;
+    }
+  }
+  result
+}
+
+const UNITS: [&str; 20] = [
+  "zero", "one", "two", "three", "four", "five", "six", "seven", "eight", "nine",
+  "ten", "eleven", "twelve", "thirteen", "fourteen", "fifteen", "sixteen", "seventeen", "eighteen", "nineteen",
+];
+const TENS: [&str; 10] = [
+  "", "", "twenty", "thirty", "forty", "fifty", "sixty", "seventy", "eighty", "ninety",
+];
+const SCALES: [(u64, &str); 6] = [
+  (1_000_000_000_000_000_000, "quintillion"),
+  (1_000_000_000_000_000, "quadrillion"),
+  (1_000_000_000_000, "trillion"),
+  (1_000_000_000, "billion"),
+  (1_000_000, "million"),
+  (1_000, "thousand"),
+];
+
+// English cardinal numbers, e.g. "one hundred and twenty-three"
+fn to_words(n: u64) -> String {
+  if n == 0 {
+    return String::from(UNITS[0])
+  }
+  let mut parts: Vec<String> = vec![];
+  let mut m = n;
+  for (v, s) in SCALES.iter() {
+    if m >= *v {
+      parts.push(format!("{} {}", hundreds_to_words(m / v), s));
+      m %= v;
+    }
+  }
+  if m > 0 {
+    if !parts.is_empty() && m < 100 {
+      parts.push(format!("and {}", hundreds_to_words(m)));
+    } else {
+      parts.push(hundreds_to_words(m));
+    }
+  }
+  parts.join(" ")
+}
+
+// Words for a number less than one thousand
+fn hundreds_to_words(n: u64) -> String {
+  let h = n / 100;
+  let r = n % 100;
+  let rest = if r < 20 {
+    String::from(UNITS[r as usize])
+  } else if r.is_multiple_of(10) {
+    String::from(TENS[(r / 10) as usize])
+  } else {
+    format!("{}-{}", TENS[(r / 10) as usize], UNITS[(r % 10) as usize])
+  };
+  match (h, r) {
+    (0, _) => rest,
+    (_, 0) => format!("{} hundred", UNITS[h as usize]),
+    _ => format!("{} hundred and {}", UNITS[h as usize], rest),
+  }
+}
+
+// English ordinal numbers, e.g. "one hundred and twenty-third"
+fn to_ordinal_words(n: u64) -> String {
+  let w = to_words(n);
+  // Only the last word (or hyphenated part) changes
+  let i = w.rfind([' ', '-']).map_or(0, |j| j + 1);
+  let (head, last) = w.split_at(i);
+  let ord = match last {
+    "one" => String::from("first"),
+    "two" => String::from("second"),
+    "three" => String::from("third"),
+    "five" => String::from("fifth"),
+    "eight" => String::from("eighth"),
+    "nine" => String::from("ninth"),
+    "twelve" => String::from("twelfth"),
+    l if l.ends_with('y') => format!("{}ieth", &l[..l.len() - 1]),
+    l => format!("{}th", l),
+  };
+  format!("{}{}", head, ord)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decimal_simple() {
+	assert_eq!(format_integer(123, "1").expect("unable to format"), "123")
+    }
+    #[test]
+    fn decimal_padded() {
+	assert_eq!(format_integer(7, "001").expect("unable to format"), "007")
+    }
+    #[test]
+    fn decimal_negative() {
+	assert_eq!(format_integer(-12, "1").expect("unable to format"), "-12")
+    }
+    #[test]
+    fn decimal_grouping_regular() {
+	assert_eq!(format_integer(1234567, "#,##0").expect("unable to format"), "1,234,567")
+    }
+    #[test]
+    fn decimal_grouping_irregular() {
+	assert_eq!(format_integer(1234567, "#,##,#00").expect("unable to format"), "12,34,567")
+    }
+    #[test]
+    fn decimal_bad_picture() {
+	assert!(format_integer(1, "#").is_err())
+    }
+    #[test]
+    fn decimal_ordinal() {
+	assert_eq!(format_integer(1, "1;o").expect("unable to format"), "1st");
+	assert_eq!(format_integer(22, "1;o").expect("unable to format"), "22nd");
+	assert_eq!(format_integer(113, "1;o").expect("unable to format"), "113th")
+    }
+    #[test]
+    fn roman() {
+	assert_eq!(format_integer(1994, "I").expect("unable to format"), "MCMXCIV");
+	assert_eq!(format_integer(14, "i").expect("unable to format"), "xiv")
+    }
+    #[test]
+    fn alphabetic() {
+	assert_eq!(format_integer(1, "a").expect("unable to format"), "a");
+	assert_eq!(format_integer(27, "A").expect("unable to format"), "AA");
+	assert_eq!(format_integer(702, "a").expect("unable to format"), "zz")
+    }
+    #[test]
+    fn words() {
+	assert_eq!(format_integer(123, "w").expect("unable to format"), "one hundred and twenty-three");
+	assert_eq!(format_integer(2005, "W").expect("unable to format"), "TWO THOUSAND AND FIVE");
+	assert_eq!(format_integer(21, "Ww").expect("unable to format"), "Twenty-one")
+    }
+    #[test]
+    fn words_ordinal() {
+	assert_eq!(format_integer(21, "w;o").expect("unable to format"), "twenty-first");
+	assert_eq!(format_integer(40, "w;o").expect("unable to format"), "fortieth");
+	assert_eq!(format_integer(12, "Ww;o").expect("unable to format"), "Twelfth")
+    }
+    #[test]
+    fn numbers_multiple() {
+	assert_eq!(format_numbers(&[1, 2, 3], "1.a.i", false, None), "1.b.iii")
+    }
+    #[test]
+    fn numbers_reuse_token() {
+	assert_eq!(format_numbers(&[3, 1, 4], "(1)", false, None), "(3.1.4)")
+    }
+    #[test]
+    fn numbers_separators() {
+	assert_eq!(format_numbers(&[2, 5, 6], "A-1.", false, None), "B-5-6.")
+    }
+    #[test]
+    fn numbers_grouping() {
+	assert_eq!(format_numbers(&[1234567], "1", false, Some((" ", 3))), "1 234 567")
+    }
+}
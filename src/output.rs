@@ -1,17 +1,52 @@
+//! # xrust::output
+//!
+//! Serialization parameters, i.e. xsl:output declarations.
+
 use core::fmt;
+use std::convert::TryFrom;
 use crate::qname::QualifiedName;
+use crate::xdmerror::{Error, ErrorKind};
+
+/// The serialization method. See XSLT and XQuery Serialization 3.1.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputMethod {
+  Xml,
+  Text,
+  Json,
+}
+
+impl TryFrom<&str> for OutputMethod {
+  type Error = Error;
+  /// Interpret the name of a serialization method. The html, xhtml and adaptive methods are not supported (NotImplemented); any other name is an invalid value (InvalidAttributeValue).
+  fn try_from(name: &str) -> Result<Self, Self::Error> {
+    match name.trim() {
+      "xml" => Ok(OutputMethod::Xml),
+      "text" => Ok(OutputMethod::Text),
+      "json" => Ok(OutputMethod::Json),
+      m @ ("html" | "xhtml" | "adaptive") => Result::Err(Error::new(ErrorKind::NotImplemented, format!("the {} output method is not supported", m))),
+      m => Result::Err(Error::new(ErrorKind::InvalidAttributeValue, format!("\"{}\" is not a valid output method", m))),
+    }
+  }
+}
 
 /// An output definition. See XSLT v3.0 26 Serialization
 #[derive(Clone, Debug)]
 pub struct OutputDefinition {
   name: Option<QualifiedName>,	// TODO: EQName
+  method: OutputMethod,
   indent: bool,
   // TODO: all the other myriad output parameters
 }
 
 impl OutputDefinition {
   pub fn new() -> OutputDefinition {
-    OutputDefinition{name: None, indent: false}
+    OutputDefinition{name: None, method: OutputMethod::Xml, indent: false}
+  }
+  pub fn get_method(&self) -> OutputMethod {
+    self.method
+  }
+  pub fn set_method(&mut self, m: OutputMethod) {
+    self.method = m;
   }
   pub fn get_name(&self) -> Option<QualifiedName> {
     self.name.clone()
@@ -31,6 +31,20 @@ pub fn name(input: &str) -> IResult<&str, &str> {
   )
   (input)
 }
+/// Does the string match the Name production?
+pub fn is_name(s: &str) -> bool {
+  let mut c = s.chars();
+  c.next().is_some_and(is_namestartchar) && c.all(is_namechar)
+}
+/// Does the string match the NCName production?
+pub fn is_ncname(s: &str) -> bool {
+  let mut c = s.chars();
+  c.next().is_some_and(is_ncnamestartchar) && c.all(is_ncnamechar)
+}
+/// Does the string match the Nmtoken production (NameChar+)?
+pub fn is_nmtoken(s: &str) -> bool {
+  !s.is_empty() && s.chars().all(is_namechar)
+}
 fn is_namechar(ch: char) -> bool {
   if is_namestartchar(ch) {
     true
@@ -177,5 +191,16 @@ mod tests {
   fn test_ncnamechar() {
     assert_eq!(ncnamechar("F"), Ok(("", "F")))
   }
+  #[test]
+  fn test_is_name() {
+    assert!(is_name("a"));
+    assert!(is_name("x:Foo-1"));
+    assert!(!is_name("1abc"));
+    assert!(!is_name(""));
+    assert!(is_ncname("_foo.bar"));
+    assert!(!is_ncname("x:foo"));
+    assert!(is_nmtoken("1abc"));
+    assert!(!is_nmtoken("a b"));
+  }
 }
 
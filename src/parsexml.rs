@@ -45,6 +45,21 @@ pub struct XMLDocument {
 }
 
 impl XMLDocument {
+    /// Parse an external general parsed entity, i.e. a fragment of XML that may have any number of top-level elements, as well as text, comments and processing instructions. The fragment may begin with a text declaration.
+    pub fn fragment(e: &str) -> Result<Self, Error> {
+	match tuple((opt(textdecl), content))(e) {
+	    Ok(("", (t, c))) => Ok(XMLDocument{
+		prologue: vec![],
+		content: c,
+		epilogue: vec![],
+		xmldecl: t,
+	    }),
+	    Ok((rest, _)) => Result::Err(Error{kind: ErrorKind::Unknown, message: format!("extra characters after fragment: \"{}\"", rest)}),
+	    Err(nom::Err::Error(c)) => Result::Err(Error{kind: ErrorKind::Unknown, message: format!("parser error: {:?}", c)}),
+	    Err(nom::Err::Incomplete(_)) => Result::Err(Error{kind: ErrorKind::Unknown, message: String::from("incomplete input")}),
+	    Err(nom::Err::Failure(_)) => Result::Err(Error{kind: ErrorKind::Unknown, message: String::from("unrecoverable parser error")}),
+	}
+    }
     /// Expand general entities in the document
     pub fn expand(&mut self) -> Result<(), Error> {
 	let mut ent: HashMap<QualifiedName, Vec<XMLNode>> = HashMap::new();
@@ -243,6 +258,45 @@ fn xmldecl(input: &str) -> IResult<&str, XMLdecl> {
     )(input)
 }
 
+// TextDecl ::= '<?xml' VersionInfo? EncodingDecl S? '?>'
+fn textdecl(input: &str) -> IResult<&str, XMLdecl> {
+    map(
+        tuple((
+            tag("<?xml"),
+            multispace0,
+            opt(
+                map(
+                tuple((
+                    tag("version"),
+                    multispace0,
+                    tag("="),
+                    multispace0,
+                    delimited_string
+                )), | (_,_,_,_,v) | v
+            )),
+            multispace0,
+            map(
+                tuple((
+                    tag("encoding"),
+                    multispace0,
+                    tag("="),
+                    multispace0,
+                    delimited_string
+                )), | (_,_,_,_,e) | e
+            ),
+            multispace0,
+            tag("?>")
+        )),
+        |(_,_, ver, _, enc,_,_)| {
+            XMLdecl{
+                version: ver.unwrap_or_else(|| String::from("1.0")),
+                encoding: Some(enc),
+                standalone: None
+            }
+        }
+    )(input)
+}
+
 fn doctypedecl(input: &str) -> IResult<&str, Vec<XMLNode>> {
     map(
         tuple((
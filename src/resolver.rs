@@ -0,0 +1,322 @@
+//! # xrust::resolver
+//!
+//! Secondary documents, i.e. documents other than the source document, are loaded by the fn:doc and fn:collection functions and the XSLT document() function. Unparsed text resources are loaded by fn:unparsed-text, and the functions based on it such as fn:json-doc.
+//!
+//! A document is identified by an absolute URI. A [DocumentResolver] retrieves the content of a document, which is then parsed into a [Tree](crate::forest::Tree) in the [Forest]. The [Documents] cache remembers which tree a URI was loaded into, so that the same URI always returns the same document node.
+//!
+//! The [FileResolver] loads documents from the file system, and is used by default. The [MemoryResolver] holds documents in memory, which is useful for testing. An application can implement its own resolver, for example to fetch documents over the network, and give it to the [Evaluator](crate::evaluate::Evaluator).
+//!
+//! Stylesheet modules are retrieved directly, using [fetch]. Unparsed text is retrieved by the resolver, and decoded using [decode].
+
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::fs;
+use url::Url;
+use crate::xdmerror::*;
+use crate::forest::{Forest, Node, TreeIndex};
+use crate::parsecommon::is_char;
+
+/// Retrieve the resource identified by an absolute URI. The "file:" and "http:" (or "https:") schemes are supported.
+pub fn fetch(url: &Url) -> Result<Vec<u8>, Error> {
+  match url.scheme() {
+    "http" | "https" => {
+      reqwest::blocking::get(url.to_string())
+	.and_then(|r| r.error_for_status())
+	.and_then(|r| r.bytes())
+	.map(|b| b.to_vec())
+	.map_err(|e| Error::new(ErrorKind::Unknown, format!("unable to fetch \"{}\": {}", url, e)))
+    }
+    "file" => {
+      let p = url.to_file_path()
+	.map_err(|_| Error::new(ErrorKind::Unknown, format!("unable to find a file for URL \"{}\"", url)))?;
+      fs::read(p)
+	.map_err(|e| Error::new(ErrorKind::Unknown, format!("unable to read \"{}\": {}", url, e)))
+    }
+    _ => Result::Err(Error::new(ErrorKind::Unknown, format!("unable to fetch URL \"{}\"", url))),
+  }
+}
+
+/// Decode text. A byte order mark determines the encoding, otherwise the given encoding is used. The default encoding is UTF-8.
+///
+/// The supported encodings are UTF-8, UTF-16 (big-endian unless there is a byte order mark), UTF-16BE, UTF-16LE, ISO-8859-1 and US-ASCII. An unsupported encoding, content that cannot be decoded, or characters that are not permitted in XML, are an error (FOUT1190).
+pub fn decode(b: &[u8], encoding: Option<&str>) -> Result<String, Error> {
+  let bad = |e: &str| Error::new(ErrorKind::UnparsedTextEncoding, format!("unable to decode text as {}", e));
+  let (enc, content) = if let Some(rest) = b.strip_prefix(&[0xEF, 0xBB, 0xBF]) {
+    (String::from("utf-8"), rest)
+  } else if let Some(rest) = b.strip_prefix(&[0xFE, 0xFF]) {
+    (String::from("utf-16be"), rest)
+  } else if let Some(rest) = b.strip_prefix(&[0xFF, 0xFE]) {
+    (String::from("utf-16le"), rest)
+  } else {
+    (encoding.unwrap_or("utf-8").trim().to_ascii_lowercase(), b)
+  };
+  let utf16 = |big: bool| {
+    if content.len() % 2 != 0 {
+      return Result::Err(bad(enc.as_str()))
+    }
+    let units: Vec<u16> = content.chunks(2)
+      .map(|c| if big {u16::from_be_bytes([c[0], c[1]])} else {u16::from_le_bytes([c[0], c[1]])})
+      .collect();
+    String::from_utf16(&units).map_err(|_| bad(enc.as_str()))
+  };
+  let s = match enc.as_str() {
+    "utf-8" | "utf8" => String::from_utf8(content.to_vec()).map_err(|_| bad("UTF-8"))?,
+    "utf-16" | "utf-16be" => utf16(true)?,
+    "utf-16le" => utf16(false)?,
+    "iso-8859-1" | "latin1" | "latin-1" => content.iter().map(|c| char::from(*c)).collect(),
+    "us-ascii" | "ascii" => {
+      if content.is_ascii() {
+	content.iter().map(|c| char::from(*c)).collect()
+      } else {
+	return Result::Err(bad("US-ASCII"))
+      }
+    }
+    _ => return Result::Err(Error::new(ErrorKind::UnparsedTextEncoding, format!("encoding \"{}\" is not supported", enc))),
+  };
+  match s.chars().find(|c| !is_char(c)) {
+    Some(c) => Result::Err(Error::new(ErrorKind::UnparsedTextEncoding, format!("text contains the character #x{:X}, which is not permitted in XML", c as u32))),
+    None => Ok(s),
+  }
+}
+
+/// Retrieves the content of documents and collections.
+pub trait DocumentResolver {
+  /// Retrieve the content of the document identified by an absolute URI.
+  /// If the document cannot be retrieved the error is FODC0002.
+  fn fetch(&self, uri: &Url) -> Result<String, Error>;
+  /// Retrieve the content of the unparsed text resource identified by an absolute URI. The content is decoded by the caller.
+  /// If the resource cannot be retrieved the error is FOUT1170.
+  /// The default implementation retrieves the resource as a document, encoded as UTF-8.
+  fn fetch_text(&self, uri: &Url) -> Result<Vec<u8>, Error> {
+    self.fetch(uri)
+      .map(String::into_bytes)
+      .map_err(|e| Error::new(ErrorKind::UnparsedTextNotFound, e.message))
+  }
+  /// The URIs of the documents in a collection. If no URI is given then the default collection is returned.
+  /// The default implementation has no collections.
+  fn collection(&self, uri: Option<&Url>) -> Result<Vec<Url>, Error> {
+    Result::Err(Error::new(ErrorKind::DocumentNotFound, format!("collection \"{}\" not found", uri.map_or(String::from("--default--"), |u| u.to_string()))))
+  }
+}
+
+/// Loads documents from the file system. Only "file:" URIs are supported.
+///
+/// A collection is a directory; the documents in the collection are the files in the directory with the extension ".xml", in name order. There is no default collection.
+pub struct FileResolver;
+
+impl FileResolver {
+  fn path(uri: &Url) -> Result<std::path::PathBuf, Error> {
+    if uri.scheme() == "file" {
+      uri.to_file_path()
+	.map_err(|_| Error::new(ErrorKind::DocumentNotFound, format!("unable to find a file for URI \"{}\"", uri)))
+    } else {
+      Result::Err(Error::new(ErrorKind::DocumentNotFound, format!("unable to retrieve \"{}\": scheme \"{}\" is not supported", uri, uri.scheme())))
+    }
+  }
+}
+
+impl DocumentResolver for FileResolver {
+  fn fetch(&self, uri: &Url) -> Result<String, Error> {
+    fs::read_to_string(FileResolver::path(uri)?)
+      .map_err(|e| Error::new(ErrorKind::DocumentNotFound, format!("unable to read \"{}\": {}", uri, e)))
+  }
+  fn fetch_text(&self, uri: &Url) -> Result<Vec<u8>, Error> {
+    let p = FileResolver::path(uri)
+      .map_err(|e| Error::new(ErrorKind::UnparsedTextNotFound, e.message))?;
+    fs::read(p)
+      .map_err(|e| Error::new(ErrorKind::UnparsedTextNotFound, format!("unable to read \"{}\": {}", uri, e)))
+  }
+  fn collection(&self, uri: Option<&Url>) -> Result<Vec<Url>, Error> {
+    let u = uri.ok_or_else(|| Error::new(ErrorKind::DocumentNotFound, String::from("there is no default collection")))?;
+    let entries = fs::read_dir(FileResolver::path(u)?)
+      .map_err(|e| Error::new(ErrorKind::DocumentNotFound, format!("unable to read collection \"{}\": {}", u, e)))?;
+    let mut paths: Vec<_> = entries
+      .filter_map(|d| d.ok().map(|e| e.path()))
+      .filter(|p| p.is_file() && p.extension().is_some_and(|x| x == "xml"))
+      .collect();
+    paths.sort();
+    paths.iter()
+      .map(|p| Url::from_file_path(p)
+	.map_err(|_| Error::new(ErrorKind::DocumentNotFound, format!("unable to create a URI for \"{}\"", p.display()))))
+      .collect()
+  }
+}
+
+/// Holds documents and collections in memory.
+#[derive(Default)]
+pub struct MemoryResolver {
+  docs: HashMap<String, String>,
+  collections: HashMap<Option<String>, Vec<Url>>,
+}
+
+impl MemoryResolver {
+  pub fn new() -> Self {
+    MemoryResolver::default()
+  }
+  /// Add a document with the given absolute URI.
+  pub fn add_document(&mut self, uri: &str, content: &str) -> Result<(), Error> {
+    let u = Url::parse(uri)
+      .map_err(|_| Error::new(ErrorKind::InvalidDocumentURI, format!("\"{}\" is not an absolute URI", uri)))?;
+    self.docs.insert(u.to_string(), content.to_string());
+    Ok(())
+  }
+  /// Define a collection. If no URI is given then this is the default collection.
+  pub fn add_collection(&mut self, uri: Option<&str>, docs: Vec<&str>) -> Result<(), Error> {
+    let key = match uri {
+      Some(c) => Some(Url::parse(c)
+	.map_err(|_| Error::new(ErrorKind::InvalidDocumentURI, format!("\"{}\" is not an absolute URI", c)))?
+	.to_string()),
+      None => None,
+    };
+    let members = docs.iter()
+      .map(|d| Url::parse(d)
+	.map_err(|_| Error::new(ErrorKind::InvalidDocumentURI, format!("\"{}\" is not an absolute URI", d))))
+      .collect::<Result<Vec<Url>, Error>>()?;
+    self.collections.insert(key, members);
+    Ok(())
+  }
+}
+
+impl DocumentResolver for MemoryResolver {
+  fn fetch(&self, uri: &Url) -> Result<String, Error> {
+    self.docs.get(uri.as_str())
+      .cloned()
+      .ok_or_else(|| Error::new(ErrorKind::DocumentNotFound, format!("document \"{}\" not found", uri)))
+  }
+  fn collection(&self, uri: Option<&Url>) -> Result<Vec<Url>, Error> {
+    self.collections.get(&uri.map(|u| u.to_string()))
+      .cloned()
+      .ok_or_else(|| Error::new(ErrorKind::DocumentNotFound, format!("collection \"{}\" not found", uri.map_or(String::from("--default--"), |u| u.to_string()))))
+  }
+}
+
+/// The documents that have been loaded, and the resolver used to load new documents.
+///
+/// Documents are cached by their URI (without any fragment identifier). The cache records the [Tree](crate::forest::Tree) that each document was parsed into, so the same [Forest] must be used for all evaluations that share the cache.
+#[derive(Clone)]
+pub struct Documents {
+  resolver: Rc<dyn DocumentResolver>,
+  cache: Rc<RefCell<HashMap<String, TreeIndex>>>,
+}
+
+impl Documents {
+  /// Create an empty cache that loads documents using the given resolver.
+  pub fn new(resolver: Rc<dyn DocumentResolver>) -> Self {
+    Documents{resolver, cache: Rc::new(RefCell::new(HashMap::new()))}
+  }
+  /// Returns the document node for the document with the given absolute URI, loading the document if it has not already been loaded.
+  pub fn document(&self, uri: &Url, f: &mut Forest) -> Result<Node, Error> {
+    let mut u = uri.clone();
+    u.set_fragment(None);
+    let cached = self.cache.borrow().get(u.as_str()).cloned();
+    let ti = match cached {
+      Some(ti) => ti,
+      None => {
+	let content = self.resolver.fetch(&u)?;
+	let ti = f.grow_tree(content.as_str().trim())
+	  .map_err(|e| Error::new(ErrorKind::DocumentNotFound, format!("unable to parse \"{}\": {}", u, e)))?;
+	if let Some(t) = f.get_ref_mut(ti) {
+	  t.set_base_uri(Some(u.to_string()));
+	  t.set_document_uri(Some(u.to_string()));
+	}
+	self.cache.borrow_mut().insert(u.to_string(), ti);
+	ti
+      }
+    };
+    f.get_ref(ti)
+      .map(|t| t.get_doc_node())
+      .ok_or_else(|| Error::new(ErrorKind::DocumentNotFound, format!("document \"{}\" is not in this forest", u)))
+  }
+  /// Returns the content of the unparsed text resource with the given absolute URI. Unlike documents, text is not cached.
+  pub fn text(&self, uri: &Url) -> Result<Vec<u8>, Error> {
+    self.resolver.fetch_text(uri)
+  }
+  /// Returns the document nodes of the documents in a collection. If no URI is given then the default collection is returned.
+  pub fn collection(&self, uri: Option<&Url>, f: &mut Forest) -> Result<Vec<Node>, Error> {
+    self.resolver.collection(uri)?
+      .iter()
+      .map(|u| self.document(u, f))
+      .collect()
+  }
+}
+
+impl Default for Documents {
+  fn default() -> Self {
+    Documents::new(Rc::new(FileResolver))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn decode_utf8() {
+    assert_eq!(decode("héllo".as_bytes(), None).expect("unable to decode"), "héllo");
+    assert_eq!(decode(&[0xEF, 0xBB, 0xBF, 0x41], Some("iso-8859-1")).expect("unable to decode"), "A");
+    assert!(decode(&[0xC3, 0x28], None).is_err());
+  }
+  #[test]
+  fn decode_utf16() {
+    assert_eq!(decode(&[0xFF, 0xFE, 0x41, 0x00, 0x42, 0x00], None).expect("unable to decode"), "AB");
+    assert_eq!(decode(&[0x00, 0x41, 0x00, 0x42], Some("UTF-16")).expect("unable to decode"), "AB");
+    assert_eq!(decode(&[0x41, 0x00], Some("utf-16le")).expect("unable to decode"), "A");
+    assert!(decode(&[0x00, 0x41, 0x00], Some("utf-16")).is_err());
+  }
+  #[test]
+  fn decode_other() {
+    assert_eq!(decode(&[0x63, 0x61, 0x66, 0xE9], Some("ISO-8859-1")).expect("unable to decode"), "café");
+    assert!(decode(&[0x63, 0x61, 0x66, 0xE9], Some("us-ascii")).is_err());
+    assert!(decode(b"text", Some("ebcdic")).is_err());
+    // Not an XML character
+    assert!(decode(&[0x41, 0x01], None).is_err());
+  }
+  #[test]
+  fn memory_cached() {
+    let mut r = MemoryResolver::new();
+    r.add_document("http://example.org/a.xml", "<A>a</A>").expect("unable to add document");
+    let d = Documents::new(Rc::new(r));
+    let mut f = Forest::new();
+    let u = Url::parse("http://example.org/a.xml").expect("unable to parse URL");
+    let first = d.document(&u, &mut f).expect("unable to load document");
+    let second = d.document(&Url::parse("http://example.org/a.xml#frag").expect("unable to parse URL"), &mut f).expect("unable to load document");
+    assert_eq!(first, second);
+    assert_eq!(first.get_first_element(&f).expect("no document element").to_xml(&f), "<A>a</A>");
+    assert_eq!(d.document(&Url::parse("http://example.org/b.xml").expect("unable to parse URL"), &mut f).err().map(|e| e.kind.to_string()), Some(ErrorKind::DocumentNotFound.to_string()))
+  }
+  #[test]
+  fn memory_collection() {
+    let mut r = MemoryResolver::new();
+    r.add_document("http://example.org/a.xml", "<A/>").expect("unable to add document");
+    r.add_document("http://example.org/b.xml", "<B/>").expect("unable to add document");
+    r.add_collection(None, vec!["http://example.org/b.xml", "http://example.org/a.xml"]).expect("unable to add collection");
+    let d = Documents::new(Rc::new(r));
+    let mut f = Forest::new();
+    let c = d.collection(None, &mut f).expect("unable to load collection");
+    assert_eq!(c.iter().map(|n| n.get_first_element(&f).expect("no document element").to_xml(&f)).collect::<Vec<String>>(), vec!["<B></B>", "<A></A>"]);
+    assert!(d.collection(Some(&Url::parse("http://example.org/none").expect("unable to parse URL")), &mut f).is_err())
+  }
+  #[test]
+  fn file() {
+    let dir = Url::from_directory_path(format!("{}/tests/xml/", env!("CARGO_MANIFEST_DIR"))).expect("unable to create URL");
+    let d = Documents::default();
+    let mut f = Forest::new();
+    let doc = d.document(&dir.join("test1.xml").expect("unable to join URL"), &mut f).expect("unable to load document");
+    assert_eq!(doc.get_first_element(&f).expect("no document element").to_xml(&f), "<Test>preamble<data>document content</data>prologue</Test>");
+  }
+  #[test]
+  fn file_collection() {
+    let dir = std::env::temp_dir().join(format!("xrust-collection-{}", std::process::id()));
+    fs::create_dir_all(&dir).expect("unable to create directory");
+    fs::write(dir.join("b.xml"), "<B/>").expect("unable to write file");
+    fs::write(dir.join("a.xml"), "<A/>").expect("unable to write file");
+    fs::write(dir.join("c.txt"), "not XML").expect("unable to write file");
+    let d = Documents::default();
+    let mut f = Forest::new();
+    let c = d.collection(Some(&Url::from_directory_path(&dir).expect("unable to create URL")), &mut f);
+    fs::remove_dir_all(&dir).expect("unable to remove directory");
+    let c = c.expect("unable to load collection");
+    assert_eq!(c.iter().map(|n| n.get_first_element(&f).expect("no document element").to_xml(&f)).collect::<Vec<String>>(), vec!["<A></A>", "<B></B>"]);
+  }
+}
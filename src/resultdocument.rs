@@ -0,0 +1,147 @@
+//! # xrust::resultdocument
+//!
+//! A transformation can produce secondary result documents, using the xsl:result-document instruction. Each result document is a [Tree](crate::forest::Tree) that is given to a [ResultDocumentHandler], which is registered on the [Evaluator](crate::evaluate::Evaluator).
+//!
+//! A result document is identified by an absolute URI, which is the href of the instruction resolved against the base output URI. The handler also receives the [OutputDefinition] for the document, i.e. the named xsl:output declaration given by the format attribute, modified by any serialization attributes of the instruction.
+//!
+//! The [RejectHandler] is used by default: it raises an error for every result document, so that a stylesheet cannot write files unless the application allows it. The [FileHandler] serializes result documents to the file system, but only below a given directory. The [MemoryHandler] keeps serialized result documents in memory, which is useful for testing.
+
+use std::cell::RefCell;
+use std::fs;
+use url::Url;
+use crate::xdmerror::*;
+use crate::forest::{Forest, Node};
+use crate::output::{OutputDefinition, OutputMethod};
+
+/// Serialize a result document using the given output definition.
+pub fn serialize(n: &Node, f: &Forest, od: &OutputDefinition) -> Result<String, Error> {
+  match od.get_method() {
+    OutputMethod::Xml => Ok(n.to_xml_with_options(f, od)),
+    OutputMethod::Text => Ok(n.to_string(f)),
+    OutputMethod::Json => n.to_json(f),
+  }
+}
+
+/// Receives the result documents produced by xsl:result-document.
+pub trait ResultDocumentHandler {
+  /// Called once for each result document. The URI is absolute, and no two result documents in a transformation have the same URI.
+  fn result_document(&self, uri: &Url, doc: &Node, f: &Forest, od: &OutputDefinition) -> Result<(), Error>;
+}
+
+/// Rejects every result document.
+pub struct RejectHandler;
+
+impl ResultDocumentHandler for RejectHandler {
+  fn result_document(&self, uri: &Url, _doc: &Node, _f: &Forest, _od: &OutputDefinition) -> Result<(), Error> {
+    Result::Err(Error::new(ErrorKind::ResultDocumentRejected, format!("unable to write \"{}\": no result document handler has been set", uri)))
+  }
+}
+
+/// Serializes result documents to the file system. Only result documents whose URI is in the directory of the base URI (or one of its subdirectories) are written; any other result document is rejected. Only "file:" URIs are supported. Directories are created as needed.
+pub struct FileHandler {
+  dir: Url,
+}
+
+impl FileHandler {
+  /// Create a handler that writes files in the directory of the given URI, which is usually the base output URI.
+  pub fn new(base: &Url) -> Result<Self, Error> {
+    if base.scheme() != "file" {
+      return Result::Err(Error::new(ErrorKind::Unknown, format!("unable to write to \"{}\": scheme \"{}\" is not supported", base, base.scheme())))
+    }
+    let dir = base.join(".")
+      .map_err(|_| Error::new(ErrorKind::Unknown, format!("unable to find the directory of \"{}\"", base)))?;
+    Ok(FileHandler{dir})
+  }
+}
+
+impl ResultDocumentHandler for FileHandler {
+  fn result_document(&self, uri: &Url, doc: &Node, f: &Forest, od: &OutputDefinition) -> Result<(), Error> {
+    // URIs are normalised when they are parsed, so a path that escapes the directory (using "..") does not have the directory as its prefix
+    if uri.scheme() != "file" || uri.host() != self.dir.host() || !uri.path().starts_with(self.dir.path()) {
+      return Result::Err(Error::new(ErrorKind::ResultDocumentRejected, format!("unable to write \"{}\": it is not in the directory \"{}\"", uri, self.dir)))
+    }
+    let p = uri.to_file_path()
+      .map_err(|_| Error::new(ErrorKind::Unknown, format!("unable to find a file for URI \"{}\"", uri)))?;
+    if let Some(d) = p.parent() {
+      fs::create_dir_all(d)
+	.map_err(|e| Error::new(ErrorKind::Unknown, format!("unable to create directory \"{}\": {}", d.display(), e)))?;
+    }
+    fs::write(&p, serialize(doc, f, od)?)
+      .map_err(|e| Error::new(ErrorKind::Unknown, format!("unable to write \"{}\": {}", uri, e)))
+  }
+}
+
+/// Keeps serialized result documents in memory.
+#[derive(Default)]
+pub struct MemoryHandler {
+  docs: RefCell<Vec<(Url, String)>>,
+}
+
+impl MemoryHandler {
+  pub fn new() -> Self {
+    MemoryHandler::default()
+  }
+  /// The URIs and serialized content of the result documents, in the order they were produced.
+  pub fn documents(&self) -> Vec<(Url, String)> {
+    self.docs.borrow().clone()
+  }
+}
+
+impl ResultDocumentHandler for MemoryHandler {
+  fn result_document(&self, uri: &Url, doc: &Node, f: &Forest, od: &OutputDefinition) -> Result<(), Error> {
+    self.docs.borrow_mut().push((uri.clone(), serialize(doc, f, od)?));
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn file() {
+    let dir = std::env::temp_dir().join(format!("xrust-result-{}", std::process::id()));
+    let mut f = Forest::new();
+    let t = f.grow_tree("<a>text</a>").expect("unable to parse XML");
+    let doc = f.get_ref(t).expect("no tree").get_doc_node();
+    let uri = Url::from_directory_path(&dir).expect("unable to create URL").join("sub/a.xml").expect("unable to join URL");
+    let h = FileHandler::new(&Url::from_directory_path(&dir).expect("unable to create URL").join("index.xml").expect("unable to join URL"))
+      .expect("unable to create handler");
+    let mut od = OutputDefinition::new();
+    h.result_document(&uri, &doc, &f, &od).expect("unable to write result document");
+    let xml = fs::read_to_string(dir.join("sub").join("a.xml"));
+    od.set_method(OutputMethod::Text);
+    h.result_document(&uri, &doc, &f, &od).expect("unable to write result document");
+    let text = fs::read_to_string(dir.join("sub").join("a.xml"));
+    fs::remove_dir_all(&dir).expect("unable to remove directory");
+    assert_eq!(xml.expect("unable to read file"), "<a>text</a>");
+    assert_eq!(text.expect("unable to read file"), "text");
+    assert!(matches!(
+      h.result_document(&Url::parse("http://example.org/a.xml").expect("unable to parse URL"), &doc, &f, &od),
+      Err(Error{kind: ErrorKind::ResultDocumentRejected, ..})
+    ));
+    let outside = Url::from_directory_path(&dir).expect("unable to create URL").join("../b.xml").expect("unable to join URL");
+    assert!(matches!(
+      h.result_document(&outside, &doc, &f, &od),
+      Err(Error{kind: ErrorKind::ResultDocumentRejected, ..})
+    ));
+    assert!(!outside.to_file_path().expect("no file path").exists());
+    // A directory whose name begins with the name of the handler's directory
+    let sibling = Url::from_file_path(dir.with_file_name(format!("xrust-result-{}-x", std::process::id())).join("b.xml")).expect("unable to create URL");
+    assert!(matches!(
+      h.result_document(&sibling, &doc, &f, &od),
+      Err(Error{kind: ErrorKind::ResultDocumentRejected, ..})
+    ));
+    assert!(FileHandler::new(&Url::parse("http://example.org/").expect("unable to parse URL")).is_err())
+  }
+  #[test]
+  fn reject() {
+    let mut f = Forest::new();
+    let t = f.grow_tree("<a/>").expect("unable to parse XML");
+    let doc = f.get_ref(t).expect("no tree").get_doc_node();
+    assert!(matches!(
+      RejectHandler.result_document(&Url::parse("file:///out/a.xml").expect("unable to parse URL"), &doc, &f, &OutputDefinition::new()),
+      Err(Error{kind: ErrorKind::ResultDocumentRejected, ..})
+    ))
+  }
+}
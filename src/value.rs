@@ -6,10 +6,14 @@ use core::fmt;
 use std::convert::TryFrom;
 use std::cmp::Ordering;
 use rust_decimal::Decimal;
+use std::str::FromStr;
+use rust_decimal::prelude::{ToPrimitive, FromPrimitive, RoundingStrategy};
 #[cfg(test)]
 use rust_decimal_macros::dec;
 use chrono::{Date, DateTime, Local};
 use crate::xdmerror::{Error, ErrorKind};
+use crate::parsecommon::{is_name, is_ncname, is_nmtoken};
+use crate::qname::QualifiedName;
 
 /// Comparison operators for values
 #[derive(Copy, Clone)]
@@ -41,6 +45,32 @@ impl Operator {
   }
 }
 
+/// Arithmetic operators for numeric values
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ArithmeticOperator {
+  Noop,
+  Add,
+  Multiply,
+  Divide,
+  IntegerDivide,
+  Subtract,
+  Modulo,
+}
+
+impl From<&str> for ArithmeticOperator {
+  fn from(a: &str) -> Self {
+    match a {
+      "+" => ArithmeticOperator::Add,
+      "*" => ArithmeticOperator::Multiply,
+      "div" => ArithmeticOperator::Divide,
+      "idiv" => ArithmeticOperator::IntegerDivide,
+      "-" => ArithmeticOperator::Subtract,
+      "mod" => ArithmeticOperator::Modulo,
+      _ => ArithmeticOperator::Noop,
+    }
+  }
+}
+
 /// A concrete type that implements atomic values.
 /// These are the 19 predefined types in XSD Schema Part 2, plus five additional types.
 #[derive(Clone)]
@@ -52,11 +82,11 @@ pub enum Value {
     /// base type of all simple types. i.e. not a node
     AnySimpleType,
     /// a list of IDREF
-    IDREFS,
+    IDREFS(IDREFS),
     /// a list of NMTOKEN
-    NMTOKENS,
+    NMTOKENS(NMTOKENS),
     /// a list of ENTITY
-    ENTITIES,
+    ENTITIES(ENTITIES),
     /// Any numeric type
     Numeric,
     /// all atomic values (no lists or unions)
@@ -87,22 +117,41 @@ pub enum Value {
     String(String),
     NormalizedString(NormalizedString),
     /// Like normalizedString, but without leading, trailing and consecutive whitespace
-    Token,
+    Token(Token),
     /// language identifiers [a-zA-Z]{1,8}(-[a-zA-Z0-9]{1,8})*
-    Language,
+    Language(Language),
     /// NameChar+
-    NMTOKEN,
+    NMTOKEN(NMTOKEN),
     /// NameStartChar NameChar+
-    Name,
+    Name(Name),
     /// (Letter | '_') NCNameChar+ (i.e. a Name without the colon)
-    NCName,
+    NCName(NCName),
     /// Same format as NCName
-    ID,
+    ID(ID),
     /// Same format as NCName
-    IDREF,
+    IDREF(IDREF),
     /// Same format as NCName
-    ENTITY,
+    ENTITY(ENTITY),
     Boolean(bool),
+    /// Binary data, represented lexically as base64
+    Base64Binary(Vec<u8>),
+    /// Binary data, represented lexically as hexadecimal digits
+    HexBinary(Vec<u8>),
+    /// A qualified name
+    QName(QualifiedName),
+    /// A URI reference, which may be relative
+    AnyURI(String),
+}
+
+// The lexical representation of xs:double special values differs from Rust's
+fn double_to_string(d: f64) -> String {
+    if d.is_nan() {
+	String::from("NaN")
+    } else if d.is_infinite() {
+	String::from(if d > 0.0 {"INF"} else {"-INF"})
+    } else {
+	d.to_string()
+    }
 }
 
 impl Value {
@@ -111,9 +160,20 @@ impl Value {
 	match self {
 	    Value::String(s) => s.to_string(),
 	    Value::NormalizedString(s) => s.0.to_string(),
-	    Value::Decimal(d) => d.to_string(),
-	    Value::Float(f) => f.to_string(),
-	    Value::Double(d) => d.to_string(),
+	    Value::Token(t) => t.to_string(),
+	    Value::Language(l) => l.to_string(),
+	    Value::NMTOKEN(n) => n.to_string(),
+	    Value::Name(n) => n.to_string(),
+	    Value::NCName(n) => n.to_string(),
+	    Value::ID(n) => n.to_string(),
+	    Value::IDREF(n) => n.to_string(),
+	    Value::ENTITY(n) => n.to_string(),
+	    Value::IDREFS(l) => l.to_string(),
+	    Value::NMTOKENS(l) => l.to_string(),
+	    Value::ENTITIES(l) => l.to_string(),
+	    Value::Decimal(d) => d.normalize().to_string(),
+	    Value::Float(f) => if f.is_finite() {f.to_string()} else {double_to_string(*f as f64)},
+	    Value::Double(d) => double_to_string(*d),
 	    Value::Integer(i) => i.to_string(),
 	    Value::Long(l) => l.to_string(),
 	    Value::Short(s) => s.to_string(),
@@ -130,6 +190,11 @@ impl Value {
 	    Value::Time(t) => t.format("%H:%M:%S.%f").to_string(),
 	    Value::DateTime(dt) => dt.format("%Y-%m-%dT%H:%M:%S%z").to_string(),
 	    Value::Date(d) => d.format("%Y-%m-%d").to_string(),
+	    Value::Boolean(b) => b.to_string(),
+	    Value::Base64Binary(b) => base64_encode(b),
+	    Value::HexBinary(b) => hex_encode(b),
+	    Value::QName(q) => q.to_string(),
+	    Value::AnyURI(u) => u.clone(),
  	    _ => "".to_string(),
 	}
     }
@@ -143,7 +208,11 @@ impl Value {
 	        t.len() != 0
             },
 	    Value::NormalizedString(s) => s.0.len() != 0,
-            Value::Double(n) => *n != 0.0,
+	    v if v.is_string_derived() => !v.to_string().is_empty(),
+	    Value::AnyURI(u) => !u.is_empty(),
+            Value::Double(n) => *n != 0.0 && !n.is_nan(),
+            Value::Float(n) => *n != 0.0 && !n.is_nan(),
+            Value::Decimal(d) => !d.is_zero(),
             Value::Integer(i) => *i != 0,
             Value::Int(i) => *i != 0,
             _ => false
@@ -174,7 +243,7 @@ impl Value {
 	    }
             Value::Integer(i) => (*i) as f64,
             Value::Double(d) => *d,
-            _ => f64::NAN,
+            _ => Numeric::from_value(self).map_or(f64::NAN, |n| n.to_double()),
 	}
     }
     pub fn value_type(&self) -> &'static str {
@@ -182,9 +251,9 @@ impl Value {
         Value::AnyType => "AnyType",
         Value::Untyped => "Untyped",
         Value::AnySimpleType => "AnySimpleType",
-        Value::IDREFS => "IDREFS",
-        Value::NMTOKENS => "NMTOKENS",
-        Value::ENTITIES => "ENTITIES",
+        Value::IDREFS(_) => "IDREFS",
+        Value::NMTOKENS(_) => "NMTOKENS",
+        Value::ENTITIES(_) => "ENTITIES",
         Value::Numeric => "Numeric",
         Value::AnyAtomicType => "AnyAtomicType",
         Value::UntypedAtomic => "UntypedAtomic",
@@ -211,18 +280,44 @@ impl Value {
         Value::Date(_) => "Date",
         Value::String(_) => "String",
         Value::NormalizedString(_) => "NormalizedString",
-        Value::Token => "Token",
-        Value::Language => "Language",
-        Value::NMTOKEN => "NMTOKEN",
-        Value::Name => "Name",
-        Value::NCName => "NCName",
-        Value::ID => "ID",
-        Value::IDREF => "IDREF",
-        Value::ENTITY => "ENTITY",
+        Value::Token(_) => "Token",
+        Value::Language(_) => "Language",
+        Value::NMTOKEN(_) => "NMTOKEN",
+        Value::Name(_) => "Name",
+        Value::NCName(_) => "NCName",
+        Value::ID(_) => "ID",
+        Value::IDREF(_) => "IDREF",
+        Value::ENTITY(_) => "ENTITY",
 	Value::Boolean(_) => "boolean",
+	Value::Base64Binary(_) => "Base64Binary",
+	Value::HexBinary(_) => "HexBinary",
+	Value::QName(_) => "QName",
+	Value::AnyURI(_) => "AnyURI",
       }
     }
     pub fn compare(&self, other: &Value, op: Operator) -> Result<bool, Error> {
+	if self.is_numeric() && other.is_numeric() {
+	    return compare_numeric(self, other, op)
+	}
+	if self.is_string_derived() || matches!(self, Value::AnyURI(_)) {
+	    return Value::String(self.to_string()).compare(other, op)
+	}
+	if let Value::QName(q) = self {
+	    return match (op, other) {
+		(Operator::Equal, Value::QName(r)) => Ok(q == r),
+		(Operator::NotEqual, Value::QName(r)) => Ok(q != r),
+		_ => Result::Err(Error::new(ErrorKind::TypeError, String::from("QNames can only be compared for equality with other QNames"))),
+	    }
+	}
+	if let (Some(a), Some(b)) = (self.octets(), other.octets()) {
+	    // Binary values are only equal if they have the same type
+	    let same = self.value_type() == other.value_type() && a == b;
+	    return match op {
+		Operator::Equal => Ok(same),
+		Operator::NotEqual => Ok(!same),
+		_ => Result::Err(Error::new(ErrorKind::TypeError, format!("type \"{}\" is not ordered", self.value_type()))),
+	    }
+	}
 	match &self {
 	    Value::Boolean(b) => {
 		let c = other.to_bool();
@@ -285,14 +380,599 @@ impl Value {
     }
 }
 
+// Compare two numeric values, after promoting them to a common type.
+fn compare_numeric(v: &Value, w: &Value, op: Operator) -> Result<bool, Error> {
+    let a = Numeric::from_value(v)?;
+    let b = Numeric::from_value(w)?;
+    let rank = a.rank().max(b.rank());
+    let ord = match (a.promote(rank), b.promote(rank)) {
+	(Numeric::Integer(i), Numeric::Integer(j)) => i.partial_cmp(&j),
+	(Numeric::Decimal(d), Numeric::Decimal(e)) => d.partial_cmp(&e),
+	(x, y) => x.to_double().partial_cmp(&y.to_double()),
+    };
+    match op {
+	Operator::Equal => Ok(ord == Some(Ordering::Equal)),
+	Operator::NotEqual => Ok(ord != Some(Ordering::Equal)),
+	Operator::LessThan => Ok(ord == Some(Ordering::Less)),
+	Operator::LessThanEqual => Ok(ord == Some(Ordering::Less) || ord == Some(Ordering::Equal)),
+	Operator::GreaterThan => Ok(ord == Some(Ordering::Greater)),
+	Operator::GreaterThanEqual => Ok(ord == Some(Ordering::Greater) || ord == Some(Ordering::Equal)),
+	Operator::Is |
+	Operator::Before |
+	Operator::After => Result::Err(Error::new(ErrorKind::TypeError, String::from("type error"))),
+    }
+}
+
+// Numeric values, after type promotion.
+// All of the integer types are treated as xs:integer.
+#[derive(Clone, Copy, Debug)]
+enum Numeric {
+    Integer(i64),
+    Decimal(Decimal),
+    Float(f32),
+    Double(f64),
+}
+
+impl Numeric {
+    // Untyped values (i.e. strings) are cast to xs:double.
+    fn from_value(v: &Value) -> Result<Numeric, Error> {
+	match v {
+	    Value::Integer(i) |
+	    Value::Long(i) => Ok(Numeric::Integer(*i)),
+	    Value::Int(i) => Ok(Numeric::Integer(*i as i64)),
+	    Value::Short(i) => Ok(Numeric::Integer(*i as i64)),
+	    Value::Byte(i) => Ok(Numeric::Integer(*i as i64)),
+	    Value::UnsignedInt(i) => Ok(Numeric::Integer(*i as i64)),
+	    Value::UnsignedShort(i) => Ok(Numeric::Integer(*i as i64)),
+	    Value::UnsignedByte(i) => Ok(Numeric::Integer(*i as i64)),
+	    Value::UnsignedLong(i) => Ok(
+		i64::try_from(*i).map_or(Numeric::Decimal(Decimal::from(*i)), Numeric::Integer)
+	    ),
+	    Value::NonPositiveInteger(i) => Ok(Numeric::Integer(i.0)),
+	    Value::NegativeInteger(i) => Ok(Numeric::Integer(i.0)),
+	    Value::NonNegativeInteger(i) => Ok(Numeric::Integer(i.0)),
+	    Value::PositiveInteger(i) => Ok(Numeric::Integer(i.0)),
+	    Value::Decimal(d) => Ok(Numeric::Decimal(*d)),
+	    Value::Float(f) => Ok(Numeric::Float(*f)),
+	    Value::Double(d) => Ok(Numeric::Double(*d)),
+	    Value::String(s) => {
+		match s.trim() {
+		    "INF" => Ok(Numeric::Double(f64::INFINITY)),
+		    "-INF" => Ok(Numeric::Double(f64::NEG_INFINITY)),
+		    "NaN" => Ok(Numeric::Double(f64::NAN)),
+		    t => t.parse::<f64>()
+			.ok()
+			.filter(|d| d.is_finite())
+			.map(Numeric::Double)
+			.ok_or_else(|| Error::new(ErrorKind::InvalidCast, format!("unable to cast \"{}\" to a double", s)))
+		}
+	    }
+	    _ => Result::Err(Error::new(ErrorKind::TypeError, format!("type \"{}\" is not a numeric type", v.value_type()))),
+	}
+    }
+    fn rank(&self) -> u8 {
+	match self {
+	    Numeric::Integer(_) => 0,
+	    Numeric::Decimal(_) => 1,
+	    Numeric::Float(_) => 2,
+	    Numeric::Double(_) => 3,
+	}
+    }
+    // Promote the value to the type with the given rank.
+    fn promote(self, rank: u8) -> Numeric {
+	match (self, rank) {
+	    (Numeric::Integer(i), 1) => Numeric::Decimal(Decimal::from(i)),
+	    (Numeric::Integer(i), 2) => Numeric::Float(i as f32),
+	    (Numeric::Integer(i), 3) => Numeric::Double(i as f64),
+	    (Numeric::Decimal(d), 2) => Numeric::Float(d.to_f32().unwrap_or(f32::NAN)),
+	    (Numeric::Decimal(d), 3) => Numeric::Double(d.to_f64().unwrap_or(f64::NAN)),
+	    (Numeric::Float(f), 3) => Numeric::Double(f as f64),
+	    (n, _) => n,
+	}
+    }
+    fn to_double(self) -> f64 {
+	match self.promote(3) {
+	    Numeric::Double(d) => d,
+	    _ => f64::NAN,
+	}
+    }
+}
+
+fn overflow() -> Error {
+    Error::new(ErrorKind::Overflow, String::from("numeric operation overflow"))
+}
+fn divide_by_zero() -> Error {
+    Error::new(ErrorKind::DivideByZero, String::from("division by zero"))
+}
+
+// Integer division of floating point values, see op:numeric-integer-divide
+fn float_idiv(a: f64, b: f64) -> Result<Value, Error> {
+    if b == 0.0 {
+	Result::Err(divide_by_zero())
+    } else if a.is_nan() || b.is_nan() || a.is_infinite() {
+	Result::Err(overflow())
+    } else {
+	let q = (a / b).trunc();
+	if q >= i64::MIN as f64 && q < i64::MAX as f64 {
+	    Ok(Value::Integer(q as i64))
+	} else {
+	    Result::Err(overflow())
+	}
+    }
+}
+
+// Round a floating point number to a whole number.
+// If the fraction is exactly one half, then round to positive infinity, or to even if requested.
+fn round_float(x: f64, half_even: bool) -> f64 {
+    if !x.is_finite() {
+	return x
+    }
+    let f = x.floor();
+    let d = x - f;
+    let r = if d > 0.5 {
+	f + 1.0
+    } else if d < 0.5 {
+	f
+    } else if half_even {
+	if f % 2.0 == 0.0 {f} else {f + 1.0}
+    } else {
+	f + 1.0
+    };
+    // Preserve the sign of negative zero
+    if r == 0.0 && x.is_sign_negative() {
+	-0.0
+    } else {
+	r
+    }
+}
+
+impl Value {
+    /// Is this value one of the numeric types?
+    pub fn is_numeric(&self) -> bool {
+	!matches!(self, Value::String(_)) && Numeric::from_value(self).is_ok()
+    }
+
+    /// Perform an arithmetic operation, following the XPath rules for numeric type promotion.
+    ///
+    /// If the operands are of different types, then one is promoted: xs:integer to xs:decimal, xs:decimal to xs:float, and xs:float to xs:double. Untyped values (i.e. strings) are cast to xs:double.
+    /// Dividing two integers gives an xs:decimal result; integer division always gives an xs:integer result.
+    /// Overflow of xs:integer or xs:decimal values is reported as an error (FOAR0002), as is dividing an xs:integer or xs:decimal by zero (FOAR0001).
+    pub fn arithmetic(&self, op: ArithmeticOperator, other: &Value) -> Result<Value, Error> {
+	let a = Numeric::from_value(self)?;
+	let b = Numeric::from_value(other)?;
+	let rank = a.rank().max(b.rank());
+	match (a.promote(rank), b.promote(rank)) {
+	    (Numeric::Integer(i), Numeric::Integer(j)) => {
+		match op {
+		    ArithmeticOperator::Noop => Ok(Value::Integer(j)),
+		    ArithmeticOperator::Add => i.checked_add(j).map(Value::Integer).ok_or_else(overflow),
+		    ArithmeticOperator::Subtract => i.checked_sub(j).map(Value::Integer).ok_or_else(overflow),
+		    ArithmeticOperator::Multiply => i.checked_mul(j).map(Value::Integer).ok_or_else(overflow),
+		    ArithmeticOperator::Divide => {
+			if j == 0 {
+			    Result::Err(divide_by_zero())
+			} else {
+			    Decimal::from(i).checked_div(Decimal::from(j)).map(Value::Decimal).ok_or_else(overflow)
+			}
+		    }
+		    ArithmeticOperator::IntegerDivide => {
+			if j == 0 {
+			    Result::Err(divide_by_zero())
+			} else {
+			    i.checked_div(j).map(Value::Integer).ok_or_else(overflow)
+			}
+		    }
+		    ArithmeticOperator::Modulo => {
+			if j == 0 {
+			    Result::Err(divide_by_zero())
+			} else if j == -1 {
+			    // The remainder is always zero, but i64::MIN % -1 would overflow
+			    Ok(Value::Integer(0))
+			} else {
+			    i.checked_rem(j).map(Value::Integer).ok_or_else(overflow)
+			}
+		    }
+		}
+	    }
+	    (Numeric::Decimal(d), Numeric::Decimal(e)) => {
+		if e.is_zero() {
+		    match op {
+			ArithmeticOperator::Divide |
+			ArithmeticOperator::IntegerDivide |
+			ArithmeticOperator::Modulo => return Result::Err(divide_by_zero()),
+			_ => {}
+		    }
+		}
+		match op {
+		    ArithmeticOperator::Noop => Ok(Value::Decimal(e)),
+		    ArithmeticOperator::Add => d.checked_add(e).map(Value::Decimal).ok_or_else(overflow),
+		    ArithmeticOperator::Subtract => d.checked_sub(e).map(Value::Decimal).ok_or_else(overflow),
+		    ArithmeticOperator::Multiply => d.checked_mul(e).map(Value::Decimal).ok_or_else(overflow),
+		    ArithmeticOperator::Divide => d.checked_div(e).map(Value::Decimal).ok_or_else(overflow),
+		    ArithmeticOperator::IntegerDivide => {
+			d.checked_div(e)
+			    .and_then(|q| q.trunc().to_i64())
+			    .map(Value::Integer)
+			    .ok_or_else(overflow)
+		    }
+		    ArithmeticOperator::Modulo => d.checked_rem(e).map(Value::Decimal).ok_or_else(overflow),
+		}
+	    }
+	    (Numeric::Float(f), Numeric::Float(g)) => {
+		match op {
+		    ArithmeticOperator::Noop => Ok(Value::Float(g)),
+		    ArithmeticOperator::Add => Ok(Value::Float(f + g)),
+		    ArithmeticOperator::Subtract => Ok(Value::Float(f - g)),
+		    ArithmeticOperator::Multiply => Ok(Value::Float(f * g)),
+		    ArithmeticOperator::Divide => Ok(Value::Float(f / g)),
+		    ArithmeticOperator::IntegerDivide => float_idiv(f as f64, g as f64),
+		    ArithmeticOperator::Modulo => Ok(Value::Float(f % g)),
+		}
+	    }
+	    (x, y) => {
+		let (f, g) = (x.to_double(), y.to_double());
+		match op {
+		    ArithmeticOperator::Noop => Ok(Value::Double(g)),
+		    ArithmeticOperator::Add => Ok(Value::Double(f + g)),
+		    ArithmeticOperator::Subtract => Ok(Value::Double(f - g)),
+		    ArithmeticOperator::Multiply => Ok(Value::Double(f * g)),
+		    ArithmeticOperator::Divide => Ok(Value::Double(f / g)),
+		    ArithmeticOperator::IntegerDivide => float_idiv(f, g),
+		    ArithmeticOperator::Modulo => Ok(Value::Double(f % g)),
+		}
+	    }
+	}
+    }
+
+    /// Absolute value. The result has the same type as the value, except that derived integer types become xs:integer.
+    pub fn abs(&self) -> Result<Value, Error> {
+	match Numeric::from_value(self)? {
+	    Numeric::Integer(i) => i.checked_abs().map(Value::Integer).ok_or_else(overflow),
+	    Numeric::Decimal(d) => Ok(Value::Decimal(d.abs())),
+	    Numeric::Float(f) => Ok(Value::Float(f.abs())),
+	    Numeric::Double(d) => Ok(Value::Double(d.abs())),
+	}
+    }
+    /// The largest whole number that is not greater than the value. The result has the same type as the value.
+    pub fn floor(&self) -> Result<Value, Error> {
+	match Numeric::from_value(self)? {
+	    Numeric::Integer(i) => Ok(Value::Integer(i)),
+	    Numeric::Decimal(d) => Ok(Value::Decimal(d.floor())),
+	    Numeric::Float(f) => Ok(Value::Float(f.floor())),
+	    Numeric::Double(d) => Ok(Value::Double(d.floor())),
+	}
+    }
+    /// The smallest whole number that is not less than the value. The result has the same type as the value.
+    pub fn ceiling(&self) -> Result<Value, Error> {
+	match Numeric::from_value(self)? {
+	    Numeric::Integer(i) => Ok(Value::Integer(i)),
+	    Numeric::Decimal(d) => Ok(Value::Decimal(d.ceil())),
+	    Numeric::Float(f) => Ok(Value::Float(f.ceil())),
+	    Numeric::Double(d) => Ok(Value::Double(d.ceil())),
+	}
+    }
+    /// Round the value to the given number of decimal places (which may be negative).
+    /// A value that is exactly half way is rounded towards positive infinity.
+    /// The result has the same type as the value.
+    pub fn round(&self, precision: i64) -> Result<Value, Error> {
+	self.round_with(precision, false)
+    }
+    /// Round the value to the given number of decimal places (which may be negative).
+    /// A value that is exactly half way is rounded to the nearest even number.
+    /// The result has the same type as the value.
+    pub fn round_half_to_even(&self, precision: i64) -> Result<Value, Error> {
+	self.round_with(precision, true)
+    }
+    fn round_with(&self, precision: i64, half_even: bool) -> Result<Value, Error> {
+	match Numeric::from_value(self)? {
+	    Numeric::Integer(i) => {
+		if precision >= 0 {
+		    return Ok(Value::Integer(i))
+		}
+		// Round to a multiple of a power of ten
+		let factor = match 10i128.checked_pow((-precision) as u32) {
+		    Some(f) if f <= i64::MAX as i128 => f,
+		    _ => return Ok(Value::Integer(0)),
+		};
+		let n = i as i128;
+		let q = n.div_euclid(factor);
+		let r = n.rem_euclid(factor);
+		let q = if r * 2 > factor || (r * 2 == factor && (!half_even || q % 2 != 0)) {q + 1} else {q};
+		i64::try_from(q * factor).map(Value::Integer).map_err(|_| overflow())
+	    }
+	    Numeric::Decimal(d) => {
+		let strategy = if half_even {
+		    RoundingStrategy::MidpointNearestEven
+		} else if d.is_sign_negative() {
+		    RoundingStrategy::MidpointTowardZero
+		} else {
+		    RoundingStrategy::MidpointAwayFromZero
+		};
+		if precision >= 0 {
+		    Ok(Value::Decimal(d.round_dp_with_strategy(precision.min(28) as u32, strategy)))
+		} else {
+		    let mut factor = Decimal::ONE;
+		    for _ in 0..-precision {
+			match factor.checked_mul(Decimal::TEN) {
+			    Some(f) => factor = f,
+			    None => return Ok(Value::Decimal(Decimal::ZERO)),
+			}
+		    }
+		    (d / factor).round_dp_with_strategy(0, strategy)
+			.checked_mul(factor)
+			.map(Value::Decimal)
+			.ok_or_else(overflow)
+		}
+	    }
+	    Numeric::Float(f) => {
+		Ok(Value::Float(round_float_precision(f as f64, precision, half_even) as f32))
+	    }
+	    Numeric::Double(d) => {
+		Ok(Value::Double(round_float_precision(d, precision, half_even)))
+	    }
+	}
+    }
+}
+
+fn round_float_precision(x: f64, precision: i64, half_even: bool) -> f64 {
+    if precision == 0 || !x.is_finite() {
+	round_float(x, half_even)
+    } else {
+	let scale = 10f64.powi(precision.clamp(-400, 400) as i32);
+	let r = round_float(x * scale, half_even) / scale;
+	if r.is_finite() {r} else {x}
+    }
+}
+
+impl Value {
+    /// Is this value of a type derived from xs:string, or a list of such values?
+    pub fn is_string_derived(&self) -> bool {
+	matches!(self,
+	    Value::NormalizedString(_) |
+	    Value::Token(_) |
+	    Value::Language(_) |
+	    Value::NMTOKEN(_) |
+	    Value::Name(_) |
+	    Value::NCName(_) |
+	    Value::ID(_) |
+	    Value::IDREF(_) |
+	    Value::ENTITY(_) |
+	    Value::IDREFS(_) |
+	    Value::NMTOKENS(_) |
+	    Value::ENTITIES(_)
+	)
+    }
+    /// The octets of a binary value, i.e. xs:base64Binary or xs:hexBinary.
+    /// Returns None for other types.
+    pub fn octets(&self) -> Option<&[u8]> {
+	match self {
+	    Value::Base64Binary(b) |
+	    Value::HexBinary(b) => Some(b),
+	    _ => None,
+	}
+    }
+    /// The members of a value of a list type, i.e. xs:IDREFS, xs:NMTOKENS and xs:ENTITIES.
+    /// Returns None for atomic types.
+    pub fn list_items(&self) -> Option<Vec<Value>> {
+	match self {
+	    Value::IDREFS(l) => Some(l.0.iter().cloned().map(Value::IDREF).collect()),
+	    Value::NMTOKENS(l) => Some(l.0.iter().cloned().map(Value::NMTOKEN).collect()),
+	    Value::ENTITIES(l) => Some(l.0.iter().cloned().map(Value::ENTITY).collect()),
+	    _ => None,
+	}
+    }
+    // The XML Schema type of the value, followed by the types it is derived from.
+    fn derivation(&self) -> &'static [&'static str] {
+	match self {
+	    Value::String(_) => &["string"],
+	    Value::NormalizedString(_) => &["normalizedString", "string"],
+	    Value::Token(_) => &["token", "normalizedString", "string"],
+	    Value::Language(_) => &["language", "token", "normalizedString", "string"],
+	    Value::NMTOKEN(_) => &["NMTOKEN", "token", "normalizedString", "string"],
+	    Value::Name(_) => &["Name", "token", "normalizedString", "string"],
+	    Value::NCName(_) => &["NCName", "Name", "token", "normalizedString", "string"],
+	    Value::ID(_) => &["ID", "NCName", "Name", "token", "normalizedString", "string"],
+	    Value::IDREF(_) => &["IDREF", "NCName", "Name", "token", "normalizedString", "string"],
+	    Value::ENTITY(_) => &["ENTITY", "NCName", "Name", "token", "normalizedString", "string"],
+	    Value::IDREFS(_) => &["IDREFS"],
+	    Value::NMTOKENS(_) => &["NMTOKENS"],
+	    Value::ENTITIES(_) => &["ENTITIES"],
+	    Value::Base64Binary(_) => &["base64Binary"],
+	    Value::HexBinary(_) => &["hexBinary"],
+	    Value::QName(_) => &["QName"],
+	    Value::AnyURI(_) => &["anyURI"],
+	    Value::Boolean(_) => &["boolean"],
+	    Value::Decimal(_) => &["decimal"],
+	    Value::Integer(_) => &["integer", "decimal"],
+	    Value::Long(_) => &["long", "integer", "decimal"],
+	    Value::Int(_) => &["int", "long", "integer", "decimal"],
+	    Value::Short(_) => &["short", "int", "long", "integer", "decimal"],
+	    Value::Byte(_) => &["byte", "short", "int", "long", "integer", "decimal"],
+	    Value::NonPositiveInteger(_) => &["nonPositiveInteger", "integer", "decimal"],
+	    Value::NegativeInteger(_) => &["negativeInteger", "nonPositiveInteger", "integer", "decimal"],
+	    Value::NonNegativeInteger(_) => &["nonNegativeInteger", "integer", "decimal"],
+	    Value::PositiveInteger(_) => &["positiveInteger", "nonNegativeInteger", "integer", "decimal"],
+	    Value::UnsignedLong(_) => &["unsignedLong", "nonNegativeInteger", "integer", "decimal"],
+	    Value::UnsignedInt(_) => &["unsignedInt", "unsignedLong", "nonNegativeInteger", "integer", "decimal"],
+	    Value::UnsignedShort(_) => &["unsignedShort", "unsignedInt", "unsignedLong", "nonNegativeInteger", "integer", "decimal"],
+	    Value::UnsignedByte(_) => &["unsignedByte", "unsignedShort", "unsignedInt", "unsignedLong", "nonNegativeInteger", "integer", "decimal"],
+	    Value::Float(_) => &["float"],
+	    Value::Double(_) => &["double"],
+	    Value::DateTime(_) => &["dateTime"],
+	    Value::DateTimeStamp => &["dateTimeStamp", "dateTime"],
+	    Value::Date(_) => &["date"],
+	    Value::Time(_) => &["time"],
+	    Value::Duration => &["duration"],
+	    Value::UntypedAtomic => &["untypedAtomic"],
+	    _ => &[],
+	}
+    }
+    /// Is the value an instance of the named XML Schema type?
+    /// The type is given by its local name in the XML Schema namespace, e.g. "NCName".
+    pub fn instance_of(&self, t: &str) -> bool {
+	match t {
+	    "anySimpleType" => true,
+	    "anyAtomicType" => self.list_items().is_none() && !self.derivation().is_empty(),
+	    "numeric" => self.is_numeric(),
+	    _ => self.derivation().contains(&t),
+	}
+    }
+
+    /// Cast the value to the named XML Schema type, given by its local name (e.g. "NMTOKENS").
+    /// An invalid value for the target type is an error (FORG0001).
+    /// Values of the list types are cast from their string value.
+    pub fn cast_as(&self, t: &str) -> Result<Value, Error> {
+	let s = self.to_string();
+	match t {
+	    "string" | "untypedAtomic" | "anyAtomicType" => Ok(Value::String(s)),
+	    "normalizedString" => {
+		NormalizedString::try_from(s.replace(['\t', '\n', '\r'], " ").as_str()).map(Value::NormalizedString)
+	    }
+	    "token" => Token::try_from(s.as_str()).map(Value::Token),
+	    "language" => Language::try_from(s.as_str()).map(Value::Language),
+	    "NMTOKEN" => NMTOKEN::try_from(s.as_str()).map(Value::NMTOKEN),
+	    "Name" => Name::try_from(s.as_str()).map(Value::Name),
+	    "NCName" => NCName::try_from(s.as_str()).map(Value::NCName),
+	    "ID" => ID::try_from(s.as_str()).map(Value::ID),
+	    "IDREF" => IDREF::try_from(s.as_str()).map(Value::IDREF),
+	    "ENTITY" => ENTITY::try_from(s.as_str()).map(Value::ENTITY),
+	    "IDREFS" => IDREFS::try_from(s.as_str()).map(Value::IDREFS),
+	    "NMTOKENS" => NMTOKENS::try_from(s.as_str()).map(Value::NMTOKENS),
+	    "ENTITIES" => ENTITIES::try_from(s.as_str()).map(Value::ENTITIES),
+	    "base64Binary" => match self {
+		Value::Base64Binary(b) |
+		Value::HexBinary(b) => Ok(Value::Base64Binary(b.clone())),
+		_ => base64_decode(&s).map(Value::Base64Binary),
+	    }
+	    "anyURI" => Ok(Value::AnyURI(collapse_whitespace(&s))),
+	    "QName" => match self {
+		Value::QName(q) => Ok(Value::QName(q.clone())),
+		// Without the static namespace context, only an unprefixed name can be cast
+		_ => {
+		    let n = collapse_whitespace(&s);
+		    if is_ncname(&n) {
+			Ok(Value::QName(QualifiedName::new(None, None, n)))
+		    } else {
+			Err(invalid_value(&s, "QName"))
+		    }
+		}
+	    }
+	    "hexBinary" => match self {
+		Value::Base64Binary(b) |
+		Value::HexBinary(b) => Ok(Value::HexBinary(b.clone())),
+		_ => hex_decode(&s).map(Value::HexBinary),
+	    }
+	    "boolean" => {
+		match self {
+		    Value::Boolean(b) => Ok(Value::Boolean(*b)),
+		    v if v.is_numeric() => Ok(Value::Boolean(v.to_bool())),
+		    _ => match s.trim() {
+			"true" | "1" => Ok(Value::Boolean(true)),
+			"false" | "0" => Ok(Value::Boolean(false)),
+			_ => Err(invalid_value(&s, "boolean")),
+		    }
+		}
+	    }
+	    "double" => self.cast_numeric(&s).map(|n| Value::Double(n.to_double())),
+	    "float" => self.cast_numeric(&s).map(|n| Value::Float(n.to_double() as f32)),
+	    "decimal" => {
+		if self.is_lexical() && !is_decimal_lexical(s.trim()) {
+		    return Err(invalid_value(&s, t))
+		}
+		match self.cast_numeric(&s)? {
+		    Numeric::Integer(i) => Ok(Value::Decimal(Decimal::from(i))),
+		    Numeric::Decimal(d) => Ok(Value::Decimal(d)),
+		    n => Decimal::from_f64(n.to_double())
+			.map(Value::Decimal)
+			.ok_or_else(|| invalid_value(&s, "decimal")),
+		}
+	    }
+	    "integer" | "long" | "int" | "short" | "byte" |
+	    "nonPositiveInteger" | "negativeInteger" |
+	    "nonNegativeInteger" | "positiveInteger" |
+	    "unsignedLong" | "unsignedInt" | "unsignedShort" | "unsignedByte" => {
+		// Only a numeric value is truncated; a string must be an integer
+		if self.is_lexical() && !is_integer_lexical(s.trim()) {
+		    return Err(invalid_value(&s, t))
+		}
+		let i = match self.cast_numeric(&s)? {
+		    Numeric::Integer(i) => Some(i),
+		    Numeric::Decimal(d) => d.trunc().to_i64(),
+		    n => {
+			let d = n.to_double().trunc();
+			if d.is_finite() && d >= i64::MIN as f64 && d < i64::MAX as f64 {Some(d as i64)} else {None}
+		    }
+		}.ok_or_else(|| invalid_value(&s, t))?;
+		let err = |_| invalid_value(&s, t);
+		match t {
+		    "integer" => Ok(Value::Integer(i)),
+		    "long" => Ok(Value::Long(i)),
+		    "int" => i32::try_from(i).map(Value::Int).map_err(err),
+		    "short" => i16::try_from(i).map(Value::Short).map_err(err),
+		    "byte" => i8::try_from(i).map(Value::Byte).map_err(err),
+		    "nonPositiveInteger" => NonPositiveInteger::try_from(i).map(Value::NonPositiveInteger).map_err(|_| invalid_value(&s, t)),
+		    "negativeInteger" => NegativeInteger::try_from(i).map(Value::NegativeInteger).map_err(|_| invalid_value(&s, t)),
+		    "nonNegativeInteger" => NonNegativeInteger::try_from(i).map(Value::NonNegativeInteger).map_err(|_| invalid_value(&s, t)),
+		    "positiveInteger" => PositiveInteger::try_from(i).map(Value::PositiveInteger).map_err(|_| invalid_value(&s, t)),
+		    "unsignedLong" => u64::try_from(i).map(Value::UnsignedLong).map_err(err),
+		    "unsignedInt" => u32::try_from(i).map(Value::UnsignedInt).map_err(err),
+		    "unsignedShort" => u16::try_from(i).map(Value::UnsignedShort).map_err(err),
+		    _ => u8::try_from(i).map(Value::UnsignedByte).map_err(err),
+		}
+	    }
+	    _ => Err(Error::new(ErrorKind::UnknownType, format!("casting to type \"{}\" is not supported", t))),
+	}
+    }
+    // Is the value cast from its lexical form, i.e. it is not a number or a boolean?
+    fn is_lexical(&self) -> bool {
+	!self.is_numeric() && !matches!(self, Value::Boolean(_))
+    }
+    // The numeric value to be cast to one of the numeric types.
+    // A string is parsed as an integer or decimal if it has that form, otherwise as a double.
+    fn cast_numeric(&self, s: &str) -> Result<Numeric, Error> {
+	match self {
+	    Value::Boolean(b) => Ok(Numeric::Integer(if *b {1} else {0})),
+	    v if v.is_numeric() => Numeric::from_value(v),
+	    _ => {
+		let t = s.trim();
+		if let Ok(i) = t.parse::<i64>() {
+		    Ok(Numeric::Integer(i))
+		} else if let Ok(d) = Decimal::from_str(t) {
+		    Ok(Numeric::Decimal(d))
+		} else {
+		    Numeric::from_value(&Value::String(t.to_string()))
+			.map_err(|_| invalid_value(s, "number"))
+		}
+	    }
+	}
+    }
+}
+
 impl PartialEq for Value {
   fn eq(&self, other: &Value) -> bool {
+    if self.is_numeric() && other.is_numeric() {
+      return compare_numeric(self, other, Operator::Equal).unwrap_or(false)
+    }
     match self {
         Value::String(s) => s.eq(&other.to_string()),
+        v if v.is_string_derived() => v.to_string() == other.to_string(),
+        Value::AnyURI(u) => *u == other.to_string(),
+	Value::QName(q) => match other {
+	  Value::QName(r) => q == r,
+	  _ => false,
+	},
 	Value::Boolean(b) => match other {
 	  Value::Boolean(c) => b == c,
 	  _ => false, // type error?
 	},
+	Value::Base64Binary(b) => match other {
+	  Value::Base64Binary(c) => b == c,
+	  _ => false,
+	},
+	Value::HexBinary(b) => match other {
+	  Value::HexBinary(c) => b == c,
+	  _ => false,
+	},
 	Value::Decimal(d) => match other {
 	  Value::Decimal(e) => d == e,
 	  _ => false, // type error?
@@ -496,6 +1176,321 @@ impl fmt::Display for NormalizedString {
     }
 }
 
+// Whitespace is collapsed for xs:token and the types derived from it.
+fn collapse_whitespace(v: &str) -> String {
+  v.split([' ', '\t', '\n', '\r'])
+    .filter(|t| !t.is_empty())
+    .collect::<Vec<&str>>()
+    .join(" ")
+}
+// The lexical form of xs:integer: an optional sign followed by digits
+fn is_integer_lexical(s: &str) -> bool {
+  let d = s.strip_prefix(['+', '-']).unwrap_or(s);
+  !d.is_empty() && d.chars().all(|c| c.is_ascii_digit())
+}
+
+// The lexical form of xs:decimal: an optional sign followed by digits, with an optional decimal point
+fn is_decimal_lexical(s: &str) -> bool {
+  let d = s.strip_prefix(['+', '-']).unwrap_or(s);
+  let (i, f) = d.split_once('.').unwrap_or((d, ""));
+  !(i.is_empty() && f.is_empty()) && i.chars().chain(f.chars()).all(|c| c.is_ascii_digit())
+}
+
+fn invalid_value(v: &str, t: &str) -> Error {
+  Error::new(ErrorKind::InvalidCast, format!("\"{}\" is not a valid {}", v, t))
+}
+
+#[derive(Clone, Debug)]
+pub struct Token(String);
+impl TryFrom<&str> for Token {
+  type Error = Error;
+  fn try_from(v: &str) -> Result<Self, Self::Error> {
+    Ok(Token(collapse_whitespace(v)))
+  }
+}
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Language(String);
+impl TryFrom<&str> for Language {
+  type Error = Error;
+  fn try_from(v: &str) -> Result<Self, Self::Error> {
+    let l = collapse_whitespace(v);
+    let mut parts = l.split('-');
+    let valid = parts.next().is_some_and(|p| (1..=8).contains(&p.len()) && p.chars().all(|c| c.is_ascii_alphabetic())) &&
+      parts.all(|p| (1..=8).contains(&p.len()) && p.chars().all(|c| c.is_ascii_alphanumeric()));
+    if valid {
+      Ok(Language(l))
+    } else {
+      Err(invalid_value(v, "language"))
+    }
+  }
+}
+impl fmt::Display for Language {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct NMTOKEN(String);
+impl TryFrom<&str> for NMTOKEN {
+  type Error = Error;
+  fn try_from(v: &str) -> Result<Self, Self::Error> {
+    let n = collapse_whitespace(v);
+    if is_nmtoken(&n) {
+      Ok(NMTOKEN(n))
+    } else {
+      Err(invalid_value(v, "NMTOKEN"))
+    }
+  }
+}
+impl fmt::Display for NMTOKEN {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Name(String);
+impl TryFrom<&str> for Name {
+  type Error = Error;
+  fn try_from(v: &str) -> Result<Self, Self::Error> {
+    let n = collapse_whitespace(v);
+    if is_name(&n) {
+      Ok(Name(n))
+    } else {
+      Err(invalid_value(v, "Name"))
+    }
+  }
+}
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct NCName(String);
+impl TryFrom<&str> for NCName {
+  type Error = Error;
+  fn try_from(v: &str) -> Result<Self, Self::Error> {
+    let n = collapse_whitespace(v);
+    if is_ncname(&n) {
+      Ok(NCName(n))
+    } else {
+      Err(invalid_value(v, "NCName"))
+    }
+  }
+}
+impl fmt::Display for NCName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ID(String);
+impl TryFrom<&str> for ID {
+  type Error = Error;
+  fn try_from(v: &str) -> Result<Self, Self::Error> {
+    let n = collapse_whitespace(v);
+    if is_ncname(&n) {
+      Ok(ID(n))
+    } else {
+      Err(invalid_value(v, "ID"))
+    }
+  }
+}
+impl fmt::Display for ID {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct IDREF(String);
+impl TryFrom<&str> for IDREF {
+  type Error = Error;
+  fn try_from(v: &str) -> Result<Self, Self::Error> {
+    let n = collapse_whitespace(v);
+    if is_ncname(&n) {
+      Ok(IDREF(n))
+    } else {
+      Err(invalid_value(v, "IDREF"))
+    }
+  }
+}
+impl fmt::Display for IDREF {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ENTITY(String);
+impl TryFrom<&str> for ENTITY {
+  type Error = Error;
+  fn try_from(v: &str) -> Result<Self, Self::Error> {
+    let n = collapse_whitespace(v);
+    if is_ncname(&n) {
+      Ok(ENTITY(n))
+    } else {
+      Err(invalid_value(v, "ENTITY"))
+    }
+  }
+}
+impl fmt::Display for ENTITY {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+const BASE64_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// The canonical representation of xs:base64Binary has no whitespace.
+fn base64_encode(b: &[u8]) -> String {
+  let mut r = String::with_capacity(b.len().div_ceil(3) * 4);
+  for c in b.chunks(3) {
+    let n = (c[0] as u32) << 16 | (*c.get(1).unwrap_or(&0) as u32) << 8 | *c.get(2).unwrap_or(&0) as u32;
+    for i in 0..4 {
+      if i <= c.len() {
+        r.push(BASE64_ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char)
+      } else {
+        r.push('=')
+      }
+    }
+  }
+  r
+}
+fn base64_decode(v: &str) -> Result<Vec<u8>, Error> {
+  let invalid = || invalid_value(v, "base64Binary");
+  let chars: Vec<u8> = v.bytes().filter(|c| !matches!(c, b' ' | b'\t' | b'\n' | b'\r')).collect();
+  if !chars.len().is_multiple_of(4) {
+    return Err(invalid())
+  }
+  let padding = chars.iter().rev().take_while(|c| **c == b'=').count();
+  if padding > 2 {
+    return Err(invalid())
+  }
+  let mut sextets = Vec::with_capacity(chars.len());
+  for c in &chars[..chars.len() - padding] {
+    match BASE64_ALPHABET.iter().position(|a| a == c) {
+      Some(p) => sextets.push(p as u32),
+      None => return Err(invalid()),
+    }
+  }
+  // The unused bits before the padding must be zero
+  let unused = match padding {1 => 0x3, 2 => 0xf, _ => 0};
+  if sextets.last().is_some_and(|l| l & unused != 0) {
+    return Err(invalid())
+  }
+  let mut r = Vec::with_capacity(sextets.len() * 3 / 4);
+  for q in sextets.chunks(4) {
+    let n = q.iter().enumerate().fold(0u32, |n, (i, s)| n | s << (18 - 6 * i));
+    for i in 0..q.len() - 1 {
+      r.push((n >> (16 - 8 * i)) as u8)
+    }
+  }
+  Ok(r)
+}
+
+// The canonical representation of xs:hexBinary uses upper case digits.
+fn hex_encode(b: &[u8]) -> String {
+  b.iter().map(|o| format!("{:02X}", o)).collect()
+}
+fn hex_decode(v: &str) -> Result<Vec<u8>, Error> {
+  let t = v.trim_matches([' ', '\t', '\n', '\r']);
+  if !t.len().is_multiple_of(2) || !t.chars().all(|c| c.is_ascii_hexdigit()) {
+    return Err(invalid_value(v, "hexBinary"))
+  }
+  (0..t.len()).step_by(2)
+    .map(|i| u8::from_str_radix(&t[i..i + 2], 16).map_err(|_| invalid_value(v, "hexBinary")))
+    .collect()
+}
+
+// List types are whitespace-separated sequences of one or more items.
+fn list_items<T: for<'a> TryFrom<&'a str, Error = Error>>(v: &str, t: &str) -> Result<Vec<T>, Error> {
+  let items = v.split_whitespace()
+    .map(T::try_from)
+    .collect::<Result<Vec<T>, Error>>()?;
+  if items.is_empty() {
+    Err(invalid_value(v, t))
+  } else {
+    Ok(items)
+  }
+}
+fn join_items<T: fmt::Display>(items: &[T], f: &mut fmt::Formatter) -> fmt::Result {
+  for (i, t) in items.iter().enumerate() {
+    if i > 0 {
+      f.write_str(" ")?;
+    }
+    write!(f, "{}", t)?;
+  }
+  Ok(())
+}
+
+#[derive(Clone, Debug)]
+pub struct IDREFS(Vec<IDREF>);
+impl TryFrom<&str> for IDREFS {
+  type Error = Error;
+  fn try_from(v: &str) -> Result<Self, Self::Error> {
+    list_items(v, "IDREFS").map(IDREFS)
+  }
+}
+impl IDREFS {
+  pub fn items(&self) -> &[IDREF] {
+    &self.0
+  }
+}
+impl fmt::Display for IDREFS {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        join_items(&self.0, f)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct NMTOKENS(Vec<NMTOKEN>);
+impl TryFrom<&str> for NMTOKENS {
+  type Error = Error;
+  fn try_from(v: &str) -> Result<Self, Self::Error> {
+    list_items(v, "NMTOKENS").map(NMTOKENS)
+  }
+}
+impl NMTOKENS {
+  pub fn items(&self) -> &[NMTOKEN] {
+    &self.0
+  }
+}
+impl fmt::Display for NMTOKENS {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        join_items(&self.0, f)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ENTITIES(Vec<ENTITY>);
+impl TryFrom<&str> for ENTITIES {
+  type Error = Error;
+  fn try_from(v: &str) -> Result<Self, Self::Error> {
+    list_items(v, "ENTITIES").map(ENTITIES)
+  }
+}
+impl ENTITIES {
+  pub fn items(&self) -> &[ENTITY] {
+    &self.0
+  }
+}
+impl fmt::Display for ENTITIES {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        join_items(&self.0, f)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -557,6 +1552,120 @@ mod tests {
 	    Err(_) => true,
 	})
     }
+    #[test]
+    fn token_collapse() {
+        assert_eq!(Token::try_from("  a\tb \n c  ").expect("invalid Token").0, "a b c");
+    }
+    #[test]
+    fn language_valid() {
+        assert_eq!(Language::try_from("en-AU").expect("invalid Language").0, "en-AU");
+    }
+    #[test]
+    fn language_invalid() {
+        assert!(Language::try_from("english-Australia").is_err());
+    }
+    #[test]
+    fn nmtoken_valid() {
+        assert_eq!(NMTOKEN::try_from(" 123-abc ").expect("invalid NMTOKEN").0, "123-abc");
+    }
+    #[test]
+    fn nmtoken_invalid() {
+        assert!(NMTOKEN::try_from("two tokens").is_err());
+    }
+    #[test]
+    fn name_valid() {
+        assert_eq!(Name::try_from("x:foo").expect("invalid Name").0, "x:foo");
+    }
+    #[test]
+    fn ncname_invalid() {
+        assert!(NCName::try_from("x:foo").is_err());
+        assert!(ID::try_from("1foo").is_err());
+    }
+    #[test]
+    fn idrefs_valid() {
+        let l = IDREFS::try_from(" a  b\nc ").expect("invalid IDREFS");
+        assert_eq!(l.items().len(), 3);
+        assert_eq!(l.to_string(), "a b c");
+    }
+    #[test]
+    fn entities_invalid() {
+        assert!(ENTITIES::try_from("   ").is_err());
+        assert!(ENTITIES::try_from("a b:c").is_err());
+    }
+    #[test]
+    fn instance_of_derived() {
+        let v = Value::ID(ID::try_from("foo").expect("invalid ID"));
+        assert!(v.instance_of("ID"));
+        assert!(v.instance_of("NCName"));
+        assert!(v.instance_of("string"));
+        assert!(!v.instance_of("IDREF"));
+    }
+    #[test]
+    fn cast_string_to_integer() {
+        assert_eq!(Value::from(" 42 ").cast_as("unsignedByte").expect("cast failed").to_string(), "42");
+        assert!(Value::from("-1").cast_as("nonNegativeInteger").is_err());
+        assert_eq!(Value::from("+7").cast_as("integer").expect("cast failed").to_string(), "7");
+        // A string must be in the lexical form of an integer; it is not truncated
+        for v in vec!["1.5", "1e3", "1_000", "", "+", "INF", "0x10"] {
+            assert!(matches!(Value::from(v).cast_as("integer"), Err(Error{kind: ErrorKind::InvalidCast, ..})), "{}", v);
+        }
+        assert_eq!(Value::from(1.9).cast_as("integer").expect("cast failed").to_string(), "1");
+    }
+    #[test]
+    fn cast_string_to_decimal() {
+        assert_eq!(Value::from(" -1.50 ").cast_as("decimal").expect("cast failed").to_string(), "-1.5");
+        assert_eq!(Value::from(".5").cast_as("decimal").expect("cast failed").to_string(), "0.5");
+        assert_eq!(Value::from("2.").cast_as("decimal").expect("cast failed").to_string(), "2");
+        for v in vec!["1e3", "1_000", ".", "NaN", "1.2.3"] {
+            assert!(matches!(Value::from(v).cast_as("decimal"), Err(Error{kind: ErrorKind::InvalidCast, ..})), "{}", v);
+        }
+    }
+    #[test]
+    fn base64_canonical() {
+        let v = Value::from("SGVs bG8=").cast_as("base64Binary").expect("cast failed");
+        assert_eq!(v.octets(), Some("Hello".as_bytes()));
+        assert_eq!(v.to_string(), "SGVsbG8=");
+    }
+    #[test]
+    fn base64_invalid() {
+        assert!(Value::from("SGVsbG9=").cast_as("base64Binary").is_err());
+        assert!(Value::from("SGVsbG8").cast_as("base64Binary").is_err());
+        assert!(Value::from("S===").cast_as("base64Binary").is_err());
+    }
+    #[test]
+    fn hexbinary_canonical() {
+        let v = Value::from(" 0fa9 ").cast_as("hexBinary").expect("cast failed");
+        assert_eq!(v.to_string(), "0FA9");
+        assert!(Value::from("0fa").cast_as("hexBinary").is_err());
+    }
+    #[test]
+    fn binary_cast_and_equality() {
+        let h = Value::HexBinary(vec![0, 1, 254]);
+        let b = h.cast_as("base64Binary").expect("cast failed");
+        assert_eq!(b.to_string(), "AAH+");
+        assert!(b == Value::Base64Binary(vec![0, 1, 254]));
+        assert!(b != h);
+        assert!(b.cast_as("hexBinary").expect("cast failed") == h);
+    }
+    #[test]
+    fn qname_equality() {
+        let a = Value::QName(QualifiedName::new(Some(String::from("urn:x")), Some(String::from("a")), String::from("foo")));
+        let b = Value::QName(QualifiedName::new(Some(String::from("urn:x")), Some(String::from("b")), String::from("foo")));
+        assert_eq!(a.to_string(), "a:foo");
+        assert!(a == b);
+        assert!(a.compare(&b, Operator::LessThan).is_err());
+    }
+    #[test]
+    fn anyuri_cast() {
+        let u = Value::from(" http://example.org/ ").cast_as("anyURI").expect("cast failed");
+        assert!(u.instance_of("anyURI"));
+        assert!(u == Value::from("http://example.org/"));
+    }
+    #[test]
+    fn cast_boolean() {
+        assert_eq!(Value::from("1").cast_as("boolean").expect("cast failed").to_string(), "true");
+        assert_eq!(Value::from(false).cast_as("integer").expect("cast failed").to_string(), "0");
+    }
 
 // Numeric is in the too hard basket for now
 //    #[test]
@@ -729,6 +1838,67 @@ mod tests {
       assert_eq!(Operator::NotEqual.to_string(), "!=")
     }
     #[test]
+    fn arith_integer_add() {
+      assert_eq!(Value::Integer(2).arithmetic(ArithmeticOperator::Add, &Value::Integer(3)).expect("unable to add").value_type(), "Integer")
+    }
+    #[test]
+    fn arith_promote_decimal() {
+      let r = Value::Integer(2).arithmetic(ArithmeticOperator::Multiply, &Value::Decimal(dec!(1.5))).expect("unable to multiply");
+      assert_eq!(r.value_type(), "Decimal");
+      assert_eq!(r.to_string(), "3")
+    }
+    #[test]
+    fn arith_promote_float() {
+      assert_eq!(Value::Decimal(dec!(1.5)).arithmetic(ArithmeticOperator::Add, &Value::Float(1.0)).expect("unable to add").value_type(), "Float")
+    }
+    #[test]
+    fn arith_promote_double() {
+      assert_eq!(Value::Float(1.5).arithmetic(ArithmeticOperator::Add, &Value::Double(1.0)).expect("unable to add").value_type(), "Double")
+    }
+    #[test]
+    fn arith_untyped() {
+      assert_eq!(Value::from("2").arithmetic(ArithmeticOperator::Add, &Value::Integer(1)).expect("unable to add").value_type(), "Double")
+    }
+    #[test]
+    fn arith_integer_overflow() {
+      assert!(Value::Integer(i64::MAX).arithmetic(ArithmeticOperator::Multiply, &Value::Integer(2)).is_err())
+    }
+    #[test]
+    fn arith_integer_mod_minus_one() {
+      assert_eq!(Value::Integer(i64::MIN).arithmetic(ArithmeticOperator::Modulo, &Value::Integer(-1)).expect("unable to calculate modulus").to_string(), "0");
+      assert_eq!(Value::Integer(7).arithmetic(ArithmeticOperator::Modulo, &Value::Integer(-1)).expect("unable to calculate modulus").to_string(), "0")
+    }
+    #[test]
+    fn arith_idiv_double() {
+      assert_eq!(Value::Double(-7.5).arithmetic(ArithmeticOperator::IntegerDivide, &Value::Double(2.0)).expect("unable to divide").to_string(), "-3");
+      assert!(Value::Double(f64::INFINITY).arithmetic(ArithmeticOperator::IntegerDivide, &Value::Double(2.0)).is_err())
+    }
+    #[test]
+    fn arith_double_divide_by_zero() {
+      assert_eq!(Value::Double(1.0).arithmetic(ArithmeticOperator::Divide, &Value::Integer(0)).expect("unable to divide").to_string(), "INF")
+    }
+    #[test]
+    fn round_half_to_even_decimal() {
+      assert_eq!(Value::Decimal(dec!(0.125)).round_half_to_even(2).expect("unable to round").to_string(), "0.12");
+      assert_eq!(Value::Decimal(dec!(-0.125)).round(2).expect("unable to round").to_string(), "-0.12")
+    }
+    #[test]
+    fn round_integer_negative_precision() {
+      assert_eq!(Value::Integer(35612).round_half_to_even(-2).expect("unable to round").to_string(), "35600");
+      assert_eq!(Value::Integer(-250).round(-2).expect("unable to round").to_string(), "-200")
+    }
+    #[test]
+    fn compare_mixed_numeric() {
+      assert!(Value::Integer(1).compare(&Value::Decimal(dec!(1.5)), Operator::LessThan).expect("unable to compare"));
+      assert!(Value::Decimal(dec!(2.0)) == Value::Integer(2))
+    }
+    #[test]
+    fn double_special_to_string() {
+      assert_eq!(Value::Double(f64::INFINITY).to_string(), "INF");
+      assert_eq!(Value::Double(f64::NEG_INFINITY).to_string(), "-INF");
+      assert_eq!(Value::Double(f64::NAN).to_string(), "NaN")
+    }
+    #[test]
     fn op_lt() {
       assert_eq!(Operator::LessThan.to_string(), "<")
     }
@@ -18,6 +18,58 @@ pub enum ErrorKind {
     MixedTypes, /// XPTY0018
     NotNodes, /// XPTY0019
     ContextNotNode, /// XPTY0020
+    UnknownType, /// XPST0051
+    UnknownPrefix, /// XPST0081
+    InvalidPicture, /// FODF1310
+    DivideByZero, /// FOAR0001
+    Overflow, /// FOAR0002
+    InvalidLexicalValue, /// FOCA0002
+    InvalidCast, /// FORG0001
+    InvalidUriArgument, /// FORG0002
+    UnknownCollation, /// FOCH0002
+    FunctionAtomization, /// FOTY0013
+    FunctionStringValue, /// FOTY0014
+    NoNamespace, /// FONS0004
+    NoBaseUri, /// FONS0005
+    DocumentNotFound, /// FODC0002
+    InvalidDocumentURI, /// FODC0005
+    UnparsedTextNotFound, /// FOUT1170
+    UnparsedTextEncoding, /// FOUT1190
+    DuplicateMapKey, /// XQDY0137
+    ArrayIndexOutOfBounds, /// FOAY0001
+    JsonSyntax, /// FOJS0001
+    JsonDuplicateKeys, /// FOJS0003
+    JsonOption, /// FOJS0005
+    JsonInvalidXml, /// FOJS0006
+    JsonInvalidEscape, /// FOJS0007
+    SerializeNumber, /// SERE0020
+    SerializeItem, /// SERE0021
+    SerializeDuplicateKey, /// SERE0022
+    SerializeSequence, /// SERE0023
+    SerializeNotAllowed, /// SENR0001
+    SerializeParameterValue, /// SEPM0016
+    SerializeParameters, /// SEPM0017
+    ParseXml, /// FODC0006
+    NoSuchTemplate, /// XTSE0650
+    MissingParameter, /// XTDE0700
+    ParameterType, /// XTTE0590
+    CircularVariable, /// XTDE0640
+    MissingStylesheetParameter, /// XTDE0050
+    NoMatchingTemplate, /// XTDE0555
+    InvalidAttributeValue, /// XTDE0030
+    InvalidElementName, /// XTDE0820
+    InvalidAttributeName, /// XTDE0850
+    InvalidProcessingInstructionName, /// XTDE0890
+    InvalidNamespaceNode, /// XTDE0920
+    NoSuchAttributeSet, /// XTSE0710
+    CircularAttributeSet, /// XTSE0720
+    NoSuchKey, /// XTDE1260
+    Terminated, /// XTMM9000
+    NoSuchOutputDefinition, /// XTDE1460
+    DuplicateResultURI, /// XTDE1490
+    ResultDocumentRejected,
+    BinaryIndexOutOfRange, /// bin:index-out-of-range
+    BinaryNegativeSize, /// bin:negative-size
     NotImplemented,
     Unknown,
 }
@@ -37,6 +89,58 @@ impl ErrorKind {
             ErrorKind::MixedTypes => "result of path operator contains both nodes and non-nodes",
             ErrorKind::NotNodes => "path expression is not a sequence of nodes",
             ErrorKind::ContextNotNode => "context item is not a node for an axis step",
+            ErrorKind::UnknownType => "type name is not defined",
+            ErrorKind::UnknownPrefix => "namespace prefix is not declared",
+            ErrorKind::InvalidPicture => "invalid decimal format picture string",
+            ErrorKind::DivideByZero => "division by zero",
+            ErrorKind::Overflow => "numeric operation overflow/underflow",
+            ErrorKind::InvalidLexicalValue => "invalid lexical value",
+            ErrorKind::UnknownCollation => "unsupported collation",
+            ErrorKind::FunctionAtomization => "a function item, map or array cannot be atomized",
+            ErrorKind::FunctionStringValue => "a function item, map or array does not have a string value",
+            ErrorKind::NoNamespace => "no namespace found for prefix",
+            ErrorKind::NoBaseUri => "base-uri not defined in the static context",
+            ErrorKind::DocumentNotFound => "error retrieving resource",
+            ErrorKind::InvalidDocumentURI => "invalid argument to fn:doc or fn:doc-available",
+            ErrorKind::UnparsedTextNotFound => "invalid URI reference, or resource cannot be retrieved",
+            ErrorKind::UnparsedTextEncoding => "cannot decode resource, or resource contains invalid characters",
+            ErrorKind::DuplicateMapKey => "duplicate key in map constructor",
+            ErrorKind::ArrayIndexOutOfBounds => "array index out of bounds",
+            ErrorKind::JsonSyntax => "JSON syntax error",
+            ErrorKind::JsonDuplicateKeys => "JSON duplicate keys",
+            ErrorKind::JsonOption => "invalid options",
+            ErrorKind::JsonInvalidXml => "invalid XML representation of JSON",
+            ErrorKind::JsonInvalidEscape => "bad JSON escape sequence",
+            ErrorKind::SerializeNumber => "numeric value cannot be serialized",
+            ErrorKind::SerializeItem => "item cannot be serialized",
+            ErrorKind::SerializeDuplicateKey => "duplicate key in serialized map",
+            ErrorKind::SerializeSequence => "sequence of more than one item cannot be serialized as JSON",
+            ErrorKind::SerializeNotAllowed => "item cannot be serialized using this method",
+            ErrorKind::SerializeParameterValue => "invalid serialization parameter value",
+            ErrorKind::SerializeParameters => "invalid serialization parameters",
+            ErrorKind::ParseXml => "string is not a well-formed document or fragment",
+            ErrorKind::NoSuchTemplate => "no template with the given name",
+            ErrorKind::MissingParameter => "no value supplied for a required parameter",
+            ErrorKind::ParameterType => "parameter value does not match its required type",
+            ErrorKind::CircularVariable => "circular definition of a variable",
+            ErrorKind::MissingStylesheetParameter => "no value supplied for a required stylesheet parameter",
+            ErrorKind::NoMatchingTemplate => "no template matches a node, and the mode's on-no-match behaviour is to fail",
+            ErrorKind::InvalidAttributeValue => "the value of an attribute value template is not valid for the attribute",
+            ErrorKind::InvalidElementName => "the name of a computed element is not a valid QName",
+            ErrorKind::InvalidAttributeName => "the name of a computed attribute is not a valid QName, or is xmlns",
+            ErrorKind::InvalidProcessingInstructionName => "the name of a processing instruction is not a valid NCName, or is xml",
+            ErrorKind::InvalidNamespaceNode => "invalid prefix or namespace URI for a namespace node",
+            ErrorKind::NoSuchAttributeSet => "no attribute set with the given name",
+            ErrorKind::CircularAttributeSet => "an attribute set uses itself, directly or indirectly",
+            ErrorKind::NoSuchKey => "no key with the given name",
+            ErrorKind::Terminated => "processing terminated by xsl:message",
+            ErrorKind::NoSuchOutputDefinition => "no output definition with the given name",
+            ErrorKind::DuplicateResultURI => "more than one result document with the same URI",
+            ErrorKind::ResultDocumentRejected => "the result document was rejected by the result document handler",
+            ErrorKind::InvalidCast => "invalid value for cast/constructor",
+            ErrorKind::InvalidUriArgument => "invalid argument to fn:resolve-uri()",
+            ErrorKind::BinaryIndexOutOfRange => "offset is out of range for binary data",
+            ErrorKind::BinaryNegativeSize => "size of binary data is negative",
             ErrorKind::NotImplemented => "not implemented",
 	    ErrorKind::Unknown => "unknown",
 	}
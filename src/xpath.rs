@@ -12,7 +12,7 @@ use nom::{
   character::complete::{char, none_of},
   sequence::{delimited, pair, tuple},
   multi::{many0, separated_list0, separated_list1},
-  combinator::{complete, map, opt, recognize},
+  combinator::{complete, map, map_opt, opt, recognize},
   bytes::complete::tag,
   error::{Error as NomError, ErrorKind as NomErrorKind},
   Err as NomErr,
@@ -27,7 +27,9 @@ use crate::evaluate::{
     Axis,
     ArithmeticOperator, ArithmeticOperand,
     Function,
+    SequenceType, ItemType, Occurrence,
 };
+use crate::qname::QualifiedName;
 #[cfg(test)]
 use crate::evaluate::{
     Evaluator,
@@ -192,9 +194,11 @@ fn simple_let_clause(input: &str) -> IResult<&str, Vec<Constructor>> {
   (input)
 }
 
+// The lexical QName of a variable. The prefix is resolved during static analysis.
 fn get_nt_localname(nt: &NodeTest) -> String {
   match nt {
     NodeTest::Name(NameTest{name: Some(WildcardOrName::Name(localpart)), ns: None, prefix: None}) => localpart.to_string(),
+    NodeTest::Name(NameTest{name: Some(WildcardOrName::Name(localpart)), ns: None, prefix: Some(p)}) => format!("{}:{}", p, localpart),
     _ => String::from("invalid qname")
   }
 }
@@ -510,13 +514,8 @@ fn instanceof_expr(input: &str) -> IResult<&str, Vec<Constructor>> {
         None => {
 	  u
 	}
-	Some(_t) => {
-	  //let mut r = Vec::new();
-	  //r.push(u);
-	  //let (_a, _b, _c, _d, _e, st) = t;
-	  //r.push(st);
-	  //vec![SequenceConstructor::new(cons_instanceof).set_args(Some(r))]
-          vec![Constructor::NotImplemented("instance_of".to_string())]
+	Some((_a, _b, _c, _d, _e, st)) => {
+          vec![Constructor::InstanceOf(u, st)]
 	}
       }
     }
@@ -525,12 +524,66 @@ fn instanceof_expr(input: &str) -> IResult<&str, Vec<Constructor>> {
 }
 
 // SequenceType ::= ( 'empty-sequence' '(' ')' | (ItemType OccurrenceIndicator?)
-// TODO: implement this parser fully
-fn sequencetype_expr(input: &str) -> IResult<&str, Vec<Constructor>> {
+fn sequencetype_expr(input: &str) -> IResult<&str, SequenceType> {
+  alt((
+    map (
+      tuple((tag("empty-sequence"), xpwhitespace, tag("("), xpwhitespace, tag(")"))),
+      |_| SequenceType::Empty
+    ),
+    map (
+      pair(itemtype, opt(occurrence_indicator)),
+      |(t, o)| SequenceType::Items(t, o.unwrap_or(Occurrence::One))
+    ),
+  ))
+  (input)
+}
+
+// ItemType ::= KindTest | ('item' '(' ')') | FunctionTest | MapTest | ArrayTest | AtomicOrUnionType | ParenthesizedItemType
+// TODO: FunctionTest, MapTest, ArrayTest, ParenthesizedItemType
+fn itemtype(input: &str) -> IResult<&str, ItemType> {
+  alt((
+    map_opt (
+      kindtest,
+      |k| match k {
+        NodeTest::Kind(kt) => Some(ItemType::Kind(kt)),
+	_ => None,
+      }
+    ),
+    map (
+      tuple((tag("item"), xpwhitespace, tag("("), xpwhitespace, tag(")"))),
+      |_| ItemType::Item
+    ),
+    map (
+      type_name,
+      ItemType::Atomic
+    ),
+  ))
+  (input)
+}
+
+// OccurrenceIndicator ::= '?' | '*' | '+'
+fn occurrence_indicator(input: &str) -> IResult<&str, Occurrence> {
   map (
-    tag("empty-sequence()"),
-    |_v| {
-      Vec::new()
+    alt((tag("?"), tag("*"), tag("+"))),
+    |o| match o {
+      "?" => Occurrence::ZeroOrOne,
+      "*" => Occurrence::ZeroOrMore,
+      _ => Occurrence::OneOrMore,
+    }
+  )
+  (input)
+}
+
+// TypeName ::= EQName
+// TODO: URIQualifiedName
+fn type_name(input: &str) -> IResult<&str, QualifiedName> {
+  map_opt (
+    qname,
+    |q| match q {
+      NodeTest::Name(NameTest{name: Some(WildcardOrName::Name(localpart)), prefix, ..}) => {
+        Some(QualifiedName::new(None, prefix, localpart))
+      }
+      _ => None,
     }
   )
   (input)
@@ -578,13 +631,8 @@ fn castable_expr(input: &str) -> IResult<&str, Vec<Constructor>> {
         None => {
 	  u
 	}
-	Some(_t) => {
-	  //let mut r = Vec::new();
-	  //r.push(u);
-	  //let (_a, _b, _c, _d, _e, st) = t;
-	  //r.push(st);
-	  //vec![SequenceConstructor::new(cons_castable).set_args(Some(r))]
-          vec![Constructor::NotImplemented("castable".to_string())]
+	Some((_a, _b, _c, _d, _e, (t, empty))) => {
+          vec![Constructor::Castable(u, t, empty)]
 	}
       }
     }
@@ -604,17 +652,17 @@ fn castable_expr(input: &str) -> IResult<&str, Vec<Constructor>> {
 // LocalPart ::= NCName
 // NCName ::= Name - (Char* ':' Char*)
 // Char ::= #x9 | #xA |#xD | [#x20-#xD7FF] | [#xE000-#xFFFD | [#x10000-#x10FFFF]
-// TODO: implement this parser fully
-fn singletype_expr(input: &str) -> IResult<&str, Vec<Constructor>> {
+// The result is the type name, and whether the empty sequence is allowed.
+fn singletype_expr(input: &str) -> IResult<&str, (QualifiedName, bool)> {
   map (
     pair(
-      qname,
+      type_name,
       opt(
-        tuple((xpwhitespace, tag("?"), xpwhitespace)),
+        tuple((xpwhitespace, tag("?"))),
       )
     ),
-    |(_u, _v)| {
-      Vec::new()
+    |(t, q)| {
+      (t, q.is_some())
     }
   )
   (input)
@@ -634,13 +682,8 @@ fn cast_expr(input: &str) -> IResult<&str, Vec<Constructor>> {
         None => {
 	  u
 	}
-	Some(_t) => {
-	  //let mut r = Vec::new();
-	  //r.push(u);
-	  //let (_a, _b, _c, _d, _e, st) = t;
-	  //r.push(st);
-	  //vec![SequenceConstructor::new(cons_cast).set_args(Some(r))]
-          vec![Constructor::NotImplemented("cast".to_string())]
+	Some((_a, _b, _c, _d, _e, (t, empty))) => {
+          vec![Constructor::Cast(u, t, empty)]
 	}
       }
     }
@@ -740,13 +783,18 @@ fn unary_expr(input: &str) -> IResult<&str, Vec<Constructor>> {
       if u.len() == 0 {
         v
       } else {
-        //let mut a = Vec::new();
-	//for i in u {
-	  //a.push(vec![SequenceConstructor::new(cons_literal).set_data(Some(Box::new(Value::String(String::from(i)))))]);
-	//}
-	//a.push(v);
-        //vec![SequenceConstructor::new(cons_unary).set_args(Some(a))]
-        vec![Constructor::NotImplemented("unary".to_string())]
+        // Negation is multiplication by -1, which preserves the type of the operand.
+        // Unary plus still requires the operand to be numeric.
+        if u.iter().filter(|o| **o == "-").count() % 2 == 1 {
+          vec![Constructor::Arithmetic(vec![
+            ArithmeticOperand{op: ArithmeticOperator::Noop, operand: vec![Constructor::Literal(Value::Integer(-1))]},
+            ArithmeticOperand{op: ArithmeticOperator::Multiply, operand: v},
+          ])]
+        } else {
+          vec![Constructor::Arithmetic(vec![
+            ArithmeticOperand{op: ArithmeticOperator::Noop, operand: v},
+          ])]
+        }
       }
     }
   )
@@ -1302,9 +1350,23 @@ fn wildcard(input: &str) -> IResult<&str, NodeTest> {
 }
 
 // PostfixExpr ::= PrimaryExpr (Predicate | ArgumentList | Lookup)*
-// TODO: predicates, arg list, lookup
+// TODO: predicates, arg list
 fn postfix_expr(input: &str) -> IResult<&str, Vec<Constructor>> {
-  primary_expr(input)
+  map(
+    pair(
+      primary_expr,
+      many0(
+        map(
+          tuple((xpwhitespace, lookup)),
+          |(_, k)| k
+        )
+      ),
+    ),
+    |(p, l)| {
+      l.into_iter().fold(p, |acc, k| vec![Constructor::Lookup(acc, k)])
+    }
+  )
+  (input)
 }
 
 // PrimaryExpr ::= Literal | VarRef | ParenthesizedExpr | ContextItemExpr | FunctionCall | FunctionItemExpr | MapConstructor | ArrayConstructor | UnaryLookup
@@ -1314,12 +1376,104 @@ fn primary_expr(input: &str) -> IResult<&str, Vec<Constructor>> {
     literal,
     context_item,
     parenthesized_expr,
+    map_constructor,
+    square_array_constructor,
+    curly_array_constructor,
+    unary_lookup,
     function_call,
     variable_reference,
   ))
   (input)
 }
 
+// MapConstructor ::= 'map' '{' (MapConstructorEntry (',' MapConstructorEntry)*)? '}'
+// MapConstructorEntry ::= ExprSingle ':' ExprSingle
+fn map_constructor(input: &str) -> IResult<&str, Vec<Constructor>> {
+  map(
+    tuple((
+      tag("map"),
+      xpwhitespace,
+      tag("{"),
+      xpwhitespace,
+      separated_list0(
+        tuple((xpwhitespace, tag(","), xpwhitespace)),
+        map(
+          tuple((expr_single, xpwhitespace, tag(":"), xpwhitespace, expr_single)),
+          |(k, _, _, _, v)| (k, v)
+        ),
+      ),
+      xpwhitespace,
+      tag("}"),
+    )),
+    |(_, _, _, _, e, _, _)| vec![Constructor::MapConstructor(e)]
+  )
+  (input)
+}
+
+// SquareArrayConstructor ::= '[' (ExprSingle (',' ExprSingle)*)? ']'
+fn square_array_constructor(input: &str) -> IResult<&str, Vec<Constructor>> {
+  map(
+    tuple((
+      tag("["),
+      xpwhitespace,
+      separated_list0(
+        tuple((xpwhitespace, tag(","), xpwhitespace)),
+        expr_single,
+      ),
+      xpwhitespace,
+      tag("]"),
+    )),
+    |(_, _, m, _, _)| vec![Constructor::ArrayConstructor(m)]
+  )
+  (input)
+}
+
+// CurlyArrayConstructor ::= 'array' '{' Expr? '}'
+fn curly_array_constructor(input: &str) -> IResult<&str, Vec<Constructor>> {
+  map(
+    tuple((
+      tag("array"),
+      xpwhitespace,
+      tag("{"),
+      xpwhitespace,
+      opt(expr),
+      xpwhitespace,
+      tag("}"),
+    )),
+    |(_, _, _, _, e, _, _)| vec![Constructor::CurlyArrayConstructor(e.unwrap_or_default())]
+  )
+  (input)
+}
+
+// UnaryLookup ::= '?' KeySpecifier
+// The lookup is applied to the context item.
+fn unary_lookup(input: &str) -> IResult<&str, Vec<Constructor>> {
+  map(
+    lookup,
+    |k| vec![Constructor::Lookup(vec![Constructor::ContextItem], k)]
+  )
+  (input)
+}
+
+// Lookup ::= '?' KeySpecifier
+// KeySpecifier ::= NCName | IntegerLiteral | ParenthesizedExpr | '*'
+// A wildcard gives None
+fn lookup(input: &str) -> IResult<&str, Option<Vec<Constructor>>> {
+  map(
+    pair(
+      tag("?"),
+      alt((
+        map(tag("*"), |_| None),
+        map(ncname, |n| Some(vec![Constructor::Literal(Value::from(n))])),
+        map(integer_literal, Some),
+        map(parenthesized_expr, Some),
+      )),
+    ),
+    |(_, k)| k
+  )
+  (input)
+}
+
 // VarRef ::= '$' VarName
 fn variable_reference(input: &str) -> IResult<&str, Vec<Constructor>> {
   map(
@@ -1349,6 +1503,12 @@ fn function_call(input: &str) -> IResult<&str, Vec<Constructor>> {
 	    a
       	  )]
 	}
+        NodeTest::Name(NameTest{name: Some(WildcardOrName::Name(localpart)), ns: None, prefix: Some(p)}) => {
+      	  vec![Constructor::FunctionCall(
+            Function::new_qualified(localpart, None, Some(p), vec![], None),
+	    a
+      	  )]
+	}
 	_ => {
       	  vec![Constructor::Literal(Value::from("invalid qname"))]
 	}
@@ -1403,13 +1563,17 @@ fn numeric_literal(input: &str) -> IResult<&str, Vec<Constructor>> {
 // IntegerLiteral ::= Digits
 fn integer_literal(input: &str) -> IResult<&str, Vec<Constructor>> {
   map(digit1, |s: &str| {
-    let n = s.parse::<i64>().unwrap();
-    vec![Constructor::Literal(Value::Integer(n))]
+    // An integer that is too large for xs:integer becomes xs:decimal
+    let i = match s.parse::<i64>() {
+      Ok(n) => Value::Integer(n),
+      Err(_) => Decimal::from_str(s).map_or(Value::Double(s.parse::<f64>().unwrap_or(f64::NAN)), Value::Decimal),
+    };
+    vec![Constructor::Literal(i)]
   })
   (input)
 }
 // DecimalLiteral ::= ('.' Digits) | (Digits '.' [0-9]*)
-// Construct a double, but if that fails fall back to decimal
+// Construct a decimal, but if that fails (i.e. too many digits) fall back to double
 fn decimal_literal(input: &str) -> IResult<&str, Vec<Constructor>> {
   map(
     alt((
@@ -1417,10 +1581,9 @@ fn decimal_literal(input: &str) -> IResult<&str, Vec<Constructor>> {
       recognize(complete(tuple((digit1, tag("."), digit0)))),
     )),
     |s: &str| {
-      let n = s.parse::<f64>();
-      let i = match n {
-        Ok(m) => Value::Double(m),
-	Err(_) => Value::Decimal(Decimal::from_str(s).unwrap()),
+      let i = match Decimal::from_str(s) {
+        Ok(d) => Value::Decimal(d),
+	Err(_) => Value::Double(s.parse::<f64>().unwrap_or(f64::NAN)),
       };
       vec![Constructor::Literal(i)]
     }
@@ -1634,10 +1797,20 @@ pub fn parse(e: &str) -> Result<Vec<Constructor>, crate::xdmerror::Error> {
   }
 }
 
+/// Parse a sequence type, e.g. the as attribute of an XSLT declaration.
+pub fn parse_sequence_type(e: &str) -> Result<SequenceType, crate::xdmerror::Error> {
+  match sequencetype_expr(e.trim()) {
+    Ok(("", st)) => Ok(st),
+    Ok((rest, _)) => Result::Err(Error{kind: ErrorKind::StaticSyntax, message: format!("extra characters after sequence type: \"{}\"", rest)}),
+    Err(_) => Result::Err(Error{kind: ErrorKind::StaticSyntax, message: format!("invalid sequence type \"{}\"", e)}),
+  }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::rc::Rc;
+    use rust_decimal_macros::dec;
     use crate::item::*;
     use crate::forest::Forest;
 
@@ -2061,7 +2234,7 @@ mod tests {
     #[test]
     fn parse_eval_fncall_string() {
 	let mut e = parse("string(('a', 'b', 'c'))").expect("failed to parse expression \"string(('a', 'b', 'c'))\"");
-	StaticContext::new_with_builtins().static_analysis(&mut e);
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
 	let mut f = Forest::new();
 	let sd = f.plant_tree();
 	let rd = f.plant_tree();
@@ -2071,7 +2244,7 @@ mod tests {
     #[test]
     fn parse_eval_fncall_concat() {
 	let mut e = parse("concat('a', 'b', 'c')").expect("failed to parse expression \"concat('a', 'b', 'c')\"");
-	StaticContext::new_with_builtins().static_analysis(&mut e);
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
 	let mut f = Forest::new();
 	let sd = f.plant_tree();
 	let rd = f.plant_tree();
@@ -2079,9 +2252,85 @@ mod tests {
 	assert_eq!(s.to_string(None), "abc")
     }
     #[test]
+    fn parse_eval_fncall_prefixed() {
+	let mut e = parse("fn:concat('a', 'b')").expect("failed to parse expression \"fn:concat('a', 'b')\"");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
+	assert_eq!(s.to_string(None), "ab")
+    }
+    #[test]
+    fn parse_eval_fncall_math_sqrt() {
+	let mut e = parse("math:sqrt(16)").expect("failed to parse expression \"math:sqrt(16)\"");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
+	assert_eq!(s.to_string(None), "4")
+    }
+    #[test]
+    fn parse_eval_fncall_math_argument_type() {
+	// A node is cast to a double, but a string is a type error
+	let s = eval_with_doc("<Test>16</Test>", "math:sqrt(.)").expect("evaluation failed");
+	assert_eq!(s.to_string(None), "4");
+	match eval_with_doc("<Test/>", "math:sqrt('abc')") {
+	    Err(e) => assert_eq!(e.kind.to_string(), ErrorKind::TypeError.to_string()),
+	    Ok(_) => panic!("string argument was accepted"),
+	}
+	match eval_with_doc("<Test/>", "math:pow(2, '10')") {
+	    Err(e) => assert_eq!(e.kind.to_string(), ErrorKind::TypeError.to_string()),
+	    Ok(_) => panic!("string argument was accepted"),
+	}
+    }
+    #[test]
+    fn parse_eval_fncall_math_pow() {
+	let mut e = parse("math:pow(2, 10)").expect("failed to parse expression \"math:pow(2, 10)\"");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
+	assert_eq!(s.to_string(None), "1024")
+    }
+    #[test]
+    fn parse_eval_fncall_math_log_zero() {
+	let mut e = parse("math:log(0)").expect("failed to parse expression \"math:log(0)\"");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
+	assert_eq!(s.to_string(None), "-INF")
+    }
+    #[test]
+    fn parse_eval_fncall_math_empty() {
+	let mut e = parse("math:sin(())").expect("failed to parse expression \"math:sin(())\"");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
+	assert_eq!(s.len(), 0)
+    }
+    #[test]
+    fn parse_eval_fncall_math_extension_namespace() {
+	let mut e = parse("m:pi()").expect("failed to parse expression \"m:pi()\"");
+	let mut sc = StaticContext::new_with_builtins();
+	sc.declare_namespace("m".to_string(), crate::evaluate::MATHNS.to_string());
+	sc.static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
+	assert_eq!(s.to_string(None), std::f64::consts::PI.to_string())
+    }
+    #[test]
     fn parse_eval_fncall_startswith_pos() {
 	let mut e = parse("starts-with('abc', 'a')").expect("failed to parse expression \"starts-with('abc', 'a')\"");
-	StaticContext::new_with_builtins().static_analysis(&mut e);
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
 	let mut f = Forest::new();
 	let sd = f.plant_tree();
 	let rd = f.plant_tree();
@@ -2091,7 +2340,7 @@ mod tests {
     #[test]
     fn parse_eval_fncall_startswith_neg() {
 	let mut e = parse("starts-with('abc', 'b')").expect("failed to parse expression \"starts-with('abc', 'a')\"");
-	StaticContext::new_with_builtins().static_analysis(&mut e);
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
 	let mut f = Forest::new();
 	let sd = f.plant_tree();
 	let rd = f.plant_tree();
@@ -2101,7 +2350,7 @@ mod tests {
     #[test]
     fn parse_eval_fncall_contains_pos() {
 	let mut e = parse("contains('abc', 'b')").expect("failed to parse expression \"contains('abc', 'b')\"");
-	StaticContext::new_with_builtins().static_analysis(&mut e);
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
 	let mut f = Forest::new();
 	let sd = f.plant_tree();
 	let rd = f.plant_tree();
@@ -2111,7 +2360,7 @@ mod tests {
     #[test]
     fn parse_eval_fncall_contains_neg() {
 	let mut e = parse("contains('abc', 'd')").expect("failed to parse expression \"contains('abc', 'd')\"");
-	StaticContext::new_with_builtins().static_analysis(&mut e);
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
 	let mut f = Forest::new();
 	let sd = f.plant_tree();
 	let rd = f.plant_tree();
@@ -2122,7 +2371,7 @@ mod tests {
     fn parse_eval_fncall_substringbefore_pos() {
 	let mut e = parse("substring-before('abc', 'b')")
             .expect("failed to parse expression \"substring-before('abc', 'b')\"");
-	StaticContext::new_with_builtins().static_analysis(&mut e);
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
 	let mut f = Forest::new();
 	let sd = f.plant_tree();
 	let rd = f.plant_tree();
@@ -2133,7 +2382,7 @@ mod tests {
     fn parse_eval_fncall_substringbefore_neg() {
 	let mut e = parse("substring-before('abc', 'd')")
             .expect("failed to parse expression \"substring-before('abc', 'd')\"");
-	StaticContext::new_with_builtins().static_analysis(&mut e);
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
 	let mut f = Forest::new();
 	let sd = f.plant_tree();
 	let rd = f.plant_tree();
@@ -2144,7 +2393,7 @@ mod tests {
     fn parse_eval_fncall_substringafter_pos_1() {
 	let mut e = parse("substring-after('abc', 'b')")
             .expect("failed to parse expression \"substring-after('abc', 'b')\"");
-	StaticContext::new_with_builtins().static_analysis(&mut e);
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
 	let mut f = Forest::new();
 	let sd = f.plant_tree();
 	let rd = f.plant_tree();
@@ -2155,7 +2404,7 @@ mod tests {
     fn parse_eval_fncall_substringafter_pos_2() {
 	let mut e = parse("substring-after('abc', 'c')")
             .expect("failed to parse expression \"substring-after('abc', 'b')\"");
-	StaticContext::new_with_builtins().static_analysis(&mut e);
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
 	let mut f = Forest::new();
 	let sd = f.plant_tree();
 	let rd = f.plant_tree();
@@ -2166,7 +2415,7 @@ mod tests {
     fn parse_eval_fncall_substringafter_neg() {
 	let mut e = parse("substring-after('abc', 'd')")
             .expect("failed to parse expression \"substring-after('abc', 'd')\"");
-	StaticContext::new_with_builtins().static_analysis(&mut e);
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
 	let mut f = Forest::new();
 	let sd = f.plant_tree();
 	let rd = f.plant_tree();
@@ -2177,7 +2426,7 @@ mod tests {
     fn parse_eval_fncall_normalizespace() {
 	let mut e = parse("normalize-space('	a  b\nc 	')")
             .expect("failed to parse expression \"normalize-space('	a  b\nc 	')\"");
-	StaticContext::new_with_builtins().static_analysis(&mut e);
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
 	let mut f = Forest::new();
 	let sd = f.plant_tree();
 	let rd = f.plant_tree();
@@ -2188,7 +2437,7 @@ mod tests {
     fn parse_eval_fncall_translate() {
 	let mut e = parse("translate('abcdeabcde', 'ade', 'XY')")
             .expect("failed to parse expression \"translate('abcdeabcde', 'ade', 'XY')\"");
-	StaticContext::new_with_builtins().static_analysis(&mut e);
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
 	let mut f = Forest::new();
 	let sd = f.plant_tree();
 	let rd = f.plant_tree();
@@ -2198,7 +2447,7 @@ mod tests {
     #[test]
     fn parse_eval_fncall_boolean_true() {
 	let mut e = parse("boolean('abcdeabcde')").expect("failed to parse expression \"boolean('abcdeabcde')\"");
-	StaticContext::new_with_builtins().static_analysis(&mut e);
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
 	let mut f = Forest::new();
 	let sd = f.plant_tree();
 	let rd = f.plant_tree();
@@ -2212,7 +2461,7 @@ mod tests {
     #[test]
     fn parse_eval_fncall_boolean_false() {
 	let mut e = parse("boolean('')").expect("failed to parse expression \"boolean('')\"");
-	StaticContext::new_with_builtins().static_analysis(&mut e);
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
 	let mut f = Forest::new();
 	let sd = f.plant_tree();
 	let rd = f.plant_tree();
@@ -2226,7 +2475,7 @@ mod tests {
     #[test]
     fn parse_eval_fncall_not_true() {
 	let mut e = parse("not('')").expect("failed to parse expression \"not('')\"");
-	StaticContext::new_with_builtins().static_analysis(&mut e);
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
 	let mut f = Forest::new();
 	let sd = f.plant_tree();
 	let rd = f.plant_tree();
@@ -2240,7 +2489,7 @@ mod tests {
     #[test]
     fn parse_eval_fncall_not_false() {
 	let mut e = parse("not('abc')").expect("failed to parse expression \"not('abc')\"");
-	StaticContext::new_with_builtins().static_analysis(&mut e);
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
 	let mut f = Forest::new();
 	let sd = f.plant_tree();
 	let rd = f.plant_tree();
@@ -2254,7 +2503,7 @@ mod tests {
     #[test]
     fn parse_eval_fncall_true() {
 	let mut e = parse("true()").expect("failed to parse expression \"true()\"");
-	StaticContext::new_with_builtins().static_analysis(&mut e);
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
 	let mut f = Forest::new();
 	let sd = f.plant_tree();
 	let rd = f.plant_tree();
@@ -2268,7 +2517,7 @@ mod tests {
     #[test]
     fn parse_eval_fncall_false() {
 	let mut e = parse("false()").expect("failed to parse expression \"false()\"");
-	StaticContext::new_with_builtins().static_analysis(&mut e);
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
 	let mut f = Forest::new();
 	let sd = f.plant_tree();
 	let rd = f.plant_tree();
@@ -2282,7 +2531,7 @@ mod tests {
     #[test]
     fn parse_eval_fncall_number_int() {
 	let mut e = parse("number('123')").expect("failed to parse expression \"number('123')\"");
-	StaticContext::new_with_builtins().static_analysis(&mut e);
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
 	let mut f = Forest::new();
 	let sd = f.plant_tree();
 	let rd = f.plant_tree();
@@ -2296,7 +2545,7 @@ mod tests {
     #[test]
     fn parse_eval_fncall_number_double() {
 	let mut e = parse("number('123.456')").expect("failed to parse expression \"number('123.456')\"");
-	StaticContext::new_with_builtins().static_analysis(&mut e);
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
 	let mut f = Forest::new();
 	let sd = f.plant_tree();
 	let rd = f.plant_tree();
@@ -2311,7 +2560,7 @@ mod tests {
     fn parse_eval_fncall_sum() {
 	let mut e = parse("sum(('123.456', 10, 20, '0'))")
             .expect("failed to parse expression \"sum(('123.456', 10, 20, '0'))\"");
-	StaticContext::new_with_builtins().static_analysis(&mut e);
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
 	let mut f = Forest::new();
 	let sd = f.plant_tree();
 	let rd = f.plant_tree();
@@ -2325,65 +2574,209 @@ mod tests {
     #[test]
     fn parse_eval_fncall_floor() {
 	let mut e = parse("floor(123.456)").expect("failed to parse expression \"floor(123.456)\"");
-	StaticContext::new_with_builtins().static_analysis(&mut e);
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
 	let mut f = Forest::new();
 	let sd = f.plant_tree();
 	let rd = f.plant_tree();
 	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
 	assert_eq!(s.len(), 1);
 	match *s[0] {
-            Item::Value(Value::Double(d)) => assert_eq!(d, 123.0),
-	    _ => panic!("not a singleton double value")
+            Item::Value(Value::Decimal(d)) => assert_eq!(d, dec!(123)),
+	    _ => panic!("not a singleton decimal value")
 	}
     }
     #[test]
     fn parse_eval_fncall_ceiling() {
 	let mut e = parse("ceiling(123.456)").expect("failed to parse expression \"ceiling(123.456)\"");
-	StaticContext::new_with_builtins().static_analysis(&mut e);
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
 	let mut f = Forest::new();
 	let sd = f.plant_tree();
 	let rd = f.plant_tree();
 	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
 	assert_eq!(s.len(), 1);
 	match *s[0] {
-            Item::Value(Value::Double(d)) => assert_eq!(d, 124.0),
-	    _ => panic!("not a singleton double value")
+            Item::Value(Value::Decimal(d)) => assert_eq!(d, dec!(124)),
+	    _ => panic!("not a singleton decimal value")
 	}
     }
     #[test]
     fn parse_eval_fncall_round_down() {
 	let mut e = parse("round(123.456)").expect("failed to parse expression \"round(123.456)\"");
-	StaticContext::new_with_builtins().static_analysis(&mut e);
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
 	let mut f = Forest::new();
 	let sd = f.plant_tree();
 	let rd = f.plant_tree();
 	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
 	assert_eq!(s.len(), 1);
 	match *s[0] {
-            Item::Value(Value::Double(d)) => assert_eq!(d, 123.0),
-	    _ => panic!("not a singleton double value")
+            Item::Value(Value::Decimal(d)) => assert_eq!(d, dec!(123)),
+	    _ => panic!("not a singleton decimal value")
 	}
     }
     #[test]
     fn parse_eval_fncall_round_up() {
 	let mut e = parse("round(123.654)").expect("failed to parse expression \"round(123.654)\"");
-	StaticContext::new_with_builtins().static_analysis(&mut e);
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
 	let mut f = Forest::new();
 	let sd = f.plant_tree();
 	let rd = f.plant_tree();
 	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
 	assert_eq!(s.len(), 1);
 	match *s[0] {
-            Item::Value(Value::Double(d)) => assert_eq!(d, 124.0),
-	    _ => panic!("not a singleton double value")
+            Item::Value(Value::Decimal(d)) => assert_eq!(d, dec!(124)),
+	    _ => panic!("not a singleton decimal value")
 	}
     }
 
+    #[test]
+    fn parse_eval_arith_decimal_add() {
+	let mut e = parse("1.10 + 2.20").expect("failed to parse expression \"1.10 + 2.20\"");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
+	assert_eq!(s.to_string(None), "3.3")
+    }
+    #[test]
+    fn parse_eval_arith_integer_divide_decimal() {
+	let mut e = parse("1 div 4").expect("failed to parse expression \"1 div 4\"");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
+	assert_eq!(s.to_string(None), "0.25")
+    }
+    #[test]
+    fn parse_eval_arith_idiv() {
+	let mut e = parse("7 idiv 2").expect("failed to parse expression \"7 idiv 2\"");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
+	assert_eq!(s.to_string(None), "3")
+    }
+    #[test]
+    fn parse_eval_arith_mod() {
+	let mut e = parse("7 mod 2").expect("failed to parse expression \"7 mod 2\"");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
+	assert_eq!(s.to_string(None), "1")
+    }
+    #[test]
+    fn parse_eval_arith_promote_double() {
+	let mut e = parse("1.5 * 2.0e0").expect("failed to parse expression \"1.5 * 2.0e0\"");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
+	assert_eq!(s.to_string(None), "3")
+    }
+    #[test]
+    fn parse_eval_arith_round_half_up() {
+	let mut e = parse("round(-2.5)").expect("failed to parse expression \"round(-2.5)\"");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
+	assert_eq!(s.to_string(None), "-2")
+    }
+    #[test]
+    fn parse_eval_arith_round_precision() {
+	let mut e = parse("round(1234.5678, 2)").expect("failed to parse expression \"round(1234.5678, 2)\"");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
+	assert_eq!(s.to_string(None), "1234.57")
+    }
+    #[test]
+    fn parse_eval_arith_round_negative_precision() {
+	let mut e = parse("round(1250, -2)").expect("failed to parse expression \"round(1250, -2)\"");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
+	assert_eq!(s.to_string(None), "1300")
+    }
+    #[test]
+    fn parse_eval_arith_round_half_to_even() {
+	let mut e = parse("round-half-to-even(2.5)").expect("failed to parse expression \"round-half-to-even(2.5)\"");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
+	assert_eq!(s.to_string(None), "2")
+    }
+    #[test]
+    fn parse_eval_arith_round_half_to_even_precision() {
+	let mut e = parse("round-half-to-even(3.567812e0, 2)").expect("failed to parse expression \"round-half-to-even(3.567812e0, 2)\"");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
+	assert_eq!(s.to_string(None), "3.57")
+    }
+    #[test]
+    fn parse_eval_arith_abs() {
+	let mut e = parse("abs(-3)").expect("failed to parse expression \"abs(-3)\"");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
+	assert_eq!(s.to_string(None), "3")
+    }
+    #[test]
+    fn parse_eval_arith_sum_decimal() {
+	let mut e = parse("sum((0.1, 0.2))").expect("failed to parse expression \"sum((0.1, 0.2))\"");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
+	assert_eq!(s.to_string(None), "0.3")
+    }
+    #[test]
+    fn parse_eval_arith_overflow() {
+	let mut e = parse("9223372036854775807 + 1").expect("failed to parse expression \"9223372036854775807 + 1\"");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	match Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd) {
+	    Err(Error{kind: ErrorKind::Overflow, ..}) => assert!(true),
+	    _ => panic!("expected Overflow error")
+	}
+    }
+    #[test]
+    fn parse_eval_arith_divide_by_zero() {
+	let mut e = parse("1 div 0").expect("failed to parse expression \"1 div 0\"");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	match Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd) {
+	    Err(Error{kind: ErrorKind::DivideByZero, ..}) => assert!(true),
+	    _ => panic!("expected DivideByZero error")
+	}
+    }
     // Variables
     #[test]
     fn parse_eval_let_1() {
 	let mut e = parse("let $x := 'a' return ($x, $x)").expect("failed to parse let expression");
-	StaticContext::new_with_builtins().static_analysis(&mut e);
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
 	let mut f = Forest::new();
 	let sd = f.plant_tree();
 	let rd = f.plant_tree();
@@ -2393,7 +2786,7 @@ mod tests {
     #[test]
     fn parse_eval_let_2() {
 	let mut e = parse("let $x := 'a', $y := 'b' return ($x, $y)").expect("failed to parse let expression");
-	StaticContext::new_with_builtins().static_analysis(&mut e);
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
 	let mut f = Forest::new();
 	let sd = f.plant_tree();
 	let rd = f.plant_tree();
@@ -2406,7 +2799,7 @@ mod tests {
     #[test]
     fn parse_eval_for_1() {
 	let mut e = parse("for $x in ('a', 'b', 'c') return ($x, $x)").expect("failed to parse let expression");
-	StaticContext::new_with_builtins().static_analysis(&mut e);
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
 	let mut f = Forest::new();
 	let sd = f.plant_tree();
 	let rd = f.plant_tree();
@@ -2417,7 +2810,7 @@ mod tests {
     #[test]
     fn parse_eval_for_2() {
 	let mut e = parse("for $x in (1, 2, 3) return $x * 2").expect("failed to parse let expression");
-	StaticContext::new_with_builtins().static_analysis(&mut e);
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
 	let mut f = Forest::new();
 	let sd = f.plant_tree();
 	let rd = f.plant_tree();
@@ -2429,7 +2822,7 @@ mod tests {
     #[test]
     fn parse_eval_if_1() {
 	let mut e = parse("if (1) then 'one' else 'not one'").expect("failed to parse let expression");
-	StaticContext::new_with_builtins().static_analysis(&mut e);
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
 	let mut f = Forest::new();
 	let sd = f.plant_tree();
 	let rd = f.plant_tree();
@@ -2440,7 +2833,7 @@ mod tests {
     #[test]
     fn parse_eval_if_2() {
 	let mut e = parse("if (0) then 'one' else 'not one'").expect("failed to parse let expression");
-	StaticContext::new_with_builtins().static_analysis(&mut e);
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
 	let mut f = Forest::new();
 	let sd = f.plant_tree();
 	let rd = f.plant_tree();
@@ -2448,5 +2841,662 @@ mod tests {
 	assert_eq!(s.len(), 1);
 	assert_eq!(s.to_string(None), "not one")
     }
+    #[test]
+    fn parse_eval_cast_token() {
+	let mut e = parse("'  a   b  ' cast as xs:token").expect("failed to parse expression \"'  a   b  ' cast as xs:token\"");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
+	assert_eq!(s.to_string(None), "a b")
+    }
+    #[test]
+    fn parse_eval_cast_ncname_invalid() {
+	let mut e = parse("'x:y' cast as xs:NCName").expect("failed to parse expression");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	match Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd) {
+	    Err(Error{kind: ErrorKind::InvalidCast, ..}) => {}
+	    _ => panic!("cast should have failed"),
+	}
+    }
+    #[test]
+    fn parse_eval_cast_nmtokens() {
+	let mut e = parse("'one two  three' cast as xs:NMTOKENS").expect("failed to parse expression \"'one two  three' cast as xs:NMTOKENS\"");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
+	assert_eq!(s.len(), 3);
+	assert_eq!(s[1].to_string(None), "two")
+    }
+    #[test]
+    fn parse_eval_castable() {
+	let mut e = parse("'x:y' castable as xs:NCName").expect("failed to parse expression \"'x:y' castable as xs:NCName\"");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
+	assert!(!s.to_bool())
+    }
+    #[test]
+    fn parse_eval_castable_empty() {
+	let mut e = parse("() castable as xs:NCName?").expect("failed to parse expression \"() castable as xs:NCName?\"");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
+	assert!(s.to_bool())
+    }
+    #[test]
+    fn parse_eval_constructor_function() {
+	let mut e = parse("xs:ID('  foo ')").expect("failed to parse expression \"xs:ID('  foo ')\"");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
+	assert_eq!(s.to_string(None), "foo")
+    }
+    #[test]
+    fn parse_eval_instance_of_derived() {
+	let mut e = parse("xs:ID('foo') instance of xs:Name").expect("failed to parse expression \"xs:ID('foo') instance of xs:Name\"");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
+	assert!(s.to_bool())
+    }
+    #[test]
+    fn parse_eval_instance_of_not_derived() {
+	let mut e = parse("xs:NMTOKEN('foo') instance of xs:Name").expect("failed to parse expression \"xs:NMTOKEN('foo') instance of xs:Name\"");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
+	assert!(!s.to_bool())
+    }
+    #[test]
+    fn parse_eval_instance_of_occurrence() {
+	let mut e = parse("(xs:language('en-AU'), xs:language('fr')) instance of xs:token+").expect("failed to parse expression \"(xs:language('en-AU'), xs:language('fr')) instance of xs:token+\"");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
+	assert!(s.to_bool())
+    }
+    #[test]
+    fn parse_eval_instance_of_empty() {
+	let mut e = parse("() instance of empty-sequence()").expect("failed to parse expression \"() instance of empty-sequence()\"");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
+	assert!(s.to_bool())
+    }
+    #[test]
+    fn parse_eval_bin_length() {
+	let mut e = parse("bin:length(xs:base64Binary('SGVsbG8='))").expect("failed to parse expression \"bin:length(xs:base64Binary('SGVsbG8='))\"");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
+	assert_eq!(s.to_string(None), "5")
+    }
+    #[test]
+    fn parse_eval_bin_part() {
+	let mut e = parse("bin:part(xs:hexBinary('0001FEFF'), 1, 2)").expect("failed to parse expression \"bin:part(xs:hexBinary('0001FEFF'), 1, 2)\"");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
+	assert_eq!(s.to_string(None), "Af4=")
+    }
+    #[test]
+    fn parse_eval_bin_to_octets() {
+	let mut e = parse("bin:to-octets(xs:base64Binary('AAH+'))").expect("failed to parse expression \"bin:to-octets(xs:base64Binary('AAH+'))\"");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
+	assert_eq!(s.len(), 3);
+	assert_eq!(s[2].to_string(None), "254")
+    }
+    #[test]
+    fn parse_eval_cast_binary() {
+	let mut e = parse("xs:hexBinary(xs:base64Binary('AAH+'))").expect("failed to parse expression \"xs:hexBinary(xs:base64Binary('AAH+'))\"");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
+	assert_eq!(s.to_string(None), "0001FE")
+    }
+    #[test]
+    fn parse_eval_bin_part_out_of_range() {
+	let mut e = parse("bin:part(xs:hexBinary('0001'), 1, 2)").expect("failed to parse expression");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	match Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd) {
+	    Err(Error{kind: ErrorKind::BinaryIndexOutOfRange, ..}) => {}
+	    _ => panic!("part should have failed"),
+	}
+    }
+    // Evaluate an expression with the document element of the source document as the context item
+    fn eval_with_doc(src: &str, expr: &str) -> Result<Sequence, Error> {
+	let mut e = parse(expr).expect("failed to parse expression");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.grow_tree(src).expect("unable to parse XML");
+	let rd = f.plant_tree();
+	let doc = f.get_ref(sd).unwrap().get_doc_node();
+	let top = doc.get_first_element(&f).expect("no document element");
+	Evaluator::new().evaluate(Some(vec![Rc::new(Item::Node(top))]), Some(0), &e, &mut f, sd, rd)
+    }
+    #[test]
+    fn parse_eval_name_prefixed() {
+	let s = eval_with_doc("<x:Test xmlns:x='urn:test'/>", "name()").expect("evaluation failed");
+	assert_eq!(s.to_string(None), "x:Test")
+    }
+    #[test]
+    fn parse_eval_node_name() {
+	let s = eval_with_doc("<x:Test xmlns:x='urn:test'/>", "namespace-uri-from-QName(node-name())").expect("evaluation failed");
+	assert_eq!(s.to_string(None), "urn:test")
+    }
+    #[test]
+    fn parse_eval_resolve_qname() {
+	let s = eval_with_doc(
+	    "<wsdl xmlns:tns='urn:service' type='tns:Port'/>",
+	    "local-name-from-QName(resolve-QName(string(attribute::type), .)), namespace-uri-from-QName(resolve-QName(string(attribute::type), .))"
+	).expect("evaluation failed");
+	assert_eq!(s.len(), 2);
+	assert_eq!(s[0].to_string(None), "Port");
+	assert_eq!(s[1].to_string(None), "urn:service")
+    }
+    #[test]
+    fn parse_eval_resolve_qname_undeclared() {
+	match eval_with_doc("<Test/>", "resolve-QName('y:foo', .)") {
+	    Err(Error{kind: ErrorKind::NoNamespace, ..}) => {}
+	    _ => panic!("resolve-QName should have failed"),
+	}
+    }
+    #[test]
+    fn parse_eval_qname() {
+	let mut e = parse("prefix-from-QName(QName('urn:test', 'x:foo'))").expect("failed to parse expression");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
+	assert_eq!(s.to_string(None), "x")
+    }
+    #[test]
+    fn parse_eval_qname_equal() {
+	let mut e = parse("QName('urn:test', 'x:foo') eq QName('urn:test', 'y:foo')").expect("failed to parse expression");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
+	assert!(s.to_bool())
+    }
+    #[test]
+    fn parse_eval_compare_codepoint() {
+	let mut e = parse("compare('B', 'a')").expect("failed to parse expression \"compare('B', 'a')\"");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
+	assert_eq!(s.to_string(None), "-1")
+    }
+    #[test]
+    fn parse_eval_compare_collation() {
+	let mut e = parse("compare('B', 'a', 'http://www.w3.org/2005/xpath-functions/collation/html-ascii-case-insensitive')").expect("failed to parse expression \"compare('B', 'a', 'http://www.w3.org/2005/xpath-functions/collation/html-ascii-case-insensitive')\"");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
+	assert_eq!(s.to_string(None), "1")
+    }
+    #[test]
+    fn parse_eval_contains_collation() {
+	let mut e = parse("contains('Hello World', 'WORLD', 'http://www.w3.org/2005/xpath-functions/collation/html-ascii-case-insensitive')").expect("failed to parse expression \"contains('Hello World', 'WORLD', 'http://www.w3.org/2005/xpath-functions/collation/html-ascii-case-insensitive')\"");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
+	assert!(s.to_bool())
+    }
+    #[test]
+    fn parse_eval_distinct_values() {
+	let mut e = parse("distinct-values(('a', 'A', 'b', 'a'), 'http://www.w3.org/2005/xpath-functions/collation/html-ascii-case-insensitive')").expect("failed to parse expression \"distinct-values(('a', 'A', 'b', 'a'), 'http://www.w3.org/2005/xpath-functions/collation/html-ascii-case-insensitive')\"");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
+	assert_eq!(s.len(), 2);
+	assert_eq!(s.to_string(None), "ab")
+    }
+    #[test]
+    fn parse_eval_distinct_values_numeric() {
+	let mut e = parse("distinct-values((1, 1.0, 2, 'x'))").expect("failed to parse expression \"distinct-values((1, 1.0, 2, 'x'))\"");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
+	assert_eq!(s.len(), 3)
+    }
+    #[test]
+    fn parse_eval_sort_numeric() {
+	let mut e = parse("sort((3, 1.5, 2))").expect("failed to parse expression \"sort((3, 1.5, 2))\"");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
+	assert_eq!(s.to_string(None), "1.523")
+    }
+    #[test]
+    fn parse_eval_sort_collation() {
+	let mut e = parse("sort(('b', 'C', 'a'), 'http://www.w3.org/2005/xpath-functions/collation/html-ascii-case-insensitive')").expect("failed to parse expression \"sort(('b', 'C', 'a'), 'http://www.w3.org/2005/xpath-functions/collation/html-ascii-case-insensitive')\"");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let s = Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
+	assert_eq!(s.to_string(None), "abC")
+    }
+    #[test]
+    fn parse_eval_unknown_collation() {
+	let mut e = parse("compare('a', 'b', 'http://example.org/unknown')").expect("failed to parse expression");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	match Evaluator::new().evaluate(None, None, &e, &mut f, sd, rd) {
+	    Err(Error{kind: ErrorKind::UnknownCollation, ..}) => {}
+	    _ => panic!("compare should have failed"),
+	}
+    }
+    #[test]
+    fn parse_eval_registered_collation() {
+	struct Reverse;
+	impl crate::collation::Collation for Reverse {
+	    fn key(&self, s: &str) -> String {
+		s.to_string()
+	    }
+	    fn compare(&self, a: &str, b: &str) -> std::cmp::Ordering {
+		b.cmp(a)
+	    }
+	}
+	let mut e = parse("sort(('a', 'c', 'b'), 'http://example.org/reverse')").expect("failed to parse expression");
+	let mut sc = StaticContext::new_with_builtins();
+	sc.register_collation("http://example.org/reverse", Rc::new(Reverse));
+	sc.static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let mut ev = Evaluator::new();
+	ev.set_collations(sc.collations.clone());
+	let s = ev.evaluate(None, None, &e, &mut f, sd, rd).expect("evaluation failed");
+	assert_eq!(s.to_string(None), "cba")
+    }
+    #[test]
+    fn parse_eval_deep_equal_values() {
+	let s = eval_with_doc("<Test/>", "deep-equal((1, 'a', xs:double('NaN')), (1.0, 'a', xs:double('NaN')))").expect("evaluation failed");
+	assert!(s.to_bool());
+	let s = eval_with_doc("<Test/>", "deep-equal((1, 2), (1, '2'))").expect("evaluation failed");
+	assert!(!s.to_bool());
+	let s = eval_with_doc("<Test/>", "deep-equal('A', 'a', 'http://www.w3.org/2005/xpath-functions/collation/html-ascii-case-insensitive')").expect("evaluation failed");
+	assert!(s.to_bool())
+    }
+    #[test]
+    fn parse_eval_deep_equal_nodes() {
+	let src = "<Test><a id='p'><b x='1'>one<!-- c --></b></a><a id='q'><b x='1'>one</b></a><a id='r'><b>one</b></a></Test>";
+	let s = eval_with_doc(src, "deep-equal(child::a[attribute::id = 'p']/child::node(), child::a[attribute::id = 'q']/child::node())").expect("evaluation failed");
+	assert!(s.to_bool());
+	let s = eval_with_doc(src, "deep-equal(child::a[attribute::id = 'p']/child::node(), child::a[attribute::id = 'r']/child::node())").expect("evaluation failed");
+	assert!(!s.to_bool())
+    }
+    fn eval_with_resolver(expr: &str) -> Result<(Sequence, Forest), Error> {
+	let mut r = crate::resolver::MemoryResolver::new();
+	r.add_document("http://example.org/a.xml", "<A>a</A>")?;
+	r.add_document("http://example.org/b.xml", "<B>b</B>")?;
+	r.add_document("http://example.org/c.json", r#"{"x": [1, 2]}"#)?;
+	r.add_collection(None, vec!["http://example.org/a.xml", "http://example.org/b.xml"])?;
+	let mut e = parse(expr).expect("failed to parse expression");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.grow_tree("<Test/>")?;
+	let rd = f.plant_tree();
+	let doc = f.get_ref(sd).unwrap().get_doc_node();
+	let mut ev = Evaluator::new();
+	ev.set_document_resolver(Rc::new(r));
+	ev.set_baseurl(url::Url::parse("http://example.org/").expect("unable to parse URL"));
+	let s = ev.evaluate(Some(vec![Rc::new(Item::Node(doc))]), Some(0), &e, &mut f, sd, rd)?;
+	Ok((s, f))
+    }
+    #[test]
+    fn parse_eval_doc() {
+	let (s, f) = eval_with_resolver("doc('a.xml')/child::A/child::text()").expect("evaluation failed");
+	assert_eq!(s.to_string(Some(&f)), "a");
+	// The same URI always gives the same document
+	let (s, _) = eval_with_resolver("(doc('a.xml'), doc('http://example.org/a.xml'))").expect("evaluation failed");
+	assert_eq!(s.len(), 2);
+	match (&*s[0], &*s[1]) {
+	    (Item::Node(m), Item::Node(n)) => assert_eq!(m, n),
+	    _ => panic!("not nodes"),
+	}
+	match eval_with_resolver("doc('missing.xml')") {
+	    Err(e) => assert_eq!(e.kind.to_string(), ErrorKind::DocumentNotFound.to_string()),
+	    Ok(_) => panic!("missing document was found"),
+	}
+    }
+    #[test]
+    fn parse_eval_doc_available() {
+	let (s, _) = eval_with_resolver("doc-available('b.xml')").expect("evaluation failed");
+	assert!(s.to_bool());
+	let (s, _) = eval_with_resolver("doc-available('missing.xml')").expect("evaluation failed");
+	assert!(!s.to_bool())
+    }
+    #[test]
+    fn parse_eval_collection() {
+	let (s, _) = eval_with_resolver("collection()").expect("evaluation failed");
+	assert_eq!(s.len(), 2);
+	let (s, f) = eval_with_resolver("collection()/child::*").expect("evaluation failed");
+	assert_eq!(s.iter().map(|i| i.to_xml(Some(&f))).collect::<Vec<String>>(), vec!["<A>a</A>", "<B>b</B>"]);
+	assert!(eval_with_resolver("collection('http://example.org/none')").is_err())
+    }
+    // Evaluate an expression with a base URI of a temporary directory that contains the given files
+    fn eval_with_files(files: Vec<(&str, &[u8])>, expr: &str) -> Result<Sequence, Error> {
+	static COUNT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+	let n = COUNT.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+	let dir = std::env::temp_dir().join(format!("xrust-unparsed-{}-{}", std::process::id(), n));
+	std::fs::create_dir_all(&dir).expect("unable to create directory");
+	for (name, content) in &files {
+	    std::fs::write(dir.join(name), content).expect("unable to write file");
+	}
+	let mut e = parse(expr).expect("failed to parse expression");
+	StaticContext::new_with_builtins().static_analysis(&mut e).expect("static analysis failed");
+	let mut f = Forest::new();
+	let sd = f.plant_tree();
+	let rd = f.plant_tree();
+	let mut ev = Evaluator::new();
+	ev.set_baseurl(url::Url::from_directory_path(&dir).expect("unable to create URL"));
+	let s = ev.evaluate(None, None, &e, &mut f, sd, rd);
+	std::fs::remove_dir_all(&dir).expect("unable to remove directory");
+	s
+    }
+    #[test]
+    fn parse_eval_unparsed_text() {
+	let s = eval_with_files(vec![("a.txt", "one,two\nthree".as_bytes())], "unparsed-text('a.txt')").expect("evaluation failed");
+	assert_eq!(s.to_string(None), "one,two\nthree");
+	let s = eval_with_files(vec![("a.txt", &[0x63, 0x61, 0x66, 0xE9])], "unparsed-text('a.txt', 'iso-8859-1')").expect("evaluation failed");
+	assert_eq!(s.to_string(None), "café");
+	match eval_with_files(vec![("a.txt", &[0x63, 0x61, 0x66, 0xE9])], "unparsed-text('a.txt')") {
+	    Err(e) => assert_eq!(e.kind.to_string(), ErrorKind::UnparsedTextEncoding.to_string()),
+	    Ok(_) => panic!("invalid UTF-8 was decoded"),
+	}
+	match eval_with_files(vec![], "unparsed-text('missing.txt')") {
+	    Err(e) => assert_eq!(e.kind.to_string(), ErrorKind::UnparsedTextNotFound.to_string()),
+	    Ok(_) => panic!("missing file was found"),
+	}
+    }
+    #[test]
+    fn parse_eval_unparsed_text_lines() {
+	let s = eval_with_files(vec![("a.csv", "a,b\r\nc,d\n\ne,f\n".as_bytes())], "unparsed-text-lines('a.csv')").expect("evaluation failed");
+	assert_eq!(s.iter().map(|i| i.to_string(None)).collect::<Vec<String>>(), vec!["a,b", "c,d", "", "e,f"]);
+    }
+    #[test]
+    fn parse_eval_unparsed_text_available() {
+	let s = eval_with_files(vec![("a.txt", "text".as_bytes())], "unparsed-text-available('a.txt')").expect("evaluation failed");
+	assert!(s.to_bool());
+	let s = eval_with_files(vec![], "unparsed-text-available('missing.txt')").expect("evaluation failed");
+	assert!(!s.to_bool())
+    }
+    #[test]
+    fn parse_eval_map_constructor() {
+	let s = eval_with_doc("<Test/>", "map { 'a' : 1, 'b' : (2, 3) }?b").expect("evaluation failed");
+	assert_eq!(s.to_string(None), "23");
+	let s = eval_with_doc("<Test/>", "map:size(map { 'a' : 1, 'b' : 2 })").expect("evaluation failed");
+	assert_eq!(s.to_string(None), "2");
+	let s = eval_with_doc("<Test/>", "map:keys(map{1:'one', 2.0:'two'})").expect("evaluation failed");
+	assert_eq!(s.len(), 2);
+	let s = eval_with_doc("<Test/>", "map:get(map{1:'one', 2.0:'two'}, 2)").expect("evaluation failed");
+	assert_eq!(s.to_string(None), "two");
+	let s = eval_with_doc("<Test/>", "map:contains(map{'x':()}, 'x')").expect("evaluation failed");
+	assert!(s.to_bool());
+	match eval_with_doc("<Test/>", "map{'a':1, 'a':2}") {
+	    Err(e) => assert_eq!(e.kind.to_string(), ErrorKind::DuplicateMapKey.to_string()),
+	    Ok(_) => panic!("duplicate key was accepted"),
+	}
+	match eval_with_doc("<Test/>", "map{map{}:1}") {
+	    Err(e) => assert_eq!(e.kind.to_string(), ErrorKind::FunctionAtomization.to_string()),
+	    Ok(_) => panic!("map was atomized"),
+	}
+    }
+    #[test]
+    fn parse_eval_string_value_of_function() {
+	// Maps and arrays do not have a string value
+	for expr in vec!["string(map{'a':1})", "string(['a'])", "string(('a', []))"] {
+	    match eval_with_doc("<Test/>", expr) {
+		Err(e) => assert_eq!(e.kind.to_string(), ErrorKind::FunctionStringValue.to_string(), "{}", expr),
+		Ok(_) => panic!("{} has a string value", expr),
+	    }
+	}
+    }
+    #[test]
+    fn parse_eval_map_merge() {
+	let s = eval_with_doc("<Test/>", "map:merge((map{'a':1}, map{'a':2, 'b':3}))?*").expect("evaluation failed");
+	assert_eq!(s.to_string(None), "13");
+	let s = eval_with_doc("<Test/>", "map:merge((map{'a':1}, map{'a':2}), map{'duplicates':'combine'})?a").expect("evaluation failed");
+	assert_eq!(s.to_string(None), "12");
+    }
+    #[test]
+    fn parse_eval_array_constructor() {
+	let s = eval_with_doc("<Test/>", "[1, (2, 3), 4]?2").expect("evaluation failed");
+	assert_eq!(s.to_string(None), "23");
+	let s = eval_with_doc("<Test/>", "array:size(array { 1, (2, 3), 4 })").expect("evaluation failed");
+	assert_eq!(s.to_string(None), "4");
+	let s = eval_with_doc("<Test/>", "array:get(['a', 'b'], 1)").expect("evaluation failed");
+	assert_eq!(s.to_string(None), "a");
+	let s = eval_with_doc("<Test/>", "[map{'x':[10, 20]}]?1?x?2").expect("evaluation failed");
+	assert_eq!(s.to_string(None), "20");
+	let s = eval_with_doc("<Test/>", "array:size([])").expect("evaluation failed");
+	assert_eq!(s.to_string(None), "0");
+	match eval_with_doc("<Test/>", "['a']?3") {
+	    Err(e) => assert_eq!(e.kind.to_string(), ErrorKind::ArrayIndexOutOfBounds.to_string()),
+	    Ok(_) => panic!("index out of bounds was accepted"),
+	}
+    }
+    #[test]
+    fn parse_eval_parse_json() {
+	let s = eval_with_doc("<Test/>", r#"parse-json('{"a": [1, "two", null, true]}')?a?2"#).expect("evaluation failed");
+	assert_eq!(s.to_string(None), "two");
+	let s = eval_with_doc("<Test/>", r#"array:size(parse-json('{"a": [1, "two", null, true]}')?a)"#).expect("evaluation failed");
+	assert_eq!(s.to_string(None), "4");
+	let s = eval_with_doc("<Test/>", r#"parse-json('{"a": 1, "a": 2}', map{'duplicates': 'use-last'})?a"#).expect("evaluation failed");
+	assert_eq!(s.to_string(None), "2");
+	let s = eval_with_doc("<Test/>", r#"parse-json('[1, 2, ]', map{'liberal': true()})?2"#).expect("evaluation failed");
+	assert_eq!(s.to_string(None), "2");
+	match eval_with_doc("<Test/>", r#"parse-json('{"a": 1, "a": 2}', map{'duplicates': 'reject'})"#) {
+	    Err(e) => assert_eq!(e.kind.to_string(), ErrorKind::JsonDuplicateKeys.to_string()),
+	    Ok(_) => panic!("duplicate key was accepted"),
+	}
+	match eval_with_doc("<Test/>", r#"parse-json('[1, 2')"#) {
+	    Err(e) => assert_eq!(e.kind.to_string(), ErrorKind::JsonSyntax.to_string()),
+	    Ok(_) => panic!("invalid JSON was accepted"),
+	}
+	match eval_with_doc("<Test/>", r#"parse-json('{}', map{'duplicates': 'retain'})"#) {
+	    Err(e) => assert_eq!(e.kind.to_string(), ErrorKind::JsonOption.to_string()),
+	    Ok(_) => panic!("invalid option was accepted"),
+	}
+    }
+    #[test]
+    fn parse_eval_json_doc() {
+	let s = eval_with_files(vec![("a.json", r#"{"name": "xrust", "tags": ["xml", "xpath"]}"#.as_bytes())], "json-doc('a.json')?tags?2").expect("evaluation failed");
+	assert_eq!(s.to_string(None), "xpath");
+	match eval_with_files(vec![], "json-doc('missing.json')") {
+	    Err(e) => assert_eq!(e.kind.to_string(), ErrorKind::UnparsedTextNotFound.to_string()),
+	    Ok(_) => panic!("missing file was found"),
+	}
+    }
+    #[test]
+    fn parse_eval_unparsed_text_resolver() {
+	// Unparsed text is retrieved by the document resolver
+	let (s, _) = eval_with_resolver("unparsed-text('a.xml'), json-doc('c.json')?x?2, unparsed-text-available('missing.txt')").expect("evaluation failed");
+	assert_eq!(s.iter().map(|i| i.to_string(None)).collect::<Vec<String>>(), vec!["<A>a</A>", "2", "false"]);
+	match eval_with_resolver("unparsed-text('missing.txt')") {
+	    Err(e) => assert_eq!(e.kind.to_string(), ErrorKind::UnparsedTextNotFound.to_string()),
+	    Ok(_) => panic!("missing resource was found"),
+	}
+    }
+    #[test]
+    fn parse_eval_json_to_xml() {
+	let s = eval_with_doc("<Test/>", r#"xml-to-json(json-to-xml('{"a": [1, "two", null, true]}'))"#).expect("evaluation failed");
+	assert_eq!(s.to_string(None), r#"{"a":[1,"two",null,true]}"#);
+	let s = eval_with_doc("<Test/>", r#"json-to-xml('{"a": 1, "a": 2}')/child::*/child::*/attribute::key"#).expect("evaluation failed");
+	assert_eq!(s.len(), 2);
+	match eval_with_doc("<Test/>", r#"json-to-xml('{}', map{'duplicates': 'use-last'})"#) {
+	    Err(e) => assert_eq!(e.kind.to_string(), ErrorKind::JsonOption.to_string()),
+	    Ok(_) => panic!("invalid option was accepted"),
+	}
+	match eval_with_doc("<Test/>", "xml-to-json(.)") {
+	    Err(e) => assert_eq!(e.kind.to_string(), ErrorKind::JsonInvalidXml.to_string()),
+	    Ok(_) => panic!("invalid XML representation was accepted"),
+	}
+    }
+    #[test]
+    fn parse_eval_parse_xml() {
+	let s = eval_with_doc("<Test/>", "parse-xml('<a><b>one</b><b>two</b></a>\n')/child::a/child::b").expect("evaluation failed");
+	assert_eq!(s.len(), 2);
+	let s = eval_with_doc("<Test/>", "serialize(parse-xml('<a><b>one</b></a>'))").expect("evaluation failed");
+	assert_eq!(s.to_string(None), "<a><b>one</b></a>");
+	match eval_with_doc("<Test/>", "parse-xml('<a><b>one</b>')") {
+	    Err(e) => assert_eq!(e.kind.to_string(), ErrorKind::ParseXml.to_string()),
+	    Ok(_) => panic!("malformed XML was parsed"),
+	}
+    }
+    #[test]
+    fn parse_eval_parse_xml_fragment() {
+	let s = eval_with_doc("<Test/>", "count(parse-xml-fragment('<a/>text<b/>')/child::node())").expect("evaluation failed");
+	assert_eq!(s.to_string(None), "3");
+	let s = eval_with_doc("<Test/>", "serialize(parse-xml-fragment('<?xml encoding=\"utf-8\"?>one <b>two</b>'))").expect("evaluation failed");
+	assert_eq!(s.to_string(None), "one <b>two</b>");
+	let s = eval_with_doc("<Test/>", "count(parse-xml-fragment('')/child::node())").expect("evaluation failed");
+	assert_eq!(s.to_string(None), "0");
+    }
+    #[test]
+    fn parse_eval_serialize() {
+	let s = eval_with_doc("<Test/>", "serialize((1, 'two', 3))").expect("evaluation failed");
+	assert_eq!(s.to_string(None), "1 two 3");
+	let s = eval_with_doc("<Test/>", "serialize(map{'a':[1, 'x']}, map{'method':'json'})").expect("evaluation failed");
+	assert_eq!(s.to_string(None), r#"{"a":[1,"x"]}"#);
+	let s = eval_with_doc("<Test/>", "serialize(parse-xml-fragment('<a>one</a><b>two</b>'), map{'method':'text'})").expect("evaluation failed");
+	assert_eq!(s.to_string(None), "onetwo");
+	let s = eval_with_doc(
+	    "<output:serialization-parameters xmlns:output='http://www.w3.org/2010/xslt-xquery-serialization'><output:method value='json'/><output:indent value='no'/></output:serialization-parameters>",
+	    "serialize([1, 2], .)"
+	).expect("evaluation failed");
+	assert_eq!(s.to_string(None), "[1,2]");
+	match eval_with_doc("<Test/>", "serialize(map{'a':1})") {
+	    Err(e) => assert_eq!(e.kind.to_string(), ErrorKind::SerializeNotAllowed.to_string()),
+	    Ok(_) => panic!("map was serialized as XML"),
+	}
+	match eval_with_doc("<Test/>", "serialize(1, map{'method':'csv'})") {
+	    Err(e) => assert_eq!(e.kind.to_string(), ErrorKind::SerializeParameterValue.to_string()),
+	    Ok(_) => panic!("invalid method was accepted"),
+	}
+	match eval_with_doc("<Test/>", "serialize(1, map{'method':'html'})") {
+	    Err(e) => assert_eq!(e.kind.to_string(), ErrorKind::NotImplemented.to_string()),
+	    Ok(_) => panic!("unsupported method was accepted"),
+	}
+	match eval_with_doc("<Test/>", "serialize(1, map{'indent':'maybe'})") {
+	    Err(e) => assert_eq!(e.kind.to_string(), ErrorKind::SerializeParameterValue.to_string()),
+	    Ok(_) => panic!("invalid indent was accepted"),
+	}
+    }
+    #[test]
+    fn parse_eval_uri_escaping() {
+	let s = eval_with_doc("<Test/>", "encode-for-uri('100% organic/~x')").expect("evaluation failed");
+	assert_eq!(s.to_string(None), "100%25%20organic%2F~x");
+	let s = eval_with_doc("<Test/>", "iri-to-uri('http://www.example.com/~bébé?a=<b>')").expect("evaluation failed");
+	assert_eq!(s.to_string(None), "http://www.example.com/~b%C3%A9b%C3%A9?a=%3Cb%3E");
+	let s = eval_with_doc("<Test/>", "escape-html-uri(\"javascript:window.open('http://www.example.com/~bébé');\")").expect("evaluation failed");
+	assert_eq!(s.to_string(None), "javascript:window.open('http://www.example.com/~b%C3%A9b%C3%A9');");
+	let s = eval_with_doc("<Test/>", "encode-for-uri(())").expect("evaluation failed");
+	assert_eq!(s.to_string(None), "");
+    }
+    #[test]
+    fn parse_eval_resolve_uri() {
+	let s = eval_with_doc("<Test/>", "resolve-uri('b/c.xml', 'http://example.org/a/index.xml')").expect("evaluation failed");
+	assert_eq!(s.to_string(None), "http://example.org/a/b/c.xml");
+	let s = eval_with_doc("<Test/>", "resolve-uri('urn:isbn:0451450523', 'http://example.org/')").expect("evaluation failed");
+	assert_eq!(s.to_string(None), "urn:isbn:0451450523");
+	let (s, _) = eval_with_resolver("resolve-uri('../x.xml'), static-base-uri()").expect("evaluation failed");
+	assert_eq!(s[0].to_string(None), "http://example.org/x.xml");
+	assert_eq!(s[1].to_string(None), "http://example.org/");
+	match eval_with_doc("<Test/>", "resolve-uri('x.xml')") {
+	    Err(e) => assert_eq!(e.kind.to_string(), ErrorKind::NoBaseUri.to_string()),
+	    Ok(_) => panic!("URI was resolved without a base URI"),
+	}
+	match eval_with_doc("<Test/>", "resolve-uri('x.xml', 'relative/')") {
+	    Err(e) => assert_eq!(e.kind.to_string(), ErrorKind::InvalidUriArgument.to_string()),
+	    Ok(_) => panic!("URI was resolved against a relative base URI"),
+	}
+    }
+    #[test]
+    fn parse_eval_base_uri() {
+	let s = eval_with_doc(
+	    "<doc xml:base='http://example.org/docs/'><section xml:base='guide/'><p>text</p></section></doc>",
+	    "base-uri(child::section/child::p), base-uri(child::section/child::p/child::text()), base-uri()"
+	).expect("evaluation failed");
+	assert_eq!(s.iter().map(|i| i.to_string(None)).collect::<Vec<String>>(), vec!["http://example.org/docs/guide/", "http://example.org/docs/guide/", "http://example.org/docs/"]);
+	let s = eval_with_doc("<Test/>", "base-uri(), document-uri()").expect("evaluation failed");
+	assert_eq!(s.len(), 0);
+	let (s, _) = eval_with_resolver("document-uri(doc('a.xml')), base-uri(doc('b.xml')/child::B)").expect("evaluation failed");
+	assert_eq!(s.iter().map(|i| i.to_string(None)).collect::<Vec<String>>(), vec!["http://example.org/a.xml", "http://example.org/b.xml"]);
+	let (s, f) = eval_with_resolver("base-uri(parse-xml('<a/>'))").expect("evaluation failed");
+	assert_eq!(s.to_string(Some(&f)), "http://example.org/");
+    }
+    #[test]
+    fn parse_eval_id() {
+	let src = "<doc><sec xml:id='a'>A</sec><sec xml:id='b'>B</sec><ref to='b a c'/></doc>";
+	let s = eval_with_doc(src, "count(id('b a c')), string(id('b')), count(id(('a', 'a b'))), count(element-with-id('a'))").expect("evaluation failed");
+	assert_eq!(s.iter().map(|i| i.to_string(None)).collect::<Vec<String>>(), vec!["2", "B", "2", "1"]);
+	let s = eval_with_doc(src, "count(id(string(child::ref/attribute::to)))").expect("evaluation failed");
+	assert_eq!(s.to_string(None), "2");
+	// The result is in document order
+	let s = eval_with_doc(src, "for $s in id(('c', 'b', 'a', 'b')) return string($s)").expect("evaluation failed");
+	assert_eq!(s.iter().map(|i| i.to_string(None)).collect::<Vec<String>>(), vec!["A", "B"]);
+	// Untyped attributes are not IDREFs
+	let s = eval_with_doc(src, "count(idref('a'))").expect("evaluation failed");
+	assert_eq!(s.to_string(None), "0");
+    }
+    #[test]
+    fn parse_eval_generate_id() {
+	let src = "<doc><sec xml:id='a'>A</sec><sec xml:id='b'>B</sec></doc>";
+	let s = eval_with_doc(src, "generate-id(id('a')) eq generate-id(id('a')), generate-id(id('a')) eq generate-id(id('b')), generate-id(()), generate-id() eq generate-id(.)").expect("evaluation failed");
+	assert_eq!(s.iter().map(|i| i.to_string(None)).collect::<Vec<String>>(), vec!["true", "false", "", "true"]);
+    }
 }
-
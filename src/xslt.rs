@@ -61,8 +61,7 @@ assert_eq!(seq.to_xml(Some(&f)), "<html><head><title>XSLT in Rust</title></head>
 */
 
 use std::convert::TryFrom;
-use std::fs;
-use std::path::Path;
+use std::collections::HashMap;
 use url::Url;
 //use reqwest::blocking::get;
 use crate::xdmerror::*;
@@ -72,6 +71,7 @@ use crate::value::*;
 use crate::forest::*;
 use crate::evaluate::*;
 use crate::xpath::*;
+use crate::resolver::{fetch, decode};
 
 const XSLTNS: &str = "http://www.w3.org/1999/XSL/Transform";
 
@@ -85,6 +85,7 @@ pub fn from_document(
 ) -> Result<Evaluator, Error>
 {
     let mut ev = Evaluator::new();
+    ev.set_collations(sc.collations.clone());
     if b.is_some() {
 	ev.set_baseurl(b.unwrap())
     }
@@ -109,6 +110,14 @@ pub fn from_document(
     };
     // TODO: rnit.next(f) should == None
 
+    // Prefixes declared on the stylesheet element may be used in expressions
+    for (p, u) in stylenode.namespaces(f) {
+	if let Some(p) = p {
+	    ev.declare_namespace(p.clone(), u.clone());
+	    sc.declare_namespace(p, u)
+	}
+    }
+
     // TODO: check version attribute
 
     // Strip whitespace from the stylesheet
@@ -120,59 +129,7 @@ pub fn from_document(
 	vec![NodeTest::Name(NameTest{ns: Some(WildcardOrName::Name(XSLTNS.to_string())), prefix: Some("xsl".to_string()), name: Some(WildcardOrName::Name("text".to_string()))})]
     );
 
-    // Define the builtin templates
-    // See XSLT 6.7. This implements text-only-copy.
-    // TODO: Support deep-copy, shallow-copy, deep-skin, shallow-skip and fail
-
-    // This matches "/" and processes the root element
-    let bi1pat = to_pattern(
-	vec![Constructor::Path(
-	    vec![
-		vec![Constructor::Root],
-            ]
-	)])?;
-    let bi1bod = vec![
-        Constructor::ApplyTemplates(
-            vec![Constructor::Step(
-	        NodeMatch{axis: Axis::Child, nodetest: NodeTest::Kind(KindTest::AnyKindTest)},
-		vec![]
-	    )],
-	),
-      ];
-    ev.add_builtin_template(bi1pat, bi1bod, None, -1.0, 0);
-    // This matches "*" and applies templates to all children
-    let bi2pat = to_pattern(
-	vec![Constructor::Path(
-	    vec![
-		vec![Constructor::Step(
-		    NodeMatch{axis: Axis::Child, nodetest: NodeTest::Name(NameTest{ns: None, prefix: None, name: Some(WildcardOrName::Wildcard)})},
-		    vec![]
-		)],
-            ]
-	)])?;
-    let bi2bod = vec![
-        Constructor::ApplyTemplates(
-            vec![Constructor::Step(
-	        NodeMatch{axis: Axis::Child, nodetest: NodeTest::Kind(KindTest::AnyKindTest)},
-		vec![]
-	    )],
-	),
-    ];
-    ev.add_builtin_template(bi2pat, bi2bod, None, -1.0, 0);
-    // This matches "text()" and copies content
-    let bi3pat = to_pattern(
-	vec![Constructor::Path(
-	    vec![
-		vec![Constructor::Step(
-		    NodeMatch{axis: Axis::Child, nodetest: NodeTest::Kind(KindTest::TextTest)},
-	    vec![]
-		)],
-            ]
-	)])?;
-    let bi3bod = vec![Constructor::ContextItem];
-    ev.add_builtin_template(bi3pat, bi3bod, None, -1.0, 0);
-
-    // Setup the serialization of the primary result document
+    // Setup the serialization of the primary result document, and the named output definitions for secondary result documents
     let mut serit = stylenode.child_iter();
     loop {
 	match serit.next(f) {
@@ -180,20 +137,18 @@ pub fn from_document(
 		if c.is_element(f) &&
 		    c.to_name(f).get_nsuri_ref() == Some(XSLTNS) &&
 		    c.to_name(f).get_localname() == "output" {
-			match c.get_attribute(f, &QualifiedName::new(None, None, "indent".to_string())) {
-			    Some(i) => {
-				let b: bool = match i.to_string(f).as_str() {
-				    "yes" |
-				    "true" |
-				    "1" => true,
-				    _ => false,
-				};
-
-      				let mut od = OutputDefinition::new();
-      				od.set_indent(b);
-      				ev.set_output_definition(od);
+			let mut od = OutputDefinition::new();
+			if let Some(m) = c.get_attribute(f, &QualifiedName::new(None, None, "method".to_string())) {
+			    od.set_method(OutputMethod::try_from(m.to_string(f).as_str())?)
+			}
+			od.set_indent(yes_no_attribute(&c, f, "indent")?);
+			match c.get_attribute(f, &QualifiedName::new(None, None, "name".to_string())) {
+			    Some(n) => {
+				let qn = to_qname(&c, f, &n.to_string(f))?;
+				od.set_name(Some(qn.clone()));
+				ev.add_output_definition(qn, od)
 			    }
-			    None => {}
+			    None => ev.set_output_definition(od),
 			}
 		}
 	    }
@@ -222,19 +177,7 @@ pub fn from_document(
 				    Ok(u) => u,
 				    Err(_) => return Result::Err(Error{kind: ErrorKind::Unknown, message: format!("unable to parse href URL \"{}\" baseurl \"{}\"", h.to_string(f), ev.baseurl().map_or(String::from("--no base--"), |b| b.to_string()))}),
 				};
-				// TODO: make a function to resolve http: vs file: scheme
-				let xml = match url.scheme() {
-				    "http" => {
-					reqwest::blocking::get(url.to_string())
-					    .map_err(|_| Error{kind: ErrorKind::Unknown, message: format!("unable to fetch href URL \"{}\"", url.to_string())})?
-					    .text()
-					    .map_err(|_| Error{kind: ErrorKind::Unknown, message: "unable to extract module data".to_string()})?
-				    }
-				    "file" => {
-					fs::read_to_string(Path::new(url.path())).map_err(|er| Error::new(ErrorKind::Unknown, er.to_string()))?
-				    }
-				    _ => return Result::Err(Error::new(ErrorKind::Unknown, format!("unable to fetch URL \"{}\"", url.to_string())))
-				};
+				let xml = decode(&fetch(&url)?, None)?;
 				let module = f.grow_tree(xml.as_str().trim())?;
 				// TODO: check that the module is a valid XSLT stylesheet, etc
 				// Copy each top-level element of the module to the main stylesheet,
@@ -284,19 +227,7 @@ pub fn from_document(
 				    Ok(u) => u,
 				    Err(_) => return Result::Err(Error{kind: ErrorKind::Unknown, message: format!("unable to parse href URL \"{}\" baseurl \"{}\"", h.to_string(f), ev.baseurl().map_or(String::from("--no base--"), |b| b.to_string()))}),
 				};
-				// TODO: make a function to resolve http: vs file: scheme
-				let xml = match url.scheme() {
-				    "http" => {
-					reqwest::blocking::get(url.to_string())
-					    .map_err(|_| Error{kind: ErrorKind::Unknown, message: format!("unable to fetch href URL \"{}\"", url.to_string())})?
-					    .text()
-					    .map_err(|_| Error{kind: ErrorKind::Unknown, message: "unable to extract module data".to_string()})?
-				    }
-				    "file" => {
-					fs::read_to_string(Path::new(url.path())).map_err(|er| Error::new(ErrorKind::Unknown, er.to_string()))?
-				    }
-				    _ => return Result::Err(Error::new(ErrorKind::Unknown, format!("unable to fetch URL \"{}\"", url.to_string())))
-				};
+				let xml = decode(&fetch(&url)?, None)?;
 				let module = f.grow_tree(xml.as_str().trim())?;
 				// TODO: check that the module is a valid XSLT stylesheet, etc
 				// Copy each top-level element of the module to the main stylesheet,
@@ -335,6 +266,137 @@ pub fn from_document(
 	}
     }
 
+    // Find the modes used in the stylesheet, and define the builtin templates for each mode.
+    // The on-no-match behaviour of a mode is given by its xsl:mode declaration, if any. The default is text-only-copy.
+    let mut modes: Vec<(Mode, String)> = vec![(Mode::Default, String::from("text-only-copy"))];
+    let mut modeit = stylenode.child_iter();
+    while let Some(c) = modeit.next(f) {
+	if c.is_element(f) &&
+	    c.to_name(f).get_nsuri_ref() == Some(XSLTNS) &&
+	    c.to_name(f).get_localname() == "mode" {
+		let m = match c.get_attribute(f, &QualifiedName::new(None, None, "name".to_string())) {
+		    Some(n) => to_mode(&c, f, &n.to_string(f))?,
+		    None => Mode::Default,
+		};
+		let onm = c.get_attribute(f, &QualifiedName::new(None, None, "on-no-match".to_string()))
+		    .map_or(String::from("text-only-copy"), |o| o.to_string(f).trim().to_string());
+		modes.retain(|(n, _)| *n != m);
+		modes.push((m, onm));
+	    }
+    }
+    let mut descit = stylenode.descend_iter(f);
+    while let Some(c) = descit.next(f) {
+	if c.is_element(f) &&
+	    c.to_name(f).get_nsuri_ref() == Some(XSLTNS) &&
+	    (c.to_name(f).get_localname() == "template" || c.to_name(f).get_localname() == "apply-templates") {
+		if let Some(ms) = c.get_attribute(f, &QualifiedName::new(None, None, "mode".to_string())) {
+		    for t in ms.to_string(f).split_whitespace() {
+			let m = to_mode(&c, f, t)?;
+			if let Mode::Named(_) = m {
+			    if !modes.iter().any(|(n, _)| *n == m) {
+				modes.push((m, String::from("text-only-copy")))
+			    }
+			}
+		    }
+		}
+	    }
+    }
+    for (m, onm) in modes {
+	add_builtin_templates(&mut ev, m, &onm)?;
+    }
+
+    // Iterate over children, looking for global variables and stylesheet parameters
+    // * compile the value (the select attribute or content)
+    // * register the declaration in the dynamic context; the value is evaluated when it is first referenced
+    let mut varit = stylenode.child_iter();
+    while let Some(c) = varit.next(f) {
+	if c.is_element(f) &&
+	    c.to_name(f).get_nsuri_ref() == Some(XSLTNS) &&
+	    (c.to_name(f).get_localname() == "variable" || c.to_name(f).get_localname() == "param") {
+		let param = c.to_name(f).get_localname() == "param";
+		let name = c.get_attribute(f, &QualifiedName::new(None, None, "name".to_string()))
+		    .ok_or(Error{kind: ErrorKind::TypeError, message: "missing name attribute".to_string()})?;
+		let as_type = to_sequence_type(&c, f)?;
+		let mut value = to_value(&c, f, as_type.is_some(), sc)?;
+		if let Some(v) = value.as_mut() {
+		    sc.static_analysis_in_scope(v, &c.namespaces(f))?
+		}
+		ev.add_global_variable(
+		    GlobalVariable{
+			name: to_qname(&c, f, &name.to_string(f))?,
+			value,
+			as_type,
+			param,
+			required: param && yes_no_attribute(&c, f, "required")?,
+		    },
+		    import_precedence(&c, f)?,
+		);
+	    }
+    }
+
+    // Iterate over children, looking for attribute sets
+    let mut asit = stylenode.child_iter();
+    let mut uses: HashMap<QualifiedName, Vec<QualifiedName>> = HashMap::new();
+    while let Some(c) = asit.next(f) {
+	if c.is_element(f) &&
+	    c.to_name(f).get_nsuri_ref() == Some(XSLTNS) &&
+	    c.to_name(f).get_localname() == "attribute-set" {
+		let name = c.get_attribute(f, &QualifiedName::new(None, None, "name".to_string()))
+		    .ok_or(Error{kind: ErrorKind::TypeError, message: "missing name attribute".to_string()})?;
+		let mut content = to_use_attribute_sets(&c, f, QualifiedName::new(None, None, "use-attribute-sets".to_string()))?;
+		let mut cit = c.child_iter();
+		while let Some(d) = cit.next(f) {
+		    if d.is_element(f) {
+			content.push(to_constructor(d, f, sc)?)
+		    }
+		}
+		sc.static_analysis_in_scope(&mut content, &c.namespaces(f))?;
+		let qn = to_qname(&c, f, &name.to_string(f))?;
+		attribute_set_uses(&c, f, uses.entry(qn.clone()).or_default())?;
+		ev.add_attribute_set(qn, content);
+	    }
+    }
+    check_attribute_set_uses(&uses)?;
+
+    // Iterate over children, looking for keys
+    let mut keyit = stylenode.child_iter();
+    while let Some(c) = keyit.next(f) {
+	if is_xsl_element(&c, f, "key") {
+	    let name = c.get_attribute(f, &QualifiedName::new(None, None, "name".to_string()))
+		.ok_or(Error{kind: ErrorKind::TypeError, message: "missing name attribute".to_string()})?;
+	    let m = c.get_attribute(f, &QualifiedName::new(None, None, "match".to_string()))
+		.ok_or(Error{kind: ErrorKind::TypeError, message: "missing match attribute".to_string()})?;
+	    let mut matches = to_pattern(parse(&m.to_string(f))?)?;
+	    sc.static_analysis_in_scope(&mut matches, &c.namespaces(f))?;
+	    // The key values are given by either the use attribute or the content
+	    let mut content = vec![];
+	    let mut cit = c.child_iter();
+	    while let Some(d) = cit.next(f) {
+		content.push(to_constructor(d, f, sc)?)
+	    }
+	    let mut key_use = match c.get_attribute(f, &QualifiedName::new(None, None, "use".to_string())) {
+		Some(_) if !content.is_empty() => {
+		    return Result::Err(Error{kind: ErrorKind::TypeError, message: "key must not have both a use attribute and content".to_string()})
+		}
+		Some(u) => parse(&u.to_string(f))?,
+		None if content.is_empty() => {
+		    return Result::Err(Error{kind: ErrorKind::TypeError, message: "key must have a use attribute or content".to_string()})
+		}
+		None => vec![Constructor::Document(content)],
+	    };
+	    sc.static_analysis_in_scope(&mut key_use, &c.namespaces(f))?;
+	    ev.add_key(
+		to_qname(&c, f, &name.to_string(f))?,
+		KeyDefinition{
+		    matches,
+		    key_use,
+		    composite: yes_no_attribute(&c, f, "composite")?,
+		    collation: c.get_attribute(f, &QualifiedName::new(None, None, "collation".to_string())).map(|u| u.to_string(f)),
+		},
+	    );
+	}
+    }
+
     // Iterate over children, looking for templates
     // * compile match pattern
     // * compile content into sequence constructor
@@ -346,78 +408,85 @@ pub fn from_document(
 		if c.is_element(f) &&
 		    c.to_name(f).get_nsuri_ref() == Some(XSLTNS) &&
 		    c.to_name(f).get_localname() == "template" {
-			match c.get_attribute(f, &QualifiedName::new(None, None, "match".to_string())) {
-			    Some(m) => {
-				let n = m.clone().to_string(f);
-				let a = parse(&n).expect("failed to parse match expression");
-				let mut pat = to_pattern(a).expect("failed to compile match pattern");
-				let mut body = vec![];
-				let mut cit = c.child_iter();
-				loop {
-				    match cit.next(f) {
-					Some(d) => {
-					    body.push(to_constructor(d, f)?)
-					}
-					None => break,
-				    }
+			let name = c.get_attribute(f, &QualifiedName::new(None, None, "name".to_string()));
+			let mtch = c.get_attribute(f, &QualifiedName::new(None, None, "match".to_string()));
+			if name.is_none() && mtch.is_none() {
+			    return Result::Err(Error{kind: ErrorKind::TypeError, message: "template does not have a match or name attribute".to_string()})
+			}
+			// Parameters are declared before the content of the template
+			let mut params = vec![];
+			let mut body = vec![];
+			let mut cit = c.child_iter();
+			while let Some(d) = cit.next(f) {
+			    if d.is_element(f) &&
+				d.to_name(f).get_nsuri_ref() == Some(XSLTNS) &&
+				d.to_name(f).get_localname() == "param" {
+				    params.push(to_param(d, f, sc)?)
+				} else {
+				    body.push(to_constructor(d, f, sc)?)
 				}
-				sc.static_analysis(&mut pat);
-				sc.static_analysis(&mut body);
-				// Determine the priority of the template
-				let prio;
-				match c.get_attribute(f, &QualifiedName::new(None, None, "priority".to_string())) {
-				    Some(pr) => prio = pr.to_string(f).parse::<f64>().unwrap(), // TODO: better error handling
-				    None => {
-					// Calculate the default priority
-					// TODO: more work to be done interpreting XSLT 6.5
-					if pat.len() <= 1 {
-					    match &pat[0] {
-						Constructor::Root => prio = -0.5,
-						Constructor::Path(_) => prio = -0.5,
-						Constructor::Step(nm, _pred) => {
-						    match &nm.nodetest {
-							NodeTest::Name(nt) => {
-							    match (nt.ns.as_ref(), nt.name.as_ref()) {
-								(Some(WildcardOrName::Wildcard), Some(WildcardOrName::Wildcard)) => prio =-0.5,
-								(Some(WildcardOrName::Wildcard), Some(WildcardOrName::Name(_))) |
-								(Some(WildcardOrName::Name(_)), Some(WildcardOrName::Wildcard)) => prio = -0.25,
-								(None, Some(WildcardOrName::Wildcard)) => prio = -0.25,
-								(Some(WildcardOrName::Name(_)), Some(WildcardOrName::Name(_))) => prio = 0.0,
-								(None, Some(WildcardOrName::Name(_))) => prio = 0.0,
-								_ => prio = 0.5,
-							    }
+			}
+			sc.static_analysis_in_scope(&mut body, &c.namespaces(f))?;
+			let import = import_precedence(&c, f)?;
+			if let Some(m) = mtch {
+			    let n = m.clone().to_string(f);
+			    let a = parse(&n).expect("failed to parse match expression");
+			    let mut pat = to_pattern(a).expect("failed to compile match pattern");
+			    sc.static_analysis_in_scope(&mut pat, &c.namespaces(f))?;
+			    // Determine the priority of the template
+			    let prio;
+			    match c.get_attribute(f, &QualifiedName::new(None, None, "priority".to_string())) {
+				Some(pr) => prio = pr.to_string(f).parse::<f64>().unwrap(), // TODO: better error handling
+				None => {
+				    // Calculate the default priority
+				    // TODO: more work to be done interpreting XSLT 6.5
+				    if pat.len() <= 1 {
+					match &pat[0] {
+					    Constructor::Root => prio = -0.5,
+					    Constructor::Path(_) => prio = -0.5,
+					    Constructor::Step(nm, _pred) => {
+						match &nm.nodetest {
+						    NodeTest::Name(nt) => {
+							match (nt.ns.as_ref(), nt.name.as_ref()) {
+							    (Some(WildcardOrName::Wildcard), Some(WildcardOrName::Wildcard)) => prio = -0.5,
+							    (Some(WildcardOrName::Wildcard), Some(WildcardOrName::Name(_))) |
+							    (Some(WildcardOrName::Name(_)), Some(WildcardOrName::Wildcard)) => prio = -0.25,
+							    (None, Some(WildcardOrName::Wildcard)) => prio = -0.25,
+							    (Some(WildcardOrName::Name(_)), Some(WildcardOrName::Name(_))) => prio = 0.0,
+							    (None, Some(WildcardOrName::Name(_))) => prio = 0.0,
+							    _ => prio = 0.5,
 							}
-							NodeTest::Kind(kt) => {
-							    match kt {
-								KindTest::DocumentTest |
-								KindTest::ElementTest |
-								KindTest::AttributeTest => prio = -0.5,
-								_ => prio = 0.5,
-							    }
+						    }
+						    NodeTest::Kind(kt) => {
+							match kt {
+							    KindTest::DocumentTest |
+							    KindTest::ElementTest |
+							    KindTest::AttributeTest => prio = -0.5,
+							    _ => prio = 0.5,
 							}
 						    }
 						}
-						_ => prio = 0.5,
 					    }
-					} else {
-					    // TODO: calculate the priority of each branch of the pattern
-					    prio = 0.5
+					    _ => prio = 0.5,
 					}
+				    } else {
+					// TODO: calculate the priority of each branch of the pattern
+					prio = 0.5
 				    }
 				}
-				// Set the import precedence
-				let mut import: usize = 0;
-				match c.get_attribute(f, &QualifiedName::new(Some(String::from("http://github.com/ballsteve/xrust")), None, String::from("import"))) {
-				    Some(im) => {
-					import = im.to_value(f).to_int()? as usize
-				    }
-				    None => {}
-				}
-				ev.add_template(pat, body, None, prio, import);
-			    }
-			    None => {
-				return Result::Err(Error{kind: ErrorKind::TypeError, message: "template does not have a match attribute".to_string()})
 			    }
+			    let modes = match c.get_attribute(f, &QualifiedName::new(None, None, "mode".to_string())) {
+				Some(ms) => {
+				    ms.to_string(f).split_whitespace()
+					.map(|t| to_mode(&c, f, t))
+					.collect::<Result<Vec<Mode>, Error>>()?
+				}
+				None => vec![Mode::Default],
+			    };
+			    ev.add_template(pat, body.clone(), params.clone(), modes, prio, import);
+			}
+			if let Some(nm) = name {
+			    ev.add_named_template(to_qname(&c, f, &nm.to_string(f))?, body, params, import);
 			}
 		    }
 	    }
@@ -428,11 +497,32 @@ pub fn from_document(
     Ok(ev)
 }
 
+// A text node with a fixed value
+fn text_constructor(v: Value) -> Constructor {
+    Constructor::Text(vec![Constructor::Literal(v)], vec![Constructor::Literal(Value::from(""))])
+}
+
 /// Compile a node in a template to a sequence constructor
-fn to_constructor(n: Node, f: &Forest) -> Result<Constructor, Error> {
+fn to_constructor(n: Node, f: &Forest, sc: &mut StaticContext) -> Result<Constructor, Error> {
+    let mut c = vec![to_instruction(n, f, sc)?];
+    resolve_prefixes(&n, f, sc, &mut c)?;
+    Ok(c.remove(0))
+}
+
+// Resolve the namespace prefixes used by the expressions of an element, if the element declares namespaces.
+// Otherwise its in-scope namespaces are those of its parent, so the prefixes are resolved along with the parent's expressions (and finally with those of the top-level declaration).
+fn resolve_prefixes(n: &Node, f: &Forest, sc: &mut StaticContext, e: &mut Vec<Constructor>) -> Result<(), Error> {
+    if n.is_element(f) && !n.namespace_declarations(f).is_empty() {
+	sc.static_analysis_in_scope(e, &n.namespaces(f))
+    } else {
+	Ok(())
+    }
+}
+
+fn to_instruction(n: Node, f: &Forest, sc: &mut StaticContext) -> Result<Constructor, Error> {
     match n.node_type(f) {
 	NodeType::Text => {
-	    Ok(Constructor::Literal(Value::String(n.to_string(f))))
+	    Ok(text_constructor(Value::String(n.to_string(f))))
 	}
 	NodeType::Element => {
 	    match (n.to_name(f).get_nsuri_ref(), n.to_name(f).get_localname().as_str()) {
@@ -440,7 +530,7 @@ fn to_constructor(n: Node, f: &Forest) -> Result<Constructor, Error> {
 		    match n.get_attribute(f, &QualifiedName::new(None, None, "disable-output-escaping".to_string())){
 			Some(doe) => {
 			    match &doe.to_string(f)[..]  {
-				"yes" => Ok(Constructor::Literal(Value::String(n.to_string(f)))),
+				"yes" => Ok(text_constructor(Value::String(n.to_string(f)))),
 				"no" => {
 				    let text = n.to_string(f)
 					.replace("&","&amp;")
@@ -448,7 +538,7 @@ fn to_constructor(n: Node, f: &Forest) -> Result<Constructor, Error> {
 					.replace("<", "&lt;")
 					.replace("'", "&apos;")
 					.replace("\"", "&quot;");
-				    Ok(Constructor::Literal(Value::from(text)))
+				    Ok(text_constructor(Value::from(text)))
 				}
 				_ => {
 				    return Result::Err(Error{kind: ErrorKind::TypeError, message: "disable-output-escaping only accepts values yes or no.".to_string()})
@@ -462,31 +552,73 @@ fn to_constructor(n: Node, f: &Forest) -> Result<Constructor, Error> {
 				.replace("<", "&lt;")
 				.replace("'", "&apos;")
 				.replace("\"", "&quot;");
-			    Ok(Constructor::Literal(Value::from(text)))
+			    Ok(text_constructor(Value::from(text)))
 			}
 		    }
 		}
 		(Some(XSLTNS), "apply-templates") => {
+		    let w = to_with_params(&n, f, sc)?;
+		    let k = to_sort_keys(&n, f, sc)?;
+		    let m = match n.get_attribute(f, &QualifiedName::new(None, None, "mode".to_string())) {
+			Some(mode) => to_mode(&n, f, &mode.to_string(f))?,
+			None => Mode::Default,
+		    };
 		    match n.get_attribute(f, &QualifiedName::new(None, None, "select".to_string())) {
 			Some(sel) => {
 			    Ok(Constructor::ApplyTemplates(
-				parse(&sel.to_string(f))?
+				sorted(parse(&sel.to_string(f))?, k),
+				m,
+				w,
 			    ))
 			}
 			None => {
 			    // If there is no select attribute, then default is "child::node()"
 			    Ok(Constructor::ApplyTemplates(
-				vec![
-	      			    Constructor::Step(
-					NodeMatch{
-	      				    axis: Axis::Child,
-	      				    nodetest: NodeTest::Kind(KindTest::AnyKindTest)
-	    				},
-	    				vec![]
-	      			    )
-	    			]
+				sorted(
+				    vec![
+	      				Constructor::Step(
+					    NodeMatch{
+	      					axis: Axis::Child,
+	      					nodetest: NodeTest::Kind(KindTest::AnyKindTest)
+	    				    },
+	    				    vec![]
+	      				)
+	    			    ],
+				    k,
+				),
+				m,
+				w,
+			    ))
+			}
+		    }
+		}
+		(Some(XSLTNS), "call-template") => {
+		    match n.get_attribute(f, &QualifiedName::new(None, None, "name".to_string())) {
+			Some(nm) => {
+			    Ok(Constructor::CallTemplate(
+				to_qname(&n, f, &nm.to_string(f))?,
+				to_with_params(&n, f, sc)?,
 			    ))
 			}
+			None => {
+			    Result::Err(Error{kind: ErrorKind::TypeError, message: "missing name attribute".to_string()})
+			}
+		    }
+		}
+		(Some(XSLTNS), "variable") => {
+		    // The variable is in scope for the following siblings of this element
+		    match n.get_attribute(f, &QualifiedName::new(None, None, "name".to_string())) {
+			Some(nm) => {
+			    let typed = n.get_attribute(f, &QualifiedName::new(None, None, "as".to_string())).is_some();
+			    Ok(Constructor::VariableDeclaration(
+				variable_name(&to_qname(&n, f, &nm.to_string(f))?),
+				to_value(&n, f, typed, sc)?
+				    .unwrap_or_else(|| if typed {vec![]} else {vec![Constructor::Literal(Value::from(""))]}),
+			    ))
+			}
+			None => {
+			    Result::Err(Error{kind: ErrorKind::TypeError, message: "missing name attribute".to_string()})
+			}
 		    }
 		}
 		(Some(XSLTNS), "apply-imports") => {
@@ -514,7 +646,7 @@ fn to_constructor(n: Node, f: &Forest) -> Result<Constructor, Error> {
 			    loop {
 				match cit.next(f) {
 				    Some(e) => {
-					body.push(to_constructor(e, f)?)
+					body.push(to_constructor(e, f, sc)?)
 				    }
 				    None => break,
 				}
@@ -557,7 +689,7 @@ fn to_constructor(n: Node, f: &Forest) -> Result<Constructor, Error> {
 							    loop {
 								match wit.next(f) {
 								    Some(e) => {
-									body.push(to_constructor(e, f)?)
+									body.push(to_constructor(e, f, sc)?)
 								    }
 								    None => break,
 								}
@@ -581,7 +713,7 @@ fn to_constructor(n: Node, f: &Forest) -> Result<Constructor, Error> {
 						    loop {
 							match oit.next(f) {
 							    Some(e) => {
-								otherwise.push(to_constructor(e, f)?)
+								otherwise.push(to_constructor(e, f, sc)?)
 							    }
 							    None => break,
 							}
@@ -627,8 +759,9 @@ fn to_constructor(n: Node, f: &Forest) -> Result<Constructor, Error> {
 			    let mut body = vec![];
 			    loop {
 				match cit.next(f) {
+				    Some(e) if is_xsl_element(&e, f, "sort") => {}
 				    Some(e) => {
-					body.push(to_constructor(e, f)?)
+					body.push(to_constructor(e, f, sc)?)
 				    }
 				    None => break,
 				}
@@ -638,6 +771,7 @@ fn to_constructor(n: Node, f: &Forest) -> Result<Constructor, Error> {
 				    parse(&s.to_string(f))?,
 				    body,
 				    None,
+				    to_sort_keys(&n, f, sc)?,
 				)
 			    )
 			}
@@ -658,8 +792,9 @@ fn to_constructor(n: Node, f: &Forest) -> Result<Constructor, Error> {
 				    let mut body = vec![];
 				    loop {
 					match cit.next(f) {
+					    Some(e) if is_xsl_element(&e, f, "sort") => {}
 					    Some(e) => {
-						body.push(to_constructor(e, f)?)
+						body.push(to_constructor(e, f, sc)?)
 					    }
 					    None => break,
 					}
@@ -669,6 +804,7 @@ fn to_constructor(n: Node, f: &Forest) -> Result<Constructor, Error> {
 					    parse(&s.to_string(f))?,
 					    body,
 					    Some(Grouping::By(parse(&by.to_string(f))?)),
+					    to_sort_keys(&n, f, sc)?,
 					)
 	      			    )
 				}
@@ -677,8 +813,9 @@ fn to_constructor(n: Node, f: &Forest) -> Result<Constructor, Error> {
 				    let mut body = vec![];
 				    loop {
 					match cit.next(f) {
+					    Some(e) if is_xsl_element(&e, f, "sort") => {}
 					    Some(e) => {
-						body.push(to_constructor(e, f)?)
+						body.push(to_constructor(e, f, sc)?)
 					    }
 					    None => break,
 					}
@@ -688,6 +825,7 @@ fn to_constructor(n: Node, f: &Forest) -> Result<Constructor, Error> {
 					    parse(&s.to_string(f))?,
 					    body,
 					    Some(Grouping::Adjacent(parse(&adj.to_string(f))?)),
+					    to_sort_keys(&n, f, sc)?,
 					)
 	      			    )
 				}
@@ -702,6 +840,23 @@ fn to_constructor(n: Node, f: &Forest) -> Result<Constructor, Error> {
 			}
 		    }
 		}
+		(Some(XSLTNS), "perform-sort") => {
+		    let mut content = vec![];
+		    let mut cit = n.child_iter();
+		    while let Some(e) = cit.next(f) {
+			if !is_xsl_element(&e, f, "sort") {
+			    content.push(to_constructor(e, f, sc)?)
+			}
+		    }
+		    let sel = match n.get_attribute(f, &QualifiedName::new(None, None, "select".to_string())) {
+			Some(_) if !content.is_empty() => {
+			    return Result::Err(Error{kind: ErrorKind::TypeError, message: "element must not have both a select attribute and content".to_string()})
+			}
+			Some(s) => parse(&s.to_string(f))?,
+			None => content,
+		    };
+		    Ok(Constructor::Sort(sel, to_sort_keys(&n, f, sc)?))
+		}
 		(Some(XSLTNS), "copy") => {
 		    // TODO: handle select attribute
 		    let mut cit = n.child_iter();
@@ -709,7 +864,7 @@ fn to_constructor(n: Node, f: &Forest) -> Result<Constructor, Error> {
 		    loop {
 			match cit.next(f) {
 			    Some(e) => {
-				body.push(to_constructor(e, f)?)
+				body.push(to_constructor(e, f, sc)?)
 			    }
 			    None => break,
 			}
@@ -733,48 +888,133 @@ fn to_constructor(n: Node, f: &Forest) -> Result<Constructor, Error> {
 		    }
 		}
 		(Some(XSLTNS), "attribute") => {
-		    match n.get_attribute(f, &QualifiedName::new(None, None, "name".to_string())) {
-			Some(m) => {
-			    let mut cit = n.child_iter();
-			    let mut body = vec![];
-			    loop {
-				match cit.next(f) {
-				    Some(e) => {
-					body.push(to_constructor(e, f)?)
-				    }
-				    None => break,
-				}
-			    }
-			    Ok(Constructor::LiteralAttribute(
-				QualifiedName::new(None, None, m.to_string(f)),
-				body,
-			    ))
+		    let (value, sep) = to_simple_content(&n, f, true, sc)?;
+		    Ok(Constructor::Attribute(to_computed_name(&n, f)?, value, sep))
+		}
+		(Some(XSLTNS), "element") => {
+		    let mut content = to_use_attribute_sets(&n, f, QualifiedName::new(None, None, "use-attribute-sets".to_string()))?;
+		    let mut cit = n.child_iter();
+		    while let Some(d) = cit.next(f) {
+			content.push(to_constructor(d, f, sc)?)
+		    }
+		    Ok(Constructor::Element(to_computed_name(&n, f)?, content))
+		}
+		(Some(XSLTNS), "result-document") => {
+		    let avt = |name: &str| -> Result<Option<Vec<Constructor>>, Error> {
+			match n.get_attribute(f, &QualifiedName::new(None, None, name.to_string())) {
+			    Some(a) => Ok(Some(parse_avt(&a.to_string(f))?)),
+			    None => Ok(None),
 			}
-			None => {
-			    return Result::Err(Error{kind: ErrorKind::TypeError, message: "missing select attribute".to_string()})
+		    };
+		    let format = avt("format")?
+			.map(|name| ComputedName{name, namespace: None, bindings: n.namespaces(f)});
+		    let mut content = vec![];
+		    let mut cit = n.child_iter();
+		    while let Some(d) = cit.next(f) {
+			content.push(to_constructor(d, f, sc)?)
+		    }
+		    Ok(Constructor::ResultDocument(
+			ResultDocument{
+			    href: avt("href")?,
+			    format,
+			    method: avt("method")?,
+			    indent: avt("indent")?,
+			},
+			vec![Constructor::Document(content)],
+		    ))
+		}
+		(Some(XSLTNS), "message") => {
+		    // The content of the message is a document node
+		    let (content, _) = to_simple_content(&n, f, false, sc)?;
+		    let terminate = match n.get_attribute(f, &QualifiedName::new(None, None, "terminate".to_string())) {
+			Some(t) => parse_avt(&t.to_string(f))?,
+			None => vec![Constructor::Literal(Value::from("no"))],
+		    };
+		    let error_code = match n.get_attribute(f, &QualifiedName::new(None, None, "error-code".to_string())) {
+			Some(e) => Some(ComputedName{
+			    name: parse_avt(&e.to_string(f))?,
+			    namespace: None,
+			    bindings: n.namespaces(f),
+			}),
+			None => None,
+		    };
+		    Ok(Constructor::Message(vec![Constructor::Document(content)], terminate, error_code))
+		}
+		(Some(XSLTNS), "value-of") => {
+		    let (value, sep) = to_simple_content(&n, f, true, sc)?;
+		    Ok(Constructor::Text(value, sep))
+		}
+		(Some(XSLTNS), "comment") => {
+		    let (value, sep) = to_simple_content(&n, f, false, sc)?;
+		    Ok(Constructor::Comment(value, sep))
+		}
+		(Some(XSLTNS), "processing-instruction") => {
+		    let name = n.get_attribute(f, &QualifiedName::new(None, None, "name".to_string()))
+			.ok_or(Error{kind: ErrorKind::TypeError, message: "missing name attribute".to_string()})?;
+		    let (value, sep) = to_simple_content(&n, f, false, sc)?;
+		    Ok(Constructor::ProcessingInstruction(parse_avt(&name.to_string(f))?, value, sep))
+		}
+		(Some(XSLTNS), "namespace") => {
+		    let name = n.get_attribute(f, &QualifiedName::new(None, None, "name".to_string()))
+			.ok_or(Error{kind: ErrorKind::TypeError, message: "missing name attribute".to_string()})?;
+		    let (value, _) = to_simple_content(&n, f, false, sc)?;
+		    Ok(Constructor::Namespace(parse_avt(&name.to_string(f))?, value))
+		}
+		(Some(XSLTNS), "number") => {
+		    let mut num = Numbering::default();
+		    if let Some(v) = n.get_attribute(f, &QualifiedName::new(None, None, "value".to_string())) {
+			num.value = Some(parse(&v.to_string(f))?)
+		    }
+		    if let Some(s) = n.get_attribute(f, &QualifiedName::new(None, None, "select".to_string())) {
+			num.select = Some(parse(&s.to_string(f))?)
+		    }
+		    if let Some(l) = n.get_attribute(f, &QualifiedName::new(None, None, "level".to_string())) {
+			num.level = match l.to_string(f).trim() {
+			    "single" => NumberLevel::Single,
+			    "multiple" => NumberLevel::Multiple,
+			    "any" => NumberLevel::Any,
+			    _ => return Result::Err(Error{kind: ErrorKind::TypeError, message: "level attribute must be single, multiple or any".to_string()})
+			}
+		    }
+		    if let Some(c) = n.get_attribute(f, &QualifiedName::new(None, None, "count".to_string())) {
+			num.count = Some(to_pattern(parse(&c.to_string(f))?)?)
+		    }
+		    if let Some(c) = n.get_attribute(f, &QualifiedName::new(None, None, "from".to_string())) {
+			num.from = Some(to_pattern(parse(&c.to_string(f))?)?)
+		    }
+		    if let Some(fmt) = n.get_attribute(f, &QualifiedName::new(None, None, "format".to_string())) {
+			num.format = parse_avt(&fmt.to_string(f))?
+		    }
+		    for (a, v) in [
+			("grouping-separator", &mut num.grouping_separator),
+			("grouping-size", &mut num.grouping_size),
+			("lang", &mut num.lang),
+			("letter-value", &mut num.letter_value),
+			("ordinal", &mut num.ordinal),
+		    ] {
+			if let Some(t) = n.get_attribute(f, &QualifiedName::new(None, None, a.to_string())) {
+			    *v = Some(parse_avt(&t.to_string(f))?)
 			}
 		    }
+		    Ok(Constructor::Number(num))
 		}
 		(Some(XSLTNS), u) => {
 		    Ok(Constructor::NotImplemented(format!("unsupported XSL element \"{}\"", u)))
 		}
 		(_, a) => {
 		    // TODO: Handle qualified element name
-		    let mut content = vec![];
+		    let mut content = to_use_attribute_sets(&n, f, QualifiedName::new(Some(XSLTNS.to_string()), None, "use-attribute-sets".to_string()))?;
 		    let mut ait = n.attribute_iter(f);
-		    loop {
-			match ait.next() {
-			    Some(e) => {
-				content.push(to_constructor(e, f)?)
-			    }
-			    None => break,
+		    while let Some(e) = ait.next() {
+			if e.to_name(f).get_nsuri_ref() != Some(XSLTNS) {
+			    content.push(to_constructor(e, f, sc)?)
 			}
 		    }
 		    let mut cit = n.child_iter();
 		    loop {
 			match cit.next(f) {
 			    Some(e) => {
-				content.push(to_constructor(e, f)?)
+				content.push(to_constructor(e, f, sc)?)
 			    }
 			    None => break,
 			}
@@ -787,8 +1027,8 @@ fn to_constructor(n: Node, f: &Forest) -> Result<Constructor, Error> {
 	    }
 	}
 	NodeType::Attribute => {
-	    // Get value as a Value
-	    Ok(Constructor::LiteralAttribute(n.to_name(f), vec![Constructor::Literal(Value::String(n.to_string(f)))]))
+	    // The value is an attribute value template
+	    Ok(Constructor::LiteralAttribute(n.to_name(f), parse_avt(&n.to_string(f))?))
 	}
 	_ => {
 	    // TODO: literal elements, etc, pretty much everything in the XSLT spec
@@ -797,6 +1037,425 @@ fn to_constructor(n: Node, f: &Forest) -> Result<Constructor, Error> {
     }
 }
 
+// Parse an attribute value template. Expressions are enclosed in curly brackets; "{{" and "}}" are literal brackets.
+// If there are no expressions then the result is a literal string.
+fn parse_avt(s: &str) -> Result<Vec<Constructor>, Error> {
+    let mut parts: Vec<Vec<Constructor>> = vec![];
+    let mut fixed = String::new();
+    let mut it = s.chars().peekable();
+    while let Some(c) = it.next() {
+	match c {
+	    '{' if it.peek() == Some(&'{') => {
+		it.next();
+		fixed.push('{')
+	    }
+	    '}' if it.peek() == Some(&'}') => {
+		it.next();
+		fixed.push('}')
+	    }
+	    '}' => {
+		return Result::Err(Error{kind: ErrorKind::StaticSyntax, message: format!("unmatched \"}}\" in attribute value template \"{}\"", s)})
+	    }
+	    '{' => {
+		// Find the end of the expression, skipping over string literals and nested brackets
+		let mut expr = String::new();
+		let mut depth = 0;
+		let mut quote: Option<char> = None;
+		let mut closed = false;
+		for d in it.by_ref() {
+		    match (quote, d) {
+			(Some(q), _) if q == d => {
+			    quote = None;
+			    expr.push(d)
+			}
+			(Some(_), _) => expr.push(d),
+			(None, '\'') | (None, '"') => {
+			    quote = Some(d);
+			    expr.push(d)
+			}
+			(None, '{') => {
+			    depth += 1;
+			    expr.push(d)
+			}
+			(None, '}') if depth == 0 => {
+			    closed = true;
+			    break
+			}
+			(None, '}') => {
+			    depth -= 1;
+			    expr.push(d)
+			}
+			_ => expr.push(d),
+		    }
+		}
+		if !closed {
+		    return Result::Err(Error{kind: ErrorKind::StaticSyntax, message: format!("unterminated expression in attribute value template \"{}\"", s)})
+		}
+		if !fixed.is_empty() {
+		    parts.push(vec![Constructor::Literal(Value::from(std::mem::take(&mut fixed)))])
+		}
+		parts.push(parse(expr.trim())?)
+	    }
+	    _ => fixed.push(c),
+	}
+    }
+    if parts.is_empty() {
+	Ok(vec![Constructor::Literal(Value::from(fixed))])
+    } else {
+	if !fixed.is_empty() {
+	    parts.push(vec![Constructor::Literal(Value::from(fixed))])
+	}
+	Ok(vec![Constructor::AttributeValueTemplate(parts)])
+    }
+}
+
+// Resolve a lexical QName, using the namespace declarations in scope for the given node.
+fn to_qname(n: &Node, f: &Forest, q: &str) -> Result<QualifiedName, Error> {
+    match q.trim().split_once(':') {
+	Some((p, l)) => {
+	    match n.namespace_uri(f, Some(p)) {
+		Some(ns) => Ok(QualifiedName::new(Some(ns), Some(p.to_string()), l.to_string())),
+		None => Result::Err(Error{kind: ErrorKind::NoNamespace, message: format!("no namespace declared for prefix \"{}\"", p)}),
+	    }
+	}
+	None => Ok(QualifiedName::new(None, None, q.trim().to_string())),
+    }
+}
+
+// Interpret a mode name. This may be one of the tokens #default, #unnamed, #all or #current.
+fn to_mode(n: &Node, f: &Forest, m: &str) -> Result<Mode, Error> {
+    match m.trim() {
+	"#default" | "#unnamed" => Ok(Mode::Default),
+	"#all" => Ok(Mode::All),
+	"#current" => Ok(Mode::Current),
+	q => Ok(Mode::Named(to_qname(n, f, q)?)),
+    }
+}
+
+// A pattern that matches a single step, e.g. child::text()
+fn step_pattern(a: Axis, nt: NodeTest) -> Result<Vec<Constructor>, Error> {
+    to_pattern(
+	vec![Constructor::Path(
+	    vec![
+		vec![Constructor::Step(NodeMatch{axis: a, nodetest: nt}, vec![])],
+	    ]
+	)]
+    )
+}
+
+// Define the builtin templates for a mode, according to its on-no-match behaviour.
+// See XSLT 6.7. If the behaviour is to fail then there are no builtin templates.
+fn add_builtin_templates(ev: &mut Evaluator, m: Mode, onm: &str) -> Result<(), Error> {
+    let root = to_pattern(vec![Constructor::Path(vec![vec![Constructor::Root]])])?;
+    let element = step_pattern(Axis::Child, NodeTest::Kind(KindTest::ElementTest))?;
+    let text = step_pattern(Axis::Child, NodeTest::Kind(KindTest::TextTest))?;
+    let attribute = step_pattern(Axis::Attribute, NodeTest::Name(NameTest{ns: None, prefix: None, name: Some(WildcardOrName::Wildcard)}))?;
+    let comment = step_pattern(Axis::Child, NodeTest::Kind(KindTest::CommentTest))?;
+    let pi = step_pattern(Axis::Child, NodeTest::Kind(KindTest::PITest))?;
+
+    // Process the children, or the attributes, of the context node in the same mode
+    let children = Constructor::ApplyTemplates(
+	vec![Constructor::Step(
+	    NodeMatch{axis: Axis::Child, nodetest: NodeTest::Kind(KindTest::AnyKindTest)},
+	    vec![]
+	)],
+	Mode::Current,
+	vec![],
+    );
+    let attributes = Constructor::ApplyTemplates(
+	vec![Constructor::Step(
+	    NodeMatch{axis: Axis::Attribute, nodetest: NodeTest::Name(NameTest{ns: None, prefix: None, name: Some(WildcardOrName::Wildcard)})},
+	    vec![]
+	)],
+	Mode::Current,
+	vec![],
+    );
+
+    let rules: Vec<(Vec<Constructor>, Vec<Constructor>)> = match onm {
+	"text-only-copy" => vec![
+	    (root, vec![children.clone()]),
+	    (element, vec![children]),
+	    (text, vec![Constructor::ContextItem]),
+	    (attribute, vec![Constructor::Concat(vec![vec![Constructor::ContextItem]])]),
+	    (comment, vec![]),
+	    (pi, vec![]),
+	],
+	"deep-copy" => vec![
+	    (root, vec![Constructor::DeepCopy(vec![Constructor::Step(
+		NodeMatch{axis: Axis::Child, nodetest: NodeTest::Kind(KindTest::AnyKindTest)},
+		vec![]
+	    )])]),
+	    (element, vec![Constructor::DeepCopy(vec![Constructor::ContextItem])]),
+	    (text, vec![Constructor::ContextItem]),
+	    (attribute, vec![Constructor::DeepCopy(vec![Constructor::ContextItem])]),
+	    (comment, vec![Constructor::DeepCopy(vec![Constructor::ContextItem])]),
+	    (pi, vec![Constructor::DeepCopy(vec![Constructor::ContextItem])]),
+	],
+	"shallow-copy" => vec![
+	    (root, vec![children.clone()]),
+	    (element, vec![Constructor::Copy(vec![], vec![attributes, children])]),
+	    (text, vec![Constructor::ContextItem]),
+	    (attribute, vec![Constructor::Copy(vec![], vec![])]),
+	    (comment, vec![Constructor::Copy(vec![], vec![])]),
+	    (pi, vec![Constructor::Copy(vec![], vec![])]),
+	],
+	"deep-skip" => vec![
+	    (root, vec![children]),
+	    (element, vec![]),
+	    (text, vec![]),
+	    (attribute, vec![]),
+	    (comment, vec![]),
+	    (pi, vec![]),
+	],
+	"shallow-skip" => vec![
+	    (root, vec![attributes.clone(), children.clone()]),
+	    (element, vec![attributes, children]),
+	    (text, vec![]),
+	    (attribute, vec![]),
+	    (comment, vec![]),
+	    (pi, vec![]),
+	],
+	"fail" => vec![],
+	_ => return Result::Err(Error{kind: ErrorKind::TypeError, message: format!("invalid on-no-match value \"{}\"", onm)}),
+    };
+    for (p, b) in rules {
+	ev.add_builtin_template(p, b, vec![m.clone()], -1.0, 0);
+    }
+    Ok(())
+}
+
+// Compile the value of a parameter. This is given by either the select attribute or the content of the element, but not both.
+// If the element has no as attribute then the content constructs a temporary tree.
+// Returns None if no value is given.
+fn to_value(n: &Node, f: &Forest, typed: bool, sc: &mut StaticContext) -> Result<Option<Vec<Constructor>>, Error> {
+    let mut content = vec![];
+    let mut cit = n.child_iter();
+    while let Some(d) = cit.next(f) {
+	content.push(to_constructor(d, f, sc)?)
+    }
+    let mut value = match n.get_attribute(f, &QualifiedName::new(None, None, "select".to_string())) {
+	Some(_) if !content.is_empty() => {
+	    return Result::Err(Error{kind: ErrorKind::TypeError, message: "element must not have both a select attribute and content".to_string()})
+	}
+	Some(sel) => Some(parse(&sel.to_string(f))?),
+	None if content.is_empty() => None,
+	None if typed => Some(content),
+	None => Some(vec![Constructor::Document(content)]),
+    };
+    if let Some(v) = value.as_mut() {
+	resolve_prefixes(n, f, sc, v)?
+    }
+    Ok(value)
+}
+
+// The name and namespace attributes of xsl:element or xsl:attribute. The in-scope namespaces are used to resolve the prefix of the name.
+fn to_computed_name(n: &Node, f: &Forest) -> Result<ComputedName, Error> {
+    let name = n.get_attribute(f, &QualifiedName::new(None, None, "name".to_string()))
+	.ok_or(Error{kind: ErrorKind::TypeError, message: "missing name attribute".to_string()})?;
+    Ok(ComputedName{
+	name: parse_avt(&name.to_string(f))?,
+	namespace: match n.get_attribute(f, &QualifiedName::new(None, None, "namespace".to_string())) {
+	    Some(ns) => Some(parse_avt(&ns.to_string(f))?),
+	    None => None,
+	},
+	bindings: n.namespaces(f),
+    })
+}
+
+// The value of xsl:attribute, xsl:comment, etc, and the separator between the items of the value.
+// The value is either the select attribute or the content. The default separator is a space for the select attribute.
+fn to_simple_content(n: &Node, f: &Forest, separator: bool, sc: &mut StaticContext) -> Result<(Vec<Constructor>, Vec<Constructor>), Error> {
+    let mut content = vec![];
+    let mut cit = n.child_iter();
+    while let Some(d) = cit.next(f) {
+	content.push(to_constructor(d, f, sc)?)
+    }
+    let sep = match n.get_attribute(f, &QualifiedName::new(None, None, "separator".to_string())) {
+	Some(s) if separator => Some(parse_avt(&s.to_string(f))?),
+	_ => None,
+    };
+    match n.get_attribute(f, &QualifiedName::new(None, None, "select".to_string())) {
+	Some(_) if !content.is_empty() => {
+	    Result::Err(Error{kind: ErrorKind::TypeError, message: "element must not have both a select attribute and content".to_string()})
+	}
+	Some(sel) => Ok((parse(&sel.to_string(f))?, sep.unwrap_or(vec![Constructor::Literal(Value::from(" "))]))),
+	None => Ok((content, sep.unwrap_or(vec![Constructor::Literal(Value::from(""))]))),
+    }
+}
+
+// The use-attribute-sets attribute, which is a whitespace-separated list of attribute set names.
+fn to_use_attribute_sets(n: &Node, f: &Forest, attr: QualifiedName) -> Result<Vec<Constructor>, Error> {
+    match n.get_attribute(f, &attr) {
+	Some(a) => {
+	    let names = a.to_string(f).split_whitespace()
+		.map(|q| to_qname(n, f, q))
+		.collect::<Result<Vec<QualifiedName>, Error>>()?;
+	    Ok(vec![Constructor::UseAttributeSets(names)])
+	}
+	None => Ok(vec![]),
+    }
+}
+
+// The attribute sets that are used by an attribute set, i.e. by its use-attribute-sets attribute or by the instructions in its content.
+fn attribute_set_uses(n: &Node, f: &Forest, result: &mut Vec<QualifiedName>) -> Result<(), Error> {
+    let attr = if n.to_name(f).get_nsuri_ref() == Some(XSLTNS) {
+	QualifiedName::new(None, None, "use-attribute-sets".to_string())
+    } else {
+	QualifiedName::new(Some(XSLTNS.to_string()), None, "use-attribute-sets".to_string())
+    };
+    if let Some(a) = n.get_attribute(f, &attr) {
+	for q in a.to_string(f).split_whitespace() {
+	    result.push(to_qname(n, f, q)?)
+	}
+    }
+    let mut cit = n.child_iter();
+    while let Some(d) = cit.next(f) {
+	if d.is_element(f) {
+	    attribute_set_uses(&d, f, result)?
+	}
+    }
+    Ok(())
+}
+
+// Check that no attribute set uses itself, directly or indirectly (XTSE0720).
+fn check_attribute_set_uses(uses: &HashMap<QualifiedName, Vec<QualifiedName>>) -> Result<(), Error> {
+    // A depth-first search. The path is the attribute sets that are being visited, and done are those that have been checked.
+    fn visit(n: &QualifiedName, uses: &HashMap<QualifiedName, Vec<QualifiedName>>, path: &mut Vec<QualifiedName>, done: &mut Vec<QualifiedName>) -> Result<(), Error> {
+	if path.contains(n) {
+	    return Result::Err(Error{kind: ErrorKind::CircularAttributeSet, message: format!("attribute set \"{}\" uses itself", n.to_string())})
+	}
+	if done.contains(n) {
+	    return Ok(())
+	}
+	path.push(n.clone());
+	for m in uses.get(n).into_iter().flatten() {
+	    visit(m, uses, path, done)?
+	}
+	path.pop();
+	done.push(n.clone());
+	Ok(())
+    }
+    let mut done = vec![];
+    for n in uses.keys() {
+	visit(n, uses, &mut vec![], &mut done)?
+    }
+    Ok(())
+}
+
+// Is the node an XSLT element with the given local name?
+fn is_xsl_element(n: &Node, f: &Forest, name: &str) -> bool {
+    n.is_element(f) &&
+	n.to_name(f).get_nsuri_ref() == Some(XSLTNS) &&
+	n.to_name(f).get_localname() == name
+}
+
+// Compile the xsl:sort children of an instruction.
+fn to_sort_keys(n: &Node, f: &Forest, sc: &mut StaticContext) -> Result<Vec<SortKey>, Error> {
+    let avt = |d: &Node, a: &str| -> Result<Option<Vec<Constructor>>, Error> {
+	match d.get_attribute(f, &QualifiedName::new(None, None, a.to_string())) {
+	    Some(v) => Ok(Some(parse_avt(&v.to_string(f))?)),
+	    None => Ok(None),
+	}
+    };
+    let mut result = vec![];
+    let mut cit = n.child_iter();
+    while let Some(d) = cit.next(f) {
+	if is_xsl_element(&d, f, "sort") {
+	    let mut key = SortKey::default();
+	    // The sort key value is either the select attribute or the content
+	    if let Some(select) = to_value(&d, f, false, sc)? {
+		key.select = select
+	    }
+	    key.order = avt(&d, "order")?;
+	    key.data_type = avt(&d, "data-type")?;
+	    key.case_order = avt(&d, "case-order")?;
+	    key.lang = avt(&d, "lang")?;
+	    key.collation = avt(&d, "collation")?;
+	    key.stable = avt(&d, "stable")?;
+	    for v in vec![&mut key.order, &mut key.data_type, &mut key.case_order, &mut key.lang, &mut key.collation, &mut key.stable].into_iter().flatten() {
+		resolve_prefixes(&d, f, sc, v)?
+	    }
+	    result.push(key)
+	}
+    }
+    Ok(result)
+}
+
+// Sort the result of an expression, if there are any sort keys
+fn sorted(e: Vec<Constructor>, k: Vec<SortKey>) -> Vec<Constructor> {
+    if k.is_empty() {
+	e
+    } else {
+	vec![Constructor::Sort(e, k)]
+    }
+}
+
+// Compile the xsl:param declaration of a template.
+fn to_param(n: Node, f: &Forest, sc: &mut StaticContext) -> Result<TemplateParam, Error> {
+    let name = n.get_attribute(f, &QualifiedName::new(None, None, "name".to_string()))
+	.ok_or(Error{kind: ErrorKind::TypeError, message: "missing name attribute".to_string()})?
+	.to_string(f);
+    let name = variable_name(&to_qname(&n, f, &name)?);
+    let as_type = to_sequence_type(&n, f)?;
+    let mut default = to_value(&n, f, as_type.is_some(), sc)?;
+    if let Some(d) = default.as_mut() {
+	sc.static_analysis_in_scope(d, &n.namespaces(f))?
+    }
+    Ok(TemplateParam{
+	name,
+	default,
+	required: yes_no_attribute(&n, f, "required")?,
+	tunnel: yes_no_attribute(&n, f, "tunnel")?,
+	as_type,
+    })
+}
+
+// The required type of a variable or parameter, i.e. the as attribute.
+fn to_sequence_type(n: &Node, f: &Forest) -> Result<Option<SequenceType>, Error> {
+    match n.get_attribute(f, &QualifiedName::new(None, None, "as".to_string())) {
+	Some(a) => Ok(Some(parse_sequence_type(&a.to_string(f))?)),
+	None => Ok(None),
+    }
+}
+
+// The import precedence of a top-level element. Elements copied from an imported stylesheet have an import attribute.
+fn import_precedence(n: &Node, f: &Forest) -> Result<usize, Error> {
+    match n.get_attribute(f, &QualifiedName::new(Some(String::from("http://github.com/ballsteve/xrust")), None, String::from("import"))) {
+	Some(im) => Ok(im.to_value(f).to_int()? as usize),
+	None => Ok(0),
+    }
+}
+
+// Compile the xsl:with-param children of an xsl:apply-templates or xsl:call-template.
+fn to_with_params(n: &Node, f: &Forest, sc: &mut StaticContext) -> Result<Vec<WithParam>, Error> {
+    let mut result = vec![];
+    let mut cit = n.child_iter();
+    while let Some(d) = cit.next(f) {
+	if d.is_element(f) &&
+	    d.to_name(f).get_nsuri_ref() == Some(XSLTNS) &&
+	    d.to_name(f).get_localname() == "with-param" {
+		let name = d.get_attribute(f, &QualifiedName::new(None, None, "name".to_string()))
+		    .ok_or(Error{kind: ErrorKind::TypeError, message: "missing name attribute".to_string()})?
+		    .to_string(f);
+		let name = variable_name(&to_qname(&d, f, &name)?);
+		let typed = d.get_attribute(f, &QualifiedName::new(None, None, "as".to_string())).is_some();
+		let value = to_value(&d, f, typed, sc)?
+		    .unwrap_or_else(|| vec![Constructor::Literal(Value::from(""))]);
+		result.push(WithParam{name, value, tunnel: yes_no_attribute(&d, f, "tunnel")?});
+	    }
+    }
+    Ok(result)
+}
+
+// The value of a boolean attribute, i.e. yes/no, true/false or 1/0. The default is false.
+// The value of a yes-or-no attribute. An absent attribute is "no".
+fn yes_no_attribute(n: &Node, f: &Forest, a: &str) -> Result<bool, Error> {
+    match n.get_attribute(f, &QualifiedName::new(None, None, a.to_string())) {
+	Some(v) => yes_no(&v.to_string(f), a),
+	None => Ok(false),
+    }
+}
+
 /// Strip whitespace nodes from a XDM [Tree].
 /// See [XSLT 4.3](https://www.w3.org/TR/2017/REC-xslt-30-20170608/#stylesheet-stripping)
 pub fn strip_whitespace(
@@ -1041,6 +1700,8 @@ mod tests {
     use super::*;
     use std::rc::Rc;
     use crate::item::*;
+    use crate::message::MessageLog;
+    use crate::resultdocument::MemoryHandler;
 
     #[test]
     fn xslt_literal_text() {
@@ -1314,4 +1975,1111 @@ mod tests {
 
 	assert_eq!(seq.to_xml(Some(&f)), "shallow1 deeper import level shallow2twothreefour")
     }
+
+    #[test]
+    fn number_single() {
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='child::Test'><xsl:apply-templates/></xsl:template>
+  <xsl:template match='child::Item'><xsl:number format='(i)'/></xsl:template>
+  <xsl:template match='child::Other'>-</xsl:template>
+</xsl:stylesheet>",
+			"<Test><Item>one</Item><Other/><Item>two</Item><Item>three</Item></Test>", |_| {}).expect("transformation failed"),
+	    "(i)-(ii)(iii)"
+	)
+    }
+    #[test]
+    fn number_multiple() {
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='child::Doc'><xsl:apply-templates/></xsl:template>
+  <xsl:template match='child::Section'><xsl:number level='multiple' format='1.a '/><xsl:apply-templates/></xsl:template>
+  <xsl:template match='child::text()'/>
+</xsl:stylesheet>",
+			"<Doc><Section><Section/><Section/></Section><Section><Section/></Section></Doc>", |_| {}).expect("transformation failed"),
+	    "1 1.a 1.b 2 2.a "
+	)
+    }
+    #[test]
+    fn number_any() {
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='child::Doc'><xsl:apply-templates/></xsl:template>
+  <xsl:template match='child::Chapter'><xsl:apply-templates/></xsl:template>
+  <xsl:template match='child::Note'>[<xsl:number level='any' count='child::Note'/>]</xsl:template>
+</xsl:stylesheet>",
+			"<Doc><Chapter><Note/><Note/></Chapter><Chapter><Note/></Chapter></Doc>", |_| {}).expect("transformation failed"),
+	    "[1][2][3]"
+	)
+    }
+    #[test]
+    fn number_any_from() {
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='child::Doc'><xsl:apply-templates/></xsl:template>
+  <xsl:template match='child::Chapter'><xsl:apply-templates/></xsl:template>
+  <xsl:template match='child::Note'>[<xsl:number level='any' from='child::Chapter'/>]</xsl:template>
+</xsl:stylesheet>",
+			"<Doc><Chapter><Note/><Note/></Chapter><Chapter><Note/></Chapter></Doc>", |_| {}).expect("transformation failed"),
+	    "[1][2][1]"
+	)
+    }
+    #[test]
+    fn number_value() {
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='/'><xsl:number value='1234567' grouping-separator=',' grouping-size='3'/></xsl:template>
+</xsl:stylesheet>",
+			"<Doc/>", |_| {}).expect("transformation failed"),
+	    "1,234,567"
+	)
+    }
+    #[test]
+    fn number_avt() {
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='/'><xsl:number value='1234567' grouping-separator='{\",\"}' grouping-size='{1+2}' lang='{\"en\"}'/></xsl:template>
+</xsl:stylesheet>",
+			"<Doc/>", |_| {}).expect("transformation failed"),
+	    "1,234,567"
+	)
+    }
+    #[test]
+    fn number_ordinal() {
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='/'><xsl:for-each select='child::Doc/child::p'><xsl:number ordinal='{\"yes\"}'/>;</xsl:for-each></xsl:template>
+</xsl:stylesheet>",
+			"<Doc><p/><p/><p/></Doc>", |_| {}).expect("transformation failed"),
+	    "1st;2nd;3rd;"
+	)
+    }
+    #[test]
+    fn number_bad_grouping_size() {
+	match transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='/'><xsl:number value='1234567' grouping-separator=',' grouping-size='{\"three\"}'/></xsl:template>
+</xsl:stylesheet>",
+			"<Doc/>", |_| {}) {
+	    Err(e) => assert_eq!(e.kind.to_string(), ErrorKind::InvalidAttributeValue.to_string()),
+	    Ok(r) => panic!("transformation succeeded with \"{}\"", r),
+	}
+    }
+
+    #[test]
+    fn document_base() {
+	let mut sc = StaticContext::new_with_xslt_builtins();
+	let mut f = Forest::new();
+	let src = f.grow_tree("<Test xml:base='http://example.org/data/'><ref>lookup.xml</ref></Test>")
+	    .expect("unable to parse XML");
+	// A URI from a node is resolved against the base URI of that node, a URI that is a string against the static base URI, and any URI against the base URI of the $base node
+	let style = f.grow_tree("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='child::Test'><xsl:sequence select='string(document(child::ref))'/>|<xsl:sequence select='string(document(string(child::ref)))'/>|<xsl:sequence select='string(document(\"lookup.xml\", .))'/></xsl:template>
+</xsl:stylesheet>").expect("unable to parse XML");
+	let mut r = crate::resolver::MemoryResolver::new();
+	r.add_document("http://example.org/lookup.xml", "<lookup>style</lookup>").expect("unable to add document");
+	r.add_document("http://example.org/data/lookup.xml", "<lookup>data</lookup>").expect("unable to add document");
+	let mut ev = from_document(&mut f, style, &mut sc, Some(Url::parse("http://example.org/style.xsl").expect("unable to parse URL")))
+	    .expect("failed to compile stylesheet");
+	ev.set_document_resolver(Rc::new(r));
+	let rd = f.plant_tree();
+	let top = Rc::new(Item::Node(f.get_ref(src).unwrap().get_doc_node().get_first_element(&f).unwrap()));
+	let t = ev.find_match(&top, &mut f, src, rd, None).expect("unable to find match");
+	let seq = ev.evaluate(Some(vec![top]), Some(0), &t, &mut f, src, rd).expect("evaluation failed");
+	assert_eq!(seq.to_string(Some(&f)), "data|style|data");
+    }
+    #[test]
+    fn document_lookup() {
+	let mut sc = StaticContext::new_with_xslt_builtins();
+
+	let mut f = Forest::new();
+	let src = f.grow_tree("<Test><Level1>one</Level1></Test>")
+	    .expect("unable to parse XML");
+	let isrc = Rc::new(Item::Node(f.get_ref(src).unwrap().get_doc_node()));
+
+	let style = f.grow_tree("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='/'><xsl:apply-templates select='document(\"lookup.xml\")/child::*/child::*'/></xsl:template>
+  <xsl:template match='child::entry'>[<xsl:copy-of select='child::text()'/>]</xsl:template>
+</xsl:stylesheet>").expect("unable to parse XML");
+
+	let mut r = crate::resolver::MemoryResolver::new();
+	r.add_document("http://example.org/lookup.xml", "<lookup><entry>a</entry><entry>b</entry></lookup>")
+	    .expect("unable to add document");
+
+	let mut ev = from_document(
+	    &mut f,
+            style,
+	    &mut sc,
+	    Some(Url::parse("http://example.org/style.xsl").expect("unable to parse URL")),
+	)
+            .expect("failed to compile stylesheet");
+	ev.set_document_resolver(Rc::new(r));
+
+	let rd = f.plant_tree();
+
+	let t = ev.find_match(&isrc, &mut f, src, rd, None)
+	    .expect("unable to find match");
+	let seq = ev.evaluate(Some(vec![Rc::clone(&isrc)]), Some(0), &t, &mut f, src, rd)
+	    .expect("evaluation failed");
+
+	assert_eq!(seq.to_string(Some(&f)), "[a][b]")
+    }
+
+    // Compile the stylesheet and apply it to the source document. The evaluator is given to the configure function before the transformation, e.g. to set a message listener.
+    fn transform<F: FnOnce(&mut Evaluator)>(style: &str, src: &str, configure: F) -> Result<String, Error> {
+	let mut sc = StaticContext::new_with_xslt_builtins();
+
+	let mut f = Forest::new();
+	let src = f.grow_tree(src)
+	    .expect("unable to parse XML");
+	let isrc = Rc::new(Item::Node(f.get_ref(src).unwrap().get_doc_node()));
+
+	let style = f.grow_tree(style).expect("unable to parse XML");
+
+	let mut ev = from_document(&mut f, style, &mut sc, None)?;
+	configure(&mut ev);
+
+	let rd = f.plant_tree();
+
+	let t = ev.find_match(&isrc, &mut f, src, rd, None)?;
+	let seq = ev.evaluate(Some(vec![Rc::clone(&isrc)]), Some(0), &t, &mut f, src, rd)?;
+
+	Ok(seq.to_xml(Some(&f)))
+    }
+
+    #[test]
+    fn call_template() {
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='/'><xsl:call-template name='show'><xsl:with-param name='a' select='\"one\"'/></xsl:call-template>|<xsl:call-template name='show'/></xsl:template>
+  <xsl:template name='show'><xsl:param name='a'/><xsl:param name='b' select='\"default\"'/>[<xsl:sequence select='$a'/>,<xsl:sequence select='$b'/>]</xsl:template>
+</xsl:stylesheet>",
+		      "<Test/>", |_| {}).expect("transformation failed"),
+	    "[one,default]|[,default]"
+	)
+    }
+    #[test]
+    fn call_template_context() {
+	// The context item is unchanged by call-template
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform' xmlns:my='http://example.org/my'>
+  <xsl:template match='child::Test'><xsl:call-template name='my:name'/></xsl:template>
+  <xsl:template name='my:name'><xsl:sequence select='name()'/></xsl:template>
+</xsl:stylesheet>",
+		      "<Test/>", |_| {}).expect("transformation failed"),
+	    "Test"
+	)
+    }
+    #[test]
+    fn call_template_unknown() {
+	assert!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='/'><xsl:call-template name='missing'/></xsl:template>
+</xsl:stylesheet>",
+		      "<Test/>", |_| {}).is_err()
+	)
+    }
+    #[test]
+    fn param_content() {
+	// Without a type, the content of with-param is a temporary tree
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='/'><xsl:call-template name='show'><xsl:with-param name='a'><b>bold</b></xsl:with-param></xsl:call-template></xsl:template>
+  <xsl:template name='show'><xsl:param name='a'/><xsl:sequence select='count($a/child::b)'/>:<xsl:sequence select='string($a)'/></xsl:template>
+</xsl:stylesheet>",
+		      "<Test/>", |_| {}).expect("transformation failed"),
+	    "1:bold"
+	)
+    }
+    #[test]
+    fn param_required() {
+	let e = transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='/'><xsl:call-template name='show'/></xsl:template>
+  <xsl:template name='show'><xsl:param name='a' required='yes'/><xsl:sequence select='$a'/></xsl:template>
+</xsl:stylesheet>",
+			  "<Test/>", |_| {});
+	match e {
+	    Err(Error{kind: ErrorKind::MissingParameter, ..}) => {}
+	    _ => panic!("expected missing parameter error")
+	}
+    }
+    #[test]
+    fn param_typed() {
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='/'><xsl:call-template name='add'><xsl:with-param name='n'>41</xsl:with-param></xsl:call-template></xsl:template>
+  <xsl:template name='add'><xsl:param name='n' as='xs:integer'/><xsl:sequence select='$n + 1'/></xsl:template>
+</xsl:stylesheet>",
+		      "<Test/>", |_| {}).expect("transformation failed"),
+	    "42"
+	);
+	let e = transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='/'><xsl:call-template name='add'><xsl:with-param name='n' select='\"forty-one\"'/></xsl:call-template></xsl:template>
+  <xsl:template name='add'><xsl:param name='n' as='xs:integer'/><xsl:sequence select='$n + 1'/></xsl:template>
+</xsl:stylesheet>",
+			  "<Test/>", |_| {});
+	match e {
+	    Err(Error{kind: ErrorKind::ParameterType, ..}) => {}
+	    _ => panic!("expected parameter type error")
+	}
+    }
+    #[test]
+    fn apply_templates_with_param() {
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='child::Test'><xsl:apply-templates><xsl:with-param name='p' select='\"-\"'/></xsl:apply-templates></xsl:template>
+  <xsl:template match='child::Item'><xsl:param name='p' select='\"+\"'/><xsl:sequence select='$p'/><xsl:apply-templates/></xsl:template>
+  <xsl:template match='child::text()'><xsl:sequence select='.'/></xsl:template>
+</xsl:stylesheet>",
+		      "<Test><Item>a</Item><Item>b</Item></Test>", |_| {}).expect("transformation failed"),
+	    "-a-b"
+	)
+    }
+    #[test]
+    fn tunnel_param() {
+	// The tunnel parameter passes through the Section template, and the built-in template, to the Para template
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='child::Doc'><xsl:apply-templates><xsl:with-param name='t' select='\"tunnelled\"' tunnel='yes'/><xsl:with-param name='n' select='\"normal\"'/></xsl:apply-templates></xsl:template>
+  <xsl:template match='child::Section'><xsl:apply-templates/></xsl:template>
+  <xsl:template match='child::Para'><xsl:param name='t' tunnel='yes'/><xsl:param name='n'/>[<xsl:sequence select='$t'/>,<xsl:sequence select='$n'/>]</xsl:template>
+</xsl:stylesheet>",
+		      "<Doc><Section><Group><Para/></Group></Section></Doc>", |_| {}).expect("transformation failed"),
+	    "[tunnelled,]"
+	)
+    }
+    #[test]
+    fn local_variable() {
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='child::Test'><xsl:variable name='v' select='\"outer\"'/><xsl:for-each select='child::Item'><xsl:variable name='v' select='string(.)'/>[<xsl:sequence select='$v'/>]</xsl:for-each><xsl:sequence select='$v'/></xsl:template>
+</xsl:stylesheet>",
+		      "<Test><Item>a</Item><Item>b</Item></Test>", |_| {}).expect("transformation failed"),
+	    "[a][b]outer"
+	)
+    }
+    #[test]
+    fn variable_content() {
+	// Without a type, the content of a variable is a temporary tree
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='/'><xsl:variable name='t'><a>one</a><a>two</a></xsl:variable><xsl:sequence select='count($t/child::a)'/>:<xsl:sequence select='string($t)'/></xsl:template>
+</xsl:stylesheet>",
+		      "<Test/>", |_| {}).expect("transformation failed"),
+	    "2:onetwo"
+	)
+    }
+    #[test]
+    fn global_variable() {
+	// Global variables may refer to variables declared later, and to the source document
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:variable name='a' select='concat($b, \"-\", $b)'/>
+  <xsl:variable name='b' select='name(child::*)'/>
+  <xsl:template match='/'><xsl:sequence select='$a'/></xsl:template>
+</xsl:stylesheet>",
+		      "<Test/>", |_| {}).expect("transformation failed"),
+	    "Test-Test"
+	)
+    }
+    #[test]
+    fn global_variable_circular() {
+	let e = transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:variable name='a' select='$b'/>
+  <xsl:variable name='b' select='$a'/>
+  <xsl:template match='/'><xsl:sequence select='$a'/></xsl:template>
+</xsl:stylesheet>",
+			  "<Test/>", |_| {});
+	match e {
+	    Err(Error{kind: ErrorKind::CircularVariable, ..}) => {}
+	    _ => panic!("expected circular variable error")
+	}
+    }
+    #[test]
+    fn global_variable_unused() {
+	// A circular variable is not an error if it is never referenced
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:variable name='a' select='$a'/>
+  <xsl:template match='/'>ok</xsl:template>
+</xsl:stylesheet>",
+		      "<Test/>", |_| {}).expect("transformation failed"),
+	    "ok"
+	)
+    }
+    #[test]
+    fn stylesheet_param() {
+	let mut sc = StaticContext::new_with_xslt_builtins();
+
+	let mut f = Forest::new();
+	let src = f.grow_tree("<Test/>")
+	    .expect("unable to parse XML");
+	let isrc = Rc::new(Item::Node(f.get_ref(src).unwrap().get_doc_node()));
+
+	let style = f.grow_tree("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:param name='p' as='xs:integer'/>
+  <xsl:param name='q' select='\"default\"'/>
+  <xsl:param name='r' required='yes'/>
+  <xsl:template match='/'><xsl:sequence select='$p + 1'/>,<xsl:sequence select='$q'/>,<xsl:sequence select='$r'/></xsl:template>
+</xsl:stylesheet>").expect("unable to parse XML");
+
+	let mut ev = from_document(&mut f, style, &mut sc, None)
+            .expect("failed to compile stylesheet");
+
+	ev.set_parameter(QualifiedName::new(None, None, String::from("p")), vec![Rc::new(Item::Value(Value::from("41")))]);
+	match ev.check_parameters() {
+	    Err(Error{kind: ErrorKind::MissingStylesheetParameter, ..}) => {}
+	    _ => panic!("expected missing stylesheet parameter error")
+	}
+	ev.set_parameter(QualifiedName::new(None, None, String::from("r")), vec![Rc::new(Item::Value(Value::from("supplied")))]);
+	ev.check_parameters().expect("required parameters not supplied");
+
+	let rd = f.plant_tree();
+
+	let t = ev.find_match(&isrc, &mut f, src, rd, None)
+	    .expect("unable to find match");
+	let seq = ev.evaluate(Some(vec![Rc::clone(&isrc)]), Some(0), &t, &mut f, src, rd)
+	    .expect("evaluation failed");
+
+	assert_eq!(seq.to_string(Some(&f)), "42,default,supplied")
+    }
+    #[test]
+    fn mode_multipass() {
+	// Build a table of contents, then the body
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='child::Doc'><toc><xsl:apply-templates select='child::Section' mode='toc'/></toc><xsl:apply-templates select='child::Section'/></xsl:template>
+  <xsl:template match='child::Section' mode='toc'><entry><xsl:sequence select='string(attribute::title)'/></entry></xsl:template>
+  <xsl:template match='child::Section'><section><xsl:apply-templates/></section></xsl:template>
+</xsl:stylesheet>",
+		      "<Doc><Section title='one'>first</Section><Section title='two'>second</Section></Doc>", |_| {}).expect("transformation failed"),
+	    "<toc><entry>one</entry><entry>two</entry></toc><section>first</section><section>second</section>"
+	)
+    }
+    #[test]
+    fn mode_current_all() {
+	// The built-in template and #current stay in the same mode; #all templates match in any mode
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='child::Doc'><xsl:apply-templates mode='m'/>|<xsl:apply-templates/></xsl:template>
+  <xsl:template match='child::Para' mode='m'>[<xsl:apply-templates mode='#current'/>]</xsl:template>
+  <xsl:template match='child::Em' mode='#all'>*</xsl:template>
+  <xsl:template match='child::text()' mode='m'>m</xsl:template>
+</xsl:stylesheet>",
+		      "<Doc><Group><Para>a<Em>b</Em></Para></Group></Doc>", |_| {}).expect("transformation failed"),
+	    "[m*]|a*"
+	)
+    }
+    #[test]
+    fn mode_shallow_copy() {
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:mode on-no-match='shallow-copy'/>
+  <xsl:template match='child::b'><i><xsl:apply-templates/></i></xsl:template>
+</xsl:stylesheet>",
+		      "<p class='x'>one <b>two</b> three</p>", |_| {}).expect("transformation failed"),
+	    "<p class='x'>one <i>two</i> three</p>"
+	)
+    }
+    #[test]
+    fn mode_skip() {
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:mode name='deep' on-no-match='deep-skip'/>
+  <xsl:mode name='shallow' on-no-match='shallow-skip'/>
+  <xsl:template match='/'><xsl:apply-templates mode='deep'/>|<xsl:apply-templates mode='shallow'/></xsl:template>
+  <xsl:template match='child::b' mode='#all'>B</xsl:template>
+</xsl:stylesheet>",
+		      "<p>one <b>two</b><q><b>three</b></q></p>", |_| {}).expect("transformation failed"),
+	    "|BB"
+	)
+    }
+    #[test]
+    fn mode_fail() {
+	let e = transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:mode name='strict' on-no-match='fail'/>
+  <xsl:template match='/'><xsl:apply-templates mode='strict'/></xsl:template>
+</xsl:stylesheet>",
+			  "<p/>", |_| {});
+	match e {
+	    Err(Error{kind: ErrorKind::NoMatchingTemplate, ..}) => {}
+	    _ => panic!("expected no matching template error")
+	}
+    }
+    #[test]
+    fn mode_deep_copy() {
+	// Templates are not applied to the descendants of a deep copied node
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:mode on-no-match='deep-copy'/>
+  <xsl:template match='child::b'>B</xsl:template>
+</xsl:stylesheet>",
+		      "<p>one <b>two</b></p>", |_| {}).expect("transformation failed"),
+	    "<p>one <b>two</b></p>"
+	)
+    }
+    #[test]
+    fn avt_literal_element() {
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='child::Link'><a href='{attribute::url}'><b title='{{{child::*}}}'><c class='x{1 + 1}y'/></b></a></xsl:template>
+</xsl:stylesheet>",
+		      "<Link url='http://example.org/'><b>one</b><b>two</b></Link>", |_| {}).expect("transformation failed"),
+	    "<a href='http://example.org/'><b title='{one two}'><c class='x2y'></c></b></a>"
+	)
+    }
+    #[test]
+    fn avt_brackets() {
+	// Brackets within string literals do not end the expression
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='/'><a b='{concat(\"}\", \"{\")}'/></xsl:template>
+</xsl:stylesheet>",
+		      "<Test/>", |_| {}).expect("transformation failed"),
+	    "<a b='}{'></a>"
+	);
+	assert!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='/'><a b='x}y'/></xsl:template>
+</xsl:stylesheet>",
+		      "<Test/>", |_| {}).is_err()
+	);
+	assert!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='/'><a b='{1 + 1'/></xsl:template>
+</xsl:stylesheet>",
+		      "<Test/>", |_| {}).is_err()
+	)
+    }
+    #[test]
+    fn avt_number_format() {
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='child::Test'><xsl:apply-templates/></xsl:template>
+  <xsl:template match='child::Item'><xsl:number format='{attribute::style}'/></xsl:template>
+</xsl:stylesheet>",
+		      "<Test><Item style='i'/><Item style='A'/><Item style='1'/></Test>", |_| {}).expect("transformation failed"),
+	    "iB3"
+	)
+    }
+    #[test]
+    fn element_computed() {
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='child::Test'><xsl:element name='{attribute::n}'><xsl:attribute name='a{1 + 1}'>v</xsl:attribute>text</xsl:element></xsl:template>
+</xsl:stylesheet>",
+		      "<Test n='Out'/>", |_| {}).expect("transformation failed"),
+	    "<Out a2='v'>text</Out>"
+	)
+    }
+    #[test]
+    fn element_namespace() {
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform' xmlns:q='urn:q'>
+  <xsl:template match='/'><xsl:element name='p:e' namespace='urn:{1}'><xsl:element name='q:f'/></xsl:element></xsl:template>
+</xsl:stylesheet>",
+		      "<Test/>", |_| {}).expect("transformation failed"),
+	    "<p:e xmlns:p='urn:1'><q:f xmlns:q='urn:q'></q:f></p:e>"
+	);
+	assert!(
+	    matches!(
+		transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='/'><xsl:element name='z:e'/></xsl:template>
+</xsl:stylesheet>",
+			  "<Test/>", |_| {}),
+		Err(Error{kind: ErrorKind::InvalidElementName, ..})
+	    )
+	)
+    }
+    #[test]
+    fn element_invalid_name() {
+	assert!(
+	    matches!(
+		transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='/'><xsl:element name='{1}x'/></xsl:template>
+</xsl:stylesheet>",
+			  "<Test/>", |_| {}),
+		Err(Error{kind: ErrorKind::InvalidElementName, ..})
+	    )
+	);
+	assert!(
+	    matches!(
+		transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='/'><e><xsl:attribute name='xmlns'>v</xsl:attribute></e></xsl:template>
+</xsl:stylesheet>",
+			  "<Test/>", |_| {}),
+		Err(Error{kind: ErrorKind::InvalidAttributeName, ..})
+	    )
+	)
+    }
+    #[test]
+    fn attribute_separator() {
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='/'><e><xsl:attribute name='a' select='(1, 2, 3)' separator='-'/><f><xsl:attribute name='b' select='(1, 2)'/></f></e></xsl:template>
+</xsl:stylesheet>",
+		      "<Test/>", |_| {}).expect("transformation failed"),
+	    "<e a='1-2-3'><f b='1 2'></f></e>"
+	)
+    }
+    #[test]
+    fn comment_pi() {
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='/'><e><xsl:comment>a--b-</xsl:comment><xsl:processing-instruction name='p{1}'>  x?>y</xsl:processing-instruction><xsl:comment select='(1, 2)'/></e></xsl:template>
+</xsl:stylesheet>",
+		      "<Test/>", |_| {}).expect("transformation failed"),
+	    "<e><!--a- -b- --><?p1 x? >y?><!--1 2--></e>"
+	);
+	assert!(
+	    matches!(
+		transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='/'><xsl:processing-instruction name='XML'/></xsl:template>
+</xsl:stylesheet>",
+			  "<Test/>", |_| {}),
+		Err(Error{kind: ErrorKind::InvalidProcessingInstructionName, ..})
+	    )
+	)
+    }
+    #[test]
+    fn namespace_node() {
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='/'><e><xsl:namespace name='z'>urn:z</xsl:namespace></e></xsl:template>
+</xsl:stylesheet>",
+		      "<Test/>", |_| {}).expect("transformation failed"),
+	    "<e xmlns:z='urn:z'></e>"
+	);
+	assert!(
+	    matches!(
+		transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='/'><e><xsl:namespace name='xmlns'>urn:z</xsl:namespace></e></xsl:template>
+</xsl:stylesheet>",
+			  "<Test/>", |_| {}),
+		Err(Error{kind: ErrorKind::InvalidNamespaceNode, ..})
+	    )
+	)
+    }
+    #[test]
+    fn attribute_sets() {
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:attribute-set name='s' use-attribute-sets='t'/>
+  <xsl:attribute-set name='t'><xsl:attribute name='b'>2</xsl:attribute></xsl:attribute-set>
+  <xsl:attribute-set name='u'><xsl:attribute name='c' select='1 + 2'/></xsl:attribute-set>
+  <xsl:template match='/'><xsl:element name='e' use-attribute-sets='s'><f xsl:use-attribute-sets='u'/></xsl:element></xsl:template>
+</xsl:stylesheet>",
+		      "<Test/>", |_| {}).expect("transformation failed"),
+	    "<e b='2'><f c='3'></f></e>"
+	)
+    }
+    #[test]
+    fn sort_for_each() {
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='child::Test'><xsl:for-each select='child::Item'><xsl:sort select='attribute::name'/><xsl:sequence select='string(attribute::name)'/></xsl:for-each>|<xsl:for-each select='child::Item'><xsl:sort select='attribute::n' data-type='number' order='descending'/><xsl:sequence select='string(attribute::n)'/>,</xsl:for-each></xsl:template>
+</xsl:stylesheet>",
+		      "<Test><Item name='b' n='9'/><Item name='c' n='100'/><Item name='a' n='10'/></Test>", |_| {}).expect("transformation failed"),
+	    "abc|100,10,9,"
+	)
+    }
+    #[test]
+    fn sort_multiple_keys() {
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='child::Test'><xsl:for-each select='child::Item'><xsl:sort select='attribute::g'/><xsl:sort select='attribute::h' order='{attribute::dir}'/><xsl:sequence select='string(attribute::id)'/></xsl:for-each></xsl:template>
+</xsl:stylesheet>",
+		      "<Test dir='descending'><Item g='2' h='x' id='1'/><Item g='1' h='x' id='2'/><Item g='2' h='y' id='3'/><Item g='1' h='x' id='4'/><Item g='1' id='5'/></Test>", |_| {}).expect("transformation failed"),
+	    "24531"
+	)
+    }
+    #[test]
+    fn sort_apply_templates() {
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='child::Test'><xsl:apply-templates><xsl:sort select='.' order='descending'/></xsl:apply-templates>|<xsl:apply-templates select='child::Item'><xsl:with-param name='p' select='1'/><xsl:sort select='.'/></xsl:apply-templates></xsl:template>\n  <xsl:template match='child::Item'><xsl:sequence select='string(.)'/></xsl:template>
+</xsl:stylesheet>",
+		      "<Test><Item>b</Item><Item>c</Item><Item>a</Item></Test>", |_| {}).expect("transformation failed"),
+	    "cba|abc"
+	)
+    }
+    #[test]
+    fn sort_for_each_group() {
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='child::Test'><xsl:for-each-group select='child::Item' group-by='attribute::g'><xsl:sort select='current-grouping-key()'/><xsl:sequence select='current-grouping-key()'/>:<xsl:sequence select='count(current-group())'/>,</xsl:for-each-group></xsl:template>
+</xsl:stylesheet>",
+		      "<Test><Item g='c'/><Item g='a'/><Item g='b'/><Item g='a'/></Test>", |_| {}).expect("transformation failed"),
+	    "a:2,b:1,c:1,"
+	)
+    }
+    #[test]
+    fn perform_sort() {
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='/'><xsl:perform-sort select='(3, 1, 2)'><xsl:sort select='.'/></xsl:perform-sort>|<xsl:perform-sort><xsl:sort order='descending'/><xsl:sequence select='\"x\"'/><xsl:sequence select='\"z\"'/><xsl:sequence select='\"y\"'/></xsl:perform-sort></xsl:template>
+</xsl:stylesheet>",
+		      "<Test/>", |_| {}).expect("transformation failed"),
+	    "123|zyx"
+	)
+    }
+    #[test]
+    fn sort_collation() {
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='child::Test'><xsl:for-each select='child::Item'><xsl:sort select='.'/><xsl:sequence select='string(.)'/></xsl:for-each>|<xsl:for-each select='child::Item'><xsl:sort select='.' collation='http://www.w3.org/2005/xpath-functions/collation/html-ascii-case-insensitive'/><xsl:sequence select='string(.)'/></xsl:for-each>|<xsl:for-each select='child::Item'><xsl:sort select='.' collation='http://www.w3.org/2005/xpath-functions/collation/html-ascii-case-insensitive' case-order='lower-first'/><xsl:sequence select='string(.)'/></xsl:for-each></xsl:template>
+</xsl:stylesheet>",
+		      "<Test><Item>b</Item><Item>A</Item><Item>B</Item><Item>a</Item></Test>", |_| {}).expect("transformation failed"),
+	    "ABab|AabB|aAbB"
+	)
+    }
+    #[test]
+    fn sort_invalid_order() {
+	assert!(
+	    matches!(
+		transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='/'><xsl:perform-sort select='(3, 1, 2)'><xsl:sort order='up'/></xsl:perform-sort></xsl:template>
+</xsl:stylesheet>",
+			  "<Test/>", |_| {}),
+		Err(Error{kind: ErrorKind::InvalidAttributeValue, ..})
+	    )
+	)
+    }
+    #[test]
+    fn key_lookup() {
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:key name='k' match='child::Item' use='attribute::g'/>\n  <xsl:template match='child::Test'><xsl:for-each select='key(\"k\", \"a\")'><xsl:sequence select='string(attribute::id)'/></xsl:for-each>|<xsl:sequence select='count(key(\"k\", (\"a\", \"b\")))'/>|<xsl:sequence select='count(key(\"k\", \"z\"))'/></xsl:template>
+</xsl:stylesheet>",
+		      "<Test><Item g='a' id='1'/><Item g='b' id='2'/><Group><Item g='a' id='3'/></Group></Test>", |_| {}).expect("transformation failed"),
+	    "13|3|0"
+	)
+    }
+    #[test]
+    fn key_context_item() {
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:key name='k' match='child::Item' use='attribute::g'/>\n  <xsl:template match='child::Test'><xsl:for-each select='child::Item'><xsl:sequence select='string(attribute::g)'/>:<xsl:sequence select='count(key(\"k\", attribute::g))'/>,</xsl:for-each></xsl:template>
+</xsl:stylesheet>",
+		      "<Test><Item g='a'/><Item g='b'/><Item g='a'/><Item g='c'/><Item g='b'/></Test>", |_| {}).expect("transformation failed"),
+	    "a:2,b:2,a:2,c:1,b:2,"
+	)
+    }
+    #[test]
+    fn key_top() {
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:key name='k' match='child::Item' use='.'/>\n  <xsl:template match='child::Test'><xsl:sequence select='count(key(\"k\", \"x\", child::Group))'/>|<xsl:sequence select='count(key(\"k\", \"x\"))'/></xsl:template>
+</xsl:stylesheet>",
+		      "<Test><Item>x</Item><Group><Item>x</Item></Group></Test>", |_| {}).expect("transformation failed"),
+	    "1|2"
+	)
+    }
+    #[test]
+    fn key_composite() {
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:key name='k' match='child::Item' use='(attribute::a, attribute::b)' composite='yes'/>\n  <xsl:key name='n' match='child::Item' use='count(attribute::*)'/>\n  <xsl:template match='child::Test'><xsl:sequence select='count(key(\"k\", (\"1\", \"2\")))'/>|<xsl:sequence select='count(key(\"k\", \"1\"))'/>|<xsl:sequence select='count(key(\"n\", 2.0))'/></xsl:template>
+</xsl:stylesheet>",
+		      "<Test><Item a='1' b='2'/><Item a='1' b='3'/></Test>", |_| {}).expect("transformation failed"),
+	    "1|0|2"
+	)
+    }
+    #[test]
+    fn key_collation() {
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:key name='k' match='child::Item' use='.' collation='http://www.w3.org/2005/xpath-functions/collation/html-ascii-case-insensitive'/>\n  <xsl:template match='child::Test'><xsl:sequence select='count(key(\"k\", \"ABC\"))'/></xsl:template>
+</xsl:stylesheet>",
+		      "<Test><Item>abc</Item><Item>Abc</Item><Item>abd</Item></Test>", |_| {}).expect("transformation failed"),
+	    "2"
+	)
+    }
+    #[test]
+    fn key_unknown() {
+	assert!(
+	    matches!(
+		transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='/'><xsl:sequence select='key(\"k\", 1)'/></xsl:template>
+</xsl:stylesheet>",
+			  "<Test/>", |_| {}),
+		Err(Error{kind: ErrorKind::NoSuchKey, ..})
+	    )
+	)
+    }
+
+    #[test]
+    fn key_prefixed() {
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform' xmlns:a='urn:x' xmlns:b='urn:x' xmlns:c='urn:y'>
+  <xsl:key name='a:k' match='child::Item' use='attribute::g'/>\n  <xsl:key name='c:k' match='child::Item' use='attribute::h'/>\n  <xsl:template match='child::Test'><xsl:sequence select='count(key(\"b:k\", \"1\"))'/>|<xsl:sequence select='count(key(\"c:k\", \"1\"))'/></xsl:template>
+</xsl:stylesheet>",
+		      "<Test><Item g='1' h='2'/><Item g='1' h='1'/></Test>", |_| {}).expect("transformation failed"),
+	    "2|1"
+	)
+    }
+    #[test]
+    fn namespace_in_scope() {
+	// Prefixes declared inside the stylesheet may be used in expressions within the scope of the declaration
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:key name='k:idx' xmlns:k='urn:k' match='child::Item' use='attribute::g'/>
+  <xsl:template match='child::Test' xmlns:q='urn:k'><xsl:variable name='q:v' select='count(key(\"q:idx\", \"1\"))'/><e xmlns:m='http://www.w3.org/2005/xpath-functions/math'><xsl:sequence select='m:sqrt(16)'/>|<xsl:sequence select='$q:v'/></e><f xmlns:m='urn:m'><xsl:variable name='m:w' select='2'/><xsl:sequence select='$m:w'/></f></xsl:template>
+</xsl:stylesheet>",
+		      "<Test><Item g='1'/><Item g='1'/><Item g='2'/></Test>", |_| {}).expect("transformation failed"),
+	    "<e>4|2</e><f>2</f>"
+	)
+    }
+    #[test]
+    fn key_per_stylesheet() {
+	// Two stylesheets define a key with the same name, and are applied to the same source document
+	let mut f = Forest::new();
+	let src = f.grow_tree("<Test><Item a='1' b='2'/><Item a='2' b='1'/></Test>").expect("unable to parse XML");
+	let isrc = Rc::new(Item::Node(f.get_ref(src).unwrap().get_doc_node()));
+	let mut results = vec![];
+	for u in vec!["attribute::a", "attribute::b"] {
+	    let mut sc = StaticContext::new_with_xslt_builtins();
+	    let style = f.grow_tree(format!("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:key name='k' match='child::Item' use='{}'/>\n  <xsl:template match='child::Test'><xsl:sequence select='string(key(\"k\", \"1\")/attribute::a)'/></xsl:template>
+</xsl:stylesheet>", u).as_str()).expect("unable to parse XML");
+	    let ev = from_document(&mut f, style, &mut sc, None).expect("unable to compile stylesheet");
+	    let rd = f.plant_tree();
+	    let t = ev.find_match(&isrc, &mut f, src, rd, None).expect("no template");
+	    let seq = ev.evaluate(Some(vec![Rc::clone(&isrc)]), Some(0), &t, &mut f, src, rd).expect("transformation failed");
+	    results.push(seq.to_xml(Some(&f)))
+	}
+	assert_eq!(results, vec!["1", "2"])
+    }
+
+    #[test]
+    fn value_of() {
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='/'><e><xsl:value-of select='(1, 2, 3)'/>|<xsl:value-of select='(1, 2, 3)' separator='-'/>|<xsl:value-of>a<xsl:sequence select='1'/><xsl:sequence select='2'/></xsl:value-of></e></xsl:template>
+</xsl:stylesheet>",
+		      "<Test><a>x</a><a>y</a></Test>", |_| {}).expect("transformation failed"),
+	    "<e>1 2 3|1-2-3|a12</e>"
+	);
+    }
+    #[test]
+    fn value_of_attributes() {
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='/'><e><xsl:value-of select='child::Test/child::a' separator=','/></e></xsl:template>
+</xsl:stylesheet>",
+		      "<Test><a>x</a><a>y</a></Test>", |_| {}).expect("transformation failed"),
+	    "<e>x,y</e>"
+	);
+    }
+    #[test]
+    fn text_node_construction() {
+	// Adjacent atomic values are separated by a space, but not from text nodes
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='/'><e>x<xsl:sequence select='1'/><xsl:sequence select='2'/><xsl:text>y</xsl:text></e></xsl:template>
+</xsl:stylesheet>",
+		      "<Test/>", |_| {}).expect("transformation failed"),
+	    "<e>x1 2y</e>"
+	);
+	// Zero-length text nodes are discarded and adjacent text nodes are merged
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='/'><xsl:variable name='v'><e>a<xsl:value-of select='()'/>b<xsl:value-of select=\"''\"/></e><f><xsl:value-of select='()'/></f></xsl:variable><xsl:value-of select='count($v/child::e/child::node())'/>|<xsl:value-of select='count($v/child::f/child::node())'/></xsl:template>
+</xsl:stylesheet>",
+		      "<Test/>", |_| {}).expect("transformation failed"),
+	    "1|0"
+	);
+    }
+    #[test]
+    fn message() {
+	let log = Rc::new(MessageLog::new());
+	let result = transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='/'><xsl:message>found <m><xsl:value-of select='count(child::Test/child::a)'/></m></xsl:message><e/><xsl:message select='(1, 2)'/></xsl:template>
+</xsl:stylesheet>",
+		      "<Test><a/><a/></Test>", |ev| ev.set_message_listener(log.clone()));
+	let messages = log.messages();
+	assert_eq!(result.expect("transformation failed"), "<e></e>");
+	assert_eq!(messages.iter().map(|m| m.content.clone()).collect::<Vec<String>>(), vec!["found <m>2</m>", "1 2"]);
+	assert!(messages.iter().all(|m| !m.terminate));
+	assert_eq!(messages[0].error_code.get_nsuri_ref(), Some(ERRNS));
+	assert_eq!(messages[0].error_code.get_localname(), "XTMM9000");
+    }
+    #[test]
+    fn message_terminate() {
+	let log = Rc::new(MessageLog::new());
+	let result = transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform' xmlns:my='http://example.org/errors'>
+  <xsl:template match='/'><e><xsl:message terminate='{if (child::Test) then \"yes\" else \"no\"}' error-code='my:E{1 + 1}'>stop</xsl:message></e></xsl:template>
+</xsl:stylesheet>",
+		      "<Test/>", |ev| ev.set_message_listener(log.clone()));
+	let messages = log.messages();
+	assert!(matches!(result, Err(Error{kind: ErrorKind::Terminated, ..})));
+	assert_eq!(messages.len(), 1);
+	assert!(messages[0].terminate);
+	assert_eq!(messages[0].content, "stop");
+	assert_eq!(messages[0].error_code.get_nsuri_ref(), Some("http://example.org/errors"));
+	assert_eq!(messages[0].error_code.get_localname(), "E2");
+    }
+    #[test]
+    fn message_invalid() {
+	// An invalid error code is replaced by the default
+	let log = Rc::new(MessageLog::new());
+	let result = transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='/'><xsl:message terminate='yes' error-code='not a name'>stop</xsl:message></xsl:template>
+</xsl:stylesheet>",
+		      "<Test/>", |ev| ev.set_message_listener(log.clone()));
+	let messages = log.messages();
+	assert!(matches!(result, Err(Error{kind: ErrorKind::Terminated, ..})));
+	assert_eq!(messages[0].error_code.get_localname(), "XTMM9000");
+	let log = Rc::new(MessageLog::new());
+	let result = transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='/'><xsl:message terminate='maybe'>stop</xsl:message></xsl:template>
+</xsl:stylesheet>",
+		      "<Test/>", |ev| ev.set_message_listener(log.clone()));
+	let messages = log.messages();
+	assert!(matches!(result, Err(Error{kind: ErrorKind::InvalidAttributeValue, ..})));
+	assert!(messages.is_empty());
+    }
+    #[test]
+    fn message_terminate_stops() {
+	// Nothing after a terminating message is evaluated
+	let log = Rc::new(MessageLog::new());
+	let result = transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='/'><xsl:message>before</xsl:message><e><xsl:message terminate='yes'>stop</xsl:message><xsl:message>inside</xsl:message></e><xsl:message>after</xsl:message></xsl:template>
+</xsl:stylesheet>",
+		      "<Test/>", |ev| ev.set_message_listener(log.clone()));
+	let messages = log.messages();
+	assert!(matches!(result, Err(Error{kind: ErrorKind::Terminated, ..})));
+	assert_eq!(messages.iter().map(|m| m.content.clone()).collect::<Vec<String>>(), vec!["before", "stop"]);
+    }
+    #[test]
+    fn result_document() {
+	let h = Rc::new(MemoryHandler::new());
+	let result = transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='/'><index><xsl:apply-templates select='child::site/child::page'/></index></xsl:template>
+  <xsl:template match='child::page'><xsl:result-document href='pages/{string(attribute::name)}.html'><h1><xsl:value-of select='.'/></h1></xsl:result-document><link/></xsl:template>
+</xsl:stylesheet>",
+		      "<site><page name='a'>Alpha</page><page name='b'>Beta</page></site>", |ev| {
+	    ev.set_result_document_handler(h.clone());
+	    ev.set_base_output_uri(Url::parse("file:///out/index.xml").expect("unable to parse URL"));
+	});
+	let docs = h.documents();
+	assert_eq!(result.expect("transformation failed"), "<index><link></link><link></link></index>");
+	assert_eq!(docs.iter().map(|(u, d)| (u.as_str(), d.as_str())).collect::<Vec<(&str, &str)>>(), vec![
+	    ("file:///out/pages/a.html", "<h1>Alpha</h1>"),
+	    ("file:///out/pages/b.html", "<h1>Beta</h1>"),
+	]);
+    }
+    #[test]
+    fn result_document_terminate() {
+	// No result document is produced after the transformation is terminated
+	let h = Rc::new(MemoryHandler::new());
+	let log = Rc::new(MessageLog::new());
+	let result = transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='/'><index><xsl:apply-templates select='child::site/child::page'/></index><xsl:result-document href='last.html'><end/></xsl:result-document></xsl:template>
+  <xsl:template match='child::page'><xsl:if test='attribute::name eq \"b\"'><xsl:message terminate='yes'>stop</xsl:message></xsl:if><xsl:result-document href='pages/{string(attribute::name)}.html'><h1><xsl:value-of select='.'/></h1></xsl:result-document></xsl:template>
+</xsl:stylesheet>",
+		      "<site><page name='a'>Alpha</page><page name='b'>Beta</page><page name='c'>Gamma</page></site>", |ev| {
+	    ev.set_result_document_handler(h.clone());
+	    ev.set_message_listener(log.clone());
+	    ev.set_base_output_uri(Url::parse("file:///out/index.xml").expect("unable to parse URL"));
+	});
+	let docs = h.documents();
+	assert!(matches!(result, Err(Error{kind: ErrorKind::Terminated, ..})));
+	assert_eq!(docs.iter().map(|(u, _)| u.as_str()).collect::<Vec<&str>>(), vec!["file:///out/pages/a.html"]);
+    }
+    #[test]
+    fn result_document_format() {
+	let h = Rc::new(MemoryHandler::new());
+	let result = transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:output name='plain' method='text'/>
+  <xsl:template match='/'><xsl:result-document href='a.txt' format='plain'><e>one</e></xsl:result-document><xsl:result-document href='b.xml'><e>two</e></xsl:result-document><xsl:result-document href='c.txt' method='{\"text\"}'><e>three</e></xsl:result-document></xsl:template>
+</xsl:stylesheet>",
+		      "<Test/>", |ev| {
+	    ev.set_result_document_handler(h.clone());
+	    ev.set_base_output_uri(Url::parse("file:///out/").expect("unable to parse URL"));
+	});
+	let docs = h.documents();
+	assert_eq!(result.expect("transformation failed"), "");
+	assert_eq!(docs.iter().map(|(_, d)| d.clone()).collect::<Vec<String>>(), vec!["one", "<e>two</e>", "three"]);
+    }
+    #[test]
+    fn result_document_errors() {
+	// Two result documents with the same URI
+	let h = Rc::new(MemoryHandler::new());
+	let result = transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='/'><xsl:result-document href='a.xml'><e/></xsl:result-document><xsl:result-document href='sub/../a.xml'><e/></xsl:result-document></xsl:template>
+</xsl:stylesheet>",
+		      "<Test/>", |ev| {
+	    ev.set_result_document_handler(h.clone());
+	    ev.set_base_output_uri(Url::parse("file:///out/").expect("unable to parse URL"));
+	});
+	let docs = h.documents();
+	assert!(matches!(result, Err(Error{kind: ErrorKind::DuplicateResultURI, ..})));
+	assert_eq!(docs.len(), 1);
+	// No such output definition
+	let h = Rc::new(MemoryHandler::new());
+	let result = transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='/'><xsl:result-document href='a.xml' format='none'><e/></xsl:result-document></xsl:template>
+</xsl:stylesheet>",
+		      "<Test/>", |ev| {
+	    ev.set_result_document_handler(h.clone());
+	    ev.set_base_output_uri(Url::parse("file:///out/").expect("unable to parse URL"));
+	});
+	assert!(matches!(result, Err(Error{kind: ErrorKind::NoSuchOutputDefinition, ..})));
+	// A relative URI with no base output URI
+	let h = Rc::new(MemoryHandler::new());
+	let result = transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='/'><xsl:result-document href='a.xml'><e/></xsl:result-document></xsl:template>
+</xsl:stylesheet>",
+		      "<Test/>", |ev| ev.set_result_document_handler(h.clone()));
+	assert!(matches!(result, Err(Error{kind: ErrorKind::InvalidAttributeValue, ..})));
+    }
+    #[test]
+    fn result_document_handler() {
+	let mut sc = StaticContext::new_with_xslt_builtins();
+	let mut f = Forest::new();
+	let src = f.grow_tree("<Test/>").expect("unable to parse XML");
+	let isrc = Rc::new(Item::Node(f.get_ref(src).unwrap().get_doc_node()));
+	let style = f.grow_tree("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='/'><xsl:result-document href='a.xml'><e/></xsl:result-document></xsl:template>
+</xsl:stylesheet>").expect("unable to parse XML");
+	let mut ev = from_document(&mut f, style, &mut sc, None).expect("unable to compile stylesheet");
+	ev.set_base_output_uri(Url::parse("file:///out/").expect("unable to parse URL"));
+	let rd = f.plant_tree();
+	let t = ev.find_match(&isrc, &mut f, src, rd, None).expect("no template");
+	// By default result documents are rejected
+	assert!(matches!(
+	    ev.evaluate(Some(vec![Rc::clone(&isrc)]), Some(0), &t, &mut f, src, rd),
+	    Err(Error{kind: ErrorKind::ResultDocumentRejected, ..})
+	));
+	// Each transformation may produce a result document with the same URI
+	let h = Rc::new(MemoryHandler::new());
+	ev.set_result_document_handler(h.clone());
+	ev.evaluate(Some(vec![Rc::clone(&isrc)]), Some(0), &t, &mut f, src, rd).expect("transformation failed");
+	ev.evaluate(Some(vec![Rc::clone(&isrc)]), Some(0), &t, &mut f, src, rd).expect("transformation failed");
+	assert_eq!(h.documents().len(), 2);
+    }
+    #[test]
+    fn output_method() {
+	// Unsupported and invalid methods are rejected in the same way by xsl:output and xsl:result-document
+	for (style, kind) in vec![
+	    ("<xsl:output method='html'/>\n  <xsl:template match='/'><e/></xsl:template>", ErrorKind::NotImplemented),
+	    ("<xsl:output method='csv'/>\n  <xsl:template match='/'><e/></xsl:template>", ErrorKind::InvalidAttributeValue),
+	    ("<xsl:output indent='maybe'/>\n  <xsl:template match='/'><e/></xsl:template>", ErrorKind::InvalidAttributeValue),
+	    ("<xsl:template match='/'><xsl:result-document href='a.html' method='html'><e/></xsl:result-document></xsl:template>", ErrorKind::NotImplemented),
+	    ("<xsl:template match='/'><xsl:result-document href='a.csv' method='csv'><e/></xsl:result-document></xsl:template>", ErrorKind::InvalidAttributeValue),
+	] {
+	    let h = Rc::new(MemoryHandler::new());
+	    let result = transform(format!("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>\n  {}\n</xsl:stylesheet>", style).as_str(),
+				   "<Test/>", |ev| {
+		ev.set_result_document_handler(h.clone());
+		ev.set_base_output_uri(Url::parse("file:///out/").expect("unable to parse URL"));
+	    });
+	    match result {
+		Err(e) => assert_eq!(e.kind.to_string(), kind.to_string(), "{}", style),
+		Ok(_) => panic!("method was accepted: {}", style),
+	    }
+	}
+    }
+    #[test]
+    fn prefixed_function() {
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform' xmlns:f='http://www.w3.org/2005/xpath-functions'>
+  <xsl:template match='/'><xsl:sequence select='f:concat(\"a\", \"b\")'/></xsl:template>
+</xsl:stylesheet>",
+		      "<Test/>", |_| {}).expect("transformation failed"),
+	    "ab"
+	);
+	assert!(
+	    matches!(
+		transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:template match='/'><xsl:sequence select='foo:bar()'/></xsl:template>
+</xsl:stylesheet>",
+			  "<Test/>", |_| {}),
+		Err(Error{kind: ErrorKind::UnknownPrefix, ..})
+	    )
+	);
+	assert!(
+	    matches!(
+		transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform' xmlns:foo='http://example.org/foo'>
+  <xsl:template match='/'><xsl:sequence select='foo:bar()'/></xsl:template>
+</xsl:stylesheet>",
+			  "<Test/>", |_| {}),
+		Err(Error{kind: ErrorKind::StaticBadFunction, ..})
+	    )
+	);
+    }
+    #[test]
+    fn variable_scope() {
+	// A called template does not see the local variables of its caller
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:variable name='x' select='\"global\"'/>
+  <xsl:template match='/'><xsl:variable name='x' select='\"local\"'/><xsl:call-template name='show'/><xsl:apply-templates select='child::Test'/></xsl:template>
+  <xsl:template name='show'>[<xsl:sequence select='$x'/>]</xsl:template>
+  <xsl:template match='child::Test'>(<xsl:sequence select='$x'/>)</xsl:template>
+</xsl:stylesheet>",
+		      "<Test/>", |_| {}).expect("transformation failed"),
+	    "[global](global)"
+	);
+    }
+    #[test]
+    fn variable_namespace() {
+	assert_eq!(
+	    transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform' xmlns:my='http://example.org/my' xmlns:other='http://example.org/my'>
+  <xsl:variable name='my:v' select='\"global\"'/>
+  <xsl:template match='/'><xsl:call-template name='show'><xsl:with-param name='my:p' select='\"param\"'/></xsl:call-template></xsl:template>
+  <xsl:template name='show'><xsl:param name='other:p'/><xsl:variable name='my:w' select='\"local\"'/><xsl:sequence select='$other:v'/>,<xsl:sequence select='$my:p'/>,<xsl:sequence select='$other:w'/></xsl:template>
+</xsl:stylesheet>",
+		      "<Test/>", |_| {}).expect("transformation failed"),
+	    "global,param,local"
+	);
+    }
+    #[test]
+    fn variable_reuse_evaluator() {
+	// Global variables are evaluated again for each transformation
+	let mut sc = StaticContext::new_with_xslt_builtins();
+	let mut f = Forest::new();
+	let style = f.grow_tree("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:param name='p' select='0'/>
+  <xsl:variable name='n' select='count(child::Test/child::a) + $p'/>
+  <xsl:template match='/'><xsl:sequence select='$n'/></xsl:template>
+</xsl:stylesheet>").expect("unable to parse XML");
+	let mut ev = from_document(&mut f, style, &mut sc, None).expect("failed to compile stylesheet");
+	let mut run = |ev: &Evaluator, src: &str| {
+	    let src = f.grow_tree(src).expect("unable to parse XML");
+	    let isrc = Rc::new(Item::Node(f.get_ref(src).unwrap().get_doc_node()));
+	    let rd = f.plant_tree();
+	    let t = ev.find_match(&isrc, &mut f, src, rd, None).expect("unable to find match");
+	    ev.evaluate(Some(vec![Rc::clone(&isrc)]), Some(0), &t, &mut f, src, rd).expect("evaluation failed").to_string(Some(&f))
+	};
+	assert_eq!(run(&ev, "<Test><a/></Test>"), "1");
+	assert_eq!(run(&ev, "<Test><a/><a/></Test>"), "2");
+	ev.set_parameter(QualifiedName::new(None, None, String::from("p")), vec![Rc::new(Item::Value(Value::Integer(10)))]);
+	assert_eq!(run(&ev, "<Test><a/><a/></Test>"), "12");
+    }
+    #[test]
+    fn attribute_set_circular() {
+	assert!(
+	    matches!(
+		transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:attribute-set name='s' use-attribute-sets='t'/>
+  <xsl:attribute-set name='t' use-attribute-sets='s'/>
+  <xsl:template match='/'><e xsl:use-attribute-sets='s'/></xsl:template>
+</xsl:stylesheet>",
+			  "<Test/>", |_| {}),
+		Err(Error{kind: ErrorKind::CircularAttributeSet, ..})
+	    )
+	);
+	// The use may be by an instruction in the content of the attribute set
+	assert!(
+	    matches!(
+		transform("<xsl:stylesheet xmlns:xsl='http://www.w3.org/1999/XSL/Transform'>
+  <xsl:attribute-set name='s'><xsl:attribute name='a'><xsl:element name='x' use-attribute-sets='s'/></xsl:attribute></xsl:attribute-set>
+  <xsl:template match='/'><e/></xsl:template>
+</xsl:stylesheet>",
+			  "<Test/>", |_| {}),
+		Err(Error{kind: ErrorKind::CircularAttributeSet, ..})
+	    )
+	);
+    }
 }